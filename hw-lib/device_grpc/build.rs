@@ -1,4 +1,11 @@
+use std::env;
+use std::path::PathBuf;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/devices.proto")?;
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("devices_descriptor.bin"))
+        .compile(&["proto/devices.proto"], &["proto"])?;
     Ok(())
 }
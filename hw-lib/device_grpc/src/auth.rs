@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use thiserror::Error;
+use tonic::metadata::{AsciiMetadataValue, MetadataMap};
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+const AUTHORIZATION: &str = "authorization";
+const BEARER: &str = "Bearer ";
+
+/// Level of access granted to a token.
+///
+/// `Control` includes everything `Read` allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Permission {
+    Read,
+    Control,
+}
+
+impl Permission {
+    pub fn allows(&self, required: Permission) -> bool {
+        *self >= required
+    }
+}
+
+impl FromStr for Permission {
+    type Err = AuthError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" | "read-only" | "readonly" => Ok(Permission::Read),
+            "control" => Ok(Permission::Control),
+            _ => Err(AuthError::Permission {
+                name: s.to_string(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("Cannot read the token file {path:?}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Unknown permission {name:?}")]
+    Permission { name: String },
+    #[error("Invalid token file line {line}: {reason}")]
+    Line { line: usize, reason: String },
+}
+
+/// Static set of accepted bearer tokens.
+///
+/// ## File format
+///
+/// One token per line, optionally followed by its permission (`read` or `control`).
+/// A token without permission gets `control`. Empty lines and lines starting with `#` are skipped.
+///
+/// ```text
+/// # dashboard
+/// 3f1c0e0d read
+/// # hub
+/// 9b7a52e4 control
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TokenStore {
+    tokens: Arc<HashMap<String, Permission>>,
+}
+
+impl TokenStore {
+    pub fn new(tokens: HashMap<String, Permission>) -> Self {
+        TokenStore {
+            tokens: Arc::new(tokens),
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, AuthError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(|source| AuthError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        content.parse()
+    }
+
+    pub fn permission(&self, token: &str) -> Option<Permission> {
+        self.tokens.get(token).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
+
+impl FromStr for TokenStore {
+    type Err = AuthError;
+
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        let mut tokens = HashMap::new();
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let token = parts.next().unwrap_or_default();
+            let permission = match parts.next() {
+                Some(name) => name.parse()?,
+                None => Permission::Control,
+            };
+            if parts.next().is_some() {
+                return Err(AuthError::Line {
+                    line: idx + 1,
+                    reason: "expected `<token> [permission]`".to_string(),
+                });
+            }
+            if tokens.insert(token.to_string(), permission).is_some() {
+                return Err(AuthError::Line {
+                    line: idx + 1,
+                    reason: "duplicate token".to_string(),
+                });
+            }
+        }
+        Ok(TokenStore::new(tokens))
+    }
+}
+
+/// Server side interceptor checking the `authorization: Bearer <token>` header.
+///
+/// The permission of an accepted token is stored in the request extensions,
+/// services check it with [`require`].
+#[derive(Debug, Clone)]
+pub struct AuthInterceptor {
+    tokens: TokenStore,
+}
+
+impl AuthInterceptor {
+    pub fn new(tokens: TokenStore) -> Self {
+        AuthInterceptor { tokens }
+    }
+}
+
+impl Interceptor for AuthInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let token = bearer_token(request.metadata())
+            .ok_or_else(|| Status::unauthenticated("missing bearer token"))?;
+        let permission = self
+            .tokens
+            .permission(token)
+            .ok_or_else(|| Status::unauthenticated("invalid bearer token"))?;
+        request.extensions_mut().insert(permission);
+        Ok(request)
+    }
+}
+
+fn bearer_token(metadata: &MetadataMap) -> Option<&str> {
+    metadata
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix(BEARER)
+}
+
+/// Checks that the caller of `request` has at least `required` permission.
+///
+/// Requests which did not pass through [`AuthInterceptor`] carry no permission
+/// and are allowed: the service is running without authentication.
+#[allow(clippy::result_large_err)]
+pub fn require<T>(request: &Request<T>, required: Permission) -> Result<(), Status> {
+    match request.extensions().get::<Permission>() {
+        Some(permission) if !permission.allows(required) => Err(Status::permission_denied(
+            format!("{:?} permission required", required),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Client side interceptor adding `authorization: Bearer <token>` to every request.
+#[derive(Debug, Clone)]
+pub struct BearerToken {
+    value: AsciiMetadataValue,
+}
+
+impl BearerToken {
+    #[allow(clippy::result_large_err)]
+    pub fn new(token: &str) -> Result<Self, Status> {
+        let value = format!("{}{}", BEARER, token)
+            .parse()
+            .map_err(|_| Status::invalid_argument("token is not a valid header value"))?;
+        Ok(BearerToken { value })
+    }
+}
+
+impl Interceptor for BearerToken {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        request
+            .metadata_mut()
+            .insert(AUTHORIZATION, self.value.clone());
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    use tonic::Code;
+
+    #[test]
+    fn parse_tokens() {
+        let tokens: TokenStore = "# comment\n\nreader read\nhub control\nadmin\n"
+            .parse()
+            .unwrap();
+
+        assert_eq!(tokens.len(), 3);
+        assert_eq!(tokens.permission("reader"), Some(Permission::Read));
+        assert_eq!(tokens.permission("hub"), Some(Permission::Control));
+        assert_eq!(tokens.permission("admin"), Some(Permission::Control));
+        assert_eq!(tokens.permission("unknown"), None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(matches!(
+            "token superuser".parse::<TokenStore>(),
+            Err(AuthError::Permission { .. })
+        ));
+        assert!(matches!(
+            "token read extra".parse::<TokenStore>(),
+            Err(AuthError::Line { line: 1, .. })
+        ));
+        assert!(matches!(
+            "token\ntoken read".parse::<TokenStore>(),
+            Err(AuthError::Line { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn from_file() {
+        let path = std::env::temp_dir().join(format!("device_grpc_tokens_{}", std::process::id()));
+        fs::write(&path, "reader read\n").unwrap();
+
+        let tokens = TokenStore::from_file(&path).unwrap();
+        assert_eq!(tokens.permission("reader"), Some(Permission::Read));
+
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            TokenStore::from_file(&path),
+            Err(AuthError::Read { .. })
+        ));
+    }
+
+    #[test]
+    fn interceptor() {
+        let mut interceptor = AuthInterceptor::new("reader read".parse().unwrap());
+
+        let status = interceptor.call(Request::new(())).unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let request = BearerToken::new("wrong")
+            .unwrap()
+            .call(Request::new(()))
+            .unwrap();
+        let status = interceptor.call(request).unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let request = BearerToken::new("reader")
+            .unwrap()
+            .call(Request::new(()))
+            .unwrap();
+        let request = interceptor.call(request).unwrap();
+        assert!(require(&request, Permission::Read).is_ok());
+        assert_eq!(
+            require(&request, Permission::Control).unwrap_err().code(),
+            Code::PermissionDenied
+        );
+
+        // without interceptor authentication is disabled
+        assert!(require(&Request::new(()), Permission::Control).is_ok());
+    }
+}
@@ -0,0 +1,465 @@
+use std::future::Future;
+use std::time::Duration;
+
+use thiserror::Error;
+use tonic::codegen::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tonic::{Code, Request, Status};
+
+use crate::auth::BearerToken;
+use crate::devices::device_control_client::DeviceControlClient;
+use crate::devices::{DeviceStatus, Empty, Toggle};
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("Invalid device url {url:?}")]
+    InvalidUrl { url: String },
+    #[error(transparent)]
+    Transport(#[from] tonic::transport::Error),
+    #[error("Device is unavailable: {message}")]
+    Unavailable { message: String },
+    #[error("Request timed out after {timeout:?}")]
+    Timeout { timeout: Duration },
+    #[error("Authentication failed: {message}")]
+    Unauthenticated { message: String },
+    #[error("Permission denied: {message}")]
+    PermissionDenied { message: String },
+    #[error("Invalid argument: {message}")]
+    InvalidArgument { message: String },
+    #[error("Device returned {code:?}: {message}")]
+    Status { code: Code, message: String },
+}
+
+impl From<Status> for ClientError {
+    fn from(status: Status) -> Self {
+        let message = status.message().to_string();
+        match status.code() {
+            Code::Unavailable => ClientError::Unavailable { message },
+            Code::Unauthenticated => ClientError::Unauthenticated { message },
+            Code::PermissionDenied => ClientError::PermissionDenied { message },
+            Code::InvalidArgument => ClientError::InvalidArgument { message },
+            code => ClientError::Status { code, message },
+        }
+    }
+}
+
+/// Retries of requests failed with `UNAVAILABLE`.
+///
+/// The delay before retry `n` is `initial_backoff * 2^n`, capped by `max_backoff`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            ..RetryPolicy::default()
+        }
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2_u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+// Adds the bearer token when the client has one.
+#[derive(Debug, Clone)]
+pub struct ClientAuth(Option<BearerToken>);
+
+impl Interceptor for ClientAuth {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        match &mut self.0 {
+            Some(token) => token.call(request),
+            None => Ok(request),
+        }
+    }
+}
+
+pub struct DeviceClientBuilder {
+    url: String,
+    timeout: Duration,
+    connect_timeout: Duration,
+    retry: RetryPolicy,
+    token: Option<String>,
+    tls: Option<ClientTlsConfig>,
+}
+
+impl DeviceClientBuilder {
+    /// Deadline of a single attempt, retries get their own deadline.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Enables TLS, the url must use the `https` scheme.
+    pub fn tls(mut self, tls: ClientTlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub async fn connect(self) -> Result<DeviceClient, ClientError> {
+        let mut endpoint = Endpoint::from_shared(self.url.clone())
+            .map_err(|_| ClientError::InvalidUrl { url: self.url })?
+            .connect_timeout(self.connect_timeout);
+        if let Some(tls) = self.tls {
+            endpoint = endpoint.tls_config(tls)?;
+        }
+        let channel = endpoint.connect().await?;
+        DeviceClient::from_channel(channel, self.token.as_deref())
+            .map(|client| client.with_retry(self.retry).with_timeout(self.timeout))
+    }
+}
+
+/// Client of a device `DeviceControl` service.
+///
+/// Clones share one HTTP/2 connection.
+#[derive(Debug, Clone)]
+pub struct DeviceClient {
+    inner: DeviceControlClient<InterceptedService<Channel, ClientAuth>>,
+    timeout: Duration,
+    retry: RetryPolicy,
+}
+
+impl DeviceClient {
+    pub fn builder(url: impl Into<String>) -> DeviceClientBuilder {
+        DeviceClientBuilder {
+            url: url.into(),
+            timeout: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(5),
+            retry: RetryPolicy::default(),
+            token: None,
+            tls: None,
+        }
+    }
+
+    pub async fn connect(url: impl Into<String>) -> Result<Self, ClientError> {
+        DeviceClient::builder(url).connect().await
+    }
+
+    /// Wraps an existing channel, e.g. one shared with other clients.
+    pub fn from_channel(channel: Channel, token: Option<&str>) -> Result<Self, ClientError> {
+        let token = token.map(BearerToken::new).transpose()?;
+        Ok(DeviceClient {
+            inner: DeviceControlClient::with_interceptor(channel, ClientAuth(token)),
+            timeout: Duration::from_secs(5),
+            retry: RetryPolicy::default(),
+        })
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub async fn status(&self) -> Result<DeviceStatus, ClientError> {
+        self.call(|mut client| async move { client.get_status(Request::new(Empty {})).await })
+            .await
+    }
+
+    pub async fn set_on(&self, on: bool) -> Result<(), ClientError> {
+        self.call(|mut client| async move { client.set_on(Request::new(Toggle { on })).await })
+            .await
+            .map(|_: Empty| ())
+    }
+
+    async fn call<T, F, Fut>(&self, request: F) -> Result<T, ClientError>
+    where
+        F: Fn(DeviceControlClient<InterceptedService<Channel, ClientAuth>>) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+    {
+        let mut retry = 0;
+        loop {
+            let result = tokio::time::timeout(self.timeout, request(self.inner.clone())).await;
+            let error = match result {
+                Ok(Ok(response)) => return Ok(response.into_inner()),
+                Ok(Err(status)) => ClientError::from(status),
+                Err(_) => ClientError::Timeout {
+                    timeout: self.timeout,
+                },
+            };
+            if !matches!(error, ClientError::Unavailable { .. }) || retry >= self.retry.max_retries
+            {
+                return Err(error);
+            }
+            tokio::time::sleep(self.retry.backoff(retry)).await;
+            retry += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use tokio::sync::oneshot;
+    use tokio::time::sleep;
+    use tonic::transport::Server;
+    use tonic::Response;
+
+    use crate::auth::{AuthInterceptor, TokenStore};
+    use crate::devices::device_control_server::{DeviceControl, DeviceControlServer};
+
+    #[derive(Default)]
+    struct MockDevice {
+        on: AtomicBool,
+        // количество запросов, которые ответят UNAVAILABLE
+        failures: AtomicU32,
+        calls: AtomicU32,
+        delay: Option<Duration>,
+    }
+
+    impl MockDevice {
+        async fn enter(&self) -> Result<(), Status> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if let Some(delay) = self.delay {
+                sleep(delay).await;
+            }
+            let failures = self.failures.load(Ordering::SeqCst);
+            if failures > 0 {
+                self.failures.store(failures - 1, Ordering::SeqCst);
+                return Err(Status::unavailable("warming up"));
+            }
+            Ok(())
+        }
+    }
+
+    #[tonic::async_trait]
+    impl DeviceControl for Arc<MockDevice> {
+        async fn switch(&self, request: Request<Toggle>) -> Result<Response<Empty>, Status> {
+            crate::auth::require(&request, crate::auth::Permission::Control)?;
+            self.enter().await?;
+            self.on.fetch_xor(true, Ordering::SeqCst);
+            Ok(Response::new(Empty {}))
+        }
+
+        async fn set_on(&self, request: Request<Toggle>) -> Result<Response<Empty>, Status> {
+            crate::auth::require(&request, crate::auth::Permission::Control)?;
+            self.enter().await?;
+            self.on.store(request.into_inner().on, Ordering::SeqCst);
+            Ok(Response::new(Empty {}))
+        }
+
+        async fn get_status(
+            &self,
+            request: Request<Empty>,
+        ) -> Result<Response<DeviceStatus>, Status> {
+            crate::auth::require(&request, crate::auth::Permission::Read)?;
+            self.enter().await?;
+            Ok(Response::new(DeviceStatus {
+                id: "mock".to_string(),
+                name: "mock".to_string(),
+                on: self.on.load(Ordering::SeqCst),
+                config: String::new(),
+            }))
+        }
+    }
+
+    async fn serve(
+        addr: &str,
+        device: Arc<MockDevice>,
+        tokens: Option<TokenStore>,
+    ) -> oneshot::Sender<()> {
+        let (signal_tx, signal_rx) = oneshot::channel();
+        let router = match tokens {
+            Some(tokens) => Server::builder().add_service(DeviceControlServer::with_interceptor(
+                device,
+                AuthInterceptor::new(tokens),
+            )),
+            None => Server::builder().add_service(DeviceControlServer::new(device)),
+        };
+        tokio::spawn(router.serve_with_shutdown(addr.parse().unwrap(), async {
+            signal_rx.await.ok();
+        }));
+        let _ = sleep(Duration::from_millis(500)).await;
+        signal_tx
+    }
+
+    #[test]
+    fn backoff() {
+        let retry = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+        };
+        assert_eq!(retry.backoff(0), Duration::from_millis(100));
+        assert_eq!(retry.backoff(1), Duration::from_millis(200));
+        assert_eq!(retry.backoff(2), Duration::from_millis(400));
+        assert_eq!(retry.backoff(3), Duration::from_millis(500));
+        assert_eq!(retry.backoff(40), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn status_into_error() {
+        assert!(matches!(
+            ClientError::from(Status::unavailable("down")),
+            ClientError::Unavailable { .. }
+        ));
+        assert!(matches!(
+            ClientError::from(Status::permission_denied("read only")),
+            ClientError::PermissionDenied { .. }
+        ));
+        assert!(matches!(
+            ClientError::from(Status::internal("boom")),
+            ClientError::Status {
+                code: Code::Internal,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_status_and_set_on() {
+        let addr = "127.0.0.1:50060";
+        let device = Arc::new(MockDevice::default());
+        let signal_tx = serve(addr, device.clone(), None).await;
+
+        let client = DeviceClient::connect("http://".to_owned() + addr)
+            .await
+            .unwrap();
+        assert!(!client.status().await.unwrap().on);
+
+        client.set_on(true).await.unwrap();
+        assert!(client.status().await.unwrap().on);
+
+        // повторная команда не переключает устройство обратно
+        client.clone().set_on(true).await.unwrap();
+        assert!(client.status().await.unwrap().on);
+
+        client.set_on(false).await.unwrap();
+        assert!(!device.on.load(Ordering::SeqCst));
+
+        let _ = signal_tx.send(());
+    }
+
+    #[tokio::test]
+    async fn test_retry_unavailable() {
+        let addr = "127.0.0.1:50061";
+        let device = Arc::new(MockDevice {
+            failures: AtomicU32::new(2),
+            ..MockDevice::default()
+        });
+        let signal_tx = serve(addr, device.clone(), None).await;
+
+        let client = DeviceClient::builder("http://".to_owned() + addr)
+            .retry(RetryPolicy {
+                max_retries: 2,
+                initial_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(50),
+            })
+            .connect()
+            .await
+            .unwrap();
+        assert!(client.status().await.is_ok());
+        assert_eq!(device.calls.load(Ordering::SeqCst), 3);
+
+        device.failures.store(5, Ordering::SeqCst);
+        let client = client.with_retry(RetryPolicy::none());
+        assert!(matches!(
+            client.status().await,
+            Err(ClientError::Unavailable { .. })
+        ));
+        assert_eq!(device.calls.load(Ordering::SeqCst), 4);
+
+        let _ = signal_tx.send(());
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let addr = "127.0.0.1:50062";
+        let device = Arc::new(MockDevice {
+            delay: Some(Duration::from_millis(500)),
+            ..MockDevice::default()
+        });
+        let signal_tx = serve(addr, device, None).await;
+
+        let client = DeviceClient::builder("http://".to_owned() + addr)
+            .timeout(Duration::from_millis(100))
+            .connect()
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.status().await,
+            Err(ClientError::Timeout { .. })
+        ));
+
+        let _ = signal_tx.send(());
+    }
+
+    #[tokio::test]
+    async fn test_token() {
+        let addr = "127.0.0.1:50063";
+        let device = Arc::new(MockDevice::default());
+        let tokens = "reader read".parse().unwrap();
+        let signal_tx = serve(addr, device, Some(tokens)).await;
+
+        let client = DeviceClient::connect("http://".to_owned() + addr)
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.status().await,
+            Err(ClientError::Unauthenticated { .. })
+        ));
+
+        let client = DeviceClient::builder("http://".to_owned() + addr)
+            .token("reader")
+            .connect()
+            .await
+            .unwrap();
+        assert!(client.status().await.is_ok());
+        assert!(matches!(
+            client.set_on(true).await,
+            Err(ClientError::PermissionDenied { .. })
+        ));
+
+        let _ = signal_tx.send(());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_url() {
+        assert!(matches!(
+            DeviceClient::connect("not a url").await,
+            Err(ClientError::InvalidUrl { .. })
+        ));
+    }
+}
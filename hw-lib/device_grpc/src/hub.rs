@@ -0,0 +1,340 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use thiserror::Error;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::devices::device_hub_server::DeviceHub;
+use crate::devices::device_message::Message;
+use crate::devices::hub_command::Command;
+use crate::devices::{CommandResult, DeviceMessage, DeviceStatus, HubCommand};
+
+#[derive(Debug, Error)]
+pub enum HubError {
+    #[error("Device {device_id:?} is not connected")]
+    NotConnected { device_id: String },
+    #[error("Device {device_id:?} disconnected before answering")]
+    Disconnected { device_id: String },
+    #[error("Device {device_id:?} did not acknowledge the command in {timeout:?}")]
+    AckTimeout {
+        device_id: String,
+        timeout: Duration,
+    },
+    #[error("Device {device_id:?} did not answer in {timeout:?}")]
+    ResultTimeout {
+        device_id: String,
+        timeout: Duration,
+    },
+}
+
+/// State report of an attached device.
+#[derive(Debug, Clone)]
+pub struct StateReport {
+    pub device_id: String,
+    pub status: DeviceStatus,
+}
+
+struct Pending {
+    ack: Option<oneshot::Sender<()>>,
+    result: oneshot::Sender<CommandResult>,
+}
+
+struct Session {
+    // по номеру сессии её поток сообщений находит свою запись, а не чужую с тем же устройством
+    session_id: u64,
+    name: String,
+    commands: mpsc::Sender<Result<HubCommand, Status>>,
+    pending: HashMap<u64, Pending>,
+    state: Option<DeviceStatus>,
+}
+
+/// Hub side of the `DeviceHub` service.
+///
+/// Devices attach with an outbound stream and announce themselves with `Hello`,
+/// then the hub sends commands over it and waits for their acks and results.
+/// A `Hello` for a device that is already attached is rejected with `ALREADY_EXISTS`,
+/// the device can attach again once its session ends.
+#[derive(Clone)]
+pub struct Hub {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    next_id: Arc<AtomicU64>,
+    states: broadcast::Sender<StateReport>,
+    ack_timeout: Duration,
+    result_timeout: Duration,
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Hub::new(Duration::from_secs(2), Duration::from_secs(10))
+    }
+}
+
+impl Hub {
+    pub fn new(ack_timeout: Duration, result_timeout: Duration) -> Self {
+        let (states, _) = broadcast::channel(64);
+        Hub {
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(1)),
+            states,
+            ack_timeout,
+            result_timeout,
+        }
+    }
+
+    /// Ids and names of attached devices.
+    pub fn devices(&self) -> Vec<(String, String)> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, session)| (id.clone(), session.name.clone()))
+            .collect()
+    }
+
+    /// The last state reported by the device.
+    pub fn state(&self, device_id: &str) -> Option<DeviceStatus> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(device_id)
+            .and_then(|session| session.state.clone())
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<StateReport> {
+        self.states.subscribe()
+    }
+
+    /// Sends a command to the device and waits for its result.
+    pub async fn send(&self, device_id: &str, command: Command) -> Result<CommandResult, HubError> {
+        let correlation_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (ack_tx, ack_rx) = oneshot::channel();
+        let (result_tx, result_rx) = oneshot::channel();
+
+        let commands = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let session = sessions
+                .get_mut(device_id)
+                .ok_or_else(|| HubError::NotConnected {
+                    device_id: device_id.to_string(),
+                })?;
+            session.pending.insert(
+                correlation_id,
+                Pending {
+                    ack: Some(ack_tx),
+                    result: result_tx,
+                },
+            );
+            session.commands.clone()
+        };
+
+        let disconnected = || HubError::Disconnected {
+            device_id: device_id.to_string(),
+        };
+
+        let result = async {
+            commands
+                .send(Ok(HubCommand {
+                    correlation_id,
+                    command: Some(command),
+                }))
+                .await
+                .map_err(|_| disconnected())?;
+
+            tokio::time::timeout(self.ack_timeout, ack_rx)
+                .await
+                .map_err(|_| HubError::AckTimeout {
+                    device_id: device_id.to_string(),
+                    timeout: self.ack_timeout,
+                })?
+                .map_err(|_| disconnected())?;
+
+            tokio::time::timeout(self.result_timeout, result_rx)
+                .await
+                .map_err(|_| HubError::ResultTimeout {
+                    device_id: device_id.to_string(),
+                    timeout: self.result_timeout,
+                })?
+                .map_err(|_| disconnected())
+        }
+        .await;
+
+        if result.is_err() {
+            if let Some(session) = self.sessions.lock().unwrap().get_mut(device_id) {
+                session.pending.remove(&correlation_id);
+            }
+        }
+        result
+    }
+
+    fn handle(&self, device_id: &str, session_id: u64, message: Message) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions
+            .get_mut(device_id)
+            .filter(|session| session.session_id == session_id)
+        else {
+            return;
+        };
+        match message {
+            Message::Ack(ack) => {
+                if let Some(ack) = session
+                    .pending
+                    .get_mut(&ack.correlation_id)
+                    .and_then(|pending| pending.ack.take())
+                {
+                    let _ = ack.send(());
+                }
+            }
+            Message::Result(result) => {
+                if let Some(pending) = session.pending.remove(&result.correlation_id) {
+                    // результат без ack тоже подтверждает получение команды
+                    if let Some(ack) = pending.ack {
+                        let _ = ack.send(());
+                    }
+                    let _ = pending.result.send(result);
+                }
+            }
+            Message::State(status) => {
+                session.state = Some(status.clone());
+                let _ = self.states.send(StateReport {
+                    device_id: device_id.to_string(),
+                    status,
+                });
+            }
+            Message::Hello(_) => {}
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl DeviceHub for Hub {
+    type AttachStream = Pin<Box<dyn Stream<Item = Result<HubCommand, Status>> + Send>>;
+
+    async fn attach(
+        &self,
+        request: Request<Streaming<DeviceMessage>>,
+    ) -> Result<Response<Self::AttachStream>, Status> {
+        let mut messages = request.into_inner();
+
+        let hello = match messages.message().await?.and_then(|m| m.message) {
+            Some(Message::Hello(hello)) => hello,
+            _ => return Err(Status::invalid_argument("session must start with Hello")),
+        };
+        let device_id = hello.device_id;
+
+        let (commands, commands_rx) = mpsc::channel(16);
+        let session_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        match self.sessions.lock().unwrap().entry(device_id.clone()) {
+            // иначе любой собеседник перехватил бы подключённое устройство
+            Entry::Occupied(_) => {
+                return Err(Status::already_exists(format!(
+                    "device {:?} is already attached",
+                    device_id
+                )))
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Session {
+                    session_id,
+                    name: hello.name,
+                    commands,
+                    pending: HashMap::new(),
+                    state: None,
+                });
+            }
+        }
+
+        let hub = self.clone();
+        tokio::spawn(async move {
+            while let Ok(Some(message)) = messages.message().await {
+                if let Some(message) = message.message {
+                    hub.handle(&device_id, session_id, message);
+                }
+            }
+            let mut sessions = hub.sessions.lock().unwrap();
+            if sessions.get(&device_id).map(|s| s.session_id) == Some(session_id) {
+                // ожидающие команды получат Disconnected
+                sessions.remove(&device_id);
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(commands_rx))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    use crate::devices::Toggle;
+
+    #[tokio::test]
+    async fn send_to_unknown_device() {
+        let hub = Hub::default();
+        assert!(hub.devices().is_empty());
+        assert!(matches!(
+            hub.send("lamp", Command::SetOn(Toggle { on: true })).await,
+            Err(HubError::NotConnected { .. })
+        ));
+        assert!(hub.state("lamp").is_none());
+    }
+
+    #[tokio::test]
+    async fn stale_session_is_ignored() {
+        let hub = Hub::default();
+        let (commands, _commands_rx) = mpsc::channel(1);
+        let (result_tx, mut result_rx) = oneshot::channel();
+        hub.sessions.lock().unwrap().insert(
+            "lamp".to_string(),
+            Session {
+                session_id: 2,
+                name: "lamp".to_string(),
+                commands,
+                pending: HashMap::from([(
+                    7,
+                    Pending {
+                        ack: None,
+                        result: result_tx,
+                    },
+                )]),
+                state: None,
+            },
+        );
+        let status = DeviceStatus {
+            on: true,
+            ..Default::default()
+        };
+
+        // сообщения прошлой сессии того же устройства не трогают текущую
+        hub.handle("lamp", 1, Message::State(status.clone()));
+        hub.handle(
+            "lamp",
+            1,
+            Message::Result(CommandResult {
+                correlation_id: 7,
+                ..Default::default()
+            }),
+        );
+        assert!(hub.state("lamp").is_none());
+        assert!(result_rx.try_recv().is_err());
+
+        hub.handle("lamp", 2, Message::State(status));
+        assert!(hub.state("lamp").unwrap().on);
+        hub.handle(
+            "lamp",
+            2,
+            Message::Result(CommandResult {
+                correlation_id: 7,
+                ok: true,
+                ..Default::default()
+            }),
+        );
+        assert!(result_rx.try_recv().unwrap().ok);
+    }
+}
@@ -1,3 +1,12 @@
+pub mod auth;
+pub mod client;
+pub mod hub;
+pub mod tls;
+
 pub mod devices {
     tonic::include_proto!("devices");
+
+    /// Encoded descriptors of `devices.proto`, used by gRPC server reflection.
+    pub const FILE_DESCRIPTOR_SET: &[u8] =
+        tonic::include_file_descriptor_set!("devices_descriptor");
 }
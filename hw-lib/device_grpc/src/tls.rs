@@ -0,0 +1,134 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
+
+use device_quic::certs::generate_self_signed;
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("Cannot read {path:?}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("{path:?} has no PEM data")]
+    NotPem { path: PathBuf },
+    #[error("Cannot generate a self-signed certificate: {message}")]
+    Generate { message: String },
+}
+
+/// Certificate chain and private key in PEM format.
+#[derive(Debug, Clone)]
+pub struct TlsIdentity {
+    pub cert_pem: Vec<u8>,
+    pub key_pem: Vec<u8>,
+}
+
+impl TlsIdentity {
+    pub fn from_files(cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self, TlsError> {
+        Ok(TlsIdentity {
+            cert_pem: read_pem(cert)?,
+            key_pem: read_pem(key)?,
+        })
+    }
+
+    /// Generates a self-signed identity with the same helper the QUIC endpoints use.
+    pub fn self_signed(subject_alt_names: Vec<String>) -> Result<Self, TlsError> {
+        let cert = generate_self_signed(subject_alt_names).map_err(|e| TlsError::Generate {
+            message: e.to_string(),
+        })?;
+        Ok(TlsIdentity {
+            cert_pem: cert.cert_pem.into_bytes(),
+            key_pem: cert.key_pem.into_bytes(),
+        })
+    }
+
+    fn identity(&self) -> Identity {
+        Identity::from_pem(&self.cert_pem, &self.key_pem)
+    }
+}
+
+/// Reads a PEM file, e.g. a CA bundle.
+pub fn read_pem(path: impl AsRef<Path>) -> Result<Vec<u8>, TlsError> {
+    let path = path.as_ref();
+    let pem = fs::read(path).map_err(|source| TlsError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    if !String::from_utf8_lossy(&pem).contains("-----BEGIN ") {
+        return Err(TlsError::NotPem {
+            path: path.to_path_buf(),
+        });
+    }
+    Ok(pem)
+}
+
+/// Server TLS configuration.
+///
+/// ## Args
+///
+/// - identity: server certificate and key.
+/// - client_ca: when set, clients must present a certificate signed by it (mutual TLS).
+pub fn server_tls(identity: &TlsIdentity, client_ca: Option<&[u8]>) -> ServerTlsConfig {
+    let config = ServerTlsConfig::new().identity(identity.identity());
+    match client_ca {
+        Some(ca) => config.client_ca_root(Certificate::from_pem(ca)),
+        None => config,
+    }
+}
+
+/// Client TLS configuration.
+///
+/// ## Args
+///
+/// - server_ca: trusted certificates of the server in PEM format.
+/// - domain: name in the server certificate.
+/// - identity: client certificate and key for mutual TLS.
+pub fn client_tls(
+    server_ca: &[u8],
+    domain: &str,
+    identity: Option<&TlsIdentity>,
+) -> ClientTlsConfig {
+    let config = ClientTlsConfig::new()
+        .ca_certificate(Certificate::from_pem(server_ca))
+        .domain_name(domain);
+    match identity {
+        Some(identity) => config.identity(identity.identity()),
+        None => config,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn from_files() {
+        let dir = std::env::temp_dir().join(format!("device_grpc_tls_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let generated = TlsIdentity::self_signed(vec!["localhost".to_string()]).unwrap();
+        fs::write(dir.join("cert.pem"), &generated.cert_pem).unwrap();
+        fs::write(dir.join("key.pem"), &generated.key_pem).unwrap();
+        fs::write(dir.join("garbage.pem"), b"garbage").unwrap();
+
+        let loaded = TlsIdentity::from_files(dir.join("cert.pem"), dir.join("key.pem")).unwrap();
+        assert_eq!(loaded.cert_pem, generated.cert_pem);
+        assert_eq!(loaded.key_pem, generated.key_pem);
+
+        assert!(matches!(
+            read_pem(dir.join("garbage.pem")),
+            Err(TlsError::NotPem { .. })
+        ));
+        assert!(matches!(
+            read_pem(dir.join("missing.pem")),
+            Err(TlsError::Read { .. })
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use uuid::Builder;
+
+use device_quic::common::make_server_endpoint;
+use device_quic::discovery::{Announcement, Announcer, GATEWAY_KIND};
+use device_quic::identity::fingerprint;
+use device_quic::settings::QuicSettings;
+use device_quic::simulator::{serve, Scenario};
+
+/// QUIC gateway of virtual sensors described by a scenario file.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Address of the QUIC telemetry server
+    #[arg(long, default_value = "0.0.0.0:5000")]
+    addr: SocketAddr,
+    /// Scenario file, see `device_quic::simulator`
+    #[arg(long)]
+    scenario: PathBuf,
+    /// Server name in the generated self-signed certificate
+    #[arg(long, default_value = "localhost")]
+    cert_address: String,
+    /// QUIC transport settings file, see `device_quic::settings`
+    #[arg(long)]
+    settings: Option<PathBuf>,
+    /// Write the generated certificate in DER format to this file for devices (`--quic-cert`)
+    #[arg(long)]
+    export_cert: Option<PathBuf>,
+    /// Announce the gateway and its sensors to this address, the `device_quic::discovery` group by default
+    #[arg(long, num_args = 0..=1, default_missing_value = "239.255.42.1:5680")]
+    announce: Option<SocketAddr>,
+    /// Interval between announcements
+    #[arg(long, default_value = "2s", value_parser = device_quic::settings::parse_duration)]
+    announce_every: Duration,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let scenario = Scenario::from_file(&args.scenario)?;
+    let settings = match &args.settings {
+        Some(path) => QuicSettings::from_file(path)?,
+        None => QuicSettings::default(),
+    };
+    let (endpoint, server_cert) = make_server_endpoint(args.addr, &args.cert_address, &settings)
+        .map_err(|e| anyhow::anyhow!("failed to create server endpoint: {}", e))?;
+    if let Some(path) = &args.export_cert {
+        std::fs::write(path, &server_cert)
+            .with_context(|| format!("failed to write certificate {:?}", path))?;
+    }
+
+    println!(
+        "simulating {} sensors on {}",
+        scenario.sensors.len(),
+        endpoint.local_addr()?
+    );
+    for sensor in &scenario.sensors {
+        println!(
+            "  {:?} of {} every {:?} via {:?}",
+            sensor.kind, sensor.device_id, sensor.every, sensor.transport
+        );
+    }
+
+    // без анонса устройства настраиваются вручную через `--export-cert`
+    let _announcer = match args.announce {
+        Some(target) => {
+            let addr = endpoint.local_addr()?;
+            let fingerprint = fingerprint(&server_cert);
+            let mut announcements = vec![Announcement {
+                kind: GATEWAY_KIND.to_string(),
+                id: Builder::from_random_bytes(rand::random()).into_uuid(),
+                name: "sensor_simulator".to_string(),
+                addr,
+                server_name: args.cert_address.clone(),
+                fingerprint: fingerprint.clone(),
+            }];
+            announcements.extend(scenario.announcements(addr, &args.cert_address, &fingerprint));
+            println!("announcing to {}", target);
+            Some(
+                Announcer::spawn(
+                    "0.0.0.0:0".parse().unwrap(),
+                    target,
+                    announcements,
+                    args.announce_every,
+                )
+                .await?,
+            )
+        }
+        None => None,
+    };
+
+    tokio::select! {
+        _ = serve(endpoint.clone(), scenario) => {},
+        _ = tokio::signal::ctrl_c() => println!("shutting down"),
+    }
+    endpoint.close(0_u8.into(), b"shutdown");
+    endpoint.wait_idle().await;
+    Ok(())
+}
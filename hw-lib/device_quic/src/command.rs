@@ -0,0 +1,213 @@
+//! Request/response commands over bidirectional QUIC streams.
+//!
+//! Every command takes its own stream, opened by the hub over the connection the device keeps
+//! for telemetry. The stream starts with the same header as a telemetry stream, so
+//! [`crate::manager::ConnectionManager`] routes it to the device. Then the hub writes one
+//! request and finishes its side, the device answers with one response.
+//!
+//! Requests and responses are protobuf messages framed by a big-endian `u32` length, so the
+//! hub and devices reuse the message types of the gRPC API.
+
+use prost::Message;
+use quinn::{Connection, ConnectionError, ReadError, RecvStream, SendStream, VarInt, WriteError};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::telemetry::{read_full, stream_header};
+
+/// Upper bound of a request or response length.
+pub const MAX_COMMAND_LEN: usize = 64 * 1024;
+
+/// Error code of a command stream the device can't take, e.g. an unknown device.
+pub const COMMAND_REJECTED: VarInt = VarInt::from_u32(2);
+
+const LEN_PREFIX: usize = 4;
+
+#[derive(Debug, Error)]
+pub enum CommandError {
+    #[error(transparent)]
+    Connection(#[from] ConnectionError),
+    #[error(transparent)]
+    Read(#[from] ReadError),
+    #[error(transparent)]
+    Write(#[from] WriteError),
+    #[error("Command stream ended before the message")]
+    Finished,
+    #[error("Message is truncated: {needed} bytes needed, {available} available")]
+    Truncated { needed: usize, available: usize },
+    #[error("Message length {len} exceeds {MAX_COMMAND_LEN}")]
+    TooLong { len: usize },
+    #[error(transparent)]
+    Decode(#[from] prost::DecodeError),
+}
+
+/// Sends a command to the device and waits for its response.
+pub async fn send_command<Req: Message, Resp: Message + Default>(
+    connection: &Connection,
+    device_id: Uuid,
+    request: &Req,
+) -> Result<Resp, CommandError> {
+    let (mut send, mut recv) = connection.open_bi().await?;
+    send.write_all(&stream_header(device_id)).await?;
+    write_message(&mut send, request).await?;
+    send.finish().await?;
+    read_message(&mut recv).await?.ok_or(CommandError::Finished)
+}
+
+pub async fn write_message<M: Message>(
+    send: &mut SendStream,
+    message: &M,
+) -> Result<(), CommandError> {
+    let body = message.encode_to_vec();
+    if body.len() > MAX_COMMAND_LEN {
+        return Err(CommandError::TooLong { len: body.len() });
+    }
+    send.write_all(&(body.len() as u32).to_be_bytes()).await?;
+    send.write_all(&body).await?;
+    Ok(())
+}
+
+/// Reads the next message of the stream.
+///
+/// Returns `None` when the stream is finished between messages.
+pub async fn read_message<M: Message + Default>(
+    recv: &mut RecvStream,
+) -> Result<Option<M>, CommandError> {
+    let mut prefix = [0_u8; LEN_PREFIX];
+    match read_full(recv, &mut prefix).await? {
+        0 => return Ok(None),
+        LEN_PREFIX => {}
+        available => {
+            return Err(CommandError::Truncated {
+                needed: LEN_PREFIX,
+                available,
+            })
+        }
+    }
+    let len = u32::from_be_bytes(prefix) as usize;
+    if len > MAX_COMMAND_LEN {
+        return Err(CommandError::TooLong { len });
+    }
+    let mut body = vec![0_u8; len];
+    let available = read_full(recv, &mut body).await?;
+    if available < len {
+        return Err(CommandError::Truncated {
+            needed: len,
+            available,
+        });
+    }
+    Ok(Some(M::decode(body.as_slice())?))
+}
+
+/// Command stream of a device with its header already read, see
+/// [`crate::manager::DeviceStreams::take_commands`].
+#[derive(Debug)]
+pub struct IncomingCommand {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl IncomingCommand {
+    pub(crate) fn new(send: SendStream, recv: RecvStream) -> Self {
+        IncomingCommand { send, recv }
+    }
+
+    pub async fn request<M: Message + Default>(&mut self) -> Result<M, CommandError> {
+        read_message(&mut self.recv)
+            .await?
+            .ok_or(CommandError::Finished)
+    }
+
+    pub async fn respond<M: Message>(mut self, response: &M) -> Result<(), CommandError> {
+        write_message(&mut self.send, response).await?;
+        self.send.finish().await?;
+        Ok(())
+    }
+
+    /// Refuses the command, the hub gets a stream error.
+    pub fn reject(mut self) {
+        let _ = self.recv.stop(COMMAND_REJECTED);
+        let _ = self.send.reset(COMMAND_REJECTED);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use crate::common::{make_client_endpoint, make_server_endpoint};
+    use crate::manager::ConnectionManager;
+    use crate::settings::QuicSettings;
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Add {
+        #[prost(int64, tag = "1")]
+        value: i64,
+    }
+
+    #[tokio::test]
+    async fn test_commands_routed_by_device() {
+        let devices = [Uuid::from_u128(1), Uuid::from_u128(2)];
+
+        let server_addr: SocketAddr = "127.0.0.1:5014".parse().unwrap();
+        let (endpoint, server_cert) =
+            make_server_endpoint(server_addr, "localhost", &QuicSettings::default()).unwrap();
+
+        // устройство прибавляет к числу свой номер
+        let manager = ConnectionManager::new(
+            make_client_endpoint(
+                "127.0.0.1:0".parse().unwrap(),
+                &[&server_cert],
+                &QuicSettings::default(),
+            )
+            .unwrap(),
+        );
+        let mut subscriptions = Vec::new();
+        for (n, device_id) in devices.iter().enumerate() {
+            let mut streams = manager
+                .subscribe(server_addr, "localhost", *device_id)
+                .await
+                .unwrap();
+            let mut commands = streams.take_commands().unwrap();
+            tokio::spawn(async move {
+                while let Some(mut command) = commands.next().await {
+                    let request: Add = command.request().await.unwrap();
+                    let response = Add {
+                        value: request.value + n as i64 + 1,
+                    };
+                    command.respond(&response).await.unwrap();
+                }
+            });
+            subscriptions.push(streams);
+        }
+
+        let connection = endpoint.accept().await.unwrap().await.unwrap();
+        for (n, device_id) in devices.iter().enumerate() {
+            let response: Add = timeout(
+                Duration::from_secs(5),
+                send_command(&connection, *device_id, &Add { value: 10 }),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            assert_eq!(response.value, 10 + n as i64 + 1);
+        }
+
+        // неизвестное устройство отклоняет команду
+        let unknown = send_command::<Add, Add>(&connection, Uuid::from_u128(3), &Add { value: 0 });
+        assert!(timeout(Duration::from_secs(5), unknown)
+            .await
+            .unwrap()
+            .is_err());
+
+        drop(subscriptions);
+        manager.endpoint().wait_idle().await;
+        endpoint.close(0_u8.into(), b"done");
+    }
+}
@@ -1,17 +1,67 @@
 use quinn::{ClientConfig, Endpoint, ServerConfig};
-use std::{error::Error, net::SocketAddr, sync::Arc};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use std::{error::Error, net::SocketAddr, sync::Arc, time::SystemTime};
+
+use crate::certs::{generate_self_signed, Identity, SelfSignedCert};
+use crate::identity::fingerprint;
+use crate::settings::QuicSettings;
 
 /// Constructs a QUIC endpoint configured for use a client only.
 ///
 /// ## Args
 ///
 /// - server_certs: list of trusted certificates.
+/// - settings: transport parameters and ALPN protocols, see [`crate::settings`].
 #[allow(unused)]
 pub fn make_client_endpoint(
     bind_addr: SocketAddr,
     server_certs: &[&[u8]],
+    settings: &QuicSettings,
+) -> Result<Endpoint, Box<dyn Error>> {
+    let client_cfg = configure_client(trusted_certs(server_certs)?, None, settings)?;
+    let mut endpoint = Endpoint::client(bind_addr)?;
+    endpoint.set_default_client_config(client_cfg);
+    Ok(endpoint)
+}
+
+/// Same as [`make_client_endpoint`], but the client authenticates itself with a certificate
+/// for servers from [`make_server_endpoint_mtls`].
+#[allow(unused)]
+pub fn make_client_endpoint_with_identity(
+    bind_addr: SocketAddr,
+    server_certs: &[&[u8]],
+    identity: &Identity,
+    settings: &QuicSettings,
+) -> Result<Endpoint, Box<dyn Error>> {
+    let client_cfg = configure_client(trusted_certs(server_certs)?, Some(identity), settings)?;
+    let mut endpoint = Endpoint::client(bind_addr)?;
+    endpoint.set_default_client_config(client_cfg);
+    Ok(endpoint)
+}
+
+/// Same as [`make_client_endpoint`], but trusts servers by the SHA-256 fingerprint of their
+/// certificate, e.g. announced by [`crate::discovery`].
+///
+/// The certificate is pinned as is: neither its names nor its validity period are checked.
+///
+/// ## Args
+///
+/// - fingerprints: lowercase hex fingerprints, see [`crate::identity::fingerprint`].
+/// - identity: client certificate for mutual TLS.
+#[allow(unused)]
+pub fn make_client_endpoint_pinned(
+    bind_addr: SocketAddr,
+    fingerprints: &[String],
+    identity: Option<&Identity>,
+    settings: &QuicSettings,
 ) -> Result<Endpoint, Box<dyn Error>> {
-    let client_cfg = configure_client(server_certs)?;
+    let verifier = Arc::new(PinnedCertVerifier {
+        fingerprints: fingerprints
+            .iter()
+            .map(|f| f.to_ascii_lowercase())
+            .collect(),
+    });
+    let client_cfg = configure_client(verifier, identity, settings)?;
     let mut endpoint = Endpoint::client(bind_addr)?;
     endpoint.set_default_client_config(client_cfg);
     Ok(endpoint)
@@ -20,6 +70,10 @@ pub fn make_client_endpoint(
 /// Constructs a QUIC endpoint configured to listen for incoming connections on a certain address
 /// and port.
 ///
+/// ## Args
+///
+/// - settings: transport parameters and ALPN protocols, see [`crate::settings`].
+///
 /// ## Returns
 ///
 /// - a stream of incoming QUIC connections
@@ -28,44 +82,170 @@ pub fn make_client_endpoint(
 pub fn make_server_endpoint(
     bind_addr: SocketAddr,
     cert_address: &str,
+    settings: &QuicSettings,
 ) -> Result<(Endpoint, Vec<u8>), Box<dyn Error>> {
-    let (server_config, server_cert) = configure_server(cert_address)?;
+    let cert = generate_self_signed(vec![cert_address.into()])?;
+    let endpoint = make_server_endpoint_with_cert(bind_addr, &cert, settings)?;
+    Ok((endpoint, cert.cert_der))
+}
+
+/// Same as [`make_server_endpoint`], but with a given certificate.
+///
+/// A server restarted with the same certificate stays trusted by its clients.
+#[allow(unused)]
+pub fn make_server_endpoint_with_cert(
+    bind_addr: SocketAddr,
+    cert: &SelfSignedCert,
+    settings: &QuicSettings,
+) -> Result<Endpoint, Box<dyn Error>> {
+    make_server_endpoint_with_identity(bind_addr, &cert.into(), settings)
+}
+
+/// Same as [`make_server_endpoint`], but with a certificate chain and key,
+/// e.g. loaded by [`crate::certs::ServerCertSource`].
+#[allow(unused)]
+pub fn make_server_endpoint_with_identity(
+    bind_addr: SocketAddr,
+    identity: &Identity,
+    settings: &QuicSettings,
+) -> Result<Endpoint, Box<dyn Error>> {
+    let server_config = configure_server(identity, None, settings)?;
     let endpoint = Endpoint::server(server_config, bind_addr)?;
-    Ok((endpoint, server_cert))
+    Ok(endpoint)
 }
 
-/// Builds default quinn client config and trusts given certificates.
+/// Same as [`make_server_endpoint_with_identity`], but accepts only clients with a certificate
+/// signed by one of `client_ca` (mutual TLS).
+///
+/// The client identity is available through [`crate::identity::peer_identity`].
 ///
 /// ## Args
 ///
-/// - server_certs: a list of trusted certificates in DER format.
-fn configure_client(server_certs: &[&[u8]]) -> Result<ClientConfig, Box<dyn Error>> {
-    let mut certs = rustls::RootCertStore::empty();
-    for cert in server_certs {
-        certs.add(&rustls::Certificate(cert.to_vec()))?;
-    }
+/// - client_ca: trusted client certificates or their CAs in DER format.
+#[allow(unused)]
+pub fn make_server_endpoint_mtls(
+    bind_addr: SocketAddr,
+    identity: &Identity,
+    client_ca: &[&[u8]],
+    settings: &QuicSettings,
+) -> Result<Endpoint, Box<dyn Error>> {
+    let server_config = configure_server(identity, Some(client_ca), settings)?;
+    let endpoint = Endpoint::server(server_config, bind_addr)?;
+    Ok(endpoint)
+}
+
+/// Builds quinn client config which trusts servers accepted by the verifier.
+///
+/// ## Args
+///
+/// - verifier: checks server certificates, see [`trusted_certs`].
+/// - identity: client certificate for mutual TLS.
+fn configure_client(
+    verifier: Arc<dyn ServerCertVerifier>,
+    identity: Option<&Identity>,
+    settings: &QuicSettings,
+) -> Result<ClientConfig, Box<dyn Error>> {
+    let crypto = rustls::ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_custom_certificate_verifier(verifier);
+    let mut crypto = match identity {
+        None => crypto.with_no_client_auth(),
+        Some(identity) => {
+            let (cert_chain, priv_key) = rustls_identity(identity);
+            crypto.with_client_auth_cert(cert_chain, priv_key)?
+        }
+    };
+    crypto.enable_early_data = true;
+    crypto.alpn_protocols = settings.alpn.clone();
 
-    let client_config = ClientConfig::with_root_certificates(certs);
+    let mut client_config = ClientConfig::new(Arc::new(crypto));
+    client_config.transport_config(Arc::new(settings.transport_config()?));
     Ok(client_config)
 }
 
-/// Returns default server configuration along with its certificate.
-fn configure_server(cert_address: &str) -> Result<(ServerConfig, Vec<u8>), Box<dyn Error>> {
-    let cert = rcgen::generate_simple_self_signed(vec![cert_address.into()]).unwrap();
-    let cert_der = cert.serialize_der().unwrap();
-    let priv_key = cert.serialize_private_key_der();
-    let priv_key = rustls::PrivateKey(priv_key);
-    let cert_chain = vec![rustls::Certificate(cert_der.clone())];
+/// Returns server configuration with the given certificate.
+///
+/// ## Args
+///
+/// - client_ca: require client certificates signed by these certificates.
+fn configure_server(
+    identity: &Identity,
+    client_ca: Option<&[&[u8]]>,
+    settings: &QuicSettings,
+) -> Result<ServerConfig, Box<dyn Error>> {
+    let (cert_chain, priv_key) = rustls_identity(identity);
 
-    let mut server_config = ServerConfig::with_single_cert(cert_chain, priv_key)?;
-    let transport_config = Arc::get_mut(&mut server_config.transport).unwrap();
-    transport_config.max_concurrent_uni_streams(0_u8.into());
+    let crypto = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])?;
+    let crypto = match client_ca {
+        None => crypto.with_no_client_auth(),
+        Some(client_ca) => {
+            let verifier = rustls::server::AllowAnyAuthenticatedClient::new(root_store(client_ca)?);
+            crypto.with_client_cert_verifier(verifier.boxed())
+        }
+    };
+    let mut crypto = crypto.with_single_cert(cert_chain, priv_key)?;
+    crypto.max_early_data_size = u32::MAX;
+    crypto.alpn_protocols = settings.alpn.clone();
 
-    Ok((server_config, cert_der))
+    let mut server_config = ServerConfig::with_crypto(Arc::new(crypto));
+    server_config.transport_config(Arc::new(settings.transport_config()?));
+    Ok(server_config)
 }
 
-#[allow(unused)]
-pub const ALPN_QUIC_HTTP: &[&[u8]] = &[b"hq-29"];
+// Обычная проверка цепочки до одного из доверенных сертификатов в DER формате.
+fn trusted_certs(server_certs: &[&[u8]]) -> Result<Arc<dyn ServerCertVerifier>, Box<dyn Error>> {
+    Ok(Arc::new(WebPkiVerifier::new(
+        root_store(server_certs)?,
+        None,
+    )))
+}
+
+struct PinnedCertVerifier {
+    fingerprints: Vec<String>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.fingerprints.contains(&fingerprint(&end_entity.0)) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::UnknownIssuer,
+            ))
+        }
+    }
+}
+
+fn root_store(certs: &[&[u8]]) -> Result<rustls::RootCertStore, Box<dyn Error>> {
+    let mut store = rustls::RootCertStore::empty();
+    for cert in certs {
+        store.add(&rustls::Certificate(cert.to_vec()))?;
+    }
+    Ok(store)
+}
+
+fn rustls_identity(identity: &Identity) -> (Vec<rustls::Certificate>, rustls::PrivateKey) {
+    let cert_chain = identity
+        .cert_chain
+        .iter()
+        .cloned()
+        .map(rustls::Certificate)
+        .collect();
+    (cert_chain, rustls::PrivateKey(identity.key_der.clone()))
+}
 
 #[cfg(test)]
 mod tests {
@@ -80,7 +260,8 @@ mod tests {
     async fn test_client_server() -> Result<(), Box<dyn std::error::Error>> {
         let server_addr = "127.0.0.1:5000".parse().unwrap();
         let cert_address = "localhost";
-        let (endpoint, server_cert) = make_server_endpoint(server_addr, cert_address)?;
+        let (endpoint, server_cert) =
+            make_server_endpoint(server_addr, cert_address, &QuicSettings::default())?;
 
         tokio::spawn(async move {
             let incoming_conn = endpoint.accept().await.unwrap();
@@ -108,7 +289,11 @@ mod tests {
 
         let _ = sleep(Duration::from_millis(1000)).await;
 
-        let endpoint = make_client_endpoint("0.0.0.0:1234".parse().unwrap(), &[&server_cert])?;
+        let endpoint = make_client_endpoint(
+            "0.0.0.0:1234".parse().unwrap(),
+            &[&server_cert],
+            &QuicSettings::default(),
+        )?;
         // connect to server
         let outcoming_conn = endpoint.connect(server_addr, cert_address).unwrap();
         let connection = outcoming_conn.await.unwrap();
@@ -125,6 +310,8 @@ mod tests {
         // println!("responce: {:?}", String::from_utf8(responce).unwrap());
         assert_eq!("response", String::from_utf8(responce).unwrap());
 
+        // keep-alive не даёт соединению закрыться по таймауту
+        connection.close(0_u8.into(), b"done");
         // Make sure the server has a chance to clean up
         endpoint.wait_idle().await;
 
@@ -133,4 +320,221 @@ mod tests {
 
         Ok(())
     }
+
+    // Сервер и клиент обмениваются сертификатом только через PEM файл
+    #[tokio::test]
+    async fn test_pem_identity() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::certs::{load_certs, ServerCertSource};
+
+        let dir = std::env::temp_dir().join(format!("device_quic_pem_{}", std::process::id()));
+        let cert_path = dir.join("cert.pem");
+        let source = ServerCertSource::Persisted {
+            cert: cert_path.clone(),
+            key: dir.join("key.pem"),
+            subject_alt_names: vec!["localhost".to_string()],
+        };
+
+        let server_addr = "127.0.0.1:5002".parse().unwrap();
+        let endpoint = make_server_endpoint_with_identity(
+            server_addr,
+            &source.load()?,
+            &QuicSettings::default(),
+        )?;
+        tokio::spawn(async move {
+            let connection = endpoint.accept().await.unwrap().await.unwrap();
+            let mut send = connection.open_uni().await.unwrap();
+            send.write_all(b"hello").await.unwrap();
+            send.finish().await.unwrap();
+            connection.closed().await;
+        });
+
+        let trusted = load_certs(&cert_path)?;
+        let trusted: Vec<&[u8]> = trusted.iter().map(Vec::as_slice).collect();
+        let endpoint = make_client_endpoint(
+            "127.0.0.1:0".parse().unwrap(),
+            &trusted,
+            &QuicSettings::default(),
+        )?;
+        let connection = endpoint.connect(server_addr, "localhost")?.await?;
+        let mut recv = connection.accept_uni().await?;
+        assert_eq!(recv.read_to_end(10).await?, b"hello");
+        connection.close(0_u8.into(), b"done");
+        endpoint.wait_idle().await;
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mtls() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::identity::{fingerprint, peer_identity};
+
+        let server_cert = generate_self_signed(vec!["localhost".to_string()])?;
+        let device_cert = generate_self_signed(vec!["thermometer-1".to_string()])?;
+        let stranger_cert = generate_self_signed(vec!["stranger".to_string()])?;
+
+        let server_addr = "127.0.0.1:5004".parse().unwrap();
+        let endpoint = make_server_endpoint_mtls(
+            server_addr,
+            &(&server_cert).into(),
+            &[&device_cert.cert_der],
+            &QuicSettings::default(),
+        )?;
+        let server = tokio::spawn(async move {
+            let connection = loop {
+                if let Ok(connection) = endpoint.accept().await.unwrap().await {
+                    break connection;
+                }
+            };
+            let identity = peer_identity(&connection).unwrap().unwrap();
+            connection.close(0_u8.into(), b"done");
+            identity
+        });
+
+        // в TLS 1.3 клиент узнаёт об отказе уже после рукопожатия
+        async fn rejected(endpoint: &Endpoint, server_addr: SocketAddr) -> bool {
+            match endpoint.connect(server_addr, "localhost").unwrap().await {
+                Err(_) => true,
+                Ok(connection) => connection.accept_uni().await.is_err(),
+            }
+        }
+
+        // клиент без сертификата не подключается
+        let anonymous = make_client_endpoint(
+            "127.0.0.1:0".parse().unwrap(),
+            &[&server_cert.cert_der],
+            &QuicSettings::default(),
+        )?;
+        assert!(rejected(&anonymous, server_addr).await);
+
+        // клиент с чужим сертификатом тоже
+        let stranger = make_client_endpoint_with_identity(
+            "127.0.0.1:0".parse().unwrap(),
+            &[&server_cert.cert_der],
+            &stranger_cert.into(),
+            &QuicSettings::default(),
+        )?;
+        assert!(rejected(&stranger, server_addr).await);
+
+        let client = make_client_endpoint_with_identity(
+            "127.0.0.1:0".parse().unwrap(),
+            &[&server_cert.cert_der],
+            &(&device_cert).into(),
+            &QuicSettings::default(),
+        )?;
+        let connection = client.connect(server_addr, "localhost")?.await?;
+
+        let server_identity = peer_identity(&connection).unwrap()?;
+        assert_eq!(
+            server_identity.fingerprint,
+            fingerprint(&server_cert.cert_der)
+        );
+
+        let identity = server.await?;
+        assert_eq!(
+            identity.subject_alt_names,
+            vec!["thermometer-1".to_string()]
+        );
+        assert_eq!(identity.fingerprint, fingerprint(&device_cert.cert_der));
+
+        client.wait_idle().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_settings() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::settings::CongestionController;
+
+        let idle = QuicSettings::default()
+            .with_idle_timeout(Some(Duration::from_millis(300)))
+            .with_keep_alive(None);
+        let server_addr = "127.0.0.1:5012".parse().unwrap();
+        let (endpoint, server_cert) = make_server_endpoint(
+            server_addr,
+            "localhost",
+            &idle.clone().with_congestion(CongestionController::Bbr),
+        )?;
+        tokio::spawn(async move {
+            while let Some(connecting) = endpoint.accept().await {
+                if let Ok(connection) = connecting.await {
+                    tokio::spawn(async move { connection.closed().await });
+                }
+            }
+        });
+
+        // без keep-alive простаивающее соединение закрывается по таймауту
+        let client = make_client_endpoint("127.0.0.1:0".parse().unwrap(), &[&server_cert], &idle)?;
+        let connection = client.connect(server_addr, "localhost")?.await?;
+        assert_eq!(
+            connection
+                .handshake_data()
+                .unwrap()
+                .downcast::<quinn::crypto::rustls::HandshakeData>()
+                .unwrap()
+                .protocol,
+            Some(crate::settings::ALPN_SMART_HOUSE.to_vec())
+        );
+        sleep(Duration::from_millis(1000)).await;
+        assert!(matches!(
+            connection.close_reason(),
+            Some(quinn::ConnectionError::TimedOut)
+        ));
+
+        let keep_alive = idle
+            .clone()
+            .with_keep_alive(Some(Duration::from_millis(100)));
+        let client =
+            make_client_endpoint("127.0.0.1:0".parse().unwrap(), &[&server_cert], &keep_alive)?;
+        let connection = client.connect(server_addr, "localhost")?.await?;
+        sleep(Duration::from_millis(1000)).await;
+        assert!(connection.close_reason().is_none());
+        connection.close(0_u8.into(), b"done");
+
+        // без общего ALPN рукопожатие не проходит
+        let other = make_client_endpoint(
+            "127.0.0.1:0".parse().unwrap(),
+            &[&server_cert],
+            &idle.with_alpn(vec![b"hq-29".to_vec()]),
+        )?;
+        assert!(other.connect(server_addr, "localhost")?.await.is_err());
+
+        client.wait_idle().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pinned_fingerprint() -> Result<(), Box<dyn std::error::Error>> {
+        let server_addr = "127.0.0.1:5018".parse().unwrap();
+        let (endpoint, server_cert) =
+            make_server_endpoint(server_addr, "localhost", &QuicSettings::default())?;
+        tokio::spawn(async move {
+            while let Some(connecting) = endpoint.accept().await {
+                if let Ok(connection) = connecting.await {
+                    tokio::spawn(async move { connection.closed().await });
+                }
+            }
+        });
+
+        // имя сервера не проверяется, только отпечаток
+        let pinned = make_client_endpoint_pinned(
+            "127.0.0.1:0".parse().unwrap(),
+            &[fingerprint(&server_cert).to_ascii_uppercase()],
+            None,
+            &QuicSettings::default(),
+        )?;
+        let connection = pinned.connect(server_addr, "gateway.local")?.await?;
+        connection.close(0_u8.into(), b"done");
+
+        let other = generate_self_signed(vec!["localhost".to_string()])?;
+        let stranger = make_client_endpoint_pinned(
+            "127.0.0.1:0".parse().unwrap(),
+            &[fingerprint(&other.cert_der)],
+            None,
+            &QuicSettings::default(),
+        )?;
+        assert!(stranger.connect(server_addr, "localhost")?.await.is_err());
+
+        pinned.wait_idle().await;
+        Ok(())
+    }
 }
@@ -0,0 +1,295 @@
+//! Unreliable telemetry over QUIC datagrams.
+//!
+//! Fast sensors replace a lost reading with a newer one soon, so they don't need the
+//! retransmissions and ordering of streams. A datagram is a big-endian `u64` sequence number
+//! followed by a frame body without its length prefix, see [`crate::telemetry`].
+//!
+//! Sequence numbers are counted per device and connection. The receiver keeps only readings
+//! newer than the last one it applied, see [`SequenceTracker`].
+
+use std::collections::HashMap;
+
+use quinn::{Connection, SendDatagramError};
+use uuid::Uuid;
+
+use crate::telemetry::{decode, encode_body, DecodeError, Telemetry};
+
+const SEQUENCE_LEN: usize = 8;
+
+/// Sequence numbers before the last seen one for which [`SequenceTracker`] remembers
+/// whether they arrived.
+pub const SEQUENCE_WINDOW: u64 = 64;
+
+pub fn encode_datagram(sequence: u64, message: &Telemetry) -> Vec<u8> {
+    let body = encode_body(message);
+    let mut datagram = Vec::with_capacity(SEQUENCE_LEN + body.len());
+    datagram.extend_from_slice(&sequence.to_be_bytes());
+    datagram.extend_from_slice(&body);
+    datagram
+}
+
+/// Decodes a datagram into its sequence number and message.
+pub fn decode_datagram(datagram: &[u8]) -> Result<(u64, Telemetry), DecodeError> {
+    if datagram.len() < SEQUENCE_LEN {
+        return Err(DecodeError::Truncated {
+            needed: SEQUENCE_LEN,
+            available: datagram.len(),
+        });
+    }
+    let sequence = u64::from_be_bytes(datagram[..SEQUENCE_LEN].try_into().unwrap());
+    Ok((sequence, decode(&datagram[SEQUENCE_LEN..])?))
+}
+
+/// Sends readings of any number of devices as datagrams of one connection.
+#[derive(Debug, Clone)]
+pub struct DatagramSender {
+    connection: Connection,
+    sequences: HashMap<Uuid, u64>,
+}
+
+impl DatagramSender {
+    pub fn new(connection: Connection) -> Self {
+        DatagramSender {
+            connection,
+            sequences: HashMap::new(),
+        }
+    }
+
+    /// Sends the reading with the next sequence number of its device and returns the number.
+    ///
+    /// The number is used up even if sending fails, the receiver counts it as lost.
+    pub fn send(&mut self, message: &Telemetry) -> Result<u64, SendDatagramError> {
+        let sequence = self.sequences.entry(message.device_id).or_default();
+        *sequence += 1;
+        let sequence = *sequence;
+        self.connection
+            .send_datagram(encode_datagram(sequence, message).into())?;
+        Ok(sequence)
+    }
+}
+
+/// How a datagram fits into the sequence of its device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arrival {
+    /// Newer than any seen before, `lost` numbers were skipped.
+    Fresh { lost: u64 },
+    /// Older than the last seen one and not seen before, the reading is dropped.
+    /// `filled` is whether its number was counted as lost by an earlier [`Arrival::Fresh`].
+    Late { filled: bool },
+    /// Seen before, the reading is dropped.
+    Duplicate,
+}
+
+/// Tracks the sequence numbers of one device within one connection.
+///
+/// Numbers older than [`SEQUENCE_WINDOW`] before the last seen one are always
+/// `Late { filled: false }`, whether they arrived before is not known.
+#[derive(Debug, Clone, Default)]
+pub struct SequenceTracker {
+    first: u64,
+    last: Option<u64>,
+    // бит i - пришёл ли номер `last - i`
+    seen: u64,
+}
+
+impl SequenceTracker {
+    pub fn arrive(&mut self, sequence: u64) -> Arrival {
+        let Some(last) = self.last else {
+            self.first = sequence;
+            self.last = Some(sequence);
+            self.seen = 1;
+            return Arrival::Fresh { lost: 0 };
+        };
+        if sequence > last {
+            let shift = sequence - last;
+            self.seen = if shift < SEQUENCE_WINDOW {
+                (self.seen << shift) | 1
+            } else {
+                1
+            };
+            self.last = Some(sequence);
+            return Arrival::Fresh { lost: shift - 1 };
+        }
+        let age = last - sequence;
+        // номера до первого принятого не считались потерянными
+        if sequence < self.first || age >= SEQUENCE_WINDOW {
+            return Arrival::Late { filled: false };
+        }
+        if self.seen & (1 << age) != 0 {
+            return Arrival::Duplicate;
+        }
+        self.seen |= 1 << age;
+        Arrival::Late { filled: true }
+    }
+}
+
+/// Datagram counters of a device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DatagramStats {
+    /// Applied readings.
+    pub received: u64,
+    /// Skipped sequence numbers that never arrived.
+    pub lost: u64,
+    /// Readings that arrived after a newer one and were dropped.
+    pub reordered: u64,
+    /// Readings that arrived again and were dropped.
+    pub duplicates: u64,
+}
+
+impl DatagramStats {
+    pub fn record(&mut self, arrival: Arrival) {
+        match arrival {
+            Arrival::Fresh { lost } => {
+                self.received += 1;
+                self.lost += lost;
+            }
+            Arrival::Late { filled } => {
+                self.reordered += 1;
+                // опоздавшее показание было посчитано потерянным
+                if filled {
+                    self.lost -= 1;
+                }
+            }
+            Arrival::Duplicate => self.duplicates += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use crate::common::{make_client_endpoint, make_server_endpoint};
+    use crate::manager::ConnectionManager;
+    use crate::settings::QuicSettings;
+    use crate::telemetry::Payload;
+
+    #[test]
+    fn roundtrip() {
+        let message = Telemetry {
+            device_id: Uuid::from_u128(7),
+            timestamp_ms: 1_700_000_000_000,
+            payload: Payload::Temperature(21.5),
+        };
+        let datagram = encode_datagram(42, &message);
+        assert_eq!(&datagram[..8], &42_u64.to_be_bytes());
+        assert_eq!(decode_datagram(&datagram), Ok((42, message)));
+
+        assert!(matches!(
+            decode_datagram(&datagram[..5]),
+            Err(DecodeError::Truncated { .. })
+        ));
+        assert!(decode_datagram(&datagram[..20]).is_err());
+    }
+
+    #[test]
+    fn sequence() {
+        let mut tracker = SequenceTracker::default();
+        let mut stats = DatagramStats::default();
+        let arrivals: Vec<Arrival> = [5, 6, 9, 7, 7, 10]
+            .into_iter()
+            .map(|sequence| tracker.arrive(sequence))
+            .collect();
+        assert_eq!(
+            arrivals,
+            vec![
+                Arrival::Fresh { lost: 0 },
+                Arrival::Fresh { lost: 0 },
+                Arrival::Fresh { lost: 2 },
+                Arrival::Late { filled: true },
+                Arrival::Duplicate,
+                Arrival::Fresh { lost: 0 },
+            ]
+        );
+        for arrival in arrivals {
+            stats.record(arrival);
+        }
+        assert_eq!(
+            stats,
+            DatagramStats {
+                received: 4,
+                lost: 1,
+                reordered: 1,
+                duplicates: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn sequence_window() {
+        let mut tracker = SequenceTracker::default();
+        let mut stats = DatagramStats::default();
+        for sequence in [10, 5, 80, 10, 30, 30, 79, 1000, 999] {
+            stats.record(tracker.arrive(sequence));
+        }
+        assert_eq!(
+            stats,
+            DatagramStats {
+                received: 3,
+                // из 11..=79 пришли 30 и 79, из 81..=999 - только 999
+                lost: 67 + 918,
+                // 5 раньше первого номера и 10 вне окна не уменьшают потери
+                reordered: 5,
+                duplicates: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_datagrams_routed_by_device() {
+        let devices = [Uuid::from_u128(1), Uuid::from_u128(2)];
+
+        let server_addr: SocketAddr = "127.0.0.1:5008".parse().unwrap();
+        let (endpoint, server_cert) =
+            make_server_endpoint(server_addr, "localhost", &QuicSettings::default()).unwrap();
+        let (subscribed, ready) = tokio::sync::oneshot::channel::<()>();
+
+        tokio::spawn(async move {
+            let connection = endpoint.accept().await.unwrap().await.unwrap();
+            ready.await.unwrap();
+            let mut sender = DatagramSender::new(connection.clone());
+            for (i, device_id) in devices.iter().enumerate() {
+                for value in 0..3 {
+                    let payload = Payload::Power((i * 10 + value) as f32);
+                    sender.send(&Telemetry::new(*device_id, payload)).unwrap();
+                }
+            }
+            connection.closed().await;
+        });
+
+        let manager = ConnectionManager::new(
+            make_client_endpoint(
+                "127.0.0.1:0".parse().unwrap(),
+                &[&server_cert],
+                &QuicSettings::default(),
+            )
+            .unwrap(),
+        );
+        let mut streams = manager
+            .subscribe(server_addr, "localhost", devices[1])
+            .await
+            .unwrap();
+        let mut datagrams = streams.take_datagrams().unwrap();
+        assert!(streams.take_datagrams().is_none());
+        subscribed.send(()).unwrap();
+
+        for expected in 1..=3 {
+            let (sequence, message) = timeout(Duration::from_secs(5), datagrams.next())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(sequence, expected);
+            assert_eq!(message.device_id, devices[1]);
+            assert_eq!(message.payload, Payload::Power((9 + expected) as f32));
+        }
+
+        drop(streams);
+        manager.endpoint().wait_idle().await;
+    }
+}
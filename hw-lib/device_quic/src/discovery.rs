@@ -0,0 +1,456 @@
+//! Discovery of gateways and devices on the local network.
+//!
+//! Gateways announce themselves and the devices they serve with UDP packets, sent periodically
+//! to a multicast group (or any unicast address, e.g. on loopback). A listener keeps the
+//! announcements it heard recently, so the hub can offer them for adoption.
+//!
+//! ## Packet format
+//!
+//! One announcement per packet: the version line, then `key=value` lines.
+//!
+//! ```text
+//! smart-house-announce/1
+//! kind=thermometer
+//! id=6f1c2d3e-0000-4000-8000-000000000001
+//! name=Kitchen thermometer
+//! addr=0.0.0.0:5000
+//! server_name=localhost
+//! fingerprint=3f0c…
+//! ```
+//!
+//! `addr` is the QUIC address of the gateway, an unspecified IP means the address the packet
+//! came from. `fingerprint` is the SHA-256 of the gateway certificate, see
+//! [`crate::identity::fingerprint`], clients pin it with
+//! [`crate::common::make_client_endpoint_pinned`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::telemetry::Kind;
+
+/// First line of every announcement.
+pub const ANNOUNCE_VERSION: &str = "smart-house-announce/1";
+
+/// Default multicast group and port of announcements.
+pub const DISCOVERY_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 42, 1), 5680);
+
+/// Kind of a gateway announcement, other kinds are devices.
+pub const GATEWAY_KIND: &str = "gateway";
+
+/// Announcements a [`Discovery`] keeps at most. Anyone on the network can announce, so
+/// when the limit is reached expired ones are dropped first, then the least recently heard.
+pub const MAX_ANNOUNCEMENTS: usize = 256;
+
+const MAX_PACKET_LEN: usize = 1500;
+
+type Heard = HashMap<Uuid, (Announcement, Instant)>;
+
+#[derive(Debug, Error)]
+pub enum DiscoveryError {
+    #[error("Invalid announcement: {reason}")]
+    Packet { reason: String },
+}
+
+/// A gateway or a device served by a gateway.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    /// `gateway` or a device kind, e.g. `thermometer`, see [`device_kind`].
+    pub kind: String,
+    pub id: Uuid,
+    pub name: String,
+    /// QUIC address of the gateway.
+    pub addr: SocketAddr,
+    /// Name the gateway certificate is issued for.
+    pub server_name: String,
+    /// SHA-256 of the gateway certificate DER, lowercase hex.
+    pub fingerprint: String,
+}
+
+impl Announcement {
+    pub fn is_gateway(&self) -> bool {
+        self.kind == GATEWAY_KIND
+    }
+}
+
+impl fmt::Display for Announcement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", ANNOUNCE_VERSION)?;
+        writeln!(f, "kind={}", self.kind)?;
+        writeln!(f, "id={}", self.id)?;
+        writeln!(f, "name={}", self.name)?;
+        writeln!(f, "addr={}", self.addr)?;
+        writeln!(f, "server_name={}", self.server_name)?;
+        writeln!(f, "fingerprint={}", self.fingerprint)
+    }
+}
+
+impl FromStr for Announcement {
+    type Err = DiscoveryError;
+
+    fn from_str(packet: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: String| DiscoveryError::Packet { reason };
+        let mut lines = packet
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty());
+        match lines.next() {
+            Some(ANNOUNCE_VERSION) => {}
+            version => return Err(invalid(format!("unsupported version {:?}", version))),
+        }
+
+        let mut fields = HashMap::new();
+        for line in lines {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("expected `<key>=<value>`, got {:?}", line)))?;
+            fields.insert(key, value);
+        }
+        let mut field = |key: &str| {
+            fields
+                .remove(key)
+                .ok_or_else(|| invalid(format!("missing {}", key)))
+        };
+
+        let kind = field("kind")?.to_string();
+        let id = field("id")?;
+        let id = id
+            .parse()
+            .map_err(|_| invalid(format!("invalid id {:?}", id)))?;
+        let name = field("name")?.to_string();
+        let addr = field("addr")?;
+        let addr = addr
+            .parse()
+            .map_err(|_| invalid(format!("invalid addr {:?}", addr)))?;
+        let server_name = field("server_name")?.to_string();
+        let fingerprint = field("fingerprint")?.to_ascii_lowercase();
+        if kind.is_empty() {
+            return Err(invalid("empty kind".to_string()));
+        }
+        // новые версии могут добавлять ключи, поэтому лишние не ошибка
+        Ok(Announcement {
+            kind,
+            id,
+            name,
+            addr,
+            server_name,
+            fingerprint,
+        })
+    }
+}
+
+/// Announced kind of a device with readings of this kind.
+pub fn device_kind(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Temperature => "thermometer",
+        Kind::Motion => "motion",
+        Kind::Power => "outlet",
+    }
+}
+
+/// Sends the announcements to `target` once.
+pub async fn announce(
+    socket: &UdpSocket,
+    target: SocketAddr,
+    announcements: &[Announcement],
+) -> io::Result<()> {
+    for announcement in announcements {
+        socket
+            .send_to(announcement.to_string().as_bytes(), target)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Repeats the announcements every `every` until dropped.
+#[derive(Debug)]
+pub struct Announcer {
+    local_addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl Announcer {
+    /// Binds a socket to `bind_addr` and starts announcing to `target`, a multicast group or a
+    /// unicast address.
+    pub async fn spawn(
+        bind_addr: SocketAddr,
+        target: SocketAddr,
+        announcements: Vec<Announcement>,
+        every: Duration,
+    ) -> io::Result<Self> {
+        let socket = UdpSocket::bind(bind_addr).await?;
+        let local_addr = socket.local_addr()?;
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            loop {
+                interval.tick().await;
+                // слушатели могут ещё не запуститься, пакет просто теряется
+                let _ = announce(&socket, target, &announcements).await;
+            }
+        });
+        Ok(Announcer { local_addr, task })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for Announcer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Listens for announcements and keeps the ones heard within `ttl`, at most
+/// [`MAX_ANNOUNCEMENTS`].
+///
+/// Invalid packets are skipped. Stops listening when dropped.
+#[derive(Debug)]
+pub struct Discovery {
+    local_addr: SocketAddr,
+    ttl: Duration,
+    heard: Arc<Mutex<Heard>>,
+    task: JoinHandle<()>,
+}
+
+impl Discovery {
+    /// Listens on `bind_addr`, joining the multicast `group` on all interfaces if given.
+    pub async fn bind(
+        bind_addr: SocketAddr,
+        group: Option<Ipv4Addr>,
+        ttl: Duration,
+    ) -> io::Result<Self> {
+        let socket = UdpSocket::bind(bind_addr).await?;
+        if let Some(group) = group {
+            socket.join_multicast_v4(group, Ipv4Addr::UNSPECIFIED)?;
+        }
+        let local_addr = socket.local_addr()?;
+        let heard = Arc::new(Mutex::new(HashMap::new()));
+        let task = tokio::spawn(listen(socket, heard.clone(), ttl));
+        Ok(Discovery {
+            local_addr,
+            ttl,
+            heard,
+            task,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Announcements heard within `ttl`, ordered by id.
+    pub fn discovered(&self) -> Vec<Announcement> {
+        let mut heard = self.heard.lock().unwrap();
+        heard.retain(|_, (_, at)| at.elapsed() <= self.ttl);
+        let mut discovered: Vec<Announcement> = heard
+            .values()
+            .map(|(announcement, _)| announcement.clone())
+            .collect();
+        discovered.sort_by_key(|announcement| announcement.id);
+        discovered
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<Announcement> {
+        let heard = self.heard.lock().unwrap();
+        heard
+            .get(&id)
+            .filter(|(_, at)| at.elapsed() <= self.ttl)
+            .map(|(announcement, _)| announcement.clone())
+    }
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn listen(socket: UdpSocket, heard: Arc<Mutex<Heard>>, ttl: Duration) {
+    let mut buf = vec![0_u8; MAX_PACKET_LEN];
+    while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+        let Ok(packet) = std::str::from_utf8(&buf[..len]) else {
+            continue;
+        };
+        let Ok(mut announcement) = packet.parse::<Announcement>() else {
+            continue;
+        };
+        // шлюз не всегда знает свой адрес в сети
+        if announcement.addr.ip().is_unspecified() {
+            announcement.addr.set_ip(from.ip());
+        }
+        let mut heard = heard.lock().unwrap();
+        if !heard.contains_key(&announcement.id) && heard.len() >= MAX_ANNOUNCEMENTS {
+            make_room(&mut heard, ttl);
+        }
+        heard.insert(announcement.id, (announcement, Instant::now()));
+    }
+}
+
+// Освобождает место для нового объявления: удаляет устаревшие, а если их нет - самое старое.
+fn make_room(heard: &mut Heard, ttl: Duration) {
+    heard.retain(|_, (_, at)| at.elapsed() <= ttl);
+    if heard.len() < MAX_ANNOUNCEMENTS {
+        return;
+    }
+    let oldest = heard
+        .iter()
+        .min_by_key(|(_, (_, at))| *at)
+        .map(|(id, _)| *id);
+    if let Some(oldest) = oldest {
+        heard.remove(&oldest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    use tokio::time::{sleep, timeout};
+
+    fn thermometer() -> Announcement {
+        Announcement {
+            kind: "thermometer".to_string(),
+            id: Uuid::from_u128(1),
+            name: "Kitchen thermometer".to_string(),
+            addr: "0.0.0.0:5000".parse().unwrap(),
+            server_name: "localhost".to_string(),
+            fingerprint: "ab".repeat(32),
+        }
+    }
+
+    #[test]
+    fn packet() {
+        let announcement = thermometer();
+        let packet = announcement.to_string();
+        assert!(packet.starts_with("smart-house-announce/1\n"));
+        assert_eq!(packet.parse::<Announcement>().unwrap(), announcement);
+
+        // неизвестные ключи пропускаются
+        let extended = format!("{}color=red\n", packet);
+        assert_eq!(extended.parse::<Announcement>().unwrap(), announcement);
+
+        for packet in [
+            packet.replace("/1", "/2"),
+            packet.replace("id=", "uuid="),
+            packet.replace("addr=0.0.0.0:5000", "addr=localhost"),
+            packet.replace("kind=thermometer", "kind thermometer"),
+            packet.replace("kind=thermometer", "kind="),
+            String::new(),
+        ] {
+            assert!(
+                matches!(
+                    packet.parse::<Announcement>(),
+                    Err(DiscoveryError::Packet { .. })
+                ),
+                "{}",
+                packet
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_discovery_on_loopback() {
+        let discovery = Discovery::bind(
+            "127.0.0.1:5016".parse().unwrap(),
+            None,
+            Duration::from_millis(500),
+        )
+        .await
+        .unwrap();
+
+        let gateway = Announcement {
+            kind: GATEWAY_KIND.to_string(),
+            id: Uuid::from_u128(9),
+            name: "gateway".to_string(),
+            ..thermometer()
+        };
+        let announcer = Announcer::spawn(
+            "127.0.0.1:0".parse().unwrap(),
+            discovery.local_addr(),
+            vec![gateway.clone(), thermometer()],
+            Duration::from_millis(50),
+        )
+        .await
+        .unwrap();
+
+        // мусор не мешает слушателю
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket
+            .send_to(b"hello", discovery.local_addr())
+            .await
+            .unwrap();
+
+        timeout(Duration::from_secs(5), async {
+            while discovery.discovered().len() < 2 {
+                sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let discovered = discovery.discovered();
+        assert_eq!(discovered[0].id, thermometer().id);
+        assert!(discovered[1].is_gateway());
+        // неуказанный адрес заменён адресом отправителя
+        assert_eq!(discovered[0].addr, "127.0.0.1:5000".parse().unwrap());
+
+        // без объявлений устройство пропадает из списка
+        drop(announcer);
+        sleep(Duration::from_millis(700)).await;
+        assert!(discovery.discovered().is_empty());
+        assert!(discovery.get(thermometer().id).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_discovery_limit() {
+        let discovery = Discovery::bind(
+            "127.0.0.1:5022".parse().unwrap(),
+            None,
+            Duration::from_secs(60),
+        )
+        .await
+        .unwrap();
+
+        let announcements: Vec<Announcement> = (1..=MAX_ANNOUNCEMENTS as u128 + 10)
+            .map(|id| Announcement {
+                id: Uuid::from_u128(id),
+                ..thermometer()
+            })
+            .collect();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for announcement in &announcements {
+            announce(
+                &socket,
+                discovery.local_addr(),
+                std::slice::from_ref(announcement),
+            )
+            .await
+            .unwrap();
+            // на loopback пакеты не теряются, если слушатель успевает
+            sleep(Duration::from_millis(1)).await;
+        }
+
+        let last = announcements.last().unwrap().id;
+        timeout(Duration::from_secs(5), async {
+            while discovery.get(last).is_none() {
+                sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(discovery.discovered().len(), MAX_ANNOUNCEMENTS);
+        // вытеснены услышанные первыми
+        assert!(discovery.get(announcements[0].id).is_none());
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt;
+
+use quinn::Connection;
+use thiserror::Error;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum IdentityError {
+    #[error("Invalid peer certificate: {message}")]
+    Certificate { message: String },
+}
+
+/// Identity of a QUIC peer taken from its leaf certificate.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PeerIdentity {
+    pub common_name: Option<String>,
+    // DNS имена и IP адреса
+    pub subject_alt_names: Vec<String>,
+    /// SHA-256 of the certificate DER, lowercase hex.
+    pub fingerprint: String,
+}
+
+impl fmt::Display for PeerIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.common_name {
+            Some(name) => write!(f, "{} ({})", name, self.fingerprint),
+            None => write!(f, "{}", self.fingerprint),
+        }
+    }
+}
+
+impl PeerIdentity {
+    pub fn from_der(cert_der: &[u8]) -> Result<Self, IdentityError> {
+        let (_, cert) =
+            X509Certificate::from_der(cert_der).map_err(|e| IdentityError::Certificate {
+                message: e.to_string(),
+            })?;
+
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|name| name.as_str().ok())
+            .map(str::to_string);
+
+        let subject_alt_names = match cert.subject_alternative_name() {
+            Ok(Some(san)) => san
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) => Some(name.to_string()),
+                    GeneralName::IPAddress(ip) => ip_to_string(ip),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Ok(PeerIdentity {
+            common_name,
+            subject_alt_names,
+            fingerprint: fingerprint(cert_der),
+        })
+    }
+
+    /// Names the peer is known by: common name first, then subject alternative names.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.common_name
+            .iter()
+            .chain(self.subject_alt_names.iter())
+            .map(String::as_str)
+    }
+}
+
+/// SHA-256 of the certificate DER, lowercase hex.
+pub fn fingerprint(cert_der: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, cert_der)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Identity of the connected peer, `None` if it presented no certificate.
+pub fn peer_identity(connection: &Connection) -> Option<Result<PeerIdentity, IdentityError>> {
+    let certs = connection
+        .peer_identity()?
+        .downcast::<Vec<rustls::Certificate>>()
+        .ok()?;
+    let leaf = certs.first()?;
+    Some(PeerIdentity::from_der(&leaf.0))
+}
+
+fn ip_to_string(ip: &[u8]) -> Option<String> {
+    match ip.len() {
+        4 => {
+            let octets: [u8; 4] = ip.try_into().ok()?;
+            Some(std::net::Ipv4Addr::from(octets).to_string())
+        }
+        16 => {
+            let octets: [u8; 16] = ip.try_into().ok()?;
+            Some(std::net::Ipv6Addr::from(octets).to_string())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    use crate::certs::generate_self_signed;
+
+    #[test]
+    fn from_der() {
+        let cert = generate_self_signed(vec![
+            "thermometer-1.local".to_string(),
+            "127.0.0.1".to_string(),
+        ])
+        .unwrap();
+        let identity = PeerIdentity::from_der(&cert.cert_der).unwrap();
+
+        assert_eq!(
+            identity.common_name.as_deref(),
+            Some("rcgen self signed cert")
+        );
+        assert_eq!(
+            identity.subject_alt_names,
+            vec!["thermometer-1.local".to_string(), "127.0.0.1".to_string()]
+        );
+        assert_eq!(identity.fingerprint, fingerprint(&cert.cert_der));
+        assert_eq!(identity.fingerprint.len(), 64);
+        assert_eq!(
+            identity.names().collect::<Vec<_>>(),
+            vec!["rcgen self signed cert", "thermometer-1.local", "127.0.0.1"]
+        );
+
+        assert!(PeerIdentity::from_der(b"not a certificate").is_err());
+    }
+}
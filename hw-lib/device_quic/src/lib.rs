@@ -1 +1,10 @@
+pub mod certs;
+pub mod command;
 pub mod common;
+pub mod datagram;
+pub mod discovery;
+pub mod identity;
+pub mod manager;
+pub mod settings;
+pub mod simulator;
+pub mod telemetry;
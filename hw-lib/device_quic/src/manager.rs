@@ -0,0 +1,558 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use quinn::{ConnectError, Connection, ConnectionError, Endpoint, RecvStream, VarInt};
+use thiserror::Error;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::command::IncomingCommand;
+use crate::datagram::decode_datagram;
+use crate::telemetry::{read_stream_header, Telemetry};
+
+/// Streams of a device kept until it subscribes, older ones are stopped.
+pub const MAX_PENDING_STREAMS: usize = 4;
+
+/// Devices without a subscriber whose streams are kept per gateway, streams of the device
+/// that came first are stopped.
+pub const MAX_PENDING_DEVICES: usize = 64;
+
+/// Datagrams queued for a device, newer ones are dropped while the queue is full.
+pub const MAX_PENDING_DATAGRAMS: usize = 64;
+
+/// Commands queued for a device, the hub waits while the queue is full.
+pub const MAX_PENDING_COMMANDS: usize = 16;
+
+const STREAM_DROPPED: VarInt = VarInt::from_u32(1);
+
+#[derive(Debug, Error)]
+pub enum ManagerError {
+    #[error(transparent)]
+    Connect(#[from] ConnectError),
+    #[error(transparent)]
+    Connection(#[from] ConnectionError),
+    #[error("Device {device_id} is already subscribed to {addr}")]
+    AlreadySubscribed { device_id: Uuid, addr: SocketAddr },
+}
+
+type GatewayKey = (SocketAddr, String);
+
+struct Subscriber {
+    streams: mpsc::Sender<RecvStream>,
+    datagrams: mpsc::Sender<(u64, Telemetry)>,
+    commands: mpsc::Sender<IncomingCommand>,
+}
+
+#[derive(Default)]
+struct Routes {
+    subscribers: HashMap<Uuid, Subscriber>,
+    // потоки, пришедшие раньше подписки устройства
+    pending: HashMap<Uuid, Vec<RecvStream>>,
+    // устройства `pending` в порядке прихода первого потока
+    pending_order: VecDeque<Uuid>,
+}
+
+impl Routes {
+    // Держит поток до подписки устройства. Идентификатор заявляет сам шлюз, поэтому
+    // число устройств и потоков каждого ограничено.
+    fn hold(&mut self, device_id: Uuid, recv: RecvStream) {
+        if !self.pending.contains_key(&device_id) {
+            if self.pending_order.len() == MAX_PENDING_DEVICES {
+                if let Some(oldest) = self.pending_order.pop_front() {
+                    for mut recv in self.pending.remove(&oldest).unwrap_or_default() {
+                        let _ = recv.stop(STREAM_DROPPED);
+                    }
+                }
+            }
+            self.pending_order.push_back(device_id);
+        }
+        let pending = self.pending.entry(device_id).or_default();
+        if pending.len() == MAX_PENDING_STREAMS {
+            let _ = pending.remove(0).stop(STREAM_DROPPED);
+        }
+        pending.push(recv);
+    }
+
+    fn take_pending(&mut self, device_id: &Uuid) -> Vec<RecvStream> {
+        let Some(pending) = self.pending.remove(device_id) else {
+            return Vec::new();
+        };
+        self.pending_order.retain(|id| id != device_id);
+        pending
+    }
+}
+
+#[derive(Clone)]
+struct Gateway {
+    connection: Connection,
+    routes: Arc<Mutex<Routes>>,
+}
+
+/// Shares one QUIC connection per gateway between all devices behind it.
+///
+/// The gateway opens a stream per device with [`crate::telemetry::open_telemetry_stream`],
+/// the manager routes it to the subscriber of that device. The connection is opened by the
+/// first subscriber and closed when the last one goes away.
+#[derive(Clone)]
+pub struct ConnectionManager {
+    endpoint: Endpoint,
+    gateways: Arc<Mutex<HashMap<GatewayKey, Gateway>>>,
+    // подключения к одному шлюзу выполняются по очереди
+    connecting: Arc<Mutex<HashMap<GatewayKey, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl fmt::Debug for ConnectionManager {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConnectionManager")
+            .field("local_addr", &self.endpoint.local_addr().ok())
+            .field("gateways", &self.gateways.lock().unwrap().keys())
+            .finish()
+    }
+}
+
+impl ConnectionManager {
+    /// ## Args
+    ///
+    /// - endpoint: client endpoint, e.g. from [`crate::common::make_client_endpoint`].
+    pub fn new(endpoint: Endpoint) -> Self {
+        ConnectionManager {
+            endpoint,
+            gateways: Arc::new(Mutex::new(HashMap::new())),
+            connecting: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Number of open gateway connections.
+    pub fn connections(&self) -> usize {
+        self.gateways.lock().unwrap().len()
+    }
+
+    /// Subscribes to the streams of `device_id` from the gateway, connecting if needed.
+    pub async fn subscribe(
+        &self,
+        addr: SocketAddr,
+        server_name: &str,
+        device_id: Uuid,
+    ) -> Result<DeviceStreams, ManagerError> {
+        let key = (addr, server_name.to_string());
+        let gateway = self.gateway(&key).await?;
+
+        let (streams, streams_rx) = mpsc::channel(MAX_PENDING_STREAMS * 2);
+        let (datagrams, datagrams_rx) = mpsc::channel(MAX_PENDING_DATAGRAMS);
+        let (commands, commands_rx) = mpsc::channel(MAX_PENDING_COMMANDS);
+        {
+            let mut routes = gateway.routes.lock().unwrap();
+            if routes.subscribers.contains_key(&device_id) {
+                return Err(ManagerError::AlreadySubscribed { device_id, addr });
+            }
+            for recv in routes.take_pending(&device_id) {
+                let _ = streams.try_send(recv);
+            }
+            routes.subscribers.insert(
+                device_id,
+                Subscriber {
+                    streams,
+                    datagrams,
+                    commands,
+                },
+            );
+        }
+
+        Ok(DeviceStreams {
+            device_id,
+            key,
+            streams: streams_rx,
+            datagrams: Some(DeviceDatagrams(datagrams_rx)),
+            commands: Some(DeviceCommands(commands_rx)),
+            gateway,
+            gateways: self.gateways.clone(),
+        })
+    }
+
+    async fn gateway(&self, key: &GatewayKey) -> Result<Gateway, ManagerError> {
+        if let Some(gateway) = self.live_gateway(key) {
+            return Ok(gateway);
+        }
+
+        let lock = self
+            .connecting
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let _connecting = lock.lock().await;
+        // другой подписчик успел подключиться, пока мы ждали
+        if let Some(gateway) = self.live_gateway(key) {
+            return Ok(gateway);
+        }
+
+        let connection = self.endpoint.connect(key.0, &key.1)?.await?;
+        let gateway = Gateway {
+            connection,
+            routes: Arc::new(Mutex::new(Routes::default())),
+        };
+        self.gateways
+            .lock()
+            .unwrap()
+            .insert(key.clone(), gateway.clone());
+        tokio::spawn(route(gateway.clone(), self.gateways.clone(), key.clone()));
+        tokio::spawn(route_datagrams(gateway.clone()));
+        tokio::spawn(route_commands(gateway.clone()));
+        Ok(gateway)
+    }
+
+    fn live_gateway(&self, key: &GatewayKey) -> Option<Gateway> {
+        self.gateways
+            .lock()
+            .unwrap()
+            .get(key)
+            .filter(|gateway| gateway.connection.close_reason().is_none())
+            .cloned()
+    }
+}
+
+// Раздаёт входящие потоки шлюза подписчикам, пока соединение открыто.
+async fn route(
+    gateway: Gateway,
+    gateways: Arc<Mutex<HashMap<GatewayKey, Gateway>>>,
+    key: GatewayKey,
+) {
+    while let Ok(mut recv) = gateway.connection.accept_uni().await {
+        let routes = gateway.routes.clone();
+        let connection = gateway.connection.clone();
+        // заголовок читается отдельно, чтобы медленный поток не задерживал остальные
+        tokio::spawn(async move {
+            let Ok(device_id) = read_stream_header(&mut recv).await else {
+                let _ = recv.stop(STREAM_DROPPED);
+                return;
+            };
+            let subscriber = {
+                let mut routes = routes.lock().unwrap();
+                match routes.subscribers.get(&device_id) {
+                    Some(subscriber) => subscriber.streams.clone(),
+                    // после закрытия соединения маршруты уже очищены
+                    None if connection.close_reason().is_some() => return,
+                    None => {
+                        routes.hold(device_id, recv);
+                        return;
+                    }
+                }
+            };
+            let _ = subscriber.send(recv).await;
+        });
+    }
+
+    // подписчики получат конец потоков
+    *gateway.routes.lock().unwrap() = Routes::default();
+    remove_gateway(&gateways, &key, &gateway.connection);
+}
+
+// Раздаёт датаграммы шлюза подписчикам. Датаграммы без подписчика и не поместившиеся в
+// очередь отбрасываются, получатель увидит их как пропуски номеров.
+async fn route_datagrams(gateway: Gateway) {
+    while let Ok(datagram) = gateway.connection.read_datagram().await {
+        let Ok((sequence, message)) = decode_datagram(&datagram) else {
+            continue;
+        };
+        let routes = gateway.routes.lock().unwrap();
+        if let Some(subscriber) = routes.subscribers.get(&message.device_id) {
+            let _ = subscriber.datagrams.try_send((sequence, message));
+        }
+    }
+}
+
+// Раздаёт команды шлюза подписчикам, команды неизвестным устройствам отклоняются сразу.
+async fn route_commands(gateway: Gateway) {
+    while let Ok((send, mut recv)) = gateway.connection.accept_bi().await {
+        let routes = gateway.routes.clone();
+        tokio::spawn(async move {
+            let device_id = read_stream_header(&mut recv).await;
+            let command = IncomingCommand::new(send, recv);
+            let Ok(device_id) = device_id else {
+                command.reject();
+                return;
+            };
+            let subscriber = routes
+                .lock()
+                .unwrap()
+                .subscribers
+                .get(&device_id)
+                .map(|subscriber| subscriber.commands.clone());
+            match subscriber {
+                Some(subscriber) => {
+                    if let Err(mpsc::error::SendError(command)) = subscriber.send(command).await {
+                        command.reject();
+                    }
+                }
+                None => command.reject(),
+            }
+        });
+    }
+}
+
+fn remove_gateway(
+    gateways: &Mutex<HashMap<GatewayKey, Gateway>>,
+    key: &GatewayKey,
+    connection: &Connection,
+) {
+    let mut gateways = gateways.lock().unwrap();
+    if gateways.get(key).map(|g| g.connection.stable_id()) == Some(connection.stable_id()) {
+        gateways.remove(key);
+    }
+}
+
+/// Telemetry streams of one device, see [`ConnectionManager::subscribe`].
+///
+/// Dropping it unsubscribes the device.
+pub struct DeviceStreams {
+    device_id: Uuid,
+    key: GatewayKey,
+    streams: mpsc::Receiver<RecvStream>,
+    datagrams: Option<DeviceDatagrams>,
+    commands: Option<DeviceCommands>,
+    gateway: Gateway,
+    gateways: Arc<Mutex<HashMap<GatewayKey, Gateway>>>,
+}
+
+impl DeviceStreams {
+    /// The next stream of the device with its header already read.
+    ///
+    /// Returns `None` when the gateway connection is closed.
+    pub async fn next(&mut self) -> Option<RecvStream> {
+        self.streams.recv().await
+    }
+
+    /// Datagrams of the device, available once so they can be read alongside the streams.
+    pub fn take_datagrams(&mut self) -> Option<DeviceDatagrams> {
+        self.datagrams.take()
+    }
+
+    /// Commands of the hub to the device, available once, see [`crate::command`].
+    pub fn take_commands(&mut self) -> Option<DeviceCommands> {
+        self.commands.take()
+    }
+
+    /// The shared gateway connection, e.g. to check the gateway identity.
+    pub fn connection(&self) -> &Connection {
+        &self.gateway.connection
+    }
+}
+
+/// Datagram readings of one device with their sequence numbers, see [`crate::datagram`].
+pub struct DeviceDatagrams(mpsc::Receiver<(u64, Telemetry)>);
+
+impl DeviceDatagrams {
+    /// Returns `None` when the device is unsubscribed or the gateway connection is closed.
+    pub async fn next(&mut self) -> Option<(u64, Telemetry)> {
+        self.0.recv().await
+    }
+}
+
+/// Commands of the hub to one device.
+pub struct DeviceCommands(mpsc::Receiver<IncomingCommand>);
+
+impl DeviceCommands {
+    /// Returns `None` when the device is unsubscribed or the gateway connection is closed.
+    pub async fn next(&mut self) -> Option<IncomingCommand> {
+        self.0.recv().await
+    }
+}
+
+impl Drop for DeviceStreams {
+    fn drop(&mut self) {
+        let idle = {
+            let mut routes = self.gateway.routes.lock().unwrap();
+            routes.subscribers.remove(&self.device_id);
+            routes.subscribers.is_empty()
+        };
+        if idle {
+            self.gateway
+                .connection
+                .close(VarInt::from_u32(0), b"no subscribers");
+            remove_gateway(&self.gateways, &self.key, &self.gateway.connection);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use crate::common::{make_client_endpoint, make_server_endpoint};
+    use crate::settings::QuicSettings;
+    use crate::telemetry::{open_telemetry_stream, read_frame, write_frame, Payload, Telemetry};
+
+    fn pending_devices(manager: &ConnectionManager) -> usize {
+        let gateways = manager.gateways.lock().unwrap();
+        let routes = gateways.values().next().unwrap().routes.lock().unwrap();
+        assert_eq!(routes.pending.len(), routes.pending_order.len());
+        routes.pending.len()
+    }
+
+    #[tokio::test]
+    async fn test_pending_devices_limit() {
+        let devices: Vec<Uuid> = (1..=MAX_PENDING_DEVICES as u128 + 1)
+            .map(Uuid::from_u128)
+            .collect();
+
+        let server_addr: SocketAddr = "127.0.0.1:5020".parse().unwrap();
+        let (endpoint, server_cert) =
+            make_server_endpoint(server_addr, "localhost", &QuicSettings::default()).unwrap();
+
+        // шлюз открывает потоки устройств, на которые ещё никто не подписан
+        let (closed_tx, closed_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn({
+            let devices = devices.clone();
+            async move {
+                let connection = endpoint.accept().await.unwrap().await.unwrap();
+                for device_id in &devices {
+                    let mut send = open_telemetry_stream(&connection, *device_id)
+                        .await
+                        .unwrap();
+                    send.finish().await.unwrap();
+                }
+                closed_rx.await.ok();
+                connection.close(VarInt::from_u32(0), b"done");
+            }
+        });
+
+        let manager = ConnectionManager::new(
+            make_client_endpoint(
+                "127.0.0.1:0".parse().unwrap(),
+                &[&server_cert],
+                &QuicSettings::default(),
+            )
+            .unwrap(),
+        );
+        let mut other = manager
+            .subscribe(server_addr, "localhost", Uuid::from_u128(0))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(pending_devices(&manager), MAX_PENDING_DEVICES);
+
+        // потоки первого устройства вытеснены
+        let mut first = manager
+            .subscribe(server_addr, "localhost", devices[0])
+            .await
+            .unwrap();
+        assert!(timeout(Duration::from_millis(200), first.next())
+            .await
+            .is_err());
+        let mut last = manager
+            .subscribe(server_addr, "localhost", *devices.last().unwrap())
+            .await
+            .unwrap();
+        assert!(timeout(Duration::from_secs(5), last.next())
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(pending_devices(&manager), MAX_PENDING_DEVICES - 1);
+
+        // закрытие соединения очищает маршруты
+        let routes = other.gateway.routes.clone();
+        let _ = closed_tx.send(());
+        assert!(timeout(Duration::from_secs(5), other.next())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(routes.lock().unwrap().pending.is_empty());
+        drop((first, last, other));
+
+        manager.endpoint().wait_idle().await;
+    }
+
+    #[tokio::test]
+    async fn test_one_connection_per_gateway() {
+        let devices: Vec<Uuid> = (1..=3).map(Uuid::from_u128).collect();
+
+        let server_addr: SocketAddr = "127.0.0.1:5006".parse().unwrap();
+        let (endpoint, server_cert) =
+            make_server_endpoint(server_addr, "localhost", &QuicSettings::default()).unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        // шлюз открывает по потоку на каждое устройство сразу после подключения
+        tokio::spawn({
+            let devices = devices.clone();
+            let accepted = accepted.clone();
+            async move {
+                while let Some(connecting) = endpoint.accept().await {
+                    let connection = connecting.await.unwrap();
+                    accepted.fetch_add(1, Ordering::SeqCst);
+                    for (i, device_id) in devices.iter().enumerate() {
+                        let mut send = open_telemetry_stream(&connection, *device_id)
+                            .await
+                            .unwrap();
+                        write_frame(
+                            &mut send,
+                            &Telemetry::new(*device_id, Payload::Temperature(i as f32)),
+                        )
+                        .await
+                        .unwrap();
+                        send.finish().await.unwrap();
+                    }
+                }
+            }
+        });
+
+        let manager = ConnectionManager::new(
+            make_client_endpoint(
+                "127.0.0.1:0".parse().unwrap(),
+                &[&server_cert],
+                &QuicSettings::default(),
+            )
+            .unwrap(),
+        );
+
+        let mut subscriptions = Vec::new();
+        for device_id in &devices {
+            subscriptions.push(
+                manager
+                    .subscribe(server_addr, "localhost", *device_id)
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert_eq!(manager.connections(), 1);
+        assert!(matches!(
+            manager
+                .subscribe(server_addr, "localhost", devices[0])
+                .await,
+            Err(ManagerError::AlreadySubscribed { .. })
+        ));
+
+        for (i, (device_id, streams)) in devices.iter().zip(subscriptions.iter_mut()).enumerate() {
+            let mut recv = timeout(Duration::from_secs(5), streams.next())
+                .await
+                .unwrap()
+                .unwrap();
+            let message = read_frame(&mut recv).await.unwrap().unwrap();
+            assert_eq!(message.device_id, *device_id);
+            assert_eq!(message.payload, Payload::Temperature(i as f32));
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        // соединение закрывается вместе с последней подпиской
+        let connection = subscriptions[0].connection().clone();
+        subscriptions.truncate(1);
+        assert_eq!(manager.connections(), 1);
+        subscriptions.clear();
+        assert_eq!(manager.connections(), 0);
+        assert!(connection.close_reason().is_some());
+
+        manager.endpoint().wait_idle().await;
+    }
+}
@@ -0,0 +1,281 @@
+//! QUIC transport parameters shared by clients and servers.
+//!
+//! ## File format
+//!
+//! TOML, or JSON for files ending in `.json`, with the fields of [`QuicSettings`]. Fields
+//! missing from the file keep their defaults, unknown fields are an error. Durations are strings
+//! with `ms`, `s` or `m` suffixes, `"off"` disables the idle timeout or keep-alive.
+//!
+//! ```toml
+//! idle_timeout = "30s"
+//! keep_alive = "10s"
+//! max_bidi_streams = 16
+//! max_uni_streams = 256
+//! # cubic, new_reno or bbr
+//! congestion = "bbr"
+//! # both ends must share at least one protocol
+//! alpn = ["smart-house/1"]
+//! ```
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use quinn::congestion::{BbrConfig, CubicConfig, NewRenoConfig};
+use quinn::{IdleTimeout, TransportConfig, VarInt};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+
+/// ALPN protocol of the smart house telemetry.
+pub const ALPN_SMART_HOUSE: &[u8] = b"smart-house/1";
+
+#[derive(Debug, Error)]
+pub enum SettingsError {
+    #[error("Cannot read the QUIC settings file {path:?}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Invalid QUIC settings file {path:?}: {reason}")]
+    Parse { path: PathBuf, reason: String },
+    #[error("Idle timeout {timeout:?} is too long")]
+    IdleTimeout { timeout: Duration },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CongestionController {
+    #[default]
+    Cubic,
+    #[serde(alias = "newreno")]
+    NewReno,
+    Bbr,
+}
+
+/// Transport parameters of a QUIC endpoint.
+///
+/// Stream limits bound the streams the peer may open towards this endpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuicSettings {
+    /// Connection is closed after this long without any packets, `None` waits forever.
+    #[serde(deserialize_with = "optional_duration")]
+    pub idle_timeout: Option<Duration>,
+    /// Sends keep-alive packets this often so an idle link doesn't time out.
+    #[serde(deserialize_with = "optional_duration")]
+    pub keep_alive: Option<Duration>,
+    pub max_bidi_streams: u32,
+    pub max_uni_streams: u32,
+    pub congestion: CongestionController,
+    /// Offered (client) or accepted (server) ALPN protocols in order of preference.
+    #[serde(deserialize_with = "protocols")]
+    pub alpn: Vec<Vec<u8>>,
+}
+
+impl Default for QuicSettings {
+    fn default() -> Self {
+        QuicSettings {
+            idle_timeout: Some(Duration::from_secs(30)),
+            keep_alive: Some(Duration::from_secs(10)),
+            max_bidi_streams: 100,
+            max_uni_streams: 100,
+            congestion: CongestionController::default(),
+            alpn: vec![ALPN_SMART_HOUSE.to_vec()],
+        }
+    }
+}
+
+impl QuicSettings {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, SettingsError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(|source| SettingsError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let parsed = match path.extension() {
+            Some(extension) if extension == "json" => {
+                serde_json::from_str(&content).map_err(|e| e.to_string())
+            }
+            _ => toml::from_str(&content).map_err(|e| e.to_string()),
+        };
+        parsed.map_err(|reason| SettingsError::Parse {
+            path: path.to_path_buf(),
+            reason,
+        })
+    }
+
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn with_keep_alive(mut self, keep_alive: Option<Duration>) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn with_congestion(mut self, congestion: CongestionController) -> Self {
+        self.congestion = congestion;
+        self
+    }
+
+    pub fn with_alpn(mut self, alpn: Vec<Vec<u8>>) -> Self {
+        self.alpn = alpn;
+        self
+    }
+
+    /// quinn transport config with these parameters.
+    pub fn transport_config(&self) -> Result<TransportConfig, SettingsError> {
+        let mut transport = TransportConfig::default();
+        let idle_timeout = self
+            .idle_timeout
+            .map(|timeout| {
+                IdleTimeout::try_from(timeout).map_err(|_| SettingsError::IdleTimeout { timeout })
+            })
+            .transpose()?;
+        transport
+            .max_idle_timeout(idle_timeout)
+            .keep_alive_interval(self.keep_alive)
+            .max_concurrent_bidi_streams(VarInt::from_u32(self.max_bidi_streams))
+            .max_concurrent_uni_streams(VarInt::from_u32(self.max_uni_streams));
+        match self.congestion {
+            CongestionController::Cubic => {
+                transport.congestion_controller_factory(Arc::new(CubicConfig::default()))
+            }
+            CongestionController::NewReno => {
+                transport.congestion_controller_factory(Arc::new(NewRenoConfig::default()))
+            }
+            CongestionController::Bbr => {
+                transport.congestion_controller_factory(Arc::new(BbrConfig::default()))
+            }
+        };
+        Ok(transport)
+    }
+}
+
+// `"off"` или длительность для `parse_duration`.
+fn optional_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    match String::deserialize(deserializer)?.as_str() {
+        "off" => Ok(None),
+        value => parse_duration(value).map(Some).map_err(D::Error::custom),
+    }
+}
+
+// Непустой список протоколов ALPN.
+fn protocols<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error> {
+    let protocols = Vec::<String>::deserialize(deserializer)?;
+    if protocols.is_empty() {
+        return Err(D::Error::custom("alpn needs at least one protocol"));
+    }
+    Ok(protocols.into_iter().map(String::into_bytes).collect())
+}
+
+/// Parses `500ms`, `1.5s` or `2m`.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration {:?}", value);
+    let (number, unit) = value
+        .find(|c: char| c.is_ascii_alphabetic())
+        .map(|idx| value.split_at(idx))
+        .ok_or_else(invalid)?;
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        _ => return Err(invalid()),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    fn settings_file(name: &str, content: &str) -> Result<QuicSettings, SettingsError> {
+        let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        let settings = QuicSettings::from_file(&path);
+        fs::remove_file(&path).unwrap();
+        settings
+    }
+
+    #[test]
+    fn parse_settings() {
+        let expected = QuicSettings {
+            idle_timeout: None,
+            keep_alive: Some(Duration::from_millis(2500)),
+            max_bidi_streams: 100,
+            max_uni_streams: 8,
+            congestion: CongestionController::Bbr,
+            alpn: vec![b"smart-house/2".to_vec(), b"smart-house/1".to_vec()],
+        };
+        let settings = settings_file(
+            "quic.toml",
+            r#"
+# комментарий
+idle_timeout = "off"
+keep_alive = "2500ms"
+max_uni_streams = 8
+congestion = "bbr"
+alpn = ["smart-house/2", "smart-house/1"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(settings, expected);
+        let settings = settings_file(
+            "quic.json",
+            r#"{"idle_timeout": "off", "keep_alive": "2500ms", "max_uni_streams": 8,
+                "congestion": "bbr", "alpn": ["smart-house/2", "smart-house/1"]}"#,
+        )
+        .unwrap();
+        assert_eq!(settings, expected);
+        assert_eq!(
+            settings_file("empty.toml", "").unwrap(),
+            QuicSettings::default()
+        );
+
+        for line in [
+            "idle_timeout",
+            "idle_timeout = \"5\"",
+            "max_uni_streams = -1",
+            "congestion = \"vegas\"",
+            "alpn = []",
+            "color = \"red\"",
+        ] {
+            assert!(
+                matches!(
+                    settings_file("invalid.toml", line),
+                    Err(SettingsError::Parse { .. })
+                ),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert!(parse_duration("5").is_err());
+        assert!(parse_duration("-1s").is_err());
+    }
+
+    #[test]
+    fn transport_config() {
+        assert!(QuicSettings::default().transport_config().is_ok());
+        assert!(matches!(
+            QuicSettings::default()
+                .with_idle_timeout(Some(Duration::from_secs(u64::MAX / 1000)))
+                .transport_config(),
+            Err(SettingsError::IdleTimeout { .. })
+        ));
+    }
+}
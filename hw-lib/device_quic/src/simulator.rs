@@ -0,0 +1,584 @@
+//! Virtual sensors which publish generated readings to every connected device.
+//!
+//! ## Scenario file
+//!
+//! One sensor per line: reading kind, device id, signal and its parameters. Empty lines and
+//! lines starting with `#` are skipped. Durations take `ms`, `s` or `m` suffixes.
+//!
+//! ```text
+//! # kind      device                                signal   parameters
+//! temperature 6f1c2d3e-0000-4000-8000-000000000001  sine     base=21 amplitude=3 period=60s
+//! temperature 6f1c2d3e-0000-4000-8000-000000000002  walk     start=20 step=0.5 min=15 max=30
+//! motion      6f1c2d3e-0000-4000-8000-000000000003  burst    period=30s length=5s
+//! power       6f1c2d3e-0000-4000-8000-000000000004  profile  0s:0 10s:1500 40s:200 period=60s
+//! ```
+//!
+//! Every sensor also takes `every=<duration>` (1s by default), the interval between readings,
+//! and the `datagram` flag to send readings as datagrams instead of a stream.
+
+use std::f32::consts::PI;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use quinn::{Connection, Endpoint};
+use rand::Rng;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::datagram::DatagramSender;
+use crate::discovery::{device_kind, Announcement};
+use crate::settings::parse_duration;
+use crate::telemetry::{open_telemetry_stream, write_frame, Kind, Payload, Telemetry};
+
+#[derive(Debug, Error)]
+pub enum ScenarioError {
+    #[error("Cannot read the scenario file {path:?}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Invalid scenario line {line}: {reason}")]
+    Line { line: usize, reason: String },
+}
+
+/// Shape of the generated values.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Signal {
+    /// `base + amplitude * sin(2π t / period)`.
+    Sine {
+        base: f32,
+        amplitude: f32,
+        period: Duration,
+    },
+    /// Moves by a random step of at most `step` each reading, kept within `min..=max`.
+    Walk {
+        start: f32,
+        step: f32,
+        min: f32,
+        max: f32,
+    },
+    /// Motion for `length` at the start of every `period`.
+    Burst { period: Duration, length: Duration },
+    /// Each value holds from its time until the next one, repeated every `period`.
+    Profile {
+        steps: Vec<(Duration, f32)>,
+        period: Duration,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Stream,
+    Datagram,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sensor {
+    pub kind: Kind,
+    pub device_id: Uuid,
+    pub signal: Signal,
+    pub every: Duration,
+    pub transport: Transport,
+}
+
+impl Sensor {
+    /// Reading generator of the sensor, one per connection.
+    pub fn generator(&self) -> Generator {
+        Generator {
+            kind: self.kind,
+            signal: self.signal.clone(),
+            value: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generator {
+    kind: Kind,
+    signal: Signal,
+    // текущее значение случайного блуждания
+    value: Option<f32>,
+}
+
+impl Generator {
+    /// Reading `elapsed` after the start of the simulation.
+    pub fn sample(&mut self, elapsed: Duration, rng: &mut impl Rng) -> Payload {
+        let value = match &self.signal {
+            Signal::Sine {
+                base,
+                amplitude,
+                period,
+            } => {
+                let phase = elapsed.as_secs_f32() / period.as_secs_f32();
+                base + amplitude * (2.0 * PI * phase).sin()
+            }
+            Signal::Walk {
+                start,
+                step,
+                min,
+                max,
+            } => {
+                let value = match self.value {
+                    Some(value) => (value + rng.gen_range(-step..=*step)).clamp(*min, *max),
+                    None => *start,
+                };
+                self.value = Some(value);
+                value
+            }
+            Signal::Burst { period, length } => {
+                let moving = elapsed.as_nanos() % period.as_nanos() < length.as_nanos();
+                return Payload::Motion(moving);
+            }
+            Signal::Profile { steps, period } => {
+                let at = Duration::from_nanos((elapsed.as_nanos() % period.as_nanos()) as u64);
+                steps
+                    .iter()
+                    .take_while(|(time, _)| *time <= at)
+                    .last()
+                    .map_or(0.0, |(_, value)| *value)
+            }
+        };
+        match self.kind {
+            Kind::Power => Payload::Power(value),
+            _ => Payload::Temperature(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Scenario {
+    pub sensors: Vec<Sensor>,
+}
+
+impl Scenario {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ScenarioError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(|source| ScenarioError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        content.parse()
+    }
+
+    /// Announcements of the sensors served at `addr`, see [`crate::discovery`].
+    ///
+    /// ## Args
+    ///
+    /// - server_name: name the gateway certificate is issued for.
+    /// - fingerprint: SHA-256 of the gateway certificate, see [`crate::identity::fingerprint`].
+    pub fn announcements(
+        &self,
+        addr: SocketAddr,
+        server_name: &str,
+        fingerprint: &str,
+    ) -> Vec<Announcement> {
+        self.sensors
+            .iter()
+            .map(|sensor| Announcement {
+                kind: device_kind(sensor.kind).to_string(),
+                id: sensor.device_id,
+                name: format!("{} {}", device_kind(sensor.kind), sensor.device_id),
+                addr,
+                server_name: server_name.to_string(),
+                fingerprint: fingerprint.to_string(),
+            })
+            .collect()
+    }
+}
+
+impl FromStr for Scenario {
+    type Err = ScenarioError;
+
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        let mut sensors = Vec::new();
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let sensor = parse_sensor(line).map_err(|reason| ScenarioError::Line {
+                line: idx + 1,
+                reason,
+            })?;
+            sensors.push(sensor);
+        }
+        Ok(Scenario { sensors })
+    }
+}
+
+fn parse_sensor(line: &str) -> Result<Sensor, String> {
+    let mut parts = line.split_whitespace();
+    let kind = match parts.next() {
+        Some("temperature") => Kind::Temperature,
+        Some("motion") => Kind::Motion,
+        Some("power") => Kind::Power,
+        kind => return Err(format!("unknown kind {:?}", kind.unwrap_or_default())),
+    };
+    let device_id = parts
+        .next()
+        .ok_or("expected `<kind> <device> <signal> [parameters]`")?;
+    let device_id = Uuid::parse_str(device_id).map_err(|e| format!("invalid device id: {}", e))?;
+    let signal = parts.next().ok_or("missing signal")?;
+
+    let mut params = Params::default();
+    for part in parts {
+        match part.split_once(['=', ':']) {
+            Some((key, value)) if part.as_bytes()[key.len()] == b'=' => {
+                params.named.push((key, value))
+            }
+            Some((time, value)) => params.steps.push((
+                parse_duration(time)?,
+                value
+                    .parse()
+                    .map_err(|_| format!("invalid value {:?}", value))?,
+            )),
+            None if part == "datagram" => params.datagram = true,
+            None => return Err(format!("unexpected {:?}", part)),
+        }
+    }
+
+    let signal = match (kind, signal) {
+        (Kind::Temperature | Kind::Power, "sine") => Signal::Sine {
+            base: params.number("base")?,
+            amplitude: params.number("amplitude")?,
+            period: params.duration("period")?,
+        },
+        (Kind::Temperature | Kind::Power, "walk") => {
+            let signal = Signal::Walk {
+                start: params.number("start")?,
+                step: params.number("step")?,
+                min: params.number("min")?,
+                max: params.number("max")?,
+            };
+            match signal {
+                Signal::Walk { step, min, max, .. } if step < 0.0 || min > max => {
+                    return Err("walk needs step >= 0 and min <= max".to_string())
+                }
+                signal => signal,
+            }
+        }
+        (Kind::Motion, "burst") => Signal::Burst {
+            period: params.duration("period")?,
+            length: params.duration("length")?,
+        },
+        (Kind::Temperature | Kind::Power, "profile") => {
+            if params.steps.is_empty() {
+                return Err("profile needs at least one `<time>:<value>` step".to_string());
+            }
+            let mut steps = std::mem::take(&mut params.steps);
+            steps.sort_by_key(|(time, _)| *time);
+            Signal::Profile {
+                steps,
+                period: params.duration("period")?,
+            }
+        }
+        (kind, signal) => return Err(format!("{:?} has no signal {:?}", kind, signal)),
+    };
+    if !params.steps.is_empty() {
+        return Err("only profiles take `<time>:<value>` steps".to_string());
+    }
+
+    let every = match params.take("every") {
+        Some(every) => parse_duration(every)?,
+        None => Duration::from_secs(1),
+    };
+    if every.is_zero() {
+        return Err("`every` must be positive".to_string());
+    }
+    if let Some((key, _)) = params.named.first() {
+        return Err(format!("unknown parameter {:?}", key));
+    }
+
+    Ok(Sensor {
+        kind,
+        device_id,
+        signal,
+        every,
+        transport: if params.datagram {
+            Transport::Datagram
+        } else {
+            Transport::Stream
+        },
+    })
+}
+
+#[derive(Default)]
+struct Params<'a> {
+    named: Vec<(&'a str, &'a str)>,
+    steps: Vec<(Duration, f32)>,
+    datagram: bool,
+}
+
+impl<'a> Params<'a> {
+    fn take(&mut self, key: &str) -> Option<&'a str> {
+        let idx = self.named.iter().position(|(name, _)| *name == key)?;
+        Some(self.named.remove(idx).1)
+    }
+
+    fn number(&mut self, key: &str) -> Result<f32, String> {
+        let value = self.take(key).ok_or_else(|| format!("missing {:?}", key))?;
+        value
+            .parse::<f32>()
+            .ok()
+            .filter(|value| value.is_finite())
+            .ok_or_else(|| format!("invalid {} {:?}", key, value))
+    }
+
+    fn duration(&mut self, key: &str) -> Result<Duration, String> {
+        let value = self.take(key).ok_or_else(|| format!("missing {:?}", key))?;
+        let duration = parse_duration(value)?;
+        if duration.is_zero() {
+            return Err(format!("{} must be positive", key));
+        }
+        Ok(duration)
+    }
+}
+
+/// Accepts devices and publishes the readings of every sensor of the scenario to each of them.
+///
+/// Runs until the endpoint is closed.
+pub async fn serve(endpoint: Endpoint, scenario: Scenario) {
+    let scenario = Arc::new(scenario);
+    let start = Instant::now();
+    while let Some(connecting) = endpoint.accept().await {
+        let scenario = scenario.clone();
+        tokio::spawn(async move {
+            let Ok(connection) = connecting.await else {
+                return;
+            };
+            // номера датаграмм общие для всех сенсоров соединения
+            let datagrams = Arc::new(Mutex::new(DatagramSender::new(connection.clone())));
+            for sensor in scenario.sensors.iter().cloned() {
+                tokio::spawn(publish(
+                    connection.clone(),
+                    datagrams.clone(),
+                    sensor,
+                    start,
+                ));
+            }
+        });
+    }
+}
+
+// Публикует показания сенсора, пока соединение открыто.
+async fn publish(
+    connection: Connection,
+    datagrams: Arc<Mutex<DatagramSender>>,
+    sensor: Sensor,
+    start: Instant,
+) {
+    let mut stream = match sensor.transport {
+        Transport::Stream => match open_telemetry_stream(&connection, sensor.device_id).await {
+            Ok(send) => Some(send),
+            Err(_) => return,
+        },
+        Transport::Datagram => None,
+    };
+    let mut generator = sensor.generator();
+    let mut interval = tokio::time::interval(sensor.every);
+    loop {
+        interval.tick().await;
+        let payload = generator.sample(start.elapsed(), &mut rand::thread_rng());
+        let message = Telemetry::new(sensor.device_id, payload);
+        let sent = match stream.as_mut() {
+            Some(send) => write_frame(send, &message).await.is_ok(),
+            None => datagrams.lock().unwrap().send(&message).is_ok(),
+        };
+        if !sent {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use tokio::time::timeout;
+
+    use crate::common::{make_client_endpoint, make_server_endpoint};
+    use crate::manager::ConnectionManager;
+    use crate::settings::QuicSettings;
+    use crate::telemetry::read_frame;
+
+    const SCENARIO: &str = "
+# комментарий
+temperature 00000000-0000-0000-0000-000000000001 sine base=20 amplitude=4 period=8s every=100ms
+temperature 00000000-0000-0000-0000-000000000002 walk start=20 step=0.5 min=19 max=21 datagram
+motion      00000000-0000-0000-0000-000000000003 burst period=30s length=5s
+power       00000000-0000-0000-0000-000000000004 profile 10s:1500 0s:0 40s:200 period=1m
+";
+
+    #[test]
+    fn parse_scenario() {
+        let scenario: Scenario = SCENARIO.parse().unwrap();
+        assert_eq!(scenario.sensors.len(), 4);
+        assert_eq!(
+            scenario.sensors[0],
+            Sensor {
+                kind: Kind::Temperature,
+                device_id: Uuid::from_u128(1),
+                signal: Signal::Sine {
+                    base: 20.0,
+                    amplitude: 4.0,
+                    period: Duration::from_secs(8),
+                },
+                every: Duration::from_millis(100),
+                transport: Transport::Stream,
+            }
+        );
+        assert_eq!(scenario.sensors[1].transport, Transport::Datagram);
+        let announcements =
+            scenario.announcements("0.0.0.0:5000".parse().unwrap(), "localhost", "ab");
+        assert_eq!(
+            announcements
+                .iter()
+                .map(|announcement| announcement.kind.as_str())
+                .collect::<Vec<_>>(),
+            vec!["thermometer", "thermometer", "motion", "outlet"]
+        );
+        assert_eq!(
+            include_str!("../scenarios/house.txt")
+                .parse::<Scenario>()
+                .unwrap()
+                .sensors
+                .len(),
+            4
+        );
+        assert_eq!(scenario.sensors[1].every, Duration::from_secs(1));
+        assert_eq!(
+            scenario.sensors[3].signal,
+            Signal::Profile {
+                steps: vec![
+                    (Duration::ZERO, 0.0),
+                    (Duration::from_secs(10), 1500.0),
+                    (Duration::from_secs(40), 200.0),
+                ],
+                period: Duration::from_secs(60),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let id = "00000000-0000-0000-0000-000000000001";
+        for line in [
+            "humidity 00000000-0000-0000-0000-000000000001 sine".to_string(),
+            "temperature not-a-uuid sine base=1 amplitude=1 period=1s".to_string(),
+            format!("temperature {} sine base=1 amplitude=1", id),
+            format!("temperature {} sine base=x amplitude=1 period=1s", id),
+            format!("temperature {} sine base=1 amplitude=1 period=0s", id),
+            format!("temperature {} sine base=1 amplitude=1 period=1h", id),
+            format!(
+                "temperature {} sine base=1 amplitude=1 period=1s color=red",
+                id
+            ),
+            format!("temperature {} sine base=1 amplitude=1 period=1s 0s:1", id),
+            format!("temperature {} burst period=1s length=1s", id),
+            format!("motion {} sine base=1 amplitude=1 period=1s", id),
+            format!("power {} profile period=1s", id),
+            format!("power {} walk start=1 step=1 min=2 max=1", id),
+        ] {
+            let error = format!("# header\n{}", line).parse::<Scenario>();
+            assert!(
+                matches!(error, Err(ScenarioError::Line { line: 2, .. })),
+                "{}: {:?}",
+                line,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn signals() {
+        let scenario: Scenario = SCENARIO.parse().unwrap();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut sample = |sensor: usize, secs: f32| {
+            scenario.sensors[sensor]
+                .generator()
+                .sample(Duration::from_secs_f32(secs), &mut rng)
+        };
+
+        assert_eq!(sample(0, 0.0), Payload::Temperature(20.0));
+        assert_eq!(sample(0, 2.0), Payload::Temperature(24.0));
+        assert_eq!(sample(2, 4.0), Payload::Motion(true));
+        assert_eq!(sample(2, 29.0), Payload::Motion(false));
+        assert_eq!(sample(2, 31.0), Payload::Motion(true));
+        assert_eq!(sample(3, 5.0), Payload::Power(0.0));
+        assert_eq!(sample(3, 39.0), Payload::Power(1500.0));
+        assert_eq!(sample(3, 59.0), Payload::Power(200.0));
+        assert_eq!(sample(3, 75.0), Payload::Power(1500.0));
+
+        let mut walk = scenario.sensors[1].generator();
+        assert_eq!(
+            walk.sample(Duration::ZERO, &mut rng),
+            Payload::Temperature(20.0)
+        );
+        let mut previous = 20.0;
+        for _ in 0..1000 {
+            let Payload::Temperature(value) = walk.sample(Duration::ZERO, &mut rng) else {
+                panic!("walk of a thermometer must give temperatures");
+            };
+            assert!((19.0..=21.0).contains(&value));
+            assert!((value - previous).abs() <= 0.5 + f32::EPSILON);
+            previous = value;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_scenario() {
+        let scenario: Scenario = SCENARIO.parse().unwrap();
+        let server_addr: SocketAddr = "127.0.0.1:5010".parse().unwrap();
+        let (endpoint, server_cert) =
+            make_server_endpoint(server_addr, "localhost", &QuicSettings::default()).unwrap();
+        tokio::spawn(serve(endpoint.clone(), scenario));
+
+        let manager = ConnectionManager::new(
+            make_client_endpoint(
+                "127.0.0.1:0".parse().unwrap(),
+                &[&server_cert],
+                &QuicSettings::default(),
+            )
+            .unwrap(),
+        );
+        let mut thermometer = manager
+            .subscribe(server_addr, "localhost", Uuid::from_u128(1))
+            .await
+            .unwrap();
+        let mut walk = manager
+            .subscribe(server_addr, "localhost", Uuid::from_u128(2))
+            .await
+            .unwrap();
+        let mut walk = walk.take_datagrams().unwrap();
+
+        let mut recv = timeout(Duration::from_secs(5), thermometer.next())
+            .await
+            .unwrap()
+            .unwrap();
+        for _ in 0..3 {
+            let message = read_frame(&mut recv).await.unwrap().unwrap();
+            assert_eq!(message.device_id, Uuid::from_u128(1));
+            let Payload::Temperature(value) = message.payload else {
+                panic!("unexpected {:?}", message.payload);
+            };
+            assert!((16.0..=24.0).contains(&value));
+        }
+
+        let (sequence, message) = timeout(Duration::from_secs(5), walk.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(sequence >= 1);
+        assert_eq!(message.device_id, Uuid::from_u128(2));
+
+        endpoint.close(0_u8.into(), b"done");
+    }
+}
@@ -0,0 +1,450 @@
+//! Framed telemetry messages sent from sensors to devices.
+//!
+//! A telemetry stream starts with a header: [`VERSION`] and the 16 byte UUID of the device
+//! the stream is for, see [`open_telemetry_stream`].
+//!
+//! Every message is a frame: a big-endian `u32` length followed by the body
+//!
+//! | field        | size | notes                                 |
+//! |--------------|------|---------------------------------------|
+//! | version      | 1    | [`VERSION`]                           |
+//! | kind         | 1    | [`Kind`]                              |
+//! | device id    | 16   | UUID of the device the reading is for |
+//! | timestamp    | 8    | milliseconds since the UNIX epoch     |
+//! | payload      | ..   | depends on kind, see [`Payload`]      |
+//!
+//! Readings may also be sent unreliably as QUIC datagrams, see [`crate::datagram`].
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use quinn::{Connection, ConnectionError, ReadError, RecvStream, SendStream, WriteError};
+use thiserror::Error;
+use uuid::Uuid;
+
+pub const VERSION: u8 = 1;
+
+/// Upper bound of the body length, longer frames are rejected before allocation.
+pub const MAX_FRAME_LEN: usize = 1024;
+
+const LEN_PREFIX: usize = 4;
+const HEADER_LEN: usize = 1 + 1 + 16 + 8;
+const STREAM_HEADER_LEN: usize = 1 + 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Kind {
+    Temperature = 1,
+    Motion = 2,
+    Power = 3,
+}
+
+impl TryFrom<u8> for Kind {
+    type Error = DecodeError;
+
+    fn try_from(kind: u8) -> Result<Self, Self::Error> {
+        match kind {
+            1 => Ok(Kind::Temperature),
+            2 => Ok(Kind::Motion),
+            3 => Ok(Kind::Power),
+            _ => Err(DecodeError::UnknownKind { kind }),
+        }
+    }
+}
+
+/// Typed reading.
+///
+/// - `Temperature`: degrees Celsius, `f32`.
+/// - `Motion`: `0` or `1`.
+/// - `Power`: watts, `f32`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Payload {
+    Temperature(f32),
+    Motion(bool),
+    Power(f32),
+}
+
+impl Payload {
+    pub fn kind(&self) -> Kind {
+        match self {
+            Payload::Temperature(_) => Kind::Temperature,
+            Payload::Motion(_) => Kind::Motion,
+            Payload::Power(_) => Kind::Power,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Telemetry {
+    pub device_id: Uuid,
+    pub timestamp_ms: u64,
+    pub payload: Payload,
+}
+
+impl Telemetry {
+    /// Reading taken now.
+    pub fn new(device_id: Uuid, payload: Payload) -> Self {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        Telemetry {
+            device_id,
+            timestamp_ms,
+            payload,
+        }
+    }
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("Frame is truncated: {needed} bytes needed, {available} available")]
+    Truncated { needed: usize, available: usize },
+    #[error("Frame length {len} exceeds {MAX_FRAME_LEN}")]
+    TooLong { len: usize },
+    #[error("Unsupported protocol version {version}")]
+    Version { version: u8 },
+    #[error("Unknown message kind {kind}")]
+    UnknownKind { kind: u8 },
+    #[error("Invalid payload of {kind:?}")]
+    InvalidPayload { kind: Kind },
+}
+
+#[derive(Debug, Error)]
+pub enum TelemetryError {
+    #[error(transparent)]
+    Read(#[from] ReadError),
+    #[error(transparent)]
+    Write(#[from] WriteError),
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    #[error(transparent)]
+    Connection(#[from] ConnectionError),
+}
+
+/// Encodes the message into a frame with its length prefix.
+pub fn encode(message: &Telemetry) -> Vec<u8> {
+    let body = encode_body(message);
+    let mut frame = Vec::with_capacity(LEN_PREFIX + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    frame
+}
+
+// Тело кадра без длины, см. [`decode`].
+pub(crate) fn encode_body(message: &Telemetry) -> Vec<u8> {
+    let mut body = Vec::with_capacity(HEADER_LEN + 4);
+    body.push(VERSION);
+    body.push(message.payload.kind() as u8);
+    body.extend_from_slice(message.device_id.as_bytes());
+    body.extend_from_slice(&message.timestamp_ms.to_be_bytes());
+    match message.payload {
+        Payload::Temperature(value) | Payload::Power(value) => {
+            body.extend_from_slice(&value.to_be_bytes())
+        }
+        Payload::Motion(value) => body.push(value as u8),
+    }
+    body
+}
+
+/// Decodes a frame body, i.e. a frame without its length prefix.
+pub fn decode(body: &[u8]) -> Result<Telemetry, DecodeError> {
+    if body.len() > MAX_FRAME_LEN {
+        return Err(DecodeError::TooLong { len: body.len() });
+    }
+    if body.len() < HEADER_LEN {
+        return Err(DecodeError::Truncated {
+            needed: HEADER_LEN,
+            available: body.len(),
+        });
+    }
+    let version = body[0];
+    if version != VERSION {
+        return Err(DecodeError::Version { version });
+    }
+    let kind = Kind::try_from(body[1])?;
+    let device_id = Uuid::from_bytes(body[2..18].try_into().unwrap());
+    let timestamp_ms = u64::from_be_bytes(body[18..26].try_into().unwrap());
+
+    let payload = &body[HEADER_LEN..];
+    let payload = match kind {
+        Kind::Temperature | Kind::Power => {
+            let bytes: [u8; 4] = payload
+                .try_into()
+                .map_err(|_| DecodeError::InvalidPayload { kind })?;
+            let value = f32::from_be_bytes(bytes);
+            if !value.is_finite() {
+                return Err(DecodeError::InvalidPayload { kind });
+            }
+            if kind == Kind::Temperature {
+                Payload::Temperature(value)
+            } else {
+                Payload::Power(value)
+            }
+        }
+        Kind::Motion => match payload {
+            [0] => Payload::Motion(false),
+            [1] => Payload::Motion(true),
+            _ => return Err(DecodeError::InvalidPayload { kind }),
+        },
+    };
+
+    Ok(Telemetry {
+        device_id,
+        timestamp_ms,
+        payload,
+    })
+}
+
+/// Decodes the first frame of `buf`.
+///
+/// ## Returns
+///
+/// - `None` if `buf` doesn't hold a whole frame yet
+/// - the message and the number of consumed bytes
+pub fn decode_frame(buf: &[u8]) -> Result<Option<(Telemetry, usize)>, DecodeError> {
+    if buf.len() < LEN_PREFIX {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes(buf[..LEN_PREFIX].try_into().unwrap()) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(DecodeError::TooLong { len });
+    }
+    if buf.len() < LEN_PREFIX + len {
+        return Ok(None);
+    }
+    let message = decode(&buf[LEN_PREFIX..LEN_PREFIX + len])?;
+    Ok(Some((message, LEN_PREFIX + len)))
+}
+
+/// Opens a telemetry stream of the device.
+pub async fn open_telemetry_stream(
+    connection: &Connection,
+    device_id: Uuid,
+) -> Result<SendStream, TelemetryError> {
+    let mut send = connection.open_uni().await?;
+    send.write_all(&stream_header(device_id)).await?;
+    Ok(send)
+}
+
+// Заголовок потока устройства, общий для телеметрии и команд.
+pub(crate) fn stream_header(device_id: Uuid) -> [u8; STREAM_HEADER_LEN] {
+    let mut header = [0_u8; STREAM_HEADER_LEN];
+    header[0] = VERSION;
+    header[1..].copy_from_slice(device_id.as_bytes());
+    header
+}
+
+/// Reads the header of a stream from [`open_telemetry_stream`] or
+/// [`crate::command::send_command`] and returns the device id.
+pub async fn read_stream_header(recv: &mut RecvStream) -> Result<Uuid, TelemetryError> {
+    let mut header = [0_u8; STREAM_HEADER_LEN];
+    let available = read_full(recv, &mut header).await?;
+    if available < STREAM_HEADER_LEN {
+        return Err(DecodeError::Truncated {
+            needed: STREAM_HEADER_LEN,
+            available,
+        }
+        .into());
+    }
+    if header[0] != VERSION {
+        return Err(DecodeError::Version { version: header[0] }.into());
+    }
+    Ok(Uuid::from_bytes(header[1..].try_into().unwrap()))
+}
+
+pub async fn write_frame(send: &mut SendStream, message: &Telemetry) -> Result<(), TelemetryError> {
+    send.write_all(&encode(message)).await?;
+    Ok(())
+}
+
+/// Reads the next frame of the stream.
+///
+/// Returns `None` when the stream is finished between frames.
+pub async fn read_frame(recv: &mut RecvStream) -> Result<Option<Telemetry>, TelemetryError> {
+    let mut prefix = [0_u8; LEN_PREFIX];
+    match read_full(recv, &mut prefix).await? {
+        0 => return Ok(None),
+        LEN_PREFIX => {}
+        available => {
+            return Err(DecodeError::Truncated {
+                needed: LEN_PREFIX,
+                available,
+            }
+            .into())
+        }
+    }
+    let len = u32::from_be_bytes(prefix) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(DecodeError::TooLong { len }.into());
+    }
+    let mut body = vec![0_u8; len];
+    let available = read_full(recv, &mut body).await?;
+    if available < len {
+        return Err(DecodeError::Truncated {
+            needed: len,
+            available,
+        }
+        .into());
+    }
+    Ok(Some(decode(&body)?))
+}
+
+// Reads until `buf` is full or the stream is finished, returns the number of read bytes.
+pub(crate) async fn read_full(recv: &mut RecvStream, buf: &mut [u8]) -> Result<usize, ReadError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match recv.read(&mut buf[filled..]).await? {
+            Some(n) => filled += n,
+            None => break,
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    use proptest::prelude::*;
+
+    fn message(payload: Payload) -> Telemetry {
+        Telemetry {
+            device_id: Uuid::from_u128(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef),
+            timestamp_ms: 1_700_000_000_000,
+            payload,
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let frame = encode(&message(Payload::Motion(true)));
+        assert_eq!(frame.len(), 4 + 27);
+        assert_eq!(&frame[..4], &27_u32.to_be_bytes());
+        assert_eq!(frame[4], VERSION);
+        assert_eq!(frame[5], Kind::Motion as u8);
+        assert_eq!(frame[6], 0x01);
+        assert_eq!(frame[30], 1);
+    }
+
+    #[test]
+    fn roundtrip() {
+        for payload in [
+            Payload::Temperature(-20.5),
+            Payload::Motion(false),
+            Payload::Power(1500.0),
+        ] {
+            let frame = encode(&message(payload));
+            let (decoded, used) = decode_frame(&frame).unwrap().unwrap();
+            assert_eq!(decoded, message(payload));
+            assert_eq!(used, frame.len());
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let frame = encode(&message(Payload::Temperature(21.0)));
+
+        let mut body = frame[4..].to_vec();
+        body[0] = 2;
+        assert_eq!(decode(&body), Err(DecodeError::Version { version: 2 }));
+
+        let mut body = frame[4..].to_vec();
+        body[1] = 42;
+        assert_eq!(decode(&body), Err(DecodeError::UnknownKind { kind: 42 }));
+
+        let body = &frame[4..frame.len() - 1];
+        assert_eq!(
+            decode(body),
+            Err(DecodeError::InvalidPayload {
+                kind: Kind::Temperature
+            })
+        );
+
+        let mut body = frame[4..].to_vec();
+        body[26..].copy_from_slice(&f32::NAN.to_be_bytes());
+        assert_eq!(
+            decode(&body),
+            Err(DecodeError::InvalidPayload {
+                kind: Kind::Temperature
+            })
+        );
+
+        let mut body = encode(&message(Payload::Motion(true)))[4..].to_vec();
+        body[26] = 7;
+        assert_eq!(
+            decode(&body),
+            Err(DecodeError::InvalidPayload { kind: Kind::Motion })
+        );
+
+        assert_eq!(
+            decode_frame(&u32::MAX.to_be_bytes()),
+            Err(DecodeError::TooLong {
+                len: u32::MAX as usize
+            })
+        );
+    }
+
+    fn payload() -> impl Strategy<Value = Payload> {
+        prop_oneof![
+            (-1000.0_f32..1000.0).prop_map(Payload::Temperature),
+            any::<bool>().prop_map(Payload::Motion),
+            (0.0_f32..100_000.0).prop_map(Payload::Power),
+        ]
+    }
+
+    fn telemetry() -> impl Strategy<Value = Telemetry> {
+        (any::<u128>(), any::<u64>(), payload()).prop_map(|(id, timestamp_ms, payload)| Telemetry {
+            device_id: Uuid::from_u128(id),
+            timestamp_ms,
+            payload,
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn prop_roundtrip(message in telemetry()) {
+            let frame = encode(&message);
+            let (decoded, used) = decode_frame(&frame).unwrap().unwrap();
+            prop_assert_eq!(decoded, message);
+            prop_assert_eq!(used, frame.len());
+        }
+
+        #[test]
+        fn prop_concatenated(messages in prop::collection::vec(telemetry(), 0..8)) {
+            let buf: Vec<u8> = messages.iter().flat_map(encode).collect();
+            let mut decoded = Vec::new();
+            let mut rest = &buf[..];
+            while let Some((message, used)) = decode_frame(rest).unwrap() {
+                decoded.push(message);
+                rest = &rest[used..];
+            }
+            prop_assert!(rest.is_empty());
+            prop_assert_eq!(decoded, messages);
+        }
+
+        #[test]
+        fn prop_truncated(message in telemetry(), cut in 0_usize..31) {
+            let frame = encode(&message);
+            let cut = cut.min(frame.len() - 1);
+            prop_assert_eq!(decode_frame(&frame[..cut]), Ok(None));
+        }
+
+        #[test]
+        fn prop_arbitrary_bytes(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+            // must never panic
+            let _ = decode(&bytes);
+            let _ = decode_frame(&bytes);
+        }
+
+        #[test]
+        fn prop_mutated(message in telemetry(), idx in any::<prop::sample::Index>(), byte in any::<u8>()) {
+            let mut frame = encode(&message);
+            let i = idx.index(frame.len());
+            frame[i] = byte;
+            if let Ok(Some((_, used))) = decode_frame(&frame) {
+                prop_assert_eq!(used, frame.len());
+            }
+        }
+    }
+}
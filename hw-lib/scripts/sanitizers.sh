@@ -0,0 +1,47 @@
+#!/bin/sh
+# Тесты C ABI `smart_house` под AddressSanitizer, Miri и ThreadSanitizer, см. Readme.md.
+#
+#     scripts/sanitizers.sh [asan] [miri] [tsan]
+#
+# Без аргументов запускаются все три. Нужен nightly с компонентами `miri` и `rust-src`.
+set -eu
+
+cd "$(dirname "$0")/.."
+
+TARGET=$(rustc +nightly -vV | sed -n 's/^host: //p')
+
+# тесты без сети и tokio
+FFI_TESTS="ffi::tests::test_add_rooms ffi::tests::test_add_devices ffi::tests::test_report \
+ffi::tests::test_devices ffi::tests::test_events ffi::tests::test_snapshot"
+THREAD_TESTS="ffi::tests::test_threads ffi::tests::test_unsubscribe_each_other ffi::tests::test_events"
+
+asan() {
+    RUSTFLAGS="-Zsanitizer=address" cargo +nightly test -p smart_house --lib \
+        --target "$TARGET" --target-dir target/asan -- --exact $FFI_TESTS
+}
+
+miri() {
+    cargo +nightly miri test -p smart_house --lib -- --exact $FFI_TESTS
+}
+
+# стандартная библиотека собирается с тем же санитайзером, иначе блокировки `Mutex`
+# не видны и сообщаются ложные гонки
+tsan() {
+    RUSTFLAGS="-Zsanitizer=thread -Cunsafe-allow-abi-mismatch=sanitizer" \
+        cargo +nightly test -Zbuild-std -p smart_house --lib \
+        --target "$TARGET" --target-dir target/tsan -- --exact $THREAD_TESTS
+}
+
+if [ $# -eq 0 ]; then
+    set -- asan miri tsan
+fi
+
+for check in "$@"; do
+    case "$check" in
+    asan | miri | tsan) "$check" ;;
+    *)
+        echo "unknown check $check, expected asan, miri or tsan" >&2
+        exit 2
+        ;;
+    esac
+done
@@ -0,0 +1,279 @@
+/* Generated by cbindgen from smart_house/src/ffi.rs, do not edit. */
+
+#ifndef SMART_HOUSE_H
+#define SMART_HOUSE_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Version of the C ABI, incremented on every change of exported functions, types or their
+// guarantees, e.g. version 4 made the handle thread-safe and version 5 added house snapshots.
+#define SMART_HOUSE_ABI_VERSION 5
+
+// What happened in the house, see [`SmartHouseEvent`].
+typedef enum {
+  SMART_HOUSE_EVENT_KIND_ROOM_ADDED = 1,
+  SMART_HOUSE_EVENT_KIND_ROOM_REMOVED = 2,
+  SMART_HOUSE_EVENT_KIND_DEVICE_ADDED = 3,
+  SMART_HOUSE_EVENT_KIND_DEVICE_REMOVED = 4,
+  // The device was turned on or off, `on` is the new state.
+  SMART_HOUSE_EVENT_KIND_DEVICE_SWITCHED = 5,
+  // The device took a reading, `value` is its new value.
+  SMART_HOUSE_EVENT_KIND_READING_CHANGED = 6,
+} SmartHouseEventKind;
+
+// Kind of a sensor reading fed with [`smart_house_feed_reading`].
+typedef enum {
+  // Degrees Celsius.
+  SMART_HOUSE_READING_KIND_TEMPERATURE = 1,
+  // Any non-zero value means motion.
+  SMART_HOUSE_READING_KIND_MOTION = 2,
+  // Watts.
+  SMART_HOUSE_READING_KIND_POWER = 3,
+} SmartHouseReadingKind;
+
+// Result of a C ABI call, the message is available through [`smart_house_last_error`].
+typedef enum {
+  SMART_HOUSE_STATUS_OK = 0,
+  // A required pointer argument is null.
+  SMART_HOUSE_STATUS_NULL_POINTER = 1,
+  // A string argument is not valid UTF-8.
+  SMART_HOUSE_STATUS_INVALID_STRING = 2,
+  // No room or device with this name.
+  SMART_HOUSE_STATUS_NOT_FOUND = 3,
+  SMART_HOUSE_STATUS_ALREADY_EXISTS = 4,
+  // The operation is not supported, e.g. an unknown device kind.
+  SMART_HOUSE_STATUS_UNSUPPORTED = 5,
+  // The library panicked, the house may be left half-updated.
+  SMART_HOUSE_STATUS_PANIC = 6,
+  // Malformed JSON parameters or an unknown enum value.
+  SMART_HOUSE_STATUS_INVALID_ARGUMENT = 7,
+} SmartHouseStatus;
+
+// Smart house handle, opaque for C.
+//
+// The handle locks the house for every call, so it can be used from several threads at once.
+typedef struct SmartHouseLib SmartHouseLib;
+
+// Event passed to a [`SmartHouseCallback`].
+//
+// The strings are valid only until the callback returns.
+typedef struct {
+  SmartHouseEventKind kind;
+  const char *room;
+  // Null for room events.
+  const char *device;
+  // Whether the device is on, false for room events.
+  bool on;
+  // Value of the device, see `value` of [`smart_house_device_status`], 0 for room events.
+  int64_t value;
+} SmartHouseEvent;
+
+// Callback registered with [`smart_house_subscribe`], must not be null.
+typedef void (*SmartHouseCallback)(const SmartHouseEvent *event, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Version of the C ABI the library was built with, see [`SMART_HOUSE_ABI_VERSION`].
+//
+// Loaders check it before calling anything else and refuse a library of another version.
+uint32_t smart_house_abi_version(void);
+
+// Message of the last failed call on this thread, null if it succeeded.
+//
+// The string belongs to the library and stays valid until the next call on this thread,
+// it must not be released.
+const char *smart_house_last_error(void);
+
+// Creates a house and writes it to `out`, the caller releases it with [`smart_house_destroy`].
+//
+// # Safety
+//
+// `name` must be a valid nul-terminated string, it is only borrowed. `out` must be valid
+// for writes.
+SmartHouseStatus smart_house_new(const char *name, SmartHouseLib **out);
+
+// Creates a house from a JSON snapshot of [`smart_house_export`] and writes it to `out`, the
+// caller releases it with [`smart_house_destroy`].
+//
+// The snapshot may come from another version of the library. Returns
+// [`SmartHouseStatus::InvalidArgument`] for malformed JSON or a reading out of the device
+// range and [`SmartHouseStatus::Unsupported`] for a newer snapshot format or an unknown
+// device kind.
+//
+// # Safety
+//
+// `snapshot` must be a valid nul-terminated string, it is only borrowed. `out` must be valid
+// for writes.
+SmartHouseStatus smart_house_import(const char *snapshot, SmartHouseLib **out);
+
+// Releases the house, its subscriptions are dropped without being called.
+//
+// # Safety
+//
+// `smarthouse` must be null or a pointer returned by [`smart_house_new`] which was not destroyed yet.
+// No other call with this house may run concurrently or start afterwards.
+SmartHouseStatus smart_house_destroy(SmartHouseLib *smarthouse);
+
+// # Safety
+//
+// `smarthouselib` must be a live pointer from [`smart_house_new`], `name` a valid nul-terminated string.
+SmartHouseStatus smart_house_add_room(SmartHouseLib *smarthouselib,
+                                      const char *name);
+
+// # Safety
+//
+// `smarthouselib` must be a live pointer from [`smart_house_new`], `name` a valid nul-terminated string.
+SmartHouseStatus smart_house_remove_room(SmartHouseLib *smarthouselib,
+                                         const char *name);
+
+// Writes an array of room names, which the caller releases with [`smart_house_free_string_list`].
+//
+// # Safety
+//
+// `smarthouselib` must be a live pointer from [`smart_house_new`], `out_buffer` and `out_size` valid
+// for writes.
+SmartHouseStatus smart_house_list_rooms(SmartHouseLib *smarthouselib,
+                                        char ***out_buffer,
+                                        size_t *out_size);
+
+// Releases an array written by [`smart_house_list_rooms`] or [`smart_house_list_devices`] together
+// with its strings. Null is ignored.
+//
+// # Safety
+//
+// `buffer` and `count` must come from one of these calls, the array is released only once.
+SmartHouseStatus smart_house_free_string_list(char **buffer, size_t count);
+
+// Releases a string returned by the library, e.g. by [`smart_house_report`]. Null is ignored.
+//
+// # Safety
+//
+// `text` must be null or a string returned by the library which was not released yet.
+SmartHouseStatus smart_house_free_string(char *text);
+
+// # Safety
+//
+// `smarthouselib` must be a live pointer from [`smart_house_new`], names valid nul-terminated strings.
+SmartHouseStatus smart_house_remove_device(SmartHouseLib *smarthouselib,
+                                           const char *room_name,
+                                           const char *device_name);
+
+// Writes an array of device names in the room, which the caller releases with
+// [`smart_house_free_string_list`].
+//
+// # Safety
+//
+// `smarthouselib` must be a live pointer from [`smart_house_new`], `room_name` a valid nul-terminated
+// string, `out_buffer` and `out_size` valid for writes.
+SmartHouseStatus smart_house_list_devices(SmartHouseLib *smarthouselib,
+                                          const char *room_name,
+                                          char ***out_buffer,
+                                          size_t *out_size);
+
+// Writes the house report to `out`, the caller releases it with [`smart_house_free_string`].
+//
+// # Safety
+//
+// `smarthouselib` must be a live pointer from [`smart_house_new`], `out` valid for writes.
+SmartHouseStatus smart_house_report(SmartHouseLib *smarthouselib, char **out);
+
+// Writes a JSON snapshot of the house to `out` for [`smart_house_import`], the caller releases
+// it with [`smart_house_free_string`]. Subscriptions are not part of the snapshot.
+//
+// # Safety
+//
+// `smarthouselib` must be a live pointer from [`smart_house_new`], `out` valid for writes.
+SmartHouseStatus smart_house_export(SmartHouseLib *smarthouselib, char **out);
+
+// Creates a device from JSON parameters `params` and adds it to the room, see the module docs.
+//
+// # Safety
+//
+// `smarthouselib` must be a live pointer from [`smart_house_new`], strings valid nul-terminated strings.
+SmartHouseStatus smart_house_add_device(SmartHouseLib *smarthouselib,
+                                        const char *room_name,
+                                        const char *params);
+
+// Turns the device on or off.
+//
+// # Safety
+//
+// `smarthouselib` must be a live pointer from [`smart_house_new`], names valid nul-terminated strings.
+SmartHouseStatus smart_house_switch_device(SmartHouseLib *smarthouselib,
+                                           const char *room_name,
+                                           const char *device_name,
+                                           bool on);
+
+// Writes the device state as JSON to `out`, the caller releases it with
+// [`smart_house_free_string`].
+//
+// # Safety
+//
+// `smarthouselib` must be a live pointer from [`smart_house_new`], names valid nul-terminated
+// strings, `out` valid for writes.
+SmartHouseStatus smart_house_device_status(SmartHouseLib *smarthouselib,
+                                           const char *room_name,
+                                           const char *device_name,
+                                           char **out);
+
+// Applies a sensor reading to the device as if it came from its gateway.
+//
+// `kind` is a [`SmartHouseReadingKind`], other values give [`SmartHouseStatus::InvalidArgument`].
+// So does a `value` that is NaN, infinite or out of the device range, e.g. -128..=127 for a
+// temperature. Returns [`SmartHouseStatus::Unsupported`] if the device doesn't take readings
+// of this kind.
+//
+// # Safety
+//
+// `smarthouselib` must be a live pointer from [`smart_house_new`], names valid nul-terminated strings.
+SmartHouseStatus smart_house_feed_reading(SmartHouseLib *smarthouselib,
+                                          const char *room_name,
+                                          const char *device_name,
+                                          uint32_t kind,
+                                          double value);
+
+// Adds an outlet, the same as [`smart_house_add_device`] with `{"kind": "outlet"}`.
+//
+// # Safety
+//
+// `smarthouselib` must be a live pointer from [`smart_house_new`], strings valid nul-terminated strings.
+SmartHouseStatus smart_house_add_test_device_outlet(SmartHouseLib *smarthouselib,
+                                                    const char *room_name,
+                                                    const char *device_name,
+                                                    const char *device_description);
+
+// Registers `callback`, called with `user_data` on every event of the house, and writes the
+// subscription id to `out_id`. See the module docs for the threading guarantees.
+//
+// # Safety
+//
+// `smarthouselib` must be a live pointer from [`smart_house_new`], `out_id` valid for writes.
+// `callback` must be safe to call with `user_data` until it is unsubscribed or the house is
+// destroyed, from any thread calling the library with this house.
+SmartHouseStatus smart_house_subscribe(SmartHouseLib *smarthouselib,
+                                       SmartHouseCallback callback,
+                                       void *user_data,
+                                       uint64_t *out_id);
+
+// Removes the subscription `id`, waiting for its callbacks running on other threads. After the
+// call returns the callback is not called anymore.
+//
+// Called from inside any callback it doesn't wait, so callbacks on different threads may
+// unsubscribe each other. Calls of `id` already running then finish after it returns.
+//
+// # Safety
+//
+// `smarthouselib` must be a live pointer from [`smart_house_new`].
+SmartHouseStatus smart_house_unsubscribe(SmartHouseLib *smarthouselib, uint64_t id);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* SMART_HOUSE_H */
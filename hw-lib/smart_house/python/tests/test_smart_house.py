@@ -0,0 +1,119 @@
+"""Тесты модуля Python `smart_house`.
+
+Модуль собирается фичей `python`, тесты берут его из `target/debug` или из
+переменной SMART_HOUSE_LIB:
+
+    cargo build -p smart_house --lib --features python
+    python3 -m unittest discover -s smart_house/python/tests
+"""
+
+import importlib.util
+import os
+import pathlib
+import shutil
+import tempfile
+import unittest
+
+HW_LIB = pathlib.Path(__file__).resolve().parents[3]
+
+
+def load_smart_house():
+    library = os.environ.get("SMART_HOUSE_LIB", HW_LIB / "target" / "debug" / "libsmart_house.so")
+    # Python ищет `PyInit_smart_house` в файле с именем модуля
+    module_path = pathlib.Path(tempfile.mkdtemp()) / "smart_house.so"
+    shutil.copy(library, module_path)
+    spec = importlib.util.spec_from_file_location("smart_house", module_path)
+    module = importlib.util.module_from_spec(spec)
+    spec.loader.exec_module(module)
+    return module
+
+
+smart_house = load_smart_house()
+
+
+class SmartHouseTest(unittest.TestCase):
+    def setUp(self):
+        self.house = smart_house.SmartHouse("Дом")
+
+    def test_rooms(self):
+        self.assertEqual(self.house.name, "Дом")
+        self.house.add_room("Кухня")
+        self.house.add_room("Гостиная")
+        self.assertEqual([room.name for room in self.house.rooms()], ["Гостиная", "Кухня"])
+
+        self.house.remove_room("Гостиная")
+        self.assertEqual([room.name for room in self.house.rooms()], ["Кухня"])
+        self.assertEqual(self.house.room("Кухня").name, "Кухня")
+
+    def test_devices(self):
+        kitchen = self.house.add_room("Кухня")
+        thermometer = kitchen.add_device("thermometer", "Термометр", description="у окна")
+        kitchen.add_device("outlet", "Розетка", on=True)
+
+        self.assertEqual([device.name for device in kitchen.devices()], ["Розетка", "Термометр"])
+        self.assertEqual(thermometer.kind, "thermometer")
+        self.assertEqual(thermometer.description, "у окна")
+        self.assertFalse(thermometer.on)
+        self.assertTrue(kitchen.device("Розетка").on)
+
+        thermometer.on = True
+        thermometer.feed("temperature", 21.6)
+        # тот же прибор, найденный заново
+        same = kitchen.device("Термометр")
+        self.assertEqual(same.id, thermometer.id)
+        self.assertTrue(same.on)
+        self.assertEqual(same.value, 22)
+
+        kitchen.remove_device("Розетка")
+        self.assertEqual([device.name for device in kitchen.devices()], ["Термометр"])
+
+    def test_report(self):
+        kitchen = self.house.add_room("Кухня")
+        outlet = kitchen.add_device("outlet", "Розетка", description="у плиты")
+        outlet.feed("power", 40)
+
+        report = self.house.report()
+        self.assertTrue(report.startswith("Name: Дом,\nRooms:\n["))
+        self.assertIn("Description: у плиты,\nPower: 40", report)
+        self.assertIn("Description: у плиты,\nPower: 40", kitchen.report())
+
+    def test_errors(self):
+        self.assertTrue(issubclass(smart_house.NotFoundError, smart_house.SmartHouseError))
+        self.assertTrue(issubclass(smart_house.UnsupportedError, smart_house.SmartHouseError))
+        self.assertTrue(issubclass(smart_house.AlreadyExistsError, smart_house.SmartHouseError))
+
+        with self.assertRaises(smart_house.NotFoundError):
+            self.house.room("Чердак")
+        with self.assertRaises(smart_house.NotFoundError):
+            self.house.remove_room("Чердак")
+
+        kitchen = self.house.add_room("Кухня")
+        with self.assertRaises(smart_house.AlreadyExistsError):
+            self.house.add_room("Кухня")
+        with self.assertRaises(smart_house.NotFoundError):
+            kitchen.device("Лампа")
+        with self.assertRaises(smart_house.UnsupportedError):
+            kitchen.add_device("lamp", "Лампа")
+
+        thermometer = kitchen.add_device("thermometer", "Термометр")
+        with self.assertRaises(smart_house.UnsupportedError):
+            thermometer.feed("motion", 1)
+        with self.assertRaises(ValueError):
+            thermometer.feed("humidity", 50)
+        for value in (float("nan"), float("inf"), 1e30, 128, -300):
+            with self.assertRaises(ValueError):
+                thermometer.feed("temperature", value)
+        with self.assertRaises(ValueError):
+            thermometer.feed("motion", float("nan"))
+        self.assertEqual(thermometer.value, 0)
+
+        # комната удалена, а объект комнаты остался
+        self.house.remove_room("Кухня")
+        with self.assertRaises(smart_house.NotFoundError):
+            kitchen.devices()
+        with self.assertRaises(smart_house.SmartHouseError):
+            kitchen.add_device("outlet", "Розетка")
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,254 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, ValueEnum};
+use tokio::signal::unix::{signal, SignalKind};
+use uuid::Uuid;
+
+use device_grpc::auth::{Permission, TokenStore};
+use device_grpc::tls::{read_pem, server_tls, TlsIdentity};
+use device_quic::certs::{load_certs, Identity};
+use device_quic::settings::QuicSettings;
+
+use smart_house::device::outlet::SmartOutlet;
+use smart_house::device::thermometer::SmartThermometer;
+use smart_house::device::{Device, RwLockDevice};
+use smart_house::identity::DeviceIdentities;
+use smart_house::link::{Backoff, QuicLink};
+use smart_house::server::DeviceServer;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Kind {
+    Outlet,
+    Thermometer,
+}
+
+// Сенсор из `--quic-publisher` и его право на устройство.
+#[derive(Debug, Clone)]
+struct Publisher {
+    sensor: String,
+    permission: Permission,
+}
+
+// `<сенсор>[:read|:control]`, без суффикса - `control`. Неизвестный суффикс - ошибка, иначе
+// опечатка в праве молча превратила бы отпечаток в имя сенсора с правом `control`.
+fn parse_publisher(value: &str) -> Result<Publisher, String> {
+    let (sensor, permission) = match value.rsplit_once(':') {
+        Some((sensor, permission)) => (
+            sensor,
+            permission.parse().map_err(|_| {
+                format!(
+                    "unknown permission {:?}, expected `read` or `control`",
+                    permission
+                )
+            })?,
+        ),
+        None => (value, Permission::Control),
+    };
+    if sensor.is_empty() {
+        return Err("empty sensor fingerprint or name".to_string());
+    }
+    Ok(Publisher {
+        sensor: sensor.to_string(),
+        permission,
+    })
+}
+
+/// gRPC server of a single smart device with health checking and server reflection.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Address of the gRPC server
+    #[arg(long, default_value = "0.0.0.0:50051")]
+    addr: SocketAddr,
+    /// Device name
+    #[arg(long)]
+    name: String,
+    /// Device type
+    #[arg(long, value_enum, default_value_t = Kind::Outlet)]
+    kind: Kind,
+    /// Device description
+    #[arg(long, default_value = "")]
+    description: String,
+    /// Device id, e.g. the one of its sensor in a simulator scenario; random without it
+    #[arg(long)]
+    id: Option<Uuid>,
+    /// File with accepted bearer tokens, authentication is disabled without it
+    #[arg(long)]
+    tokens: Option<PathBuf>,
+    /// Address of the QUIC telemetry server
+    #[arg(long)]
+    quic_server: Option<String>,
+    /// Local address of the QUIC telemetry client
+    #[arg(long, default_value = "0.0.0.0:0")]
+    quic_client: String,
+    /// Server name in the QUIC telemetry server certificate
+    #[arg(long, default_value = "localhost")]
+    cert_address: String,
+    /// QUIC telemetry server certificate in DER format
+    #[arg(long)]
+    quic_cert: Option<PathBuf>,
+    /// Trusted QUIC telemetry server certificates or their CAs in PEM format
+    #[arg(long)]
+    quic_ca: Vec<PathBuf>,
+    /// SHA-256 fingerprint of a trusted QUIC telemetry server certificate, e.g. announced
+    /// on the LAN; replaces `--quic-cert` and `--quic-ca`
+    #[arg(long)]
+    quic_fingerprint: Vec<String>,
+    /// QUIC transport settings file in TOML or JSON (timeouts, stream limits, congestion control,
+    /// ALPN), see `device_quic::settings`
+    #[arg(long)]
+    quic_settings: Option<PathBuf>,
+    /// Device certificate chain in PEM format for QUIC telemetry servers with mutual TLS
+    #[arg(long, requires = "quic_identity_key")]
+    quic_identity_cert: Option<PathBuf>,
+    /// Device private key in PEM format for QUIC telemetry servers with mutual TLS
+    #[arg(long, requires = "quic_identity_cert")]
+    quic_identity_key: Option<PathBuf>,
+    /// SHA-256 fingerprint or name of a sensor certificate allowed to publish readings
+    /// of this device, readings of any trusted server are accepted without it.
+    /// A `:read` suffix denies the sensor hub commands that change the device, `:control`
+    /// (the default) allows them; a name containing `:` needs one of the suffixes
+    #[arg(long, value_parser = parse_publisher)]
+    quic_publisher: Vec<Publisher>,
+    /// Timeout of a QUIC telemetry connection attempt, in milliseconds
+    #[arg(long, default_value_t = 5000)]
+    connect_timeout_ms: u64,
+    /// Initial delay before reconnecting the QUIC telemetry link, in milliseconds
+    #[arg(long, default_value_t = 200)]
+    retry_ms: u64,
+    /// Maximum delay before reconnecting the QUIC telemetry link, in milliseconds
+    #[arg(long, default_value_t = 30000)]
+    retry_max_ms: u64,
+    /// TLS certificate chain in PEM format
+    #[arg(long, requires = "tls_key", conflicts_with = "tls_self_signed")]
+    tls_cert: Option<PathBuf>,
+    /// TLS private key in PEM format
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Serve TLS with a generated self-signed certificate for this name
+    #[arg(long)]
+    tls_self_signed: Option<String>,
+    /// Write the generated self-signed certificate to this file for clients
+    #[arg(long, requires = "tls_self_signed")]
+    tls_export_cert: Option<PathBuf>,
+    /// CA certificates in PEM format which sign accepted client certificates (mutual TLS)
+    #[arg(long)]
+    tls_client_ca: Option<PathBuf>,
+}
+
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => {},
+        _ = tokio::signal::ctrl_c() => {},
+    }
+    println!("shutting down");
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let device = RwLockDevice::new(Arc::new(RwLock::new(Device::new(
+        args.name,
+        match args.kind {
+            Kind::Outlet => Arc::new(RwLock::new(SmartOutlet::new(args.description, None))),
+            Kind::Thermometer => {
+                Arc::new(RwLock::new(SmartThermometer::new(args.description, None)))
+            }
+        },
+        None,
+    ))));
+
+    if let Some(id) = args.id {
+        device.write().unwrap().id = id;
+    }
+    let device_id = device.read().unwrap().id;
+    let mut server = DeviceServer::new(device);
+
+    if let Some(path) = args.tokens {
+        let tokens = TokenStore::from_file(&path)?;
+        server = server.with_tokens(tokens);
+    }
+
+    if let Some(server_addr) = args.quic_server {
+        let mut server_certs = Vec::new();
+        if let Some(path) = &args.quic_cert {
+            server_certs.push(
+                std::fs::read(path)
+                    .with_context(|| format!("failed to read certificate {:?}", path))?,
+            );
+        }
+        for path in &args.quic_ca {
+            server_certs.extend(load_certs(path)?);
+        }
+        if server_certs.is_empty() && args.quic_fingerprint.is_empty() {
+            return Err(anyhow!(
+                "--quic-cert, --quic-ca or --quic-fingerprint is required"
+            ));
+        }
+        server = server.with_link(QuicLink {
+            client_addr: args.quic_client,
+            server_addr,
+            cert_address: args.cert_address,
+            server_certs,
+            server_fingerprints: args.quic_fingerprint,
+            settings: match &args.quic_settings {
+                Some(path) => QuicSettings::from_file(path)?,
+                None => QuicSettings::default(),
+            },
+            identity: match (args.quic_identity_cert, args.quic_identity_key) {
+                (Some(cert), Some(key)) => Some(Identity::from_pem_files(cert, key)?),
+                _ => None,
+            },
+            publishers: (!args.quic_publisher.is_empty()).then(|| {
+                args.quic_publisher
+                    .iter()
+                    .fold(DeviceIdentities::new(), |identities, publisher| {
+                        let Publisher { sensor, permission } = publisher;
+                        // отпечаток SHA-256 - 64 шестнадцатеричные цифры
+                        if sensor.len() == 64 && sensor.chars().all(|c| c.is_ascii_hexdigit()) {
+                            identities.with_fingerprint_permission(sensor, device_id, *permission)
+                        } else {
+                            identities.with_name_permission(sensor, device_id, *permission)
+                        }
+                    })
+            }),
+            manager: None,
+            connect_timeout: Duration::from_millis(args.connect_timeout_ms),
+            backoff: Backoff {
+                initial: Duration::from_millis(args.retry_ms),
+                max: Duration::from_millis(args.retry_max_ms),
+                ..Backoff::default()
+            },
+        });
+    }
+
+    let identity = match (args.tls_cert, args.tls_key, args.tls_self_signed) {
+        (Some(cert), Some(key), _) => Some(TlsIdentity::from_files(cert, key)?),
+        (_, _, Some(name)) => {
+            let identity = TlsIdentity::self_signed(vec![name])?;
+            if let Some(path) = &args.tls_export_cert {
+                std::fs::write(path, &identity.cert_pem)
+                    .with_context(|| format!("failed to write certificate {:?}", path))?;
+            }
+            Some(identity)
+        }
+        _ => None,
+    };
+    if let Some(identity) = identity {
+        let client_ca = args.tls_client_ca.map(read_pem).transpose()?;
+        server = server.with_tls(server_tls(&identity, client_ca.as_deref()));
+    } else if args.tls_client_ca.is_some() {
+        return Err(anyhow!("--tls-client-ca requires a server TLS certificate"));
+    }
+
+    println!("device {} server listening on {}", device_id, args.addr);
+    server
+        .serve_with_shutdown(args.addr, shutdown_signal())
+        .await
+}
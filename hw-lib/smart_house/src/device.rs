@@ -1,3 +1,4 @@
+pub mod command;
 pub mod outlet;
 pub(crate) mod smartdevice;
 pub mod thermometer;
@@ -5,6 +6,7 @@ pub mod thermometer;
 use std::fmt::{self, Debug};
 
 use std::option::Option;
+use std::time::Duration;
 
 use uuid::Uuid;
 
@@ -14,27 +16,112 @@ use anyhow::{anyhow, Result};
 use std::sync::Arc;
 // use std::sync::Mutex;
 use std::sync::RwLock;
-use std::sync::{LockResult, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::{LockResult, Mutex, RwLockReadGuard, RwLockWriteGuard};
 
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::watch;
 use tonic::{Request, Response, Status};
 
+use self::outlet::{SmartOutlet, POWER_RANGE};
 use self::smartdevice::SmartDevices;
+use self::thermometer::{SmartThermometer, TEMPERATURE_RANGE};
+use crate::link::QuicLink;
 
+use device_grpc::auth::{require, Permission};
 use device_grpc::devices;
 use device_grpc::devices::device_control_server::DeviceControl;
-use device_grpc::devices::{DeviceStatus, Empty, Toggle};
+use device_grpc::devices::{DeviceStatus, Empty, HubCommand, Toggle};
+
+use device_quic::command::IncomingCommand;
+use device_quic::datagram::{Arrival, DatagramStats, SequenceTracker};
+use device_quic::manager::ConnectionManager;
+use device_quic::telemetry::{read_frame, Kind, Payload, Telemetry};
+
+/// Типы устройств, которые дом умеет создавать, см. [`new_config`].
+pub const DEVICE_KINDS: [&str; 2] = ["outlet", "thermometer"];
+
+/// Настройки нового устройства типа `kind` из [`DEVICE_KINDS`], `None` для неизвестного типа.
+pub fn new_config(
+    kind: &str,
+    description: String,
+) -> Option<Arc<RwLock<dyn SmartDevices + Send + Sync>>> {
+    let config: Arc<RwLock<dyn SmartDevices + Send + Sync>> = match kind {
+        "outlet" => Arc::new(RwLock::new(SmartOutlet::new(description, None))),
+        "thermometer" => Arc::new(RwLock::new(SmartThermometer::new(description, None))),
+        _ => return None,
+    };
+    Some(config)
+}
+
+/// Ошибка показания, поданного через [`RwLockDevice::feed`].
+#[derive(Debug, Error)]
+pub enum FeedError {
+    #[error("{kind:?} reading {value} is out of range")]
+    OutOfRange { kind: Kind, value: f64 },
+    /// Устройство не принимает показания этого вида.
+    #[error(transparent)]
+    Unsupported(anyhow::Error),
+}
+
+/// Параметры нового устройства, например из JSON
+/// `{"kind": "thermometer", "name": "Kitchen", "description": "by the window", "on": true}`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceParams {
+    pub kind: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub on: bool,
+}
+
+/// Состояние одного устройства, которое отдаётся клиентам в JSON и хранится в снимке дома.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceState {
+    pub id: String,
+    pub name: String,
+    pub kind: String,
+    pub description: String,
+    pub on: bool,
+    /// Округлённое показание.
+    pub value: i64,
+    /// Показание без округления, по нему дом восстанавливается из снимка. В снимках старых
+    /// версий его нет, тогда берётся `value`.
+    #[serde(default)]
+    pub reading: Option<f32>,
+}
 
-use device_quic::common::make_client_endpoint;
+/// Состояние QUIC канала телеметрии устройства.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    // канал не запущен или остановлен
+    Down,
+    Connecting,
+    Up,
+    // ожидание перед попыткой подключения номер `attempt`
+    BackingOff { attempt: u32, delay: Duration },
+}
 
 #[derive(Debug, Clone)]
 pub struct RwLockDevice {
     device: Arc<RwLock<Device>>,
+    link: Arc<watch::Sender<LinkState>>,
+    changes: Arc<watch::Sender<()>>,
+    datagrams: Arc<Mutex<DatagramStats>>,
 }
 
 impl RwLockDevice {
-    #[allow(dead_code)]
-    pub(crate) fn new(device: Arc<RwLock<Device>>) -> Self {
-        RwLockDevice { device }
+    pub fn new(device: Arc<RwLock<Device>>) -> Self {
+        let (link, _) = watch::channel(LinkState::Down);
+        let (changes, _) = watch::channel(());
+        RwLockDevice {
+            device,
+            link: Arc::new(link),
+            changes: Arc::new(changes),
+            datagrams: Arc::new(Mutex::new(DatagramStats::default())),
+        }
     }
 
     pub fn read(&self) -> LockResult<RwLockReadGuard<'_, Device>> {
@@ -44,66 +131,200 @@ impl RwLockDevice {
     pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, Device>> {
         self.device.write()
     }
+
+    /// Подписка на изменения состояния канала телеметрии.
+    pub fn link_state(&self) -> watch::Receiver<LinkState> {
+        self.link.subscribe()
+    }
+
+    pub(crate) fn set_link_state(&self, state: LinkState) {
+        self.link.send_replace(state);
+    }
+
+    /// Подписка на изменения устройства: переключение, новые показания, новые значения.
+    pub fn changes(&self) -> watch::Receiver<()> {
+        self.changes.subscribe()
+    }
+
+    /// Сообщает подписчикам `changes` об изменении устройства.
+    pub fn notify_changed(&self) {
+        self.changes.send_replace(());
+    }
+
+    /// Счётчики показаний, полученных датаграммами, за все сессии канала.
+    pub fn datagram_stats(&self) -> DatagramStats {
+        *self.datagrams.lock().unwrap()
+    }
+
+    pub fn status(&self) -> DeviceStatus {
+        let device = self.read().unwrap();
+        DeviceStatus {
+            id: device.id().to_string(),
+            name: device.name().to_string(),
+            on: device.on().to_owned(),
+            config: format!("{:?}", device.config()),
+        }
+    }
+
+    pub fn state(&self) -> DeviceState {
+        let device = self.read().unwrap();
+        let config = device.config().read().unwrap();
+        DeviceState {
+            id: device.id().to_string(),
+            name: device.name().to_string(),
+            kind: config.kind().to_string(),
+            description: config.description().to_string(),
+            on: device.on,
+            value: config.value(),
+            reading: Some(config.reading()),
+        }
+    }
+
+    /// Включает или выключает устройство.
+    pub fn set_on(&self, on: bool) {
+        self.write().unwrap().on = on;
+        self.notify_changed();
+    }
+
+    /// Применяет показание датчика вида `kind`, так же, как показание, пришедшее по QUIC.
+    ///
+    /// Показания по QUIC устройство насыщает, а здесь значение вне диапазона устройства или
+    /// не число - ошибка [`FeedError::OutOfRange`].
+    pub fn feed(&self, kind: Kind, value: f64) -> Result<(), FeedError> {
+        let reading = value as f32;
+        let payload = match kind {
+            Kind::Temperature if TEMPERATURE_RANGE.contains(&reading) => {
+                Payload::Temperature(reading)
+            }
+            Kind::Power if POWER_RANGE.contains(&reading) => Payload::Power(reading),
+            Kind::Motion if value.is_finite() => Payload::Motion(value != 0.0),
+            _ => return Err(FeedError::OutOfRange { kind, value }),
+        };
+        self.listen(payload).map_err(FeedError::Unsupported)
+    }
+
+    fn listen(&self, payload: Payload) -> Result<(), anyhow::Error> {
+        let config = self.read().unwrap().config().clone();
+        let applied = config.write().unwrap().listening(payload);
+        if applied.is_ok() {
+            self.notify_changed();
+        }
+        applied
+    }
+}
+
+// Переводит канал в `Down` при любом выходе из `listening`, в том числе при отмене задачи.
+struct LinkGuard<'a>(&'a watch::Sender<LinkState>);
+
+impl Drop for LinkGuard<'_> {
+    fn drop(&mut self) {
+        self.0.send_replace(LinkState::Down);
+    }
 }
 
 // #[async_trait]
 impl RwLockDevice {
-    #[allow(dead_code)]
-    async fn listening(
-        &self,
-        client_addr: &str,
-        server_addr: &str,
-        cert_address: &str,
-        server_cert: &Vec<u8>,
-    ) -> Result<(), anyhow::Error> {
-        let endpoint_client = make_client_endpoint(client_addr.parse().unwrap(), &[server_cert])
-            .map_err(|e| anyhow!("failed to make client endpoint: {}", e))
-            .unwrap();
-        // connect to server
-        let outcoming_conn = endpoint_client
-            .connect(server_addr.parse().unwrap(), cert_address)
-            .map_err(|e| anyhow!("failed to make connecting: {}", e))
-            .unwrap();
+    /// Одна сессия QUIC канала: подписывается на потоки и датаграммы устройства у шлюза и
+    /// применяет показания, пока шлюз не закроет соединение. Устаревшие датаграммы
+    /// отбрасываются, см. [`RwLockDevice::datagram_stats`].
+    ///
+    /// По тому же соединению хаб управляет устройством: команды `HubCommand` из gRPC API
+    /// приходят в двунаправленных потоках, см. [`device_quic::command`].
+    ///
+    /// Без общего [`ConnectionManager`] в `link` сессия открывает своё соединение.
+    pub async fn listening(&self, link: &QuicLink) -> Result<(), anyhow::Error> {
+        let manager = match &link.manager {
+            Some(manager) => manager.clone(),
+            None => ConnectionManager::new(link.make_endpoint()?),
+        };
+        let device_id = self.read().unwrap().id;
 
-        let connection = outcoming_conn
+        let mut streams = manager
+            .subscribe(link.server_addr.parse()?, &link.cert_address, device_id)
             .await
-            .map_err(|e| anyhow!("failed to create client connection: {}", e))
-            .unwrap();
+            .map_err(|e| anyhow!("failed to subscribe to the gateway: {}", e))?;
+
+        // без `publishers` права не проверяются, как в gRPC без токенов
+        let authorized = match &link.publishers {
+            Some(publishers) => publishers
+                .authorize(streams.connection(), device_id)
+                .map(Some),
+            None => Ok(None),
+        };
 
-        while let Ok(mut recv) = connection
-            .accept_uni()
-            .await
-            .map_err(|e| anyhow!("failed to create client Uni listener: {}", e))
-        {
-            // println!("start");
-            'listener: loop {
+        if let Ok(permission) = authorized {
+            let _link = LinkGuard(&self.link);
+            self.link.send_replace(LinkState::Up);
+
+            let mut datagrams = streams.take_datagrams();
+            let mut commands = streams.take_commands();
+            let on_streams = async {
                 // Because it is a unidirectional stream, we can only receive not send back.
-                let tempe = &mut [255_u8; 1];
-                let size = recv
-                    .read_exact(tempe)
-                    .await
-                    .map_err(|e| anyhow!("failed to read: {}", e));
-                // println!("size {:?}", size);
-                if size.is_err() {
-                    // println!("finish");
-                    break 'listener;
+                while let Some(mut recv) = streams.next().await {
+                    // поток закончился или прислал испорченный кадр
+                    while let Ok(Some(message)) = read_frame(&mut recv).await {
+                        self.apply(message);
+                    }
                 }
-                let _ = self
-                    .write()
-                    .unwrap()
-                    .config()
-                    .write()
-                    .unwrap()
-                    .listening(tempe.to_vec());
+            };
+            let on_datagrams = async {
+                let Some(datagrams) = datagrams.as_mut() else {
+                    return std::future::pending().await;
+                };
+                // номера датаграмм начинаются заново с каждым соединением
+                let mut sequence = SequenceTracker::default();
+                while let Some((number, message)) = datagrams.next().await {
+                    let arrival = sequence.arrive(number);
+                    self.datagrams.lock().unwrap().record(arrival);
+                    if matches!(arrival, Arrival::Fresh { .. }) {
+                        self.apply(message);
+                    }
+                }
+            };
+            let on_commands = async {
+                let Some(commands) = commands.as_mut() else {
+                    return std::future::pending().await;
+                };
+                while let Some(command) = commands.next().await {
+                    // медленный хаб не задерживает остальные команды
+                    tokio::spawn(self.clone().answer(command, permission));
+                }
+            };
+            // все источники заканчиваются с закрытием соединения
+            tokio::select! {
+                _ = on_streams => {}
+                _ = on_datagrams => {}
+                _ = on_commands => {}
+            }
+        }
+
+        // своё соединение закрывается вместе с последней подпиской
+        drop(streams);
+        if link.manager.is_none() {
+            // Make sure the server has a chance to clean up
+            manager.endpoint().wait_idle().await;
+        }
+        authorized.map(|_| ()).map_err(Into::into)
+    }
 
-                // println!("recv {:?}", i8::from_ne_bytes(*tempe));
-                // self.temperature = i8::from_ne_bytes(*tempe);
+    // Выполняет команду хаба, пришедшую по QUIC, ответ тот же, что по gRPC.
+    async fn answer(self, mut command: IncomingCommand, permission: Option<Permission>) {
+        match command.request::<HubCommand>().await {
+            Ok(request) => {
+                let result = self.handle_command_as(&request, permission);
+                let _ = command.respond(&result).await;
             }
+            Err(_) => command.reject(),
         }
-        // Make sure the server has a chance to clean up
-        endpoint_client.wait_idle().await;
-        // println!("exit");
-        Ok(())
+    }
+
+    // Применяет показание, адресованное этому устройству.
+    fn apply(&self, message: Telemetry) {
+        if message.device_id != self.read().unwrap().id {
+            return;
+        }
+        // неподходящие показания просто пропускаются
+        let _ = self.listen(message.payload);
     }
 }
 
@@ -129,8 +350,7 @@ impl fmt::Display for Device {
 }
 
 impl Device {
-    #[allow(dead_code)]
-    pub(crate) fn new(
+    pub fn new(
         name: String,
         config: Arc<RwLock<dyn SmartDevices + Send + Sync>>,
         on: Option<bool>,
@@ -162,26 +382,30 @@ impl Device {
 
 #[tonic::async_trait]
 impl DeviceControl for RwLockDevice {
-    async fn switch(&self, _request: Request<Toggle>) -> Result<Response<Empty>, Status> {
+    async fn switch(&self, request: Request<Toggle>) -> Result<Response<Empty>, Status> {
         // println!("Received request from: {:?}", request);
+        require(&request, Permission::Control)?;
 
         let status = self.read().unwrap().on;
-        self.write().unwrap().on = !status;
+        self.set_on(!status);
 
         let response = devices::Empty {};
 
         Ok(Response::new(response))
     }
 
-    async fn get_status(&self, _request: Request<Empty>) -> Result<Response<DeviceStatus>, Status> {
-        let response = devices::DeviceStatus {
-            id: self.read().unwrap().id().to_string(),
-            name: self.read().unwrap().name().to_string(),
-            on: self.read().unwrap().on().to_owned(),
-            config: format!("{:?}", self.read().unwrap().config()),
-        };
+    async fn set_on(&self, request: Request<Toggle>) -> Result<Response<Empty>, Status> {
+        require(&request, Permission::Control)?;
 
-        Ok(Response::new(response))
+        RwLockDevice::set_on(self, request.into_inner().on);
+
+        Ok(Response::new(devices::Empty {}))
+    }
+
+    async fn get_status(&self, request: Request<Empty>) -> Result<Response<DeviceStatus>, Status> {
+        require(&request, Permission::Read)?;
+
+        Ok(Response::new(self.status()))
     }
 }
 
@@ -199,12 +423,19 @@ mod tests {
     use tokio::sync::oneshot;
     use tokio::time::{sleep, Duration};
 
+    use device_grpc::auth::{AuthInterceptor, BearerToken, TokenStore};
+    use device_grpc::client::{DeviceClient, RetryPolicy};
     use device_grpc::devices::device_control_client::DeviceControlClient;
     use device_grpc::devices::device_control_server::DeviceControlServer;
-    use tonic::transport::Server;
+    use device_grpc::tls::{client_tls, server_tls, TlsIdentity};
+    use tonic::transport::{Endpoint, Server};
+    use tonic::Code;
 
     use device_quic::common::make_server_endpoint;
-    // use device_quic::common::make_client_endpoint;
+    use device_quic::datagram::encode_datagram;
+    use device_quic::settings::QuicSettings;
+    use device_quic::telemetry::{open_telemetry_stream, write_frame, Payload, Telemetry};
+    // use device_quic::manager::ConnectionManager;
 
     #[test]
     fn get_name() {
@@ -254,9 +485,7 @@ mod tests {
         )));
         let test_dev = Device::new("test_device".to_string(), test_outlet, None);
 
-        let test_dev_rwlock = RwLockDevice {
-            device: Arc::new(RwLock::new(test_dev.clone())),
-        };
+        let test_dev_rwlock = RwLockDevice::new(Arc::new(RwLock::new(test_dev.clone())));
 
         let addr = "127.0.0.1:50052";
 
@@ -316,6 +545,201 @@ mod tests {
         let _ = signal_tx.send(());
     }
 
+    #[tokio::test]
+    async fn test_client_server_auth() {
+        let test_outlet = Arc::new(RwLock::new(SmartOutlet::new(
+            "test_outlet".to_string(),
+            None,
+        )));
+        let test_dev = Device::new("test_device".to_string(), test_outlet, None);
+
+        let test_dev_rwlock = RwLockDevice::new(Arc::new(RwLock::new(test_dev)));
+
+        let tokens: TokenStore = "reader read\nhub control".parse().unwrap();
+
+        let addr = "127.0.0.1:50055";
+
+        let (signal_tx, signal_rx) = oneshot::channel();
+
+        tokio::task::spawn(
+            Server::builder()
+                .add_service(DeviceControlServer::with_interceptor(
+                    test_dev_rwlock.clone(),
+                    AuthInterceptor::new(tokens),
+                ))
+                .serve_with_shutdown(addr.parse().unwrap(), async {
+                    signal_rx.await.ok();
+                }),
+        );
+
+        let _ = sleep(Duration::from_millis(1000)).await;
+
+        let channel = Endpoint::from_shared("http://".to_owned() + addr)
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+
+        // no token
+        let mut client = DeviceControlClient::new(channel.clone());
+        let response = client.get_status(Request::new(Empty {})).await;
+        assert_eq!(response.unwrap_err().code(), Code::Unauthenticated);
+
+        // unknown token
+        let mut client = DeviceControlClient::with_interceptor(
+            channel.clone(),
+            BearerToken::new("guest").unwrap(),
+        );
+        let response = client.get_status(Request::new(Empty {})).await;
+        assert_eq!(response.unwrap_err().code(), Code::Unauthenticated);
+
+        // read-only token
+        let mut client = DeviceControlClient::with_interceptor(
+            channel.clone(),
+            BearerToken::new("reader").unwrap(),
+        );
+        let response = client.get_status(Request::new(Empty {})).await;
+        assert!(!response.unwrap().into_inner().on);
+
+        let response = client.switch(Request::new(Toggle { on: true })).await;
+        assert_eq!(response.unwrap_err().code(), Code::PermissionDenied);
+        assert!(!test_dev_rwlock.read().unwrap().on);
+
+        // control token
+        let mut client =
+            DeviceControlClient::with_interceptor(channel, BearerToken::new("hub").unwrap());
+        let response = client.switch(Request::new(Toggle { on: true })).await;
+        assert!(response.is_ok());
+
+        let response = client.get_status(Request::new(Empty {})).await;
+        assert!(response.unwrap().into_inner().on);
+
+        // Switch переключает, `on` не важен, SetOn ставит заданное состояние
+        let response = client.switch(Request::new(Toggle { on: true })).await;
+        assert!(response.is_ok());
+        assert!(!test_dev_rwlock.read().unwrap().on);
+        let response = client.set_on(Request::new(Toggle { on: true })).await;
+        assert!(response.is_ok());
+        let response = client.set_on(Request::new(Toggle { on: true })).await;
+        assert!(response.is_ok());
+        assert!(test_dev_rwlock.read().unwrap().on);
+
+        let _ = signal_tx.send(());
+    }
+
+    #[tokio::test]
+    async fn test_client_server_tls() {
+        let test_outlet = Arc::new(RwLock::new(SmartOutlet::new(
+            "test_outlet".to_string(),
+            None,
+        )));
+        let test_dev = Device::new("test_device".to_string(), test_outlet, None);
+        let test_dev_rwlock = RwLockDevice::new(Arc::new(RwLock::new(test_dev)));
+
+        let server_identity = TlsIdentity::self_signed(vec!["localhost".to_string()]).unwrap();
+
+        let addr = "127.0.0.1:50064";
+
+        let (signal_tx, signal_rx) = oneshot::channel();
+
+        tokio::task::spawn(
+            Server::builder()
+                .tls_config(server_tls(&server_identity, None))
+                .unwrap()
+                .add_service(DeviceControlServer::new(test_dev_rwlock))
+                .serve_with_shutdown(addr.parse().unwrap(), async {
+                    signal_rx.await.ok();
+                }),
+        );
+
+        let _ = sleep(Duration::from_millis(1000)).await;
+
+        // plaintext client can't talk to TLS server
+        let client = DeviceClient::builder("http://".to_owned() + addr)
+            .retry(RetryPolicy::none())
+            .connect()
+            .await
+            .unwrap();
+        assert!(client.status().await.is_err());
+
+        // client doesn't trust another certificate
+        let other = TlsIdentity::self_signed(vec!["localhost".to_string()]).unwrap();
+        let client = DeviceClient::builder("https://".to_owned() + addr)
+            .tls(client_tls(&other.cert_pem, "localhost", None))
+            .connect()
+            .await;
+        assert!(client.is_err());
+
+        let client = DeviceClient::builder("https://".to_owned() + addr)
+            .tls(client_tls(&server_identity.cert_pem, "localhost", None))
+            .connect()
+            .await
+            .unwrap();
+
+        assert!(!client.status().await.unwrap().on);
+        client.set_on(true).await.unwrap();
+        assert!(client.status().await.unwrap().on);
+
+        let _ = signal_tx.send(());
+    }
+
+    #[tokio::test]
+    async fn test_client_server_mtls() {
+        let test_outlet = Arc::new(RwLock::new(SmartOutlet::new(
+            "test_outlet".to_string(),
+            None,
+        )));
+        let test_dev = Device::new("test_device".to_string(), test_outlet, None);
+        let test_dev_rwlock = RwLockDevice::new(Arc::new(RwLock::new(test_dev)));
+
+        let server_identity = TlsIdentity::self_signed(vec!["localhost".to_string()]).unwrap();
+        let hub_identity = TlsIdentity::self_signed(vec!["hub".to_string()]).unwrap();
+        let stranger_identity = TlsIdentity::self_signed(vec!["stranger".to_string()]).unwrap();
+
+        let addr = "127.0.0.1:50065";
+
+        let (signal_tx, signal_rx) = oneshot::channel();
+
+        tokio::task::spawn(
+            Server::builder()
+                .tls_config(server_tls(&server_identity, Some(&hub_identity.cert_pem)))
+                .unwrap()
+                .add_service(DeviceControlServer::new(test_dev_rwlock))
+                .serve_with_shutdown(addr.parse().unwrap(), async {
+                    signal_rx.await.ok();
+                }),
+        );
+
+        let _ = sleep(Duration::from_millis(1000)).await;
+
+        for identity in [None, Some(&stranger_identity)] {
+            let client = DeviceClient::builder("https://".to_owned() + addr)
+                .tls(client_tls(&server_identity.cert_pem, "localhost", identity))
+                .retry(RetryPolicy::none())
+                .connect()
+                .await;
+            // handshake fails either on connect or on the first request
+            if let Ok(client) = client {
+                assert!(client.status().await.is_err());
+            }
+        }
+
+        let client = DeviceClient::builder("https://".to_owned() + addr)
+            .tls(client_tls(
+                &server_identity.cert_pem,
+                "localhost",
+                Some(&hub_identity),
+            ))
+            .connect()
+            .await
+            .unwrap();
+
+        client.set_on(true).await.unwrap();
+        assert!(client.status().await.unwrap().on);
+
+        let _ = signal_tx.send(());
+    }
+
     #[tokio::test]
     async fn test_client_server_thermometer() -> Result<(), Error> {
         let test_thermometer = Arc::new(RwLock::new(SmartThermometer::new(
@@ -324,20 +748,22 @@ mod tests {
         )));
 
         let test_dev = Device::new("test_device".to_string(), test_thermometer, None);
+        let device_id = test_dev.id;
 
-        let test_dev_rwlock = RwLockDevice {
-            device: Arc::new(RwLock::new(test_dev)),
-        };
+        let test_dev_rwlock = RwLockDevice::new(Arc::new(RwLock::new(test_dev)));
 
         let server_addr = "127.0.0.1:50053";
         let client_addr = "127.0.0.1:50054";
         let cert_address = "localhost";
 
-        let arr2send = vec![30_i8, 0_i8, -20_i8];
+        let arr2send = [30_i8, 0_i8, -20_i8];
 
-        let (endpoint_server, server_cert) =
-            make_server_endpoint(server_addr.parse().unwrap(), cert_address)
-                .map_err(|e| anyhow!("failed to create server endpoint: {}", e))?;
+        let (endpoint_server, server_cert) = make_server_endpoint(
+            server_addr.parse().unwrap(),
+            cert_address,
+            &QuicSettings::default(),
+        )
+        .map_err(|e| anyhow!("failed to create server endpoint: {}", e))?;
 
         let test_dev_rwlock_test = test_dev_rwlock.clone();
 
@@ -355,8 +781,7 @@ mod tests {
             //     &connection.remote_address()
             // );
 
-            let mut send = connection
-                .open_uni()
+            let mut send = open_telemetry_stream(&connection, device_id)
                 .await
                 .map_err(|e| anyhow!("failed to connection SendStream: {}", e))
                 .unwrap();
@@ -364,10 +789,20 @@ mod tests {
             for temp in arr2send.iter() {
                 let _ = sleep(Duration::from_millis(1000)).await;
 
-                send.write_all(&(temp.to_ne_bytes()))
-                    .await
-                    .map_err(|e| anyhow!("failed to send request: {}", e))
-                    .unwrap();
+                // показания другого устройства игнорируются
+                write_frame(
+                    &mut send,
+                    &Telemetry::new(Uuid::new_v4(), Payload::Temperature(99.0)),
+                )
+                .await
+                .unwrap();
+                write_frame(
+                    &mut send,
+                    &Telemetry::new(device_id, Payload::Temperature(*temp as f32)),
+                )
+                .await
+                .map_err(|e| anyhow!("failed to send request: {}", e))
+                .unwrap();
 
                 // println!("send {}", &temp);
 
@@ -389,8 +824,7 @@ mod tests {
 
             let _ = sleep(Duration::from_millis(1000)).await;
 
-            let _ = send
-                .finish()
+            send.finish()
                 .await
                 .map_err(|e| anyhow!("failed to finish: {}", e))
                 .unwrap();
@@ -403,9 +837,32 @@ mod tests {
         let _ = sleep(Duration::from_millis(1000)).await;
 
         let _ = test_dev_rwlock
-            .listening(client_addr, server_addr, cert_address, &server_cert)
+            .listening(&QuicLink {
+                client_addr: client_addr.to_string(),
+                server_addr: server_addr.to_string(),
+                cert_address: cert_address.to_string(),
+                server_certs: vec![server_cert],
+                server_fingerprints: Vec::new(),
+                connect_timeout: Duration::from_secs(1),
+                backoff: Default::default(),
+                settings: QuicSettings::default(),
+                identity: None,
+                publishers: None,
+                manager: None,
+            })
             .await;
 
+        assert_eq!(
+            test_dev_rwlock
+                .read()
+                .unwrap()
+                .config()
+                .read()
+                .unwrap()
+                .to_string(),
+            "Description: test_thermometer,\nTemperature: -20"
+        );
+
         // let _ = test_dev_rwlock
         //     .write()
         //     .unwrap()
@@ -476,4 +933,76 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_datagram_telemetry() {
+        let device = RwLockDevice::new(Arc::new(RwLock::new(Device::new(
+            "test_device".to_string(),
+            Arc::new(RwLock::new(SmartThermometer::new(
+                "test_thermometer".to_string(),
+                None,
+            ))),
+            None,
+        ))));
+        let device_id = device.read().unwrap().id;
+        let mut link_state = device.link_state();
+
+        let server_addr = "127.0.0.1:50071";
+        let (endpoint, server_cert) = make_server_endpoint(
+            server_addr.parse().unwrap(),
+            "localhost",
+            &QuicSettings::default(),
+        )
+        .unwrap();
+
+        tokio::spawn(async move {
+            let connection = endpoint.accept().await.unwrap().await.unwrap();
+            // датаграммы до подписки устройства отбрасываются
+            link_state
+                .wait_for(|state| *state == LinkState::Up)
+                .await
+                .unwrap();
+            // 3 потерялась, 4 опоздала
+            for (sequence, temperature) in [(1, 10.0), (2, 11.0), (5, 14.0), (4, 13.0), (6, 15.0)] {
+                let message = Telemetry::new(device_id, Payload::Temperature(temperature));
+                connection
+                    .send_datagram(encode_datagram(sequence, &message).into())
+                    .unwrap();
+                sleep(Duration::from_millis(50)).await;
+            }
+            sleep(Duration::from_millis(200)).await;
+            connection.close(0_u8.into(), b"done");
+        });
+
+        device
+            .listening(&QuicLink {
+                client_addr: "127.0.0.1:0".to_string(),
+                server_addr: server_addr.to_string(),
+                cert_address: "localhost".to_string(),
+                server_certs: vec![server_cert],
+                server_fingerprints: Vec::new(),
+                connect_timeout: Duration::from_secs(1),
+                backoff: Default::default(),
+                settings: QuicSettings::default(),
+                identity: None,
+                publishers: None,
+                manager: None,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            device.read().unwrap().config().read().unwrap().to_string(),
+            "Description: test_thermometer,\nTemperature: 15"
+        );
+        assert_eq!(
+            device.datagram_stats(),
+            DatagramStats {
+                received: 4,
+                lost: 1,
+                reordered: 1,
+                duplicates: 0,
+            }
+        );
+    }
 }
@@ -0,0 +1,538 @@
+use anyhow::{anyhow, Result};
+
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::Channel;
+
+use device_grpc::auth::Permission;
+use device_grpc::devices::device_hub_client::DeviceHubClient;
+use device_grpc::devices::device_message::Message;
+use device_grpc::devices::hub_command::Command;
+use device_grpc::devices::{Ack, CommandResult, DeviceMessage, Hello, HubCommand};
+
+use super::RwLockDevice;
+
+fn message(message: Message) -> DeviceMessage {
+    DeviceMessage {
+        message: Some(message),
+    }
+}
+
+// Право, нужное для команды хаба, то же, что у метода DeviceControl.
+fn required_permission(command: &HubCommand) -> Permission {
+    match command.command {
+        Some(Command::SetOn(_)) | Some(Command::SetValue(_)) => Permission::Control,
+        Some(Command::GetStatus(_)) | None => Permission::Read,
+    }
+}
+
+impl RwLockDevice {
+    /// Выполняет команду хаба от собеседника с правами `permission`, см. [`Self::handle_command`].
+    ///
+    /// Команда без нужного права отклоняется без состояния устройства, как `PERMISSION_DENIED`
+    /// в gRPC. Без прав (`None`) проверка не выполняется.
+    pub fn handle_command_as(
+        &self,
+        command: &HubCommand,
+        permission: Option<Permission>,
+    ) -> CommandResult {
+        let required = required_permission(command);
+        match permission {
+            Some(permission) if !permission.allows(required) => CommandResult {
+                correlation_id: command.correlation_id,
+                ok: false,
+                error: format!("{:?} permission required", required),
+                status: None,
+            },
+            _ => self.handle_command(command),
+        }
+    }
+
+    /// Выполняет команду хаба и возвращает результат вместе с состоянием устройства.
+    pub fn handle_command(&self, command: &HubCommand) -> CommandResult {
+        let result = match &command.command {
+            Some(Command::SetOn(toggle)) => {
+                self.write().unwrap().on = toggle.on;
+                Ok(true)
+            }
+            Some(Command::SetValue(value)) => self
+                .read()
+                .unwrap()
+                .config()
+                .write()
+                .unwrap()
+                .set_value(value.value)
+                .map(|_| true),
+            Some(Command::GetStatus(_)) => Ok(false),
+            None => Err(anyhow!("empty command")),
+        };
+
+        if let Ok(true) = result {
+            self.notify_changed();
+        }
+
+        CommandResult {
+            correlation_id: command.correlation_id,
+            ok: result.is_ok(),
+            error: result.err().map(|e| e.to_string()).unwrap_or_default(),
+            status: Some(self.status()),
+        }
+    }
+
+    /// Держит сессию с хабом по исходящему соединению `channel`.
+    ///
+    /// Подтверждает и выполняет команды хаба, а об изменениях устройства сообщает сам.
+    /// Завершается, когда хаб закрывает сессию.
+    pub async fn attach(&self, channel: Channel) -> Result<()> {
+        let (messages, messages_rx) = mpsc::channel(16);
+
+        let hello = {
+            let device = self.read().unwrap();
+            Hello {
+                device_id: device.id().to_string(),
+                name: device.name().to_string(),
+            }
+        };
+        messages.send(message(Message::Hello(hello))).await?;
+
+        let mut commands = DeviceHubClient::new(channel)
+            .attach(ReceiverStream::new(messages_rx))
+            .await?
+            .into_inner();
+
+        let mut changes = self.changes();
+        changes.borrow_and_update();
+        messages
+            .send(message(Message::State(self.status())))
+            .await?;
+
+        loop {
+            tokio::select! {
+                command = commands.message() => {
+                    let Some(command) = command? else {
+                        break;
+                    };
+                    messages
+                        .send(message(Message::Ack(Ack {
+                            correlation_id: command.correlation_id,
+                        })))
+                        .await?;
+                    let result = self.handle_command(&command);
+                    // состояние после своей команды уже есть в результате
+                    changes.borrow_and_update();
+                    messages.send(message(Message::Result(result))).await?;
+                }
+                changed = changes.changed() => {
+                    changed?;
+                    messages.send(message(Message::State(self.status()))).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    use std::sync::{Arc, RwLock};
+
+    use tokio::sync::oneshot;
+    use tokio::time::{sleep, timeout, Duration};
+    use tonic::transport::{Endpoint, Server};
+
+    use device_grpc::devices::device_hub_server::DeviceHubServer;
+    use device_grpc::devices::{Empty, SetValue, Toggle};
+    use device_grpc::hub::{Hub, HubError};
+
+    use uuid::Uuid;
+
+    use device_quic::command::send_command;
+    use device_quic::common::make_server_endpoint;
+    use device_quic::identity::fingerprint;
+    use device_quic::settings::QuicSettings;
+    use device_quic::telemetry::{open_telemetry_stream, write_frame, Payload, Telemetry};
+
+    use crate::device::outlet::SmartOutlet;
+    use crate::device::Device;
+    use crate::identity::DeviceIdentities;
+    use crate::link::{Backoff, QuicLink};
+
+    fn outlet() -> RwLockDevice {
+        RwLockDevice::new(Arc::new(RwLock::new(Device::new(
+            "test_device".to_string(),
+            Arc::new(RwLock::new(SmartOutlet::new(
+                "test_outlet".to_string(),
+                None,
+            ))),
+            None,
+        ))))
+    }
+
+    fn command(correlation_id: u64, command: Command) -> HubCommand {
+        HubCommand {
+            correlation_id,
+            command: Some(command),
+        }
+    }
+
+    #[test]
+    fn handle_command() {
+        let device = outlet();
+        let mut changes = device.changes();
+
+        let result = device.handle_command(&command(1, Command::SetOn(Toggle { on: true })));
+        assert!(result.ok);
+        assert_eq!(result.correlation_id, 1);
+        assert!(result.status.unwrap().on);
+        assert!(changes.has_changed().unwrap());
+        changes.borrow_and_update();
+
+        // повтор не переключает устройство обратно
+        let result = device.handle_command(&command(1, Command::SetOn(Toggle { on: true })));
+        assert!(result.status.unwrap().on);
+        changes.borrow_and_update();
+
+        let result = device.handle_command(&command(2, Command::SetValue(SetValue { value: 300 })));
+        assert!(!result.ok);
+        assert_eq!(result.error, "power 300 is out of range 0..=255");
+        assert!(!changes.has_changed().unwrap());
+
+        let result = device.handle_command(&command(3, Command::GetStatus(Empty {})));
+        assert!(result.ok);
+        assert!(!changes.has_changed().unwrap());
+
+        let result = device.handle_command(&HubCommand {
+            correlation_id: 4,
+            command: None,
+        });
+        assert!(!result.ok);
+    }
+
+    #[test]
+    fn handle_command_as() {
+        let device = outlet();
+
+        let read = Some(Permission::Read);
+        let result =
+            device.handle_command_as(&command(1, Command::SetOn(Toggle { on: true })), read);
+        assert!(!result.ok);
+        assert_eq!(result.correlation_id, 1);
+        assert_eq!(result.error, "Control permission required");
+        assert!(result.status.is_none());
+        let result =
+            device.handle_command_as(&command(2, Command::SetValue(SetValue { value: 42 })), read);
+        assert!(!result.ok);
+        assert!(!device.read().unwrap().on);
+        assert!(device.status().config.contains("power: 0"));
+
+        let result = device.handle_command_as(&command(3, Command::GetStatus(Empty {})), read);
+        assert!(result.ok);
+
+        for permission in [Some(Permission::Control), None] {
+            let result = device
+                .handle_command_as(&command(4, Command::SetOn(Toggle { on: true })), permission);
+            assert!(result.ok);
+        }
+        assert!(device.read().unwrap().on);
+    }
+
+    #[tokio::test]
+    async fn test_hub_session() {
+        let hub = Hub::new(Duration::from_secs(1), Duration::from_secs(1));
+        let addr = "127.0.0.1:50066";
+
+        let (signal_tx, signal_rx) = oneshot::channel();
+
+        tokio::task::spawn(
+            Server::builder()
+                .add_service(DeviceHubServer::new(hub.clone()))
+                .serve_with_shutdown(addr.parse().unwrap(), async {
+                    signal_rx.await.ok();
+                }),
+        );
+
+        let _ = sleep(Duration::from_millis(1000)).await;
+
+        let device = outlet();
+        let device_id = device.read().unwrap().id().to_string();
+
+        let channel = Endpoint::from_shared("http://".to_owned() + addr)
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut states = hub.subscribe();
+        let session = tokio::spawn({
+            let device = device.clone();
+            async move { device.attach(channel).await }
+        });
+
+        // первое сообщение после Hello - текущее состояние
+        let report = timeout(Duration::from_secs(1), states.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.device_id, device_id);
+        assert!(!report.status.on);
+        assert_eq!(
+            hub.devices(),
+            vec![(device_id.clone(), "test_device".to_string())]
+        );
+
+        let result = hub
+            .send(&device_id, Command::SetOn(Toggle { on: true }))
+            .await
+            .unwrap();
+        assert!(result.ok);
+        assert!(result.status.unwrap().on);
+        assert!(device.read().unwrap().on);
+
+        let result = hub
+            .send(&device_id, Command::SetValue(SetValue { value: 42 }))
+            .await
+            .unwrap();
+        assert!(result.ok);
+        assert!(result.status.unwrap().config.contains("power: 42"));
+
+        let result = hub
+            .send(&device_id, Command::SetValue(SetValue { value: -1 }))
+            .await
+            .unwrap();
+        assert!(!result.ok);
+
+        // изменение на стороне устройства приходит без запроса
+        device.write().unwrap().on = false;
+        device.notify_changed();
+        let report = timeout(Duration::from_secs(1), states.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!report.status.on);
+        assert!(!hub.state(&device_id).unwrap().on);
+
+        // другой собеседник с тем же Hello не перехватывает устройство
+        let (hijack, hijack_rx) = mpsc::channel(1);
+        hijack
+            .send(message(Message::Hello(Hello {
+                device_id: device_id.clone(),
+                name: "чужое".to_string(),
+            })))
+            .await
+            .unwrap();
+        let channel = Endpoint::from_shared("http://".to_owned() + addr)
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let error = DeviceHubClient::new(channel)
+            .attach(ReceiverStream::new(hijack_rx))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), tonic::Code::AlreadyExists);
+        assert_eq!(
+            hub.devices(),
+            vec![(device_id.clone(), "test_device".to_string())]
+        );
+        let result = hub
+            .send(&device_id, Command::GetStatus(Empty {}))
+            .await
+            .unwrap();
+        assert!(result.ok);
+
+        session.abort();
+        let _ = sleep(Duration::from_millis(500)).await;
+
+        assert!(hub.devices().is_empty());
+        assert!(matches!(
+            hub.send(&device_id, Command::GetStatus(Empty {})).await,
+            Err(HubError::NotConnected { .. })
+        ));
+
+        let _ = signal_tx.send(());
+    }
+
+    #[tokio::test]
+    async fn test_quic_commands() {
+        let device = outlet();
+        let device_id = device.read().unwrap().id;
+
+        let server_addr = "127.0.0.1:50072";
+        let (endpoint, server_cert) = make_server_endpoint(
+            server_addr.parse().unwrap(),
+            "localhost",
+            &QuicSettings::default(),
+        )
+        .unwrap();
+
+        let session = tokio::spawn({
+            let device = device.clone();
+            async move {
+                device
+                    .listening(&QuicLink {
+                        client_addr: "127.0.0.1:0".to_string(),
+                        server_addr: server_addr.to_string(),
+                        cert_address: "localhost".to_string(),
+                        server_certs: vec![server_cert],
+                        server_fingerprints: Vec::new(),
+                        connect_timeout: Duration::from_secs(1),
+                        backoff: Backoff::default(),
+                        settings: QuicSettings::default(),
+                        identity: None,
+                        publishers: None,
+                        manager: None,
+                    })
+                    .await
+            }
+        });
+
+        // хаб управляет устройством по соединению телеметрии
+        let connection = endpoint.accept().await.unwrap().await.unwrap();
+        let send = |correlation_id, command| {
+            let connection = connection.clone();
+            async move {
+                let result: CommandResult = timeout(
+                    Duration::from_secs(5),
+                    send_command(
+                        &connection,
+                        device_id,
+                        &HubCommand {
+                            correlation_id,
+                            command: Some(command),
+                        },
+                    ),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+                assert_eq!(result.correlation_id, correlation_id);
+                result
+            }
+        };
+
+        let result = send(1, Command::SetOn(Toggle { on: true })).await;
+        assert!(result.ok);
+        assert!(device.read().unwrap().on);
+
+        let mut power = open_telemetry_stream(&connection, device_id).await.unwrap();
+        write_frame(
+            &mut power,
+            &Telemetry::new(device_id, Payload::Power(120.0)),
+        )
+        .await
+        .unwrap();
+        sleep(Duration::from_millis(200)).await;
+        let result = send(2, Command::GetStatus(Empty {})).await;
+        assert!(result.status.unwrap().config.contains("power: 120"));
+
+        let result = send(3, Command::SetValue(SetValue { value: 42 })).await;
+        assert!(result.ok);
+        assert!(result.status.unwrap().config.contains("power: 42"));
+
+        let result = send(4, Command::SetValue(SetValue { value: -1 })).await;
+        assert!(!result.ok);
+        assert_eq!(result.error, "power -1 is out of range 0..=255");
+
+        // команда другому устройству отклоняется
+        let other: Result<CommandResult, _> = send_command(
+            &connection,
+            Uuid::new_v4(),
+            &HubCommand {
+                correlation_id: 5,
+                command: Some(Command::GetStatus(Empty {})),
+            },
+        )
+        .await;
+        assert!(other.is_err());
+
+        connection.close(0_u8.into(), b"done");
+        timeout(Duration::from_secs(5), session)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_commands_read_only() {
+        let device = outlet();
+        let device_id = device.read().unwrap().id;
+
+        let server_addr = "127.0.0.1:50075";
+        let (endpoint, server_cert) = make_server_endpoint(
+            server_addr.parse().unwrap(),
+            "localhost",
+            &QuicSettings::default(),
+        )
+        .unwrap();
+
+        // шлюз публикует показания, но управлять устройством не может
+        let publishers = DeviceIdentities::new().with_fingerprint_permission(
+            &fingerprint(&server_cert),
+            device_id,
+            Permission::Read,
+        );
+        let session = tokio::spawn({
+            let device = device.clone();
+            async move {
+                device
+                    .listening(&QuicLink {
+                        client_addr: "127.0.0.1:0".to_string(),
+                        server_addr: server_addr.to_string(),
+                        cert_address: "localhost".to_string(),
+                        server_certs: vec![server_cert],
+                        server_fingerprints: Vec::new(),
+                        connect_timeout: Duration::from_secs(1),
+                        backoff: Backoff::default(),
+                        settings: QuicSettings::default(),
+                        identity: None,
+                        publishers: Some(publishers),
+                        manager: None,
+                    })
+                    .await
+            }
+        });
+
+        let connection = endpoint.accept().await.unwrap().await.unwrap();
+        let send = |correlation_id, command| {
+            let connection = connection.clone();
+            async move {
+                let result: CommandResult = timeout(
+                    Duration::from_secs(5),
+                    send_command(
+                        &connection,
+                        device_id,
+                        &HubCommand {
+                            correlation_id,
+                            command: Some(command),
+                        },
+                    ),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+                result
+            }
+        };
+
+        let result = send(1, Command::SetOn(Toggle { on: true })).await;
+        assert!(!result.ok);
+        assert_eq!(result.error, "Control permission required");
+        let result = send(2, Command::SetValue(SetValue { value: 42 })).await;
+        assert!(!result.ok);
+        assert!(!device.read().unwrap().on);
+
+        let result = send(3, Command::GetStatus(Empty {})).await;
+        assert!(result.ok);
+        assert!(result.status.unwrap().config.contains("power: 0"));
+
+        connection.close(0_u8.into(), b"done");
+        timeout(Duration::from_secs(5), session)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -1,18 +1,83 @@
 use std::fmt;
+use std::ops::RangeInclusive;
 
 use std::option::Option;
 
+use anyhow::{anyhow, Error, Result};
+
+use device_quic::telemetry::Payload;
+
 use super::SmartDevices;
 
 // Розетка
 
-#[derive(Debug, Clone, PartialEq)]
+/// Мощность, которую розетка хранит без насыщения.
+pub const POWER_RANGE: RangeInclusive<f32> = 0.0..=255.0;
+
+#[derive(Clone, PartialEq)]
 pub struct SmartOutlet {
     description: String,
     power: u8,
+    // показание до округления
+    reading: f32,
 }
 
-impl SmartDevices for SmartOutlet {}
+impl SmartDevices for SmartOutlet {
+    fn kind(&self) -> &'static str {
+        "outlet"
+    }
+    fn description(&self) -> &str {
+        &self.description
+    }
+    fn value(&self) -> i64 {
+        self.power.into()
+    }
+    fn listening(&mut self, payload: Payload) -> Result<(), Error> {
+        match payload {
+            Payload::Power(value) => {
+                // значения за пределами u8 насыщаются, как при `as`
+                self.reading = if value.is_nan() {
+                    0.0
+                } else {
+                    value.clamp(u8::MIN.into(), u8::MAX.into())
+                };
+                self.power = self.reading.round() as u8;
+                Ok(())
+            }
+            other => Err(anyhow!(
+                "outlet does not accept {:?} readings",
+                other.kind()
+            )),
+        }
+    }
+    fn set_value(&mut self, value: i64) -> Result<(), Error> {
+        self.power =
+            u8::try_from(value).map_err(|_| anyhow!("power {} is out of range 0..=255", value))?;
+        self.reading = self.power.into();
+        Ok(())
+    }
+    fn reading(&self) -> f32 {
+        self.reading
+    }
+    fn restore(&mut self, reading: f32) -> Result<(), Error> {
+        if !POWER_RANGE.contains(&reading) {
+            return Err(anyhow!("power {} is out of range 0..=255", reading));
+        }
+        self.reading = reading;
+        self.power = self.reading.round() as u8;
+        Ok(())
+    }
+}
+
+// `config` в ответе gRPC, показание до округления в него не входит
+impl fmt::Debug for SmartOutlet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SmartOutlet")
+            .field("description", &self.description)
+            .field("power", &self.power)
+            .finish()
+    }
+}
 
 impl fmt::Display for SmartOutlet {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -26,15 +91,13 @@ impl fmt::Display for SmartOutlet {
 
 #[allow(dead_code)]
 impl SmartOutlet {
-    pub(crate) fn new(description: String, power: Option<u8>) -> Self {
+    pub fn new(description: String, power: Option<u8>) -> Self {
         SmartOutlet {
             description,
             power: power.unwrap_or(0),
+            reading: power.unwrap_or(0).into(),
         }
     }
-    pub(crate) fn description(&self) -> &str {
-        &self.description
-    }
     pub(crate) fn power(&self) -> &u8 {
         &self.power
     }
@@ -52,6 +115,7 @@ mod tests {
             SmartOutlet {
                 description: "test".to_string(),
                 power: 0,
+                reading: 0.0,
             }
         );
     }
@@ -63,4 +127,25 @@ mod tests {
             "Description: test,\nPower: 0"
         );
     }
+
+    #[test]
+    fn set_value() {
+        let mut outlet = SmartOutlet::new("test".to_string(), None);
+        assert!(outlet.set_value(220).is_ok());
+        assert_eq!(outlet.power(), &220);
+        assert!(outlet.set_value(256).is_err());
+        assert!(outlet.set_value(-1).is_err());
+        assert_eq!(outlet.power(), &220);
+    }
+
+    #[test]
+    fn restore() {
+        let mut outlet = SmartOutlet::new("test".to_string(), None);
+        outlet.restore(40.4).unwrap();
+        assert_eq!(outlet.power(), &40);
+        assert_eq!(outlet.reading(), 40.4);
+        assert!(outlet.restore(-1.0).is_err());
+        assert!(outlet.restore(f32::INFINITY).is_err());
+        assert_eq!(outlet.power(), &40);
+    }
 }
@@ -1,9 +1,32 @@
 use std::fmt::{self};
 
-use anyhow::{Error, Result};
+use anyhow::{anyhow, Error, Result};
+
+use device_quic::telemetry::Payload;
 
 pub trait SmartDevices: fmt::Debug + fmt::Display {
-    fn listening(&mut self, _data: Vec<u8>) -> Result<(), Error> {
-        panic!("trait listening is unrealized")
+    // тип устройства, тот же, что в объявлениях шлюзов, например `thermometer`
+    fn kind(&self) -> &'static str;
+    fn description(&self) -> &str;
+    // текущее значение: мощность розетки, температура термометра
+    fn value(&self) -> i64;
+    // показание датчика, присланное по QUIC
+    fn listening(&mut self, payload: Payload) -> Result<(), Error> {
+        Err(anyhow!(
+            "device does not accept {:?} readings",
+            payload.kind()
+        ))
+    }
+    // установка значения по команде хаба
+    fn set_value(&mut self, _value: i64) -> Result<(), Error> {
+        Err(anyhow!("device has no settable value"))
+    }
+    // показание, из которого получено `value`, без округления
+    fn reading(&self) -> f32 {
+        self.value() as f32
+    }
+    // показание из снимка дома, вне диапазона устройства - ошибка
+    fn restore(&mut self, _reading: f32) -> Result<(), Error> {
+        Err(anyhow!("device has no reading"))
     }
 }
@@ -1,32 +1,83 @@
 use std::fmt;
+use std::ops::RangeInclusive;
 
 use std::option::Option;
 
 use anyhow::{anyhow, Error, Result};
 
+use device_quic::telemetry::Payload;
+
 use super::SmartDevices;
 
 // use device_quic::common::make_client_endpoint;
 
 // Термометр
 
-#[derive(Debug, Clone, PartialEq)]
+/// Температуры, которые термометр хранит без насыщения.
+pub const TEMPERATURE_RANGE: RangeInclusive<f32> = -128.0..=127.0;
+
+#[derive(Clone, PartialEq)]
 pub struct SmartThermometer {
     description: String,
     temperature: i8,
+    // показание до округления
+    reading: f32,
 }
 
 impl SmartDevices for SmartThermometer {
-    fn listening(&mut self, data: Vec<u8>) -> Result<(), Error> {
-        let bytes: [u8; 1] = data.try_into().map_err(|v: Vec<u8>| {
-            anyhow!("Expected a Vec of length {} but it was {}", 1, v.len())
-        })?;
-
-        self.temperature = i8::from_ne_bytes(bytes);
+    fn kind(&self) -> &'static str {
+        "thermometer"
+    }
+    fn description(&self) -> &str {
+        &self.description
+    }
+    fn value(&self) -> i64 {
+        self.temperature.into()
+    }
+    fn listening(&mut self, payload: Payload) -> Result<(), Error> {
+        match payload {
+            Payload::Temperature(value) => {
+                // значения за пределами i8 насыщаются, как при `as`
+                self.reading = if value.is_nan() {
+                    0.0
+                } else {
+                    value.clamp(i8::MIN.into(), i8::MAX.into())
+                };
+                self.temperature = self.reading.round() as i8;
+                Ok(())
+            }
+            other => Err(anyhow!(
+                "thermometer does not accept {:?} readings",
+                other.kind()
+            )),
+        }
+    }
+    fn reading(&self) -> f32 {
+        self.reading
+    }
+    fn restore(&mut self, reading: f32) -> Result<(), Error> {
+        if !TEMPERATURE_RANGE.contains(&reading) {
+            return Err(anyhow!(
+                "temperature {} is out of range -128..=127",
+                reading
+            ));
+        }
+        self.reading = reading;
+        self.temperature = self.reading.round() as i8;
         Ok(())
     }
 }
 
+// `config` в ответе gRPC, показание до округления в него не входит
+impl fmt::Debug for SmartThermometer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SmartThermometer")
+            .field("description", &self.description)
+            .field("temperature", &self.temperature)
+            .finish()
+    }
+}
+
 impl fmt::Display for SmartThermometer {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -39,15 +90,13 @@ impl fmt::Display for SmartThermometer {
 
 #[allow(dead_code)]
 impl SmartThermometer {
-    pub(crate) fn new(description: String, temperature: Option<i8>) -> Self {
+    pub fn new(description: String, temperature: Option<i8>) -> Self {
         SmartThermometer {
             description,
             temperature: temperature.unwrap_or(0),
+            reading: temperature.unwrap_or(0).into(),
         }
     }
-    pub(crate) fn description(&self) -> &str {
-        &self.description
-    }
     pub(crate) fn temperature(&self) -> &i8 {
         &self.temperature
     }
@@ -65,6 +114,7 @@ mod tests {
             SmartThermometer {
                 description: "test".to_string(),
                 temperature: 0,
+                reading: 0.0,
             }
         );
     }
@@ -76,4 +126,28 @@ mod tests {
             "Description: test,\nTemperature: 0"
         );
     }
+
+    #[test]
+    fn listening() {
+        let mut thermometer = SmartThermometer::new("test".to_string(), None);
+        thermometer.listening(Payload::Temperature(21.6)).unwrap();
+        assert_eq!(thermometer.temperature, 22);
+        assert_eq!(thermometer.reading(), 21.6);
+        thermometer.listening(Payload::Temperature(-300.0)).unwrap();
+        assert_eq!(thermometer.temperature, i8::MIN);
+        assert_eq!(thermometer.reading(), -128.0);
+        assert!(thermometer.listening(Payload::Motion(true)).is_err());
+        assert_eq!(thermometer.temperature, i8::MIN);
+    }
+
+    #[test]
+    fn restore() {
+        let mut thermometer = SmartThermometer::new("test".to_string(), None);
+        thermometer.restore(21.6).unwrap();
+        assert_eq!(thermometer.temperature, 22);
+        assert_eq!(thermometer.reading(), 21.6);
+        assert!(thermometer.restore(300.0).is_err());
+        assert!(thermometer.restore(f32::NAN).is_err());
+        assert_eq!(thermometer.temperature, 22);
+    }
 }
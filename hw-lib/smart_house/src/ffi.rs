@@ -0,0 +1,1914 @@
+//! C ABI библиотеки `libsmart_house`.
+//!
+//! Заголовок для C генерирует cbindgen в `include/smart_house.h`, тест
+//! `tests::header_is_up_to_date` проверяет, что он совпадает с кодом. Все символы начинаются
+//! с `smart_house_`. Перед первым вызовом загрузчик сверяет [`smart_house_abi_version`] с
+//! [`SMART_HOUSE_ABI_VERSION`], с которой был собран.
+//!
+//! ## Владение памятью
+//!
+//! - Входные строки `*const c_char` только заимствуются на время вызова: библиотека читает
+//!   их через [`CStr`] и никогда не освобождает, память остаётся за вызывающим.
+//! - Строки, которые возвращает библиотека ([`smart_house_report`],
+//!   [`smart_house_device_status`], [`smart_house_export`]), выделены библиотекой, вызывающий освобождает их через
+//!   [`smart_house_free_string`] и только через неё.
+//! - Списки строк ([`smart_house_list_rooms`], [`smart_house_list_devices`]) освобождаются
+//!   целиком через [`smart_house_free_string_list`].
+//! - Дом, созданный [`smart_house_new`] или [`smart_house_import`], освобождается через
+//!   [`smart_house_destroy`].
+//!
+//! Память, выделенная одной стороной, не освобождается `free` другой: у библиотеки и
+//! вызывающего могут быть разные аллокаторы.
+//!
+//! ## Потоки
+//!
+//! Дом можно вызывать из нескольких потоков одновременно: каждый вызов берёт внутреннюю
+//! блокировку дома, обратные вызовы выполняются уже без неё. Только [`smart_house_destroy`]
+//! не должен пересекаться с другими вызовами с тем же домом.
+//!
+//! ## Ошибки
+//!
+//! Каждая функция возвращает [`SmartHouseStatus`], результаты отдаются через выходные
+//! параметры. Паника внутри библиотеки не выходит за границу FFI, а превращается в
+//! [`SmartHouseStatus::Panic`]. Текст ошибки последнего вызова в потоке возвращает
+//! [`smart_house_last_error`].
+//!
+//! ## Устройства
+//!
+//! [`smart_house_add_device`] создаёт устройство любого типа из [`crate::device::DEVICE_KINDS`]
+//! по JSON параметрам, например `{"kind": "thermometer", "name": "Kitchen", "description": "by
+//! the window", "on": true}`; `description` и `on` необязательны. [`smart_house_device_status`]
+//! отдаёт состояние устройства в JSON:
+//!
+//! ```text
+//! {"id":"…","name":"Kitchen","kind":"thermometer","description":"by the window","on":true,"value":21,"reading":21.4}
+//! ```
+//!
+//! ## Снимки
+//!
+//! [`smart_house_export`] сохраняет комнаты и состояние устройств дома в JSON снимок
+//! [`crate::house::HouseSnapshot`], [`smart_house_import`] создаёт по нему новый дом. Формат
+//! снимка версионируется отдельно от ABI ([`crate::house::SNAPSHOT_VERSION`]) и читается
+//! более новыми версиями библиотеки, так загрузчик переносит дома при замене библиотеки.
+//! Подписки в снимок не входят.
+//!
+//! ## События
+//!
+//! [`smart_house_subscribe`] регистрирует функцию обратного вызова с `user_data`, она получает
+//! [`SmartHouseEvent`] о добавленных и удалённых комнатах и устройствах, переключениях и
+//! новых показаниях.
+//!
+//! - Обратный вызов выполняется синхронно в потоке, чей вызов библиотеки вызвал событие, уже
+//!   после изменения и до возврата из этого вызова. Собственных потоков для обратных вызовов у
+//!   библиотеки нет.
+//! - Строки события действительны только до возврата из обратного вызова.
+//! - Вызовы из разных потоков могут выполнять обратные вызовы одновременно, поэтому обратный
+//!   вызов и `user_data` должны быть потокобезопасны, если дом используется из нескольких
+//!   потоков.
+//! - Из обратного вызова можно вызывать библиотеку, в том числе с тем же домом. Новая подписка
+//!   из обратного вызова действует со следующего события.
+//! - [`smart_house_unsubscribe`] ждёт обратные вызовы этой подписки, уже выполняющиеся в других
+//!   потоках. После возврата из неё или из [`smart_house_destroy`] обратный вызов больше не
+//!   вызывается, и `user_data` можно освобождать.
+//! - Исключение: отписка из обратного вызова любой подписки ничего не ждёт. Начатые вызовы
+//!   этой подписки, в том числе текущий, доработают уже после отписки, поэтому их `user_data`
+//!   освобождают, только когда они закончатся. Так обратные вызовы в разных потоках могут
+//!   отписывать друг друга без взаимной блокировки.
+
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock};
+
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::ffi::c_void;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use thiserror::Error;
+
+use device_quic::telemetry::Kind;
+
+use crate::device::outlet::SmartOutlet;
+use crate::device::Device;
+use crate::device::DeviceParams;
+use crate::device::FeedError;
+use crate::device::RwLockDevice;
+use crate::house::room::RoomError;
+use crate::house::{HouseSnapshot, SmartHouse, SmartHouseError};
+
+/// Version of the C ABI, incremented on every change of exported functions, types or their
+/// guarantees, e.g. version 4 made the handle thread-safe and version 5 added house snapshots.
+pub const SMART_HOUSE_ABI_VERSION: u32 = 5;
+
+/// Smart house handle, opaque for C.
+///
+/// The handle locks the house for every call, so it can be used from several threads at once.
+pub struct SmartHouseLib {
+    state: Mutex<LibState>,
+}
+
+struct LibState {
+    smarthouse: SmartHouse,
+    subscribers: Vec<Arc<Subscriber>>,
+    next_subscription: u64,
+}
+
+impl SmartHouseLib {
+    // Новый дом без подписчиков, владение передаётся вызывающему.
+    fn into_raw(smarthouse: SmartHouse) -> *mut SmartHouseLib {
+        Box::into_raw(Box::new(SmartHouseLib {
+            state: Mutex::new(LibState {
+                smarthouse,
+                subscribers: Vec::new(),
+                next_subscription: 1,
+            }),
+        }))
+    }
+
+    // Паника в другом вызове не делает дом недоступным, см. `SmartHouseStatus::Panic`.
+    fn lock(&self) -> MutexGuard<'_, LibState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Вызывает подписчиков уже без блокировки, поэтому обратный вызов может снова
+    // вызвать библиотеку с тем же домом.
+    fn notify(&self, state: MutexGuard<'_, LibState>, event: Event) {
+        // копия списка: обратный вызов может изменить подписки
+        let subscribers = state.subscribers.clone();
+        drop(state);
+        if subscribers.is_empty() {
+            return;
+        }
+        let room = c_string(&event.room);
+        let device = event.device.as_deref().map(c_string);
+        let event = SmartHouseEvent {
+            kind: event.kind,
+            room: room.as_ptr(),
+            device: device
+                .as_ref()
+                .map_or(std::ptr::null(), |device| device.as_ptr()),
+            on: event.on,
+            value: event.value,
+        };
+        for subscriber in subscribers {
+            subscriber.call(&event);
+        }
+    }
+}
+
+/// What happened in the house, see [`SmartHouseEvent`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmartHouseEventKind {
+    RoomAdded = 1,
+    RoomRemoved = 2,
+    DeviceAdded = 3,
+    DeviceRemoved = 4,
+    /// The device was turned on or off, `on` is the new state.
+    DeviceSwitched = 5,
+    /// The device took a reading, `value` is its new value.
+    ReadingChanged = 6,
+}
+
+/// Event passed to a [`SmartHouseCallback`].
+///
+/// The strings are valid only until the callback returns.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SmartHouseEvent {
+    pub kind: SmartHouseEventKind,
+    pub room: *const c_char,
+    /// Null for room events.
+    pub device: *const c_char,
+    /// Whether the device is on, false for room events.
+    pub on: bool,
+    /// Value of the device, see `value` of [`smart_house_device_status`], 0 for room events.
+    pub value: i64,
+}
+
+/// Callback registered with [`smart_house_subscribe`], must not be null.
+pub type SmartHouseCallback =
+    Option<unsafe extern "C" fn(event: *const SmartHouseEvent, user_data: *mut c_void)>;
+
+#[derive(Debug)]
+struct Subscriber {
+    id: u64,
+    callback: SmartHouseCallback,
+    user_data: *mut c_void,
+    calls: Mutex<Calls>,
+    finished: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Calls {
+    unsubscribed: bool,
+    // сколько обратных вызовов сейчас выполняется
+    running: usize,
+}
+
+thread_local! {
+    // сколько обратных вызовов любых подписок выполняется в этом потоке
+    static IN_CALLBACK: Cell<usize> = const { Cell::new(0) };
+}
+
+// SAFETY: `smart_house_subscribe` требует, чтобы обратный вызов с `user_data` можно было
+// вызывать из любого потока, который вызывает библиотеку.
+unsafe impl Send for Subscriber {}
+unsafe impl Sync for Subscriber {}
+
+impl Subscriber {
+    fn call(&self, event: &SmartHouseEvent) {
+        let Some(callback) = self.callback else {
+            return;
+        };
+        {
+            let mut calls = self.calls.lock().unwrap();
+            // отписан после того, как `notify` скопировал список
+            if calls.unsubscribed {
+                return;
+            }
+            calls.running += 1;
+        }
+        IN_CALLBACK.with(|depth| depth.set(depth.get() + 1));
+        unsafe { callback(event, self.user_data) };
+        IN_CALLBACK.with(|depth| depth.set(depth.get() - 1));
+        let mut calls = self.calls.lock().unwrap();
+        calls.running -= 1;
+        self.finished.notify_all();
+    }
+
+    // Больше не вызывается и ждёт уже начатые обратные вызовы. Из обратного вызова не ждёт:
+    // два обратных вызова в разных потоках, отписывающие друг друга, ждали бы друг друга вечно.
+    fn unsubscribe(&self) {
+        let mut calls = self.calls.lock().unwrap();
+        calls.unsubscribed = true;
+        if IN_CALLBACK.with(Cell::get) > 0 {
+            return;
+        }
+        while calls.running > 0 {
+            calls = self.finished.wait(calls).unwrap();
+        }
+    }
+}
+
+// Событие до перевода в `SmartHouseEvent`.
+struct Event {
+    kind: SmartHouseEventKind,
+    room: String,
+    device: Option<String>,
+    on: bool,
+    value: i64,
+}
+
+impl Event {
+    fn room(kind: SmartHouseEventKind, room: String) -> Self {
+        Event {
+            kind,
+            room,
+            device: None,
+            on: false,
+            value: 0,
+        }
+    }
+
+    fn device(kind: SmartHouseEventKind, room: String, device: &RwLockDevice) -> Self {
+        let state = device.state();
+        Event {
+            kind,
+            room,
+            device: Some(state.name),
+            on: state.on,
+            value: state.value,
+        }
+    }
+}
+
+/// Result of a C ABI call, the message is available through [`smart_house_last_error`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmartHouseStatus {
+    Ok = 0,
+    /// A required pointer argument is null.
+    NullPointer = 1,
+    /// A string argument is not valid UTF-8.
+    InvalidString = 2,
+    /// No room or device with this name.
+    NotFound = 3,
+    AlreadyExists = 4,
+    /// The operation is not supported, e.g. an unknown device kind.
+    Unsupported = 5,
+    /// The library panicked, the house may be left half-updated.
+    Panic = 6,
+    /// Malformed JSON parameters or an unknown enum value.
+    InvalidArgument = 7,
+}
+
+/// Kind of a sensor reading fed with [`smart_house_feed_reading`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmartHouseReadingKind {
+    /// Degrees Celsius.
+    Temperature = 1,
+    /// Any non-zero value means motion.
+    Motion = 2,
+    /// Watts.
+    Power = 3,
+}
+
+// Из C приходит число: значение вне перечисления в самом enum было бы UB.
+impl TryFrom<u32> for SmartHouseReadingKind {
+    type Error = u32;
+
+    fn try_from(kind: u32) -> Result<Self, Self::Error> {
+        match kind {
+            1 => Ok(SmartHouseReadingKind::Temperature),
+            2 => Ok(SmartHouseReadingKind::Motion),
+            3 => Ok(SmartHouseReadingKind::Power),
+            _ => Err(kind),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+enum FfiError {
+    #[error("Argument {arg} is null")]
+    NullPointer { arg: &'static str },
+    #[error("Argument {arg} is not valid UTF-8")]
+    InvalidString { arg: &'static str },
+    #[error("No subscription {id}")]
+    UnknownSubscription { id: u64 },
+    #[error("Unknown reading kind {kind}")]
+    UnknownReadingKind { kind: u32 },
+    #[error("Invalid device parameters: {0}")]
+    InvalidParams(#[from] serde_json::Error),
+    #[error("Invalid house snapshot: {0}")]
+    InvalidSnapshot(serde_json::Error),
+    #[error(transparent)]
+    Feed(#[from] FeedError),
+    #[error(transparent)]
+    House(#[from] SmartHouseError),
+}
+
+impl FfiError {
+    fn status(&self) -> SmartHouseStatus {
+        match self {
+            FfiError::NullPointer { .. } => SmartHouseStatus::NullPointer,
+            FfiError::InvalidString { .. } => SmartHouseStatus::InvalidString,
+            FfiError::UnknownSubscription { .. } => SmartHouseStatus::NotFound,
+            FfiError::InvalidParams(_)
+            | FfiError::InvalidSnapshot(_)
+            | FfiError::UnknownReadingKind { .. } => SmartHouseStatus::InvalidArgument,
+            FfiError::Feed(FeedError::OutOfRange { .. }) => SmartHouseStatus::InvalidArgument,
+            // устройство не принимает показания этого вида
+            FfiError::Feed(FeedError::Unsupported(_)) => SmartHouseStatus::Unsupported,
+            FfiError::House(error) => error.into(),
+        }
+    }
+}
+
+/// Status of the C ABI for a house error, for Rust callers that report errors the same way.
+impl From<&SmartHouseError> for SmartHouseStatus {
+    fn from(error: &SmartHouseError) -> Self {
+        match error {
+            SmartHouseError::AddRoomError { .. }
+            | SmartHouseError::RoomError(RoomError::AddError { .. }) => {
+                SmartHouseStatus::AlreadyExists
+            }
+            SmartHouseError::RemoveRoomError { .. }
+            | SmartHouseError::GetRoomError { .. }
+            | SmartHouseError::GetDeviceError { .. }
+            | SmartHouseError::NotDiscoveredError { .. }
+            | SmartHouseError::RoomError(RoomError::RemoveError { .. }) => {
+                SmartHouseStatus::NotFound
+            }
+            SmartHouseError::UnsupportedDeviceError { .. }
+            | SmartHouseError::SnapshotVersionError { .. } => SmartHouseStatus::Unsupported,
+            SmartHouseError::ReadingRangeError { .. } => SmartHouseStatus::InvalidArgument,
+        }
+    }
+}
+
+thread_local! {
+    // текст ошибки последнего вызова в этом потоке
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', "")).expect("no interior nul bytes");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    let message = match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => message,
+        (_, Some(message)) => message.as_str(),
+        _ => "unknown panic",
+    };
+    format!("Smart house library panicked: {}", message)
+}
+
+// Общая обёртка всех функций C ABI: сбрасывает прошлую ошибку, ловит панику
+// и переводит ошибку в код статуса.
+fn ffi_call(call: impl FnOnce() -> Result<(), FfiError>) -> SmartHouseStatus {
+    LAST_ERROR.with(|last| last.borrow_mut().take());
+    match catch_unwind(AssertUnwindSafe(call)) {
+        Ok(Ok(())) => SmartHouseStatus::Ok,
+        Ok(Err(error)) => {
+            set_last_error(error.to_string());
+            error.status()
+        }
+        Err(panic) => {
+            set_last_error(panic_message(panic.as_ref()));
+            SmartHouseStatus::Panic
+        }
+    }
+}
+
+fn c_string(text: &str) -> CString {
+    // нулевой байт закончил бы строку раньше времени
+    CString::new(text.replace('\0', "")).expect("no interior nul bytes")
+}
+
+// Строка для C, которой владеет вызывающий до `smart_house_free_string`.
+fn owned_c_string(text: &str) -> *mut c_char {
+    c_string(text).into_raw()
+}
+
+// Копия заимствованной строки вызывающего, сама строка не освобождается.
+unsafe fn borrowed_str(text: *const c_char, arg: &'static str) -> Result<String, FfiError> {
+    if text.is_null() {
+        return Err(FfiError::NullPointer { arg });
+    }
+    unsafe { CStr::from_ptr(text) }
+        .to_str()
+        .map(str::to_string)
+        .map_err(|_| FfiError::InvalidString { arg })
+}
+
+unsafe fn save2buf(
+    strings: &[&str],
+    out_buffer: *mut *mut *mut c_char,
+    out_size: *mut usize,
+) -> Result<(), FfiError> {
+    if out_buffer.is_null() {
+        return Err(FfiError::NullPointer { arg: "out_buffer" });
+    }
+    if out_size.is_null() {
+        return Err(FfiError::NullPointer { arg: "out_size" });
+    }
+    // Преобразуем строки Rust в строки C и выделяем память для них в куче
+    let c_strings: Vec<*mut c_char> = strings.iter().map(|s| owned_c_string(s)).collect();
+    let size = c_strings.len();
+
+    // Выделяем память для массива строк
+    let buffer = c_strings.into_boxed_slice();
+    let raw_buffer = Box::into_raw(buffer);
+
+    // Записываем данные в выходные параметры
+    unsafe {
+        *out_buffer = raw_buffer as *mut *mut c_char;
+        *out_size = size;
+    }
+    Ok(())
+}
+
+unsafe fn get_lib<'a>(lib: *mut SmartHouseLib) -> Result<&'a SmartHouseLib, FfiError> {
+    if lib.is_null() {
+        return Err(FfiError::NullPointer {
+            arg: "smarthouselib",
+        });
+    }
+    Ok(unsafe { &*lib })
+}
+
+/// Version of the C ABI the library was built with, see [`SMART_HOUSE_ABI_VERSION`].
+///
+/// Loaders check it before calling anything else and refuse a library of another version.
+#[no_mangle]
+pub extern "C" fn smart_house_abi_version() -> u32 {
+    SMART_HOUSE_ABI_VERSION
+}
+
+/// Message of the last failed call on this thread, null if it succeeded.
+///
+/// The string belongs to the library and stays valid until the next call on this thread,
+/// it must not be released.
+#[no_mangle]
+pub extern "C" fn smart_house_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Creates a house and writes it to `out`, the caller releases it with [`smart_house_destroy`].
+///
+/// # Safety
+///
+/// `name` must be a valid nul-terminated string, it is only borrowed. `out` must be valid
+/// for writes.
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_new(
+    name: *const c_char,
+    out: *mut *mut SmartHouseLib,
+) -> SmartHouseStatus {
+    ffi_call(|| {
+        let name_str = unsafe { borrowed_str(name, "name") }?;
+        if out.is_null() {
+            return Err(FfiError::NullPointer { arg: "out" });
+        }
+        let smarthouse = SmartHouse::new(name_str);
+        unsafe { out.write(SmartHouseLib::into_raw(smarthouse)) };
+        Ok(())
+    })
+}
+
+/// Creates a house from a JSON snapshot of [`smart_house_export`] and writes it to `out`, the
+/// caller releases it with [`smart_house_destroy`].
+///
+/// The snapshot may come from another version of the library. Returns
+/// [`SmartHouseStatus::InvalidArgument`] for malformed JSON or a reading out of the device
+/// range and [`SmartHouseStatus::Unsupported`] for a newer snapshot format or an unknown
+/// device kind.
+///
+/// # Safety
+///
+/// `snapshot` must be a valid nul-terminated string, it is only borrowed. `out` must be valid
+/// for writes.
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_import(
+    snapshot: *const c_char,
+    out: *mut *mut SmartHouseLib,
+) -> SmartHouseStatus {
+    ffi_call(|| {
+        let snapshot_str = unsafe { borrowed_str(snapshot, "snapshot") }?;
+        if out.is_null() {
+            return Err(FfiError::NullPointer { arg: "out" });
+        }
+        let snapshot: HouseSnapshot =
+            serde_json::from_str(&snapshot_str).map_err(FfiError::InvalidSnapshot)?;
+        let smarthouse = SmartHouse::restore(snapshot)?;
+        unsafe { out.write(SmartHouseLib::into_raw(smarthouse)) };
+        Ok(())
+    })
+}
+
+/// Releases the house, its subscriptions are dropped without being called.
+///
+/// # Safety
+///
+/// `smarthouse` must be null or a pointer returned by [`smart_house_new`] which was not destroyed yet.
+/// No other call with this house may run concurrently or start afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_destroy(smarthouse: *mut SmartHouseLib) -> SmartHouseStatus {
+    ffi_call(|| {
+        if !smarthouse.is_null() {
+            let _ = unsafe { Box::from_raw(smarthouse) };
+        }
+        Ok(())
+    })
+}
+
+/// # Safety
+///
+/// `smarthouselib` must be a live pointer from [`smart_house_new`], `name` a valid nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_add_room(
+    smarthouselib: *mut SmartHouseLib,
+    name: *const c_char,
+) -> SmartHouseStatus {
+    ffi_call(|| {
+        let lib = unsafe { get_lib(smarthouselib) }?;
+        let mut state = lib.lock();
+        let name_str = unsafe { borrowed_str(name, "name") }?;
+        let name_str = state.smarthouse.add_room(name_str)?;
+        lib.notify(state, Event::room(SmartHouseEventKind::RoomAdded, name_str));
+        Ok(())
+    })
+}
+
+/// # Safety
+///
+/// `smarthouselib` must be a live pointer from [`smart_house_new`], `name` a valid nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_remove_room(
+    smarthouselib: *mut SmartHouseLib,
+    name: *const c_char,
+) -> SmartHouseStatus {
+    ffi_call(|| {
+        let lib = unsafe { get_lib(smarthouselib) }?;
+        let mut state = lib.lock();
+        let name_str = unsafe { borrowed_str(name, "name") }?;
+        let name_str = state.smarthouse.remove_room(name_str)?;
+        lib.notify(
+            state,
+            Event::room(SmartHouseEventKind::RoomRemoved, name_str),
+        );
+        Ok(())
+    })
+}
+
+/// Writes an array of room names, which the caller releases with [`smart_house_free_string_list`].
+///
+/// # Safety
+///
+/// `smarthouselib` must be a live pointer from [`smart_house_new`], `out_buffer` and `out_size` valid
+/// for writes.
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_list_rooms(
+    smarthouselib: *mut SmartHouseLib,
+    out_buffer: *mut *mut *mut c_char,
+    out_size: *mut usize,
+) -> SmartHouseStatus {
+    ffi_call(|| {
+        let lib = unsafe { get_lib(smarthouselib) }?;
+        let state = lib.lock();
+        let list_room: Vec<&str> = state
+            .smarthouse
+            .get(None)
+            .iter()
+            .map(|s| s.name())
+            .collect();
+
+        unsafe { save2buf(&list_room, out_buffer, out_size) }
+    })
+}
+
+/// Releases an array written by [`smart_house_list_rooms`] or [`smart_house_list_devices`] together
+/// with its strings. Null is ignored.
+///
+/// # Safety
+///
+/// `buffer` and `count` must come from one of these calls, the array is released only once.
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_free_string_list(
+    buffer: *mut *mut c_char,
+    count: usize,
+) -> SmartHouseStatus {
+    ffi_call(|| {
+        if buffer.is_null() {
+            return Ok(());
+        }
+        unsafe {
+            // Массив был выделен как срез длины `count`, так же его и освобождаем
+            let cstrings = Box::from_raw(std::ptr::slice_from_raw_parts_mut(buffer, count));
+
+            // Преобразуем каждый сырой указатель обратно в CString, автоматически освобождая память
+            for &cstring in cstrings.iter() {
+                let _ = CString::from_raw(cstring);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Releases a string returned by the library, e.g. by [`smart_house_report`]. Null is ignored.
+///
+/// # Safety
+///
+/// `text` must be null or a string returned by the library which was not released yet.
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_free_string(text: *mut c_char) -> SmartHouseStatus {
+    ffi_call(|| {
+        if !text.is_null() {
+            let _ = unsafe { CString::from_raw(text) };
+        }
+        Ok(())
+    })
+}
+
+/// # Safety
+///
+/// `smarthouselib` must be a live pointer from [`smart_house_new`], names valid nul-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_remove_device(
+    smarthouselib: *mut SmartHouseLib,
+    room_name: *const c_char,
+    device_name: *const c_char,
+) -> SmartHouseStatus {
+    ffi_call(|| {
+        let lib = unsafe { get_lib(smarthouselib) }?;
+        let mut state = lib.lock();
+        let room_name_str = unsafe { borrowed_str(room_name, "room_name") }?;
+        let device_name_str = unsafe { borrowed_str(device_name, "device_name") }?;
+        let device = state
+            .smarthouse
+            .device(room_name_str.clone(), device_name_str.clone())?;
+        state
+            .smarthouse
+            .remove_device(room_name_str.clone(), device_name_str)?;
+        let event = Event::device(SmartHouseEventKind::DeviceRemoved, room_name_str, &device);
+        lib.notify(state, event);
+        Ok(())
+    })
+}
+
+/// Writes an array of device names in the room, which the caller releases with
+/// [`smart_house_free_string_list`].
+///
+/// # Safety
+///
+/// `smarthouselib` must be a live pointer from [`smart_house_new`], `room_name` a valid nul-terminated
+/// string, `out_buffer` and `out_size` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_list_devices(
+    smarthouselib: *mut SmartHouseLib,
+    room_name: *const c_char,
+    out_buffer: *mut *mut *mut c_char,
+    out_size: *mut usize,
+) -> SmartHouseStatus {
+    ffi_call(|| {
+        let lib = unsafe { get_lib(smarthouselib) }?;
+        let state = lib.lock();
+        let room_name_str = unsafe { borrowed_str(room_name, "room_name") }?;
+        let devices: Vec<&str> = state
+            .smarthouse
+            .devices(room_name_str)?
+            .into_iter()
+            .map(String::as_str)
+            .collect();
+
+        unsafe { save2buf(&devices, out_buffer, out_size) }
+    })
+}
+
+/// Writes the house report to `out`, the caller releases it with [`smart_house_free_string`].
+///
+/// # Safety
+///
+/// `smarthouselib` must be a live pointer from [`smart_house_new`], `out` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_report(
+    smarthouselib: *mut SmartHouseLib,
+    out: *mut *mut c_char,
+) -> SmartHouseStatus {
+    ffi_call(|| {
+        let lib = unsafe { get_lib(smarthouselib) }?;
+        let state = lib.lock();
+        if out.is_null() {
+            return Err(FfiError::NullPointer { arg: "out" });
+        }
+        unsafe { out.write(owned_c_string(state.smarthouse.report(None).as_str())) };
+        Ok(())
+    })
+}
+
+/// Writes a JSON snapshot of the house to `out` for [`smart_house_import`], the caller releases
+/// it with [`smart_house_free_string`]. Subscriptions are not part of the snapshot.
+///
+/// # Safety
+///
+/// `smarthouselib` must be a live pointer from [`smart_house_new`], `out` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_export(
+    smarthouselib: *mut SmartHouseLib,
+    out: *mut *mut c_char,
+) -> SmartHouseStatus {
+    ffi_call(|| {
+        let lib = unsafe { get_lib(smarthouselib) }?;
+        if out.is_null() {
+            return Err(FfiError::NullPointer { arg: "out" });
+        }
+        let snapshot = lib.lock().smarthouse.snapshot();
+        let json = serde_json::to_string(&snapshot).expect("house snapshot is serializable");
+        unsafe { out.write(owned_c_string(&json)) };
+        Ok(())
+    })
+}
+
+/// Creates a device from JSON parameters `params` and adds it to the room, see the module docs.
+///
+/// # Safety
+///
+/// `smarthouselib` must be a live pointer from [`smart_house_new`], strings valid nul-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_add_device(
+    smarthouselib: *mut SmartHouseLib,
+    room_name: *const c_char,
+    params: *const c_char,
+) -> SmartHouseStatus {
+    ffi_call(|| {
+        let lib = unsafe { get_lib(smarthouselib) }?;
+        let mut state = lib.lock();
+        let room_name_str = unsafe { borrowed_str(room_name, "room_name") }?;
+        let params_str = unsafe { borrowed_str(params, "params") }?;
+        let params: DeviceParams = serde_json::from_str(&params_str)?;
+        let device = state
+            .smarthouse
+            .create_device(room_name_str.clone(), params)?;
+        let event = Event::device(SmartHouseEventKind::DeviceAdded, room_name_str, &device);
+        lib.notify(state, event);
+        Ok(())
+    })
+}
+
+/// Turns the device on or off.
+///
+/// # Safety
+///
+/// `smarthouselib` must be a live pointer from [`smart_house_new`], names valid nul-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_switch_device(
+    smarthouselib: *mut SmartHouseLib,
+    room_name: *const c_char,
+    device_name: *const c_char,
+    on: bool,
+) -> SmartHouseStatus {
+    ffi_call(|| {
+        let lib = unsafe { get_lib(smarthouselib) }?;
+        let state = lib.lock();
+        let room_name_str = unsafe { borrowed_str(room_name, "room_name") }?;
+        let device_name_str = unsafe { borrowed_str(device_name, "device_name") }?;
+        let device = state
+            .smarthouse
+            .device(room_name_str.clone(), device_name_str)?;
+        device.set_on(on);
+        let event = Event::device(SmartHouseEventKind::DeviceSwitched, room_name_str, &device);
+        lib.notify(state, event);
+        Ok(())
+    })
+}
+
+/// Writes the device state as JSON to `out`, the caller releases it with
+/// [`smart_house_free_string`].
+///
+/// # Safety
+///
+/// `smarthouselib` must be a live pointer from [`smart_house_new`], names valid nul-terminated
+/// strings, `out` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_device_status(
+    smarthouselib: *mut SmartHouseLib,
+    room_name: *const c_char,
+    device_name: *const c_char,
+    out: *mut *mut c_char,
+) -> SmartHouseStatus {
+    ffi_call(|| {
+        let lib = unsafe { get_lib(smarthouselib) }?;
+        let state = lib.lock();
+        let room_name_str = unsafe { borrowed_str(room_name, "room_name") }?;
+        let device_name_str = unsafe { borrowed_str(device_name, "device_name") }?;
+        if out.is_null() {
+            return Err(FfiError::NullPointer { arg: "out" });
+        }
+        let device = state.smarthouse.device(room_name_str, device_name_str)?;
+        drop(state);
+        let json = serde_json::to_string(&device.state()).expect("device state is serializable");
+        unsafe { out.write(owned_c_string(&json)) };
+        Ok(())
+    })
+}
+
+/// Applies a sensor reading to the device as if it came from its gateway.
+///
+/// `kind` is a [`SmartHouseReadingKind`], other values give [`SmartHouseStatus::InvalidArgument`].
+/// So does a `value` that is NaN, infinite or out of the device range, e.g. -128..=127 for a
+/// temperature. Returns [`SmartHouseStatus::Unsupported`] if the device doesn't take readings
+/// of this kind.
+///
+/// # Safety
+///
+/// `smarthouselib` must be a live pointer from [`smart_house_new`], names valid nul-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_feed_reading(
+    smarthouselib: *mut SmartHouseLib,
+    room_name: *const c_char,
+    device_name: *const c_char,
+    kind: u32,
+    value: f64,
+) -> SmartHouseStatus {
+    ffi_call(|| {
+        let lib = unsafe { get_lib(smarthouselib) }?;
+        let state = lib.lock();
+        let room_name_str = unsafe { borrowed_str(room_name, "room_name") }?;
+        let device_name_str = unsafe { borrowed_str(device_name, "device_name") }?;
+        let kind = SmartHouseReadingKind::try_from(kind)
+            .map_err(|kind| FfiError::UnknownReadingKind { kind })?;
+        let kind = match kind {
+            SmartHouseReadingKind::Temperature => Kind::Temperature,
+            SmartHouseReadingKind::Motion => Kind::Motion,
+            SmartHouseReadingKind::Power => Kind::Power,
+        };
+        let device = state
+            .smarthouse
+            .device(room_name_str.clone(), device_name_str)?;
+        device.feed(kind, value)?;
+        let event = Event::device(SmartHouseEventKind::ReadingChanged, room_name_str, &device);
+        lib.notify(state, event);
+        Ok(())
+    })
+}
+
+/// Adds an outlet, the same as [`smart_house_add_device`] with `{"kind": "outlet"}`.
+///
+/// # Safety
+///
+/// `smarthouselib` must be a live pointer from [`smart_house_new`], strings valid nul-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_add_test_device_outlet(
+    smarthouselib: *mut SmartHouseLib,
+    room_name: *const c_char,
+    device_name: *const c_char,
+    device_description: *const c_char,
+) -> SmartHouseStatus {
+    ffi_call(|| {
+        let lib = unsafe { get_lib(smarthouselib) }?;
+        let mut state = lib.lock();
+        let room_name_str = unsafe { borrowed_str(room_name, "room_name") }?;
+        let device_name_str = unsafe { borrowed_str(device_name, "device_name") }?;
+        let device_description_str =
+            unsafe { borrowed_str(device_description, "device_description") }?;
+        let device = RwLockDevice::new(Arc::new(RwLock::new(Device::new(
+            device_name_str,
+            Arc::new(RwLock::new(SmartOutlet::new(device_description_str, None))),
+            None,
+        ))));
+        state
+            .smarthouse
+            .add_device(room_name_str.clone(), device.clone())?;
+        let event = Event::device(SmartHouseEventKind::DeviceAdded, room_name_str, &device);
+        lib.notify(state, event);
+        Ok(())
+    })
+}
+
+/// Registers `callback`, called with `user_data` on every event of the house, and writes the
+/// subscription id to `out_id`. See the module docs for the threading guarantees.
+///
+/// # Safety
+///
+/// `smarthouselib` must be a live pointer from [`smart_house_new`], `out_id` valid for writes.
+/// `callback` must be safe to call with `user_data` until it is unsubscribed or the house is
+/// destroyed, from any thread calling the library with this house.
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_subscribe(
+    smarthouselib: *mut SmartHouseLib,
+    callback: SmartHouseCallback,
+    user_data: *mut c_void,
+    out_id: *mut u64,
+) -> SmartHouseStatus {
+    ffi_call(|| {
+        let lib = unsafe { get_lib(smarthouselib) }?;
+        if callback.is_none() {
+            return Err(FfiError::NullPointer { arg: "callback" });
+        }
+        if out_id.is_null() {
+            return Err(FfiError::NullPointer { arg: "out_id" });
+        }
+        let mut state = lib.lock();
+        let id = state.next_subscription;
+        state.next_subscription += 1;
+        state.subscribers.push(Arc::new(Subscriber {
+            id,
+            callback,
+            user_data,
+            calls: Mutex::new(Calls::default()),
+            finished: Condvar::new(),
+        }));
+        unsafe { out_id.write(id) };
+        Ok(())
+    })
+}
+
+/// Removes the subscription `id`, waiting for its callbacks running on other threads. After the
+/// call returns the callback is not called anymore.
+///
+/// Called from inside any callback it doesn't wait, so callbacks on different threads may
+/// unsubscribe each other. Calls of `id` already running then finish after it returns.
+///
+/// # Safety
+///
+/// `smarthouselib` must be a live pointer from [`smart_house_new`].
+#[no_mangle]
+pub unsafe extern "C" fn smart_house_unsubscribe(
+    smarthouselib: *mut SmartHouseLib,
+    id: u64,
+) -> SmartHouseStatus {
+    ffi_call(|| {
+        let lib = unsafe { get_lib(smarthouselib) }?;
+        let mut state = lib.lock();
+        let index = state
+            .subscribers
+            .iter()
+            .position(|subscriber| subscriber.id == id)
+            .ok_or(FfiError::UnknownSubscription { id })?;
+        let subscriber = state.subscribers.remove(index);
+        // другие потоки могли взять подписчика до отписки
+        drop(state);
+        subscriber.unsubscribe();
+        Ok(())
+    })
+}
+
+// Тесты C ABI не используют tokio и сеть, поэтому запускаются под санитайзерами и Miri,
+// см. Readme.md.
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+    use std::sync::Barrier;
+
+    // Строка вызывающего, которую библиотека только заимствует.
+    fn c(text: &str) -> CString {
+        CString::new(text).unwrap()
+    }
+
+    fn last_error() -> Option<String> {
+        let message = smart_house_last_error();
+        (!message.is_null()).then(|| {
+            unsafe { CStr::from_ptr(message) }
+                .to_str()
+                .unwrap()
+                .to_owned()
+        })
+    }
+
+    fn new_house(name: &str) -> *mut SmartHouseLib {
+        let name = c(name);
+        let mut lib = std::ptr::null_mut();
+        assert_eq!(
+            unsafe { smart_house_new(name.as_ptr(), &mut lib) },
+            SmartHouseStatus::Ok
+        );
+        lib
+    }
+
+    fn readbuf(buffer: *mut *mut c_char, size: usize) -> Vec<String> {
+        let string_ptrs = unsafe { std::slice::from_raw_parts(buffer, size) };
+        string_ptrs
+            .iter()
+            .map(|&s| unsafe { CStr::from_ptr(s).to_str().unwrap().to_owned() })
+            .collect()
+    }
+
+    fn get_list_rooms_name_vec(lib: *mut SmartHouseLib) -> Vec<String> {
+        let mut buffer: *mut *mut c_char = std::ptr::null_mut();
+        let mut size: usize = 0;
+
+        let status = unsafe { smart_house_list_rooms(lib, &mut buffer, &mut size) };
+        assert_eq!(status, SmartHouseStatus::Ok);
+
+        let vec_str = readbuf(buffer, size);
+
+        assert_eq!(
+            unsafe { smart_house_free_string_list(buffer, size) },
+            SmartHouseStatus::Ok
+        );
+
+        vec_str
+    }
+
+    fn get_list_devices_name_vec(
+        lib: *mut SmartHouseLib,
+        room_name: &str,
+    ) -> Result<Vec<String>, SmartHouseStatus> {
+        let mut buffer: *mut *mut c_char = std::ptr::null_mut();
+        let mut size: usize = 0;
+
+        let room_name = c(room_name);
+        match unsafe { smart_house_list_devices(lib, room_name.as_ptr(), &mut buffer, &mut size) } {
+            SmartHouseStatus::Ok => {}
+            status => return Err(status),
+        }
+
+        let vec_str = readbuf(buffer, size);
+
+        assert_eq!(
+            unsafe { smart_house_free_string_list(buffer, size) },
+            SmartHouseStatus::Ok
+        );
+
+        Ok(vec_str)
+    }
+
+    fn report_string(lib: *mut SmartHouseLib) -> String {
+        let mut text = std::ptr::null_mut();
+        assert_eq!(
+            unsafe { smart_house_report(lib, &mut text) },
+            SmartHouseStatus::Ok
+        );
+        let result = unsafe { CStr::from_ptr(text) }.to_str().unwrap().to_owned();
+        assert_eq!(
+            unsafe { smart_house_free_string(text) },
+            SmartHouseStatus::Ok
+        );
+        result
+    }
+
+    fn add_outlet(lib: *mut SmartHouseLib, room: &str, name: &str, description: &str) {
+        let (room, name, description) = (c(room), c(name), c(description));
+        let status = unsafe {
+            smart_house_add_test_device_outlet(
+                lib,
+                room.as_ptr(),
+                name.as_ptr(),
+                description.as_ptr(),
+            )
+        };
+        assert_eq!(status, SmartHouseStatus::Ok);
+    }
+
+    #[test]
+    fn test_add_rooms() {
+        let name = c("тестовая");
+        let my_struct = new_house("тестовая");
+
+        let room_1 = c("комната 1");
+        let room_2 = c("комната 2");
+        assert_eq!(
+            unsafe { smart_house_add_room(my_struct, room_1.as_ptr()) },
+            SmartHouseStatus::Ok
+        );
+
+        assert_eq!(
+            unsafe { smart_house_add_room(my_struct, room_2.as_ptr()) },
+            SmartHouseStatus::Ok
+        );
+        assert_eq!(
+            unsafe { smart_house_add_room(my_struct, room_2.as_ptr()) },
+            SmartHouseStatus::AlreadyExists
+        );
+
+        let mut rooms = get_list_rooms_name_vec(my_struct);
+        rooms.sort();
+        assert_eq!(
+            rooms,
+            vec!["комната 1".to_string(), "комната 2".to_string()]
+        );
+
+        assert_eq!(
+            unsafe { smart_house_remove_room(my_struct, room_1.as_ptr()) },
+            SmartHouseStatus::Ok
+        );
+
+        assert_eq!(
+            get_list_rooms_name_vec(my_struct),
+            vec!["комната 2".to_string()]
+        );
+
+        // строки вызывающего остались целы после всех вызовов
+        assert_eq!(room_1.to_str().unwrap(), "комната 1");
+        assert_eq!(name.to_str().unwrap(), "тестовая");
+
+        assert_eq!(
+            unsafe { smart_house_destroy(my_struct) },
+            SmartHouseStatus::Ok
+        );
+    }
+
+    #[test]
+    fn test_add_devices() {
+        let my_struct = new_house("тестовая");
+        let room_name = c("комната 1");
+
+        assert_eq!(
+            unsafe { smart_house_add_room(my_struct, room_name.as_ptr()) },
+            SmartHouseStatus::Ok
+        );
+
+        assert_eq!(
+            get_list_devices_name_vec(my_struct, "комната 1"),
+            Ok(vec![])
+        );
+
+        add_outlet(
+            my_struct,
+            "комната 1",
+            "устройство 1",
+            "тестовое устройство 1",
+        );
+        add_outlet(
+            my_struct,
+            "комната 1",
+            "устройство 2",
+            "тестовое устройство 2",
+        );
+
+        let mut test = get_list_devices_name_vec(my_struct, "комната 1").unwrap();
+        test.sort();
+
+        assert_eq!(
+            test,
+            vec!["устройство 1".to_string(), "устройство 2".to_string()]
+        );
+
+        let device = c("устройство 1");
+        assert_eq!(
+            unsafe { smart_house_remove_device(my_struct, room_name.as_ptr(), device.as_ptr()) },
+            SmartHouseStatus::Ok
+        );
+        assert_eq!(
+            get_list_devices_name_vec(my_struct, "комната 1"),
+            Ok(vec!["устройство 2".to_string()])
+        );
+
+        assert_eq!(
+            unsafe { smart_house_remove_room(my_struct, room_name.as_ptr()) },
+            SmartHouseStatus::Ok
+        );
+
+        assert_eq!(
+            unsafe { smart_house_destroy(my_struct) },
+            SmartHouseStatus::Ok
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let my_struct = new_house("тестовая");
+        let room_name = c("комната 1");
+
+        assert_eq!(
+            unsafe { smart_house_add_room(my_struct, room_name.as_ptr()) },
+            SmartHouseStatus::Ok
+        );
+
+        add_outlet(
+            my_struct,
+            "комната 1",
+            "устройство 1",
+            "тестовое устройство 1",
+        );
+
+        let test = report_string(my_struct);
+
+        assert_eq!(test, "Name: тестовая,\nRooms:\n[\n{\nName: комната 1,\nDevices:\n[\n{\nName: устройство 1,\nOn: false,\nDescription: тестовое устройство 1,\nPower: 0\n},\n]\n},\n]".to_string());
+
+        // каждый вызов возвращает новую строку
+        assert_eq!(report_string(my_struct), test);
+
+        assert_eq!(
+            unsafe { smart_house_free_string(std::ptr::null_mut()) },
+            SmartHouseStatus::Ok
+        );
+        assert_eq!(
+            unsafe { smart_house_destroy(my_struct) },
+            SmartHouseStatus::Ok
+        );
+    }
+
+    fn export_string(lib: *mut SmartHouseLib) -> String {
+        let mut json = std::ptr::null_mut();
+        assert_eq!(
+            unsafe { smart_house_export(lib, &mut json) },
+            SmartHouseStatus::Ok
+        );
+        let result = unsafe { CStr::from_ptr(json) }.to_str().unwrap().to_owned();
+        assert_eq!(
+            unsafe { smart_house_free_string(json) },
+            SmartHouseStatus::Ok
+        );
+        result
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let my_struct = new_house("тестовая");
+        let room = c("кухня");
+        assert_eq!(
+            unsafe { smart_house_add_room(my_struct, room.as_ptr()) },
+            SmartHouseStatus::Ok
+        );
+        let params = c(r#"{"kind": "thermometer", "name": "термометр", "on": true}"#);
+        assert_eq!(
+            unsafe { smart_house_add_device(my_struct, room.as_ptr(), params.as_ptr()) },
+            SmartHouseStatus::Ok
+        );
+        let thermometer = c("термометр");
+        assert_eq!(
+            unsafe {
+                smart_house_feed_reading(
+                    my_struct,
+                    room.as_ptr(),
+                    thermometer.as_ptr(),
+                    SmartHouseReadingKind::Temperature as u32,
+                    19.0,
+                )
+            },
+            SmartHouseStatus::Ok
+        );
+
+        let snapshot = c(&export_string(my_struct));
+        let mut imported = std::ptr::null_mut();
+        assert_eq!(
+            unsafe { smart_house_import(snapshot.as_ptr(), &mut imported) },
+            SmartHouseStatus::Ok
+        );
+        assert_eq!(report_string(imported), report_string(my_struct));
+        assert_eq!(
+            device_status(imported, "кухня", "термометр"),
+            device_status(my_struct, "кухня", "термометр")
+        );
+        assert_eq!(
+            export_string(imported),
+            snapshot.to_str().unwrap().to_string()
+        );
+
+        let mut other = std::ptr::null_mut();
+        let broken = c("{\"version\": 1");
+        assert_eq!(
+            unsafe { smart_house_import(broken.as_ptr(), &mut other) },
+            SmartHouseStatus::InvalidArgument
+        );
+        let newer = c(r#"{"version": 1000, "name": "тестовая", "rooms": []}"#);
+        assert_eq!(
+            unsafe { smart_house_import(newer.as_ptr(), &mut other) },
+            SmartHouseStatus::Unsupported
+        );
+        assert!(other.is_null());
+
+        for lib in [my_struct, imported] {
+            assert_eq!(unsafe { smart_house_destroy(lib) }, SmartHouseStatus::Ok);
+        }
+    }
+
+    fn device_status(lib: *mut SmartHouseLib, room: &str, device: &str) -> serde_json::Value {
+        let (room, device) = (c(room), c(device));
+        let mut json: *mut c_char = std::ptr::null_mut();
+        assert_eq!(
+            unsafe { smart_house_device_status(lib, room.as_ptr(), device.as_ptr(), &mut json) },
+            SmartHouseStatus::Ok
+        );
+        let state = serde_json::from_str(unsafe { CStr::from_ptr(json) }.to_str().unwrap());
+        assert_eq!(
+            unsafe { smart_house_free_string(json) },
+            SmartHouseStatus::Ok
+        );
+        state.unwrap()
+    }
+
+    #[test]
+    fn test_devices() {
+        let my_struct = new_house("тестовая");
+        let room = c("кухня");
+        let thermometer = c("термометр");
+        let outlet = c("розетка");
+        assert_eq!(
+            unsafe { smart_house_add_room(my_struct, room.as_ptr()) },
+            SmartHouseStatus::Ok
+        );
+
+        let params = c(r#"{"kind": "thermometer", "name": "термометр", "description": "у окна"}"#);
+        assert_eq!(
+            unsafe { smart_house_add_device(my_struct, room.as_ptr(), params.as_ptr()) },
+            SmartHouseStatus::Ok
+        );
+        let params = c(r#"{"kind": "outlet", "name": "розетка", "on": true}"#);
+        assert_eq!(
+            unsafe { smart_house_add_device(my_struct, room.as_ptr(), params.as_ptr()) },
+            SmartHouseStatus::Ok
+        );
+
+        let state = device_status(my_struct, "кухня", "термометр");
+        assert_eq!(state["kind"], "thermometer");
+        assert_eq!(state["description"], "у окна");
+        assert_eq!(state["on"], false);
+        assert_eq!(state["value"], 0);
+
+        assert_eq!(
+            unsafe {
+                smart_house_switch_device(my_struct, room.as_ptr(), thermometer.as_ptr(), true)
+            },
+            SmartHouseStatus::Ok
+        );
+        assert_eq!(
+            unsafe {
+                smart_house_feed_reading(
+                    my_struct,
+                    room.as_ptr(),
+                    thermometer.as_ptr(),
+                    SmartHouseReadingKind::Temperature as u32,
+                    22.6,
+                )
+            },
+            SmartHouseStatus::Ok
+        );
+        let state = device_status(my_struct, "кухня", "термометр");
+        assert_eq!(state["on"], true);
+        assert_eq!(state["value"], 23);
+
+        assert_eq!(
+            unsafe {
+                smart_house_feed_reading(
+                    my_struct,
+                    room.as_ptr(),
+                    outlet.as_ptr(),
+                    SmartHouseReadingKind::Power as u32,
+                    60.0,
+                )
+            },
+            SmartHouseStatus::Ok
+        );
+        let state = device_status(my_struct, "кухня", "розетка");
+        assert_eq!(state["on"], true);
+        assert_eq!(state["value"], 60);
+
+        // термометр не принимает показания движения
+        assert_eq!(
+            unsafe {
+                smart_house_feed_reading(
+                    my_struct,
+                    room.as_ptr(),
+                    thermometer.as_ptr(),
+                    SmartHouseReadingKind::Motion as u32,
+                    1.0,
+                )
+            },
+            SmartHouseStatus::Unsupported
+        );
+        // вида показания 42 нет в `SmartHouseReadingKind`
+        assert_eq!(
+            unsafe {
+                smart_house_feed_reading(my_struct, room.as_ptr(), thermometer.as_ptr(), 42, 1.0)
+            },
+            SmartHouseStatus::InvalidArgument
+        );
+        assert_eq!(last_error(), Some("Unknown reading kind 42".to_string()));
+
+        // не число и значения вне диапазона устройства не насыщаются, а отклоняются
+        for (device, kind, value) in [
+            (&thermometer, SmartHouseReadingKind::Temperature, 1e30),
+            (&thermometer, SmartHouseReadingKind::Temperature, f64::NAN),
+            (&outlet, SmartHouseReadingKind::Power, -1.0),
+            (&outlet, SmartHouseReadingKind::Power, f64::INFINITY),
+            (&outlet, SmartHouseReadingKind::Motion, f64::NAN),
+        ] {
+            assert_eq!(
+                unsafe {
+                    smart_house_feed_reading(
+                        my_struct,
+                        room.as_ptr(),
+                        device.as_ptr(),
+                        kind as u32,
+                        value,
+                    )
+                },
+                SmartHouseStatus::InvalidArgument
+            );
+        }
+        assert_eq!(
+            last_error(),
+            Some("Motion reading NaN is out of range".to_string())
+        );
+        assert_eq!(device_status(my_struct, "кухня", "термометр")["value"], 23);
+        assert_eq!(device_status(my_struct, "кухня", "розетка")["value"], 60);
+
+        let missing = c("нет такого");
+        assert_eq!(
+            unsafe { smart_house_switch_device(my_struct, room.as_ptr(), missing.as_ptr(), true) },
+            SmartHouseStatus::NotFound
+        );
+        let mut json: *mut c_char = std::ptr::null_mut();
+        assert_eq!(
+            unsafe {
+                smart_house_device_status(my_struct, missing.as_ptr(), outlet.as_ptr(), &mut json)
+            },
+            SmartHouseStatus::NotFound
+        );
+        assert!(json.is_null());
+
+        for (params, status) in [
+            (
+                r#"{"kind": "lamp", "name": "лампа"}"#,
+                SmartHouseStatus::Unsupported,
+            ),
+            (r#"{"kind": "outlet"}"#, SmartHouseStatus::InvalidArgument),
+            (
+                r#"{"kind": "outlet", "name": "x", "color": 1}"#,
+                SmartHouseStatus::InvalidArgument,
+            ),
+            ("not json", SmartHouseStatus::InvalidArgument),
+        ] {
+            let params = c(params);
+            assert_eq!(
+                unsafe { smart_house_add_device(my_struct, room.as_ptr(), params.as_ptr()) },
+                status
+            );
+            assert!(last_error().is_some());
+        }
+
+        assert_eq!(
+            unsafe { smart_house_destroy(my_struct) },
+            SmartHouseStatus::Ok
+        );
+    }
+
+    // Записывает события в `Vec<String>`, переданный через `user_data`.
+    unsafe extern "C" fn record(event: *const SmartHouseEvent, user_data: *mut c_void) {
+        let event = unsafe { &*event };
+        let events = unsafe { &mut *(user_data as *mut Vec<String>) };
+        let text = |text: *const c_char| {
+            (!text.is_null()).then(|| unsafe { CStr::from_ptr(text) }.to_str().unwrap())
+        };
+        events.push(format!(
+            "{:?} {} {:?} {} {}",
+            event.kind,
+            text(event.room).unwrap(),
+            text(event.device),
+            event.on,
+            event.value
+        ));
+    }
+
+    // Отписывается сам при первом событии, id подписки лежит в `user_data`.
+    unsafe extern "C" fn once(event: *const SmartHouseEvent, user_data: *mut c_void) {
+        let (lib, id, calls) = unsafe { &mut *(user_data as *mut (*mut SmartHouseLib, u64, u32)) };
+        *calls += 1;
+        // вызов библиотеки из обратного вызова
+        let room = unsafe { (*event).room };
+        let mut buffer: *mut *mut c_char = std::ptr::null_mut();
+        let mut size: usize = 0;
+        assert_eq!(
+            unsafe { smart_house_list_devices(*lib, room, &mut buffer, &mut size) },
+            SmartHouseStatus::Ok
+        );
+        unsafe { smart_house_free_string_list(buffer, size) };
+        assert_eq!(
+            unsafe { smart_house_unsubscribe(*lib, *id) },
+            SmartHouseStatus::Ok
+        );
+    }
+
+    #[test]
+    fn test_events() {
+        let my_struct = new_house("тестовая");
+        let room = c("кухня");
+        let outlet = c("розетка");
+
+        let mut events: Vec<String> = Vec::new();
+        let mut id = 0;
+        assert_eq!(
+            unsafe {
+                smart_house_subscribe(
+                    my_struct,
+                    Some(record),
+                    &mut events as *mut Vec<String> as *mut c_void,
+                    &mut id,
+                )
+            },
+            SmartHouseStatus::Ok
+        );
+        let mut once_data = (my_struct, 0_u64, 0_u32);
+        // один указатель и для `user_data`, и для id, чтобы не нарушать заимствования под Miri
+        let once_ptr: *mut (*mut SmartHouseLib, u64, u32) = &mut once_data;
+        assert_eq!(
+            unsafe {
+                smart_house_subscribe(
+                    my_struct,
+                    Some(once),
+                    once_ptr as *mut c_void,
+                    std::ptr::addr_of_mut!((*once_ptr).1),
+                )
+            },
+            SmartHouseStatus::Ok
+        );
+
+        let params = c(r#"{"kind": "outlet", "name": "розетка"}"#);
+        unsafe {
+            smart_house_add_room(my_struct, room.as_ptr());
+            smart_house_add_device(my_struct, room.as_ptr(), params.as_ptr());
+            smart_house_switch_device(my_struct, room.as_ptr(), outlet.as_ptr(), true);
+            smart_house_feed_reading(
+                my_struct,
+                room.as_ptr(),
+                outlet.as_ptr(),
+                SmartHouseReadingKind::Power as u32,
+                40.0,
+            );
+            // неудачный вызов событий не даёт, и комната с розеткой не заменяется
+            smart_house_switch_device(my_struct, outlet.as_ptr(), outlet.as_ptr(), false);
+            smart_house_add_room(my_struct, room.as_ptr());
+            smart_house_remove_device(my_struct, room.as_ptr(), outlet.as_ptr());
+        }
+        assert_ne!(id, once_data.1);
+        assert_eq!(once_data.2, 1);
+
+        assert_eq!(
+            unsafe { smart_house_unsubscribe(my_struct, id) },
+            SmartHouseStatus::Ok
+        );
+        assert_eq!(
+            unsafe { smart_house_unsubscribe(my_struct, id) },
+            SmartHouseStatus::NotFound
+        );
+        unsafe { smart_house_remove_room(my_struct, room.as_ptr()) };
+
+        assert_eq!(
+            events,
+            [
+                "RoomAdded кухня None false 0",
+                "DeviceAdded кухня Some(\"розетка\") false 0",
+                "DeviceSwitched кухня Some(\"розетка\") true 0",
+                "ReadingChanged кухня Some(\"розетка\") true 40",
+                "DeviceRemoved кухня Some(\"розетка\") true 40",
+            ]
+        );
+
+        assert_eq!(
+            unsafe { smart_house_subscribe(my_struct, None, std::ptr::null_mut(), &mut id) },
+            SmartHouseStatus::NullPointer
+        );
+        assert_eq!(
+            unsafe { smart_house_destroy(my_struct) },
+            SmartHouseStatus::Ok
+        );
+    }
+
+    // Дом, который тест передаёт в другие потоки.
+    #[derive(Clone, Copy)]
+    struct Shared(*mut SmartHouseLib);
+
+    unsafe impl Send for Shared {}
+    unsafe impl Sync for Shared {}
+
+    unsafe extern "C" fn count(_event: *const SmartHouseEvent, user_data: *mut c_void) {
+        let counter = unsafe { &*(user_data as *const AtomicUsize) };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn test_threads() {
+        const THREADS: usize = 8;
+        const ROUNDS: usize = 50;
+
+        let house = Shared(new_house("тестовая"));
+        let events = AtomicUsize::new(0);
+        let mut id = 0;
+        assert_eq!(
+            unsafe {
+                smart_house_subscribe(
+                    house.0,
+                    Some(count),
+                    &events as *const AtomicUsize as *mut c_void,
+                    &mut id,
+                )
+            },
+            SmartHouseStatus::Ok
+        );
+
+        std::thread::scope(|scope| {
+            for thread in 0..THREADS {
+                scope.spawn(move || {
+                    let house = house;
+                    let room = c(&format!("комната {}", thread));
+                    let device = c("розетка");
+                    let params = c(r#"{"kind": "outlet", "name": "розетка"}"#);
+                    // свои подписки на чужие события
+                    let local = AtomicUsize::new(0);
+                    for round in 0..ROUNDS {
+                        let mut local_id = 0;
+                        unsafe {
+                            assert_eq!(
+                                smart_house_subscribe(
+                                    house.0,
+                                    Some(count),
+                                    &local as *const AtomicUsize as *mut c_void,
+                                    &mut local_id,
+                                ),
+                                SmartHouseStatus::Ok
+                            );
+                            assert_eq!(
+                                smart_house_add_room(house.0, room.as_ptr()),
+                                SmartHouseStatus::Ok
+                            );
+                            assert_eq!(
+                                smart_house_add_device(house.0, room.as_ptr(), params.as_ptr()),
+                                SmartHouseStatus::Ok
+                            );
+                            assert_eq!(
+                                smart_house_switch_device(
+                                    house.0,
+                                    room.as_ptr(),
+                                    device.as_ptr(),
+                                    round % 2 == 0
+                                ),
+                                SmartHouseStatus::Ok
+                            );
+                            assert_eq!(
+                                smart_house_feed_reading(
+                                    house.0,
+                                    room.as_ptr(),
+                                    device.as_ptr(),
+                                    SmartHouseReadingKind::Power as u32,
+                                    round as f64,
+                                ),
+                                SmartHouseStatus::Ok
+                            );
+                            let mut report: *mut c_char = std::ptr::null_mut();
+                            assert_eq!(
+                                smart_house_report(house.0, &mut report),
+                                SmartHouseStatus::Ok
+                            );
+                            smart_house_free_string(report);
+                            get_list_rooms_name_vec(house.0);
+                            assert_eq!(
+                                smart_house_remove_room(house.0, room.as_ptr()),
+                                SmartHouseStatus::Ok
+                            );
+                            // после отписки `local` больше не трогают
+                            assert_eq!(
+                                smart_house_unsubscribe(house.0, local_id),
+                                SmartHouseStatus::Ok
+                            );
+                        }
+                    }
+                    assert!(local.load(Ordering::Relaxed) >= ROUNDS * 5);
+                });
+            }
+        });
+
+        // по пять событий на круг: комната, устройство, переключение, показание, удаление комнаты
+        assert_eq!(events.load(Ordering::Relaxed), THREADS * ROUNDS * 5);
+        assert_eq!(get_list_rooms_name_vec(house.0), Vec::<String>::new());
+        assert_eq!(
+            unsafe { smart_house_destroy(house.0) },
+            SmartHouseStatus::Ok
+        );
+    }
+
+    // Подписка, которая при первом вызове дожидается второй и отписывает её.
+    struct Mutual {
+        house: Shared,
+        armed: AtomicBool,
+        other: AtomicU64,
+        barrier: Arc<Barrier>,
+    }
+
+    unsafe extern "C" fn unsubscribe_other(_event: *const SmartHouseEvent, user_data: *mut c_void) {
+        let mutual = unsafe { &*(user_data as *const Mutual) };
+        if mutual.armed.swap(false, Ordering::SeqCst) {
+            // обе подписки сейчас выполняются, каждая в своём потоке
+            mutual.barrier.wait();
+            let other = mutual.other.load(Ordering::SeqCst);
+            assert_eq!(
+                unsafe { smart_house_unsubscribe(mutual.house.0, other) },
+                SmartHouseStatus::Ok
+            );
+        }
+    }
+
+    #[test]
+    fn test_unsubscribe_each_other() {
+        let house = Shared(new_house("тестовая"));
+        let barrier = Arc::new(Barrier::new(2));
+        let mutual = [(); 2].map(|_| Mutual {
+            house,
+            armed: AtomicBool::new(true),
+            other: AtomicU64::new(0),
+            barrier: barrier.clone(),
+        });
+        let mut ids = [0; 2];
+        for (subscriber, id) in mutual.iter().zip(ids.iter_mut()) {
+            assert_eq!(
+                unsafe {
+                    smart_house_subscribe(
+                        house.0,
+                        Some(unsubscribe_other),
+                        subscriber as *const Mutual as *mut c_void,
+                        id,
+                    )
+                },
+                SmartHouseStatus::Ok
+            );
+        }
+        mutual[0].other.store(ids[1], Ordering::SeqCst);
+        mutual[1].other.store(ids[0], Ordering::SeqCst);
+
+        std::thread::scope(|scope| {
+            for thread in 0..2 {
+                scope.spawn(move || {
+                    let house = house;
+                    let room = c(&format!("комната {}", thread));
+                    assert_eq!(
+                        unsafe { smart_house_add_room(house.0, room.as_ptr()) },
+                        SmartHouseStatus::Ok
+                    );
+                });
+            }
+        });
+
+        // обе подписки сняты
+        for id in ids {
+            assert_eq!(
+                unsafe { smart_house_unsubscribe(house.0, id) },
+                SmartHouseStatus::NotFound
+            );
+        }
+        assert_eq!(
+            unsafe { smart_house_destroy(house.0) },
+            SmartHouseStatus::Ok
+        );
+    }
+
+    #[test]
+    fn test_handle_is_thread_safe() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SmartHouseLib>();
+    }
+
+    #[test]
+    fn test_errors() {
+        let my_struct = new_house("тестовая");
+        let missing = c("нет такой");
+
+        // раньше здесь была паника через границу FFI
+        let mut buffer: *mut *mut c_char = std::ptr::null_mut();
+        let mut size: usize = 0;
+        let status = unsafe {
+            smart_house_list_devices(my_struct, missing.as_ptr(), &mut buffer, &mut size)
+        };
+        assert_eq!(status, SmartHouseStatus::NotFound);
+        assert!(buffer.is_null());
+        assert_eq!(
+            last_error().unwrap(),
+            "Cannot get the room named \"нет такой\""
+        );
+
+        // успешный вызов сбрасывает ошибку
+        assert_eq!(get_list_rooms_name_vec(my_struct), Vec::<String>::new());
+        assert_eq!(last_error(), None);
+
+        assert_eq!(
+            unsafe { smart_house_remove_room(my_struct, missing.as_ptr()) },
+            SmartHouseStatus::NotFound
+        );
+        assert_eq!(
+            unsafe { smart_house_remove_device(my_struct, missing.as_ptr(), missing.as_ptr()) },
+            SmartHouseStatus::NotFound
+        );
+
+        assert_eq!(
+            unsafe { smart_house_add_room(my_struct, std::ptr::null()) },
+            SmartHouseStatus::NullPointer
+        );
+        assert_eq!(last_error().unwrap(), "Argument name is null");
+        assert_eq!(
+            unsafe { smart_house_add_room(std::ptr::null_mut(), missing.as_ptr()) },
+            SmartHouseStatus::NullPointer
+        );
+        assert_eq!(
+            unsafe { smart_house_report(my_struct, std::ptr::null_mut()) },
+            SmartHouseStatus::NullPointer
+        );
+        assert_eq!(
+            unsafe { smart_house_list_rooms(my_struct, &mut buffer, std::ptr::null_mut()) },
+            SmartHouseStatus::NullPointer
+        );
+
+        let invalid = CString::new(vec![0xff, 0xfe]).unwrap();
+        assert_eq!(
+            unsafe { smart_house_add_room(my_struct, invalid.as_ptr()) },
+            SmartHouseStatus::InvalidString
+        );
+
+        // ошибка видна только в потоке, где она произошла
+        let other = std::thread::spawn(last_error).join().unwrap();
+        assert_eq!(other, None);
+        assert!(last_error().is_some());
+
+        assert_eq!(
+            unsafe { smart_house_destroy(my_struct) },
+            SmartHouseStatus::Ok
+        );
+    }
+
+    #[test]
+    fn test_abi_version() {
+        assert_eq!(smart_house_abi_version(), SMART_HOUSE_ABI_VERSION);
+    }
+
+    // Заголовок для C совпадает с тем, что cbindgen генерирует из этого модуля.
+    #[test]
+    fn header_is_up_to_date() {
+        let crate_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        let path = crate_dir.join("include/smart_house.h");
+
+        let mut generated = Vec::new();
+        cbindgen::generate(crate_dir)
+            .expect("cbindgen failed")
+            .write(&mut generated);
+        let generated = String::from_utf8(generated).unwrap();
+
+        if std::env::var_os("UPDATE_HEADER").is_some() {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, &generated).unwrap();
+        }
+        let header = std::fs::read_to_string(&path).unwrap_or_default();
+        assert!(
+            header == generated,
+            "{:?} is outdated, regenerate it with UPDATE_HEADER=1 cargo test -p smart_house \
+             header_is_up_to_date",
+            path
+        );
+    }
+
+    #[test]
+    fn test_panic_is_caught() {
+        let status = ffi_call(|| panic!("boom"));
+        assert_eq!(status, SmartHouseStatus::Panic);
+        assert_eq!(last_error().unwrap(), "Smart house library panicked: boom");
+    }
+}
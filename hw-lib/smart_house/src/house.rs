@@ -3,6 +3,7 @@ pub(crate) mod room;
 // use std::sync::Arc;
 // use std::sync::Mutex;
 // use std::sync::RwLock;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::option::Option;
 
@@ -12,7 +13,14 @@ use self::uuid::Uuid;
 use self::room::RoomError;
 use self::room::SmartRoom;
 
-use crate::device::RwLockDevice;
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use device_quic::discovery::{Announcement, Discovery};
+
+use crate::device::{new_config, Device, DeviceParams, DeviceState, RwLockDevice};
+use crate::link::QuicLink;
 
 // Умный дом
 
@@ -21,6 +29,27 @@ pub struct SmartHouse {
     id: Uuid,
     name: String,
     rooms: HashMap<String, SmartRoom>,
+    // устройства, объявившие себя в локальной сети
+    discovery: Option<Discovery>,
+}
+
+/// Версия формата [`HouseSnapshot`]. Формат только расширяется: новые поля получают
+/// значения по умолчанию, поэтому снимок старой версии читается новой библиотекой.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Снимок дома для переноса в другую версию библиотеки: комнаты и состояние устройств
+/// без каналов телеметрии.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HouseSnapshot {
+    pub version: u32,
+    pub name: String,
+    pub rooms: Vec<RoomSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomSnapshot {
+    pub name: String,
+    pub devices: Vec<DeviceState>,
 }
 
 use thiserror::Error;
@@ -32,6 +61,16 @@ pub enum SmartHouseError {
     RemoveRoomError { name: String },
     #[error("Cannot get the room named {name:?}")]
     GetRoomError { name: String },
+    #[error("Cannot get the device named {name:?} in the room {room:?}")]
+    GetDeviceError { room: String, name: String },
+    #[error("Device {id} was not discovered")]
+    NotDiscoveredError { id: Uuid },
+    #[error("Cannot adopt a device of kind {kind:?}")]
+    UnsupportedDeviceError { kind: String },
+    #[error("Snapshot version {version} is newer than {}", SNAPSHOT_VERSION)]
+    SnapshotVersionError { version: u32 },
+    #[error("Reading {reading} is out of range of a {kind:?} device")]
+    ReadingRangeError { kind: String, reading: f32 },
     #[error(transparent)]
     RoomError(#[from] RoomError),
 }
@@ -44,8 +83,15 @@ impl SmartHouse {
             id: Uuid::new_v4(),
             name,
             rooms: HashMap::new(),
+            discovery: None,
         }
     }
+
+    /// Дом со списком устройств, найденных в локальной сети, см. [`SmartHouse::discovered`].
+    pub fn with_discovery(mut self, discovery: Discovery) -> Self {
+        self.discovery = Some(discovery);
+        self
+    }
     pub fn id(&self) -> &Uuid {
         &self.id
     }
@@ -56,10 +102,15 @@ impl SmartHouse {
         &self.rooms
     }
 
+    /// Добавляет пустую комнату, занятое имя - ошибка: комната с устройствами не заменяется.
     pub fn add_room(&mut self, name: String) -> Result<String, SmartHouseError> {
-        self.rooms
-            .insert(name.clone(), SmartRoom::new(name.clone()));
-        Ok(name)
+        match self.rooms.entry(name.clone()) {
+            Entry::Occupied(_) => Err(SmartHouseError::AddRoomError { name }),
+            Entry::Vacant(entry) => {
+                entry.insert(SmartRoom::new(name.clone()));
+                Ok(name)
+            }
+        }
     }
 
     pub fn remove_room(&mut self, name: String) -> Result<String, SmartHouseError> {
@@ -81,6 +132,41 @@ impl SmartHouse {
         Ok(smartroom.add_device(device, None)?)
     }
 
+    /// Создаёт устройство по параметрам и добавляет его в комнату `room`.
+    pub fn create_device(
+        &mut self,
+        room: String,
+        params: DeviceParams,
+    ) -> Result<RwLockDevice, SmartHouseError> {
+        if !self.rooms.contains_key(&room) {
+            return Err(SmartHouseError::GetRoomError { name: room });
+        }
+        let config = new_config(&params.kind, params.description).ok_or(
+            SmartHouseError::UnsupportedDeviceError {
+                kind: params.kind.clone(),
+            },
+        )?;
+        let device = RwLockDevice::new(Arc::new(RwLock::new(Device::new(
+            params.name,
+            config,
+            Some(params.on),
+        ))));
+        self.add_device(room, device.clone())?;
+        Ok(device)
+    }
+
+    pub fn device(&self, room: String, name: String) -> Result<RwLockDevice, SmartHouseError> {
+        let smartroom = self
+            .rooms
+            .get(&room)
+            .ok_or(SmartHouseError::GetRoomError { name: room.clone() })?;
+        smartroom
+            .devices()
+            .get(&name)
+            .cloned()
+            .ok_or(SmartHouseError::GetDeviceError { room, name })
+    }
+
     pub fn remove_device(
         &mut self,
         room: String,
@@ -120,6 +206,117 @@ impl SmartHouse {
         Ok(smartroom.devices().keys().collect())
     }
 
+    /// Найденные в локальной сети устройства, которых ещё нет ни в одной комнате.
+    /// Шлюзы в список не входят.
+    pub fn discovered(&self) -> Vec<Announcement> {
+        let Some(discovery) = &self.discovery else {
+            return Vec::new();
+        };
+        discovery
+            .discovered()
+            .into_iter()
+            .filter(|announcement| !announcement.is_gateway() && !self.contains(announcement.id))
+            .collect()
+    }
+
+    /// Добавляет найденное устройство в комнату `room`.
+    ///
+    /// Возвращает устройство и канал телеметрии к его шлюзу, который запускается
+    /// [`RwLockDevice::supervise`].
+    pub fn adopt(
+        &mut self,
+        id: Uuid,
+        room: String,
+    ) -> Result<(RwLockDevice, QuicLink), SmartHouseError> {
+        let announcement = self
+            .discovered()
+            .into_iter()
+            .find(|announcement| announcement.id == id)
+            .ok_or(SmartHouseError::NotDiscoveredError { id })?;
+        if !self.rooms.contains_key(&room) {
+            return Err(SmartHouseError::GetRoomError { name: room });
+        }
+
+        let config = new_config(&announcement.kind, String::new()).ok_or(
+            SmartHouseError::UnsupportedDeviceError {
+                kind: announcement.kind.clone(),
+            },
+        )?;
+        let mut device = Device::new(announcement.name.clone(), config, None);
+        device.id = id;
+        let device = RwLockDevice::new(Arc::new(RwLock::new(device)));
+
+        self.add_device(room, device.clone())?;
+        Ok((device, QuicLink::discovered(&announcement)))
+    }
+
+    fn contains(&self, id: Uuid) -> bool {
+        self.rooms.values().any(|room| {
+            room.devices()
+                .values()
+                .any(|device| device.read().unwrap().id == id)
+        })
+    }
+
+    /// Снимок дома, комнаты и устройства упорядочены по имени.
+    pub fn snapshot(&self) -> HouseSnapshot {
+        let mut rooms: Vec<RoomSnapshot> = self
+            .rooms
+            .values()
+            .map(|room| {
+                let mut devices: Vec<DeviceState> =
+                    room.devices().values().map(RwLockDevice::state).collect();
+                devices.sort_by(|a, b| a.name.cmp(&b.name));
+                RoomSnapshot {
+                    name: room.name().to_string(),
+                    devices,
+                }
+            })
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        HouseSnapshot {
+            version: SNAPSHOT_VERSION,
+            name: self.name.clone(),
+            rooms,
+        }
+    }
+
+    /// Дом из снимка [`SmartHouse::snapshot`], в том числе сделанного другой версией
+    /// библиотеки. Устройства сохраняют номера, каналы телеметрии нужно запустить заново.
+    pub fn restore(snapshot: HouseSnapshot) -> Result<Self, SmartHouseError> {
+        if snapshot.version > SNAPSHOT_VERSION {
+            return Err(SmartHouseError::SnapshotVersionError {
+                version: snapshot.version,
+            });
+        }
+        let mut house = SmartHouse::new(snapshot.name);
+        for room in snapshot.rooms {
+            house.add_room(room.name.clone())?;
+            for state in room.devices {
+                let config = new_config(&state.kind, state.description).ok_or(
+                    SmartHouseError::UnsupportedDeviceError {
+                        kind: state.kind.clone(),
+                    },
+                )?;
+                let reading = state.reading.unwrap_or(state.value as f32);
+                config.write().unwrap().restore(reading).map_err(|_| {
+                    SmartHouseError::ReadingRangeError {
+                        kind: state.kind.clone(),
+                        reading,
+                    }
+                })?;
+                let mut device = Device::new(state.name, config, Some(state.on));
+                // номер из снимка, если он корректен
+                if let Ok(id) = Uuid::parse_str(&state.id) {
+                    device.id = id;
+                }
+                let device = RwLockDevice::new(Arc::new(RwLock::new(device)));
+                house.add_device(room.name.clone(), device)?;
+            }
+        }
+        Ok(house)
+    }
+
     pub fn report(&self, rooms: Option<Vec<String>>) -> String {
         let mut result = format!("Name: {},\n", self.name);
         result += "Rooms:\n[\n";
@@ -148,6 +345,9 @@ mod tests {
     // #[warn(unused_imports)]
     // use device::thermometer::SmartThermometer;
 
+    use crate::device::FeedError;
+    use device_quic::telemetry::Kind;
+
     #[test]
     fn get_null() {
         let test_house = SmartHouse::new("test_house".to_string());
@@ -167,6 +367,13 @@ mod tests {
             test_house.get(Some(vec!["test_room".to_string()]))[0].name(),
             "test_room"
         );
+
+        // комната с тем же именем не заменяет существующую
+        assert!(matches!(
+            test_house.add_room("test_room".to_string()),
+            Err(SmartHouseError::AddRoomError { .. })
+        ));
+        assert_eq!(test_house.get(None).len(), 1);
     }
 
     #[test]
@@ -210,6 +417,111 @@ mod tests {
         assert_eq!(test_house.devices(name_room.clone()).unwrap(), good_result);
     }
 
+    #[test]
+    fn create_device() {
+        let mut test_house = SmartHouse::new("test_house".to_string());
+        let _ = test_house.add_room("kitchen".to_string());
+
+        let params = DeviceParams {
+            kind: "thermometer".to_string(),
+            name: "test_thermometer".to_string(),
+            description: "by the window".to_string(),
+            on: true,
+        };
+        let device = test_house
+            .create_device("kitchen".to_string(), params.clone())
+            .unwrap();
+        device.feed(Kind::Temperature, 21.4).unwrap();
+        assert!(matches!(
+            device.feed(Kind::Power, 1.0),
+            Err(FeedError::Unsupported(_))
+        ));
+
+        let state = test_house
+            .device("kitchen".to_string(), "test_thermometer".to_string())
+            .unwrap()
+            .state();
+        assert_eq!(state.kind, "thermometer");
+        assert_eq!(state.description, "by the window");
+        assert!(state.on);
+        assert_eq!(state.value, 21);
+
+        assert!(matches!(
+            test_house.device("kitchen".to_string(), "lamp".to_string()),
+            Err(SmartHouseError::GetDeviceError { .. })
+        ));
+        assert!(matches!(
+            test_house.create_device("hall".to_string(), params.clone()),
+            Err(SmartHouseError::GetRoomError { .. })
+        ));
+        let lamp = DeviceParams {
+            kind: "lamp".to_string(),
+            ..params
+        };
+        assert!(matches!(
+            test_house.create_device("kitchen".to_string(), lamp),
+            Err(SmartHouseError::UnsupportedDeviceError { .. })
+        ));
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut test_house = SmartHouse::new("test_house".to_string());
+        let _ = test_house.add_room("kitchen".to_string());
+        let _ = test_house.add_room("hall".to_string());
+        let thermometer = test_house
+            .create_device(
+                "kitchen".to_string(),
+                DeviceParams {
+                    kind: "thermometer".to_string(),
+                    name: "test_thermometer".to_string(),
+                    description: "by the window".to_string(),
+                    on: true,
+                },
+            )
+            .unwrap();
+        thermometer.feed(Kind::Temperature, 21.6).unwrap();
+
+        let snapshot = test_house.snapshot();
+        assert_eq!(snapshot.version, SNAPSHOT_VERSION);
+        assert_eq!(snapshot.rooms[0].name, "hall");
+        assert_eq!(snapshot.rooms[1].devices, vec![thermometer.state()]);
+
+        let restored = SmartHouse::restore(snapshot.clone()).unwrap();
+        assert_eq!(restored.snapshot(), snapshot);
+        // показание переносится без округления
+        let state = &restored.snapshot().rooms[1].devices[0];
+        assert_eq!((state.value, state.reading), (22, Some(21.6)));
+
+        // в снимке старой версии только округлённое значение
+        let mut old = snapshot.clone();
+        old.rooms[1].devices[0].reading = None;
+        let state = &SmartHouse::restore(old).unwrap().snapshot().rooms[1].devices[0];
+        assert_eq!((state.value, state.reading), (22, Some(22.0)));
+
+        let mut hot = snapshot.clone();
+        hot.rooms[1].devices[0].reading = Some(300.0);
+        assert!(matches!(
+            SmartHouse::restore(hot),
+            Err(SmartHouseError::ReadingRangeError { .. })
+        ));
+
+        let newer = HouseSnapshot {
+            version: SNAPSHOT_VERSION + 1,
+            ..snapshot.clone()
+        };
+        assert!(matches!(
+            SmartHouse::restore(newer),
+            Err(SmartHouseError::SnapshotVersionError { .. })
+        ));
+        let mut lamp = snapshot;
+        lamp.rooms[1].devices[0].kind = "lamp".to_string();
+        assert!(matches!(
+            SmartHouse::restore(lamp),
+            Err(SmartHouseError::UnsupportedDeviceError { .. })
+        ));
+    }
+
     #[test]
     fn remove_device() {
         let name_room = "test_room".to_string();
@@ -280,4 +592,114 @@ mod tests {
         // print!("{}\n",test_house.report(None));
         assert_eq!(test_house.report(None), "Name: test_house,\nRooms:\n[\n{\nName: test_room,\nDevices:\n[\n{\nName: test_device,\nOn: false,\nDescription: test_outlet,\nPower: 0\n},\n]\n},\n]".to_string());
     }
+
+    // Шлюз объявляет себя и свои сенсоры на loopback, дом принимает термометр в комнату
+    // и получает его показания по отпечатку сертификата из объявления.
+    #[tokio::test]
+    async fn test_adopt_discovered() {
+        use std::time::Duration;
+        use tokio::time::{sleep, timeout};
+
+        use device_quic::common::make_server_endpoint;
+        use device_quic::discovery::{Announcer, GATEWAY_KIND};
+        use device_quic::identity::fingerprint;
+        use device_quic::settings::QuicSettings;
+        use device_quic::simulator::{serve, Scenario};
+
+        let thermometer = Uuid::from_u128(1);
+        let motion = Uuid::from_u128(2);
+        let scenario: Scenario = format!(
+            "temperature {} sine base=25 amplitude=0 period=1s every=50ms\n\
+             motion {} burst period=2s length=1s",
+            thermometer, motion
+        )
+        .parse()
+        .unwrap();
+
+        let gateway_addr = "127.0.0.1:50073".parse().unwrap();
+        let (endpoint, server_cert) =
+            make_server_endpoint(gateway_addr, "localhost", &QuicSettings::default()).unwrap();
+        let fingerprint = fingerprint(&server_cert);
+        let mut announcements = vec![Announcement {
+            kind: GATEWAY_KIND.to_string(),
+            id: Uuid::from_u128(9),
+            name: "gateway".to_string(),
+            addr: "0.0.0.0:50073".parse().unwrap(),
+            server_name: "localhost".to_string(),
+            fingerprint: fingerprint.clone(),
+        }];
+        announcements.extend(scenario.announcements(
+            "0.0.0.0:50073".parse().unwrap(),
+            "localhost",
+            &fingerprint,
+        ));
+        tokio::spawn(serve(endpoint.clone(), scenario));
+
+        let discovery = Discovery::bind(
+            "127.0.0.1:50074".parse().unwrap(),
+            None,
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        let _announcer = Announcer::spawn(
+            "127.0.0.1:0".parse().unwrap(),
+            discovery.local_addr(),
+            announcements,
+            Duration::from_millis(50),
+        )
+        .await
+        .unwrap();
+
+        let mut house = SmartHouse::new("test_house".to_string()).with_discovery(discovery);
+        house.add_room("kitchen".to_string()).unwrap();
+        timeout(Duration::from_secs(5), async {
+            while house.discovered().len() < 2 {
+                sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let discovered: Vec<Uuid> = house.discovered().iter().map(|a| a.id).collect();
+        assert_eq!(discovered, vec![thermometer, motion]);
+
+        assert!(matches!(
+            house.adopt(thermometer, "hall".to_string()),
+            Err(SmartHouseError::GetRoomError { .. })
+        ));
+        assert!(matches!(
+            house.adopt(motion, "kitchen".to_string()),
+            Err(SmartHouseError::UnsupportedDeviceError { .. })
+        ));
+        assert!(matches!(
+            house.adopt(Uuid::from_u128(9), "kitchen".to_string()),
+            Err(SmartHouseError::NotDiscoveredError { .. })
+        ));
+
+        let (device, link) = house.adopt(thermometer, "kitchen".to_string()).unwrap();
+        assert_eq!(link.server_addr, "127.0.0.1:50073");
+        assert_eq!(house.devices("kitchen".to_string()).unwrap().len(), 1);
+        assert_eq!(
+            house.discovered().iter().map(|a| a.id).collect::<Vec<_>>(),
+            vec![motion]
+        );
+        assert!(matches!(
+            house.adopt(thermometer, "kitchen".to_string()),
+            Err(SmartHouseError::NotDiscoveredError { .. })
+        ));
+
+        let supervisor = device.supervise(link);
+        let mut changes = device.changes();
+        timeout(Duration::from_secs(5), changes.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            device.read().unwrap().config().read().unwrap().to_string(),
+            "Description: ,\nTemperature: 25"
+        );
+
+        supervisor.cancel().await;
+        endpoint.close(0_u8.into(), b"done");
+    }
 }
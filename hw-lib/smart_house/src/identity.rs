@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+
+use quinn::Connection;
+use thiserror::Error;
+use uuid::Uuid;
+
+use device_grpc::auth::Permission;
+use device_quic::identity::{peer_identity, PeerIdentity};
+
+#[derive(Debug, Error)]
+pub enum IdentityError {
+    #[error("Sensor presented no certificate")]
+    Anonymous,
+    #[error(transparent)]
+    Certificate(#[from] device_quic::identity::IdentityError),
+    #[error("Sensor {identity} is not registered")]
+    Unknown { identity: PeerIdentity },
+    #[error("Sensor {identity} may not publish readings of device {device_id}")]
+    Forbidden {
+        identity: PeerIdentity,
+        device_id: Uuid,
+    },
+}
+
+/// Какие сертификаты сенсоров публикуют показания каких устройств и с какими правами
+/// управляют ими по тому же соединению.
+///
+/// Сертификат ищется по отпечатку, затем по CN и SAN. Шлюз может публиковать
+/// показания нескольких устройств.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceIdentities {
+    fingerprints: HashMap<String, HashMap<Uuid, Permission>>,
+    names: HashMap<String, HashMap<Uuid, Permission>>,
+}
+
+impl DeviceIdentities {
+    pub fn new() -> Self {
+        DeviceIdentities::default()
+    }
+
+    /// Сенсор с правом `Permission::Control`, как токен без указанного права.
+    pub fn with_fingerprint(self, fingerprint: &str, device_id: Uuid) -> Self {
+        self.with_fingerprint_permission(fingerprint, device_id, Permission::Control)
+    }
+
+    pub fn with_fingerprint_permission(
+        mut self,
+        fingerprint: &str,
+        device_id: Uuid,
+        permission: Permission,
+    ) -> Self {
+        self.fingerprints
+            .entry(fingerprint.to_ascii_lowercase())
+            .or_default()
+            .insert(device_id, permission);
+        self
+    }
+
+    /// Сенсор с правом `Permission::Control`, как токен без указанного права.
+    pub fn with_name(self, name: &str, device_id: Uuid) -> Self {
+        self.with_name_permission(name, device_id, Permission::Control)
+    }
+
+    pub fn with_name_permission(
+        mut self,
+        name: &str,
+        device_id: Uuid,
+        permission: Permission,
+    ) -> Self {
+        self.names
+            .entry(name.to_string())
+            .or_default()
+            .insert(device_id, permission);
+        self
+    }
+
+    /// Устройства, показания которых публикует сенсор, с его правами на них.
+    pub fn resolve(&self, identity: &PeerIdentity) -> Option<&HashMap<Uuid, Permission>> {
+        self.fingerprints
+            .get(&identity.fingerprint)
+            .or_else(|| identity.names().find_map(|name| self.names.get(name)))
+    }
+
+    /// Проверяет, что сенсор на другом конце `connection` публикует показания `device_id`,
+    /// и возвращает его права на команды этому устройству.
+    pub fn authorize(
+        &self,
+        connection: &Connection,
+        device_id: Uuid,
+    ) -> Result<Permission, IdentityError> {
+        let identity = peer_identity(connection).ok_or(IdentityError::Anonymous)??;
+        match self.resolve(&identity) {
+            Some(devices) if devices.contains_key(&device_id) => Ok(devices[&device_id]),
+            Some(_) => Err(IdentityError::Forbidden {
+                identity,
+                device_id,
+            }),
+            None => Err(IdentityError::Unknown { identity }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    use std::sync::{Arc, RwLock};
+    use std::time::Duration;
+
+    use device_quic::certs::generate_self_signed;
+    use device_quic::common::make_server_endpoint_mtls;
+    use device_quic::identity::fingerprint;
+    use device_quic::settings::QuicSettings;
+    use device_quic::telemetry::{open_telemetry_stream, write_frame, Payload, Telemetry};
+
+    use crate::device::thermometer::SmartThermometer;
+    use crate::device::{Device, RwLockDevice};
+    use crate::link::{Backoff, QuicLink};
+
+    fn identity(names: &[&str], fingerprint: &str) -> PeerIdentity {
+        let mut names = names.iter().map(|name| name.to_string());
+        PeerIdentity {
+            common_name: names.next(),
+            subject_alt_names: names.collect(),
+            fingerprint: fingerprint.to_string(),
+        }
+    }
+
+    #[test]
+    fn resolve() {
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let identities = DeviceIdentities::new()
+            .with_fingerprint("ABCD", a)
+            .with_name("gateway", b)
+            .with_name_permission("gateway", c, Permission::Read);
+
+        assert_eq!(
+            identities.resolve(&identity(&["x"], "abcd")),
+            Some(&HashMap::from([(a, Permission::Control)]))
+        );
+        assert_eq!(
+            identities.resolve(&identity(&["x", "gateway"], "ffff")),
+            Some(&HashMap::from([
+                (b, Permission::Control),
+                (c, Permission::Read)
+            ]))
+        );
+        // отпечаток важнее имени
+        assert_eq!(
+            identities.resolve(&identity(&["gateway"], "abcd")),
+            Some(&HashMap::from([(a, Permission::Control)]))
+        );
+        assert_eq!(identities.resolve(&identity(&["x"], "ffff")), None);
+    }
+
+    #[tokio::test]
+    async fn test_sensor_publishes_only_own_readings() {
+        let thermometer = || {
+            RwLockDevice::new(Arc::new(RwLock::new(Device::new(
+                "test_device".to_string(),
+                Arc::new(RwLock::new(SmartThermometer::new(
+                    "test_thermometer".to_string(),
+                    None,
+                ))),
+                None,
+            ))))
+        };
+        let device = thermometer();
+        let device_id = device.read().unwrap().id;
+        let other = thermometer();
+
+        let sensor_cert = generate_self_signed(vec!["localhost".to_string()]).unwrap();
+        let device_cert = generate_self_signed(vec!["device".to_string()]).unwrap();
+
+        let server_addr = "127.0.0.1:50069";
+        let endpoint = make_server_endpoint_mtls(
+            server_addr.parse().unwrap(),
+            &(&sensor_cert).into(),
+            &[&device_cert.cert_der],
+            &QuicSettings::default(),
+        )
+        .unwrap();
+        tokio::spawn(async move {
+            while let Some(connecting) = endpoint.accept().await {
+                let Ok(connection) = connecting.await else {
+                    continue;
+                };
+                tokio::spawn(async move {
+                    let Ok(mut send) = open_telemetry_stream(&connection, device_id).await else {
+                        return;
+                    };
+                    let _ = write_frame(
+                        &mut send,
+                        &Telemetry::new(device_id, Payload::Temperature(23.0)),
+                    )
+                    .await;
+                    let _ = send.finish().await;
+                    connection.close(0_u8.into(), b"done");
+                });
+            }
+        });
+
+        let link = QuicLink {
+            client_addr: "127.0.0.1:0".to_string(),
+            server_addr: server_addr.to_string(),
+            cert_address: "localhost".to_string(),
+            server_certs: vec![sensor_cert.cert_der.clone()],
+            server_fingerprints: Vec::new(),
+            connect_timeout: Duration::from_secs(1),
+            backoff: Backoff::default(),
+            settings: QuicSettings::default(),
+            identity: Some(device_cert.into()),
+            publishers: Some(
+                DeviceIdentities::new()
+                    .with_fingerprint(&fingerprint(&sensor_cert.cert_der), device_id),
+            ),
+            manager: None,
+        };
+
+        device.listening(&link).await.unwrap();
+        assert_eq!(
+            device.read().unwrap().config().read().unwrap().to_string(),
+            "Description: test_thermometer,\nTemperature: 23"
+        );
+
+        // сенсор зарегистрирован за другим устройством
+        let error = other.listening(&link).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<IdentityError>(),
+            Some(IdentityError::Forbidden { .. })
+        ));
+        assert_eq!(
+            other.read().unwrap().config().read().unwrap().to_string(),
+            "Description: test_thermometer,\nTemperature: 0"
+        );
+    }
+}
@@ -0,0 +1,549 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use rand::Rng;
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+
+use device_quic::certs::Identity;
+use device_quic::common::{
+    make_client_endpoint, make_client_endpoint_pinned, make_client_endpoint_with_identity,
+};
+use device_quic::discovery::Announcement;
+use device_quic::manager::ConnectionManager;
+use device_quic::settings::QuicSettings;
+use quinn::Endpoint;
+
+use crate::device::{LinkState, RwLockDevice};
+use crate::identity::DeviceIdentities;
+
+/// Параметры QUIC канала телеметрии устройства.
+#[derive(Debug, Clone)]
+pub struct QuicLink {
+    pub client_addr: String,
+    pub server_addr: String,
+    pub cert_address: String,
+    // доверенные сертификаты сервера или его CA в DER формате
+    pub server_certs: Vec<Vec<u8>>,
+    // отпечатки SHA-256 сертификата сервера, если заданы, заменяют `server_certs`
+    pub server_fingerprints: Vec<String>,
+    // попытка подключения, не завершившаяся за это время, считается неудачной
+    pub connect_timeout: Duration,
+    pub backoff: Backoff,
+    // параметры транспорта и ALPN, должны совпадать с ALPN шлюза
+    pub settings: QuicSettings,
+    // сертификат устройства для сенсоров с mutual TLS
+    pub identity: Option<Identity>,
+    // без него показания принимаются от любого доверенного сервера
+    pub publishers: Option<DeviceIdentities>,
+    // общее соединение со шлюзом для всех его устройств, со своими сертификатами
+    pub manager: Option<ConnectionManager>,
+}
+
+impl QuicLink {
+    /// Канал к шлюзу, объявившему устройство в локальной сети: сертификат шлюза
+    /// проверяется по отпечатку из объявления.
+    pub fn discovered(announcement: &Announcement) -> Self {
+        QuicLink {
+            client_addr: match announcement.addr {
+                SocketAddr::V4(_) => "0.0.0.0:0".to_string(),
+                SocketAddr::V6(_) => "[::]:0".to_string(),
+            },
+            server_addr: announcement.addr.to_string(),
+            cert_address: announcement.server_name.clone(),
+            server_certs: Vec::new(),
+            server_fingerprints: vec![announcement.fingerprint.clone()],
+            connect_timeout: Duration::from_secs(5),
+            backoff: Backoff::default(),
+            settings: QuicSettings::default(),
+            identity: None,
+            publishers: None,
+            manager: None,
+        }
+    }
+
+    /// Клиентский endpoint с сертификатами канала.
+    pub fn make_endpoint(&self) -> Result<Endpoint> {
+        let server_certs: Vec<&[u8]> = self.server_certs.iter().map(Vec::as_slice).collect();
+        let client_addr = self.client_addr.parse()?;
+        if !self.server_fingerprints.is_empty() {
+            return make_client_endpoint_pinned(
+                client_addr,
+                &self.server_fingerprints,
+                self.identity.as_ref(),
+                &self.settings,
+            )
+            .map_err(|e| anyhow!("failed to make client endpoint: {}", e));
+        }
+        match &self.identity {
+            Some(identity) => make_client_endpoint_with_identity(
+                client_addr,
+                &server_certs,
+                identity,
+                &self.settings,
+            ),
+            None => make_client_endpoint(client_addr, &server_certs, &self.settings),
+        }
+        .map_err(|e| anyhow!("failed to make client endpoint: {}", e))
+    }
+}
+
+/// Экспоненциальная задержка между попытками подключения.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub factor: f64,
+    // доля задержки, на которую она случайно сокращается, от 0 до 1
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(200),
+            max: Duration::from_secs(30),
+            factor: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl Backoff {
+    /// Задержка перед попыткой `attempt`, считая с нуля: `initial * factor^attempt`,
+    /// не больше `max`, уменьшенная на случайную долю до `jitter`.
+    ///
+    /// Отрицательная или NaN задержка от неверного `factor` заменяется на `max`,
+    /// `jitter` вне 0..=1 обрезается, NaN считается нулём.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let max = self.max.as_secs_f64();
+        let base =
+            self.initial.as_secs_f64() * self.factor.powi(attempt.min(i32::MAX as u32) as i32);
+        // `f64::min` с NaN возвращает `max`
+        let base = base.min(max);
+        let base = if base >= 0.0 { base } else { max };
+        let jitter = if self.jitter.is_nan() {
+            0.0
+        } else {
+            self.jitter.clamp(0.0, 1.0)
+        };
+        let scale = 1.0 - jitter * rand::thread_rng().gen::<f64>();
+        Duration::try_from_secs_f64(base * scale).unwrap_or(self.max)
+    }
+}
+
+/// Задача, которая держит QUIC канал устройства и переподключается после обрывов.
+///
+/// Состояние канала доступно через [`LinkSupervisor::state`] и [`RwLockDevice::link_state`].
+/// Задача останавливается [`LinkSupervisor::cancel`] или при удалении супервизора.
+pub struct LinkSupervisor {
+    state: watch::Receiver<LinkState>,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl LinkSupervisor {
+    pub fn spawn(device: RwLockDevice, link: QuicLink) -> Self {
+        let state = device.link_state();
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let task = tokio::spawn(supervise(device, link, shutdown_rx));
+        LinkSupervisor {
+            state,
+            shutdown: Some(shutdown),
+            task: Some(task),
+        }
+    }
+
+    pub fn state(&self) -> watch::Receiver<LinkState> {
+        self.state.clone()
+    }
+
+    /// Останавливает задачу и дожидается закрытия канала.
+    pub async fn cancel(mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+impl Drop for LinkSupervisor {
+    fn drop(&mut self) {
+        // закрытый канал `shutdown` тоже останавливает задачу
+        self.shutdown.take();
+    }
+}
+
+impl RwLockDevice {
+    /// Запускает [`LinkSupervisor`] для QUIC канала устройства.
+    pub fn supervise(&self, link: QuicLink) -> LinkSupervisor {
+        LinkSupervisor::spawn(self.clone(), link)
+    }
+}
+
+async fn supervise(device: RwLockDevice, link: QuicLink, mut shutdown: oneshot::Receiver<()>) {
+    let mut attempt = 0_u32;
+    loop {
+        device.set_link_state(LinkState::Connecting);
+        tokio::select! {
+            connected = session(&device, &link) => {
+                // после рабочей сессии задержки начинаются заново
+                if let Ok(true) = connected {
+                    attempt = 0;
+                }
+            }
+            _ = &mut shutdown => break,
+        }
+
+        let delay = link.backoff.delay(attempt);
+        attempt = attempt.saturating_add(1);
+        device.set_link_state(LinkState::BackingOff { attempt, delay });
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = &mut shutdown => break,
+        }
+    }
+    device.set_link_state(LinkState::Down);
+}
+
+// Одна сессия канала, возвращает, удалось ли подключиться.
+async fn session(device: &RwLockDevice, link: &QuicLink) -> Result<bool> {
+    let mut state = device.link_state();
+    let listening = device.listening(link);
+    tokio::pin!(listening);
+
+    let connect = tokio::time::timeout(
+        link.connect_timeout,
+        state.wait_for(|state| *state == LinkState::Up),
+    );
+    tokio::select! {
+        result = &mut listening => return result.map(|_| true),
+        connected = connect => match connected {
+            Ok(Ok(_)) => {}
+            _ => return Err(anyhow!("failed to connect in {:?}", link.connect_timeout)),
+        },
+    }
+
+    listening.await.map(|_| true)
+}
+
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, RwLock};
+
+    use quinn::Endpoint;
+    use tokio::time::{sleep, timeout};
+    use uuid::Uuid;
+
+    use device_quic::certs::{generate_self_signed, SelfSignedCert};
+    use device_quic::common::make_server_endpoint_with_cert;
+    use device_quic::telemetry::{open_telemetry_stream, write_frame, Payload, Telemetry};
+
+    use crate::device::thermometer::SmartThermometer;
+    use crate::device::Device;
+
+    #[test]
+    fn backoff_delay() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+            factor: 2.0,
+            jitter: 0.0,
+        };
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(1), Duration::from_millis(200));
+        assert_eq!(backoff.delay(3), Duration::from_millis(800));
+        assert_eq!(backoff.delay(4), Duration::from_secs(1));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(1));
+
+        let backoff = Backoff {
+            jitter: 0.5,
+            ..backoff
+        };
+        for _ in 0..100 {
+            let delay = backoff.delay(2);
+            assert!(delay >= Duration::from_millis(200) && delay <= Duration::from_millis(400));
+        }
+    }
+
+    #[test]
+    fn backoff_invalid_params() {
+        let max = Duration::from_secs(1);
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max,
+            factor: -2.0,
+            jitter: f64::NAN,
+        };
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(1), max);
+
+        for factor in [f64::NAN, f64::INFINITY, f64::MAX] {
+            let backoff = Backoff { factor, ..backoff };
+            assert_eq!(backoff.delay(3), max);
+        }
+
+        let backoff = Backoff {
+            factor: 2.0,
+            jitter: -1.0,
+            ..backoff
+        };
+        assert_eq!(backoff.delay(1), Duration::from_millis(200));
+
+        let backoff = Backoff {
+            max: Duration::MAX,
+            factor: f64::INFINITY,
+            jitter: 0.0,
+            ..backoff
+        };
+        assert_eq!(backoff.delay(1), Duration::MAX);
+    }
+
+    async fn wait_state(state: &mut watch::Receiver<LinkState>, expected: LinkState) {
+        timeout(
+            Duration::from_secs(5),
+            state.wait_for(|state| *state == expected),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    }
+
+    // сервер сенсора, который отправляет одно показание и ждёт закрытия
+    async fn serve(
+        addr: &str,
+        cert: &SelfSignedCert,
+        device_id: Uuid,
+        temperature: f32,
+    ) -> Endpoint {
+        // сокет закрытого сервера освобождается, когда завершатся его фоновые задачи
+        let mut endpoint =
+            make_server_endpoint_with_cert(addr.parse().unwrap(), cert, &QuicSettings::default());
+        for _ in 0..50 {
+            if endpoint.is_ok() {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+            endpoint = make_server_endpoint_with_cert(
+                addr.parse().unwrap(),
+                cert,
+                &QuicSettings::default(),
+            );
+        }
+        let endpoint = endpoint.unwrap();
+        let accept = endpoint.clone();
+        tokio::spawn(async move {
+            while let Some(connecting) = accept.accept().await {
+                let Ok(connection) = connecting.await else {
+                    continue;
+                };
+                let mut send = open_telemetry_stream(&connection, device_id).await.unwrap();
+                write_frame(
+                    &mut send,
+                    &Telemetry::new(device_id, Payload::Temperature(temperature)),
+                )
+                .await
+                .unwrap();
+                // соединение живёт, пока жив `send`
+                tokio::spawn(async move {
+                    let _ = connection.closed().await;
+                    drop(send);
+                });
+            }
+        });
+        endpoint
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_after_server_restart() {
+        let device = RwLockDevice::new(Arc::new(RwLock::new(Device::new(
+            "test_device".to_string(),
+            Arc::new(RwLock::new(SmartThermometer::new(
+                "test_thermometer".to_string(),
+                None,
+            ))),
+            None,
+        ))));
+        let device_id = device.read().unwrap().id;
+        let temperature = || device.read().unwrap().config().read().unwrap().to_string();
+
+        let server_addr = "127.0.0.1:50067";
+        let cert = generate_self_signed(vec!["localhost".to_string()]).unwrap();
+
+        let link = QuicLink {
+            client_addr: "127.0.0.1:0".to_string(),
+            server_addr: server_addr.to_string(),
+            cert_address: "localhost".to_string(),
+            server_certs: vec![cert.cert_der.clone()],
+            server_fingerprints: Vec::new(),
+            connect_timeout: Duration::from_millis(300),
+            backoff: Backoff {
+                initial: Duration::from_millis(50),
+                max: Duration::from_millis(200),
+                factor: 2.0,
+                jitter: 0.5,
+            },
+            settings: QuicSettings::default(),
+            identity: None,
+            publishers: None,
+            manager: None,
+        };
+
+        // сервера ещё нет: попытки подключения чередуются с ожиданием
+        let supervisor = device.supervise(link);
+        let mut state = supervisor.state();
+        timeout(
+            Duration::from_secs(5),
+            state.wait_for(|state| matches!(state, LinkState::BackingOff { attempt: 2, .. })),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        let endpoint = serve(server_addr, &cert, device_id, 21.0).await;
+        wait_state(&mut state, LinkState::Up).await;
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(
+            temperature(),
+            "Description: test_thermometer,\nTemperature: 21"
+        );
+
+        // перезапуск сервера
+        endpoint.close(0_u8.into(), b"restart");
+        endpoint.wait_idle().await;
+        drop(endpoint);
+        timeout(
+            Duration::from_secs(5),
+            state.wait_for(|state| *state != LinkState::Up),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        let endpoint = serve(server_addr, &cert, device_id, -5.0).await;
+        wait_state(&mut state, LinkState::Up).await;
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(
+            temperature(),
+            "Description: test_thermometer,\nTemperature: -5"
+        );
+
+        timeout(Duration::from_secs(5), supervisor.cancel())
+            .await
+            .unwrap();
+        assert_eq!(*device.link_state().borrow(), LinkState::Down);
+
+        endpoint.close(0_u8.into(), b"done");
+    }
+
+    #[tokio::test]
+    async fn test_devices_share_gateway_connection() {
+        let thermometer = || {
+            RwLockDevice::new(Arc::new(RwLock::new(Device::new(
+                "test_device".to_string(),
+                Arc::new(RwLock::new(SmartThermometer::new(
+                    "test_thermometer".to_string(),
+                    None,
+                ))),
+                None,
+            ))))
+        };
+        let devices = [thermometer(), thermometer()];
+        let ids: Vec<Uuid> = devices.iter().map(|d| d.read().unwrap().id).collect();
+
+        let server_addr = "127.0.0.1:50070";
+        let cert = generate_self_signed(vec!["localhost".to_string()]).unwrap();
+        let endpoint = make_server_endpoint_with_cert(
+            server_addr.parse().unwrap(),
+            &cert,
+            &QuicSettings::default(),
+        )
+        .unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        // шлюз публикует показания обоих устройств в одном соединении
+        tokio::spawn({
+            let endpoint = endpoint.clone();
+            let accepted = accepted.clone();
+            let ids = ids.clone();
+            async move {
+                while let Some(connecting) = endpoint.accept().await {
+                    let Ok(connection) = connecting.await else {
+                        continue;
+                    };
+                    accepted.fetch_add(1, Ordering::SeqCst);
+                    let mut streams = Vec::new();
+                    for (i, device_id) in ids.iter().enumerate() {
+                        let mut send = open_telemetry_stream(&connection, *device_id)
+                            .await
+                            .unwrap();
+                        write_frame(
+                            &mut send,
+                            &Telemetry::new(*device_id, Payload::Temperature(10.0 + i as f32)),
+                        )
+                        .await
+                        .unwrap();
+                        streams.push(send);
+                    }
+                    tokio::spawn(async move {
+                        let _ = connection.closed().await;
+                        drop(streams);
+                    });
+                }
+            }
+        });
+
+        let manager = ConnectionManager::new(
+            device_quic::common::make_client_endpoint(
+                "127.0.0.1:0".parse().unwrap(),
+                &[&cert.cert_der],
+                &QuicSettings::default(),
+            )
+            .unwrap(),
+        );
+        let link = QuicLink {
+            client_addr: "127.0.0.1:0".to_string(),
+            server_addr: server_addr.to_string(),
+            cert_address: "localhost".to_string(),
+            server_certs: vec![cert.cert_der.clone()],
+            server_fingerprints: Vec::new(),
+            connect_timeout: Duration::from_secs(1),
+            backoff: Backoff::default(),
+            settings: QuicSettings::default(),
+            identity: None,
+            publishers: None,
+            manager: Some(manager.clone()),
+        };
+
+        let supervisors: Vec<_> = devices
+            .iter()
+            .map(|device| device.supervise(link.clone()))
+            .collect();
+        for supervisor in &supervisors {
+            wait_state(&mut supervisor.state(), LinkState::Up).await;
+        }
+        sleep(Duration::from_millis(200)).await;
+
+        for (i, device) in devices.iter().enumerate() {
+            assert_eq!(
+                device.read().unwrap().config().read().unwrap().to_string(),
+                format!("Description: test_thermometer,\nTemperature: {}", 10 + i)
+            );
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        assert_eq!(manager.connections(), 1);
+
+        for supervisor in supervisors {
+            supervisor.cancel().await;
+        }
+        assert_eq!(manager.connections(), 0);
+        endpoint.close(0_u8.into(), b"done");
+    }
+}
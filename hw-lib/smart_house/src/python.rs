@@ -0,0 +1,293 @@
+//! Модуль Python `smart_house`, собирается с фичей `python`.
+//!
+//! ```python
+//! import smart_house
+//!
+//! house = smart_house.SmartHouse("Дом")
+//! kitchen = house.add_room("Кухня")
+//! thermometer = kitchen.add_device("thermometer", "Термометр", description="у окна")
+//! thermometer.on = True
+//! thermometer.feed("temperature", 21.5)
+//! print(house.report())
+//! ```
+//!
+//! Ошибки дома становятся исключениями [`SmartHouseError`] и его наследниками, те же
+//! случаи, что коды `SmartHouseStatus` в C ABI, а неверные аргументы (`INVALID_ARGUMENT`) -
+//! `ValueError`. Тесты модуля в `python/tests`, см. Readme.md.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+
+use device_quic::telemetry::Kind;
+
+use crate::device::{DeviceParams, FeedError, RwLockDevice};
+use crate::ffi::SmartHouseStatus;
+use crate::house::{SmartHouse, SmartHouseError as HouseError};
+
+create_exception!(
+    smart_house,
+    SmartHouseError,
+    PyException,
+    "Base class of smart house errors."
+);
+create_exception!(
+    smart_house,
+    NotFoundError,
+    SmartHouseError,
+    "No room or device with this name."
+);
+create_exception!(
+    smart_house,
+    AlreadyExistsError,
+    SmartHouseError,
+    "A room or device with this name already exists."
+);
+create_exception!(
+    smart_house,
+    UnsupportedError,
+    SmartHouseError,
+    "Unknown device kind or a reading the device doesn't take."
+);
+
+// Те же классы ошибок, что коды C ABI для ошибок дома.
+impl From<HouseError> for PyErr {
+    fn from(error: HouseError) -> Self {
+        let message = error.to_string();
+        match SmartHouseStatus::from(&error) {
+            SmartHouseStatus::AlreadyExists => AlreadyExistsError::new_err(message),
+            SmartHouseStatus::NotFound => NotFoundError::new_err(message),
+            SmartHouseStatus::Unsupported => UnsupportedError::new_err(message),
+            SmartHouseStatus::InvalidArgument => PyValueError::new_err(message),
+            _ => SmartHouseError::new_err(message),
+        }
+    }
+}
+
+type SharedHouse = Arc<Mutex<SmartHouse>>;
+
+fn lock(house: &SharedHouse) -> MutexGuard<'_, SmartHouse> {
+    house.lock().unwrap()
+}
+
+/// Smart house with rooms of devices.
+#[pyclass(name = "SmartHouse")]
+struct PySmartHouse {
+    house: SharedHouse,
+}
+
+#[pymethods]
+impl PySmartHouse {
+    #[new]
+    fn new(name: String) -> Self {
+        PySmartHouse {
+            house: Arc::new(Mutex::new(SmartHouse::new(name))),
+        }
+    }
+
+    #[getter]
+    fn name(&self) -> String {
+        lock(&self.house).name().to_string()
+    }
+
+    fn add_room(&self, name: String) -> PyResult<PyRoom> {
+        let name = lock(&self.house).add_room(name)?;
+        Ok(self.room_handle(name))
+    }
+
+    fn remove_room(&self, name: String) -> PyResult<()> {
+        lock(&self.house).remove_room(name)?;
+        Ok(())
+    }
+
+    fn room(&self, name: String) -> PyResult<PyRoom> {
+        if !lock(&self.house).rooms().contains_key(&name) {
+            return Err(HouseError::GetRoomError { name }.into());
+        }
+        Ok(self.room_handle(name))
+    }
+
+    /// Rooms ordered by name.
+    fn rooms(&self) -> Vec<PyRoom> {
+        let mut names: Vec<String> = lock(&self.house).rooms().keys().cloned().collect();
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| self.room_handle(name))
+            .collect()
+    }
+
+    fn report(&self) -> String {
+        lock(&self.house).report(None)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("SmartHouse({:?})", self.name())
+    }
+}
+
+impl PySmartHouse {
+    fn room_handle(&self, name: String) -> PyRoom {
+        PyRoom {
+            house: self.house.clone(),
+            name,
+        }
+    }
+}
+
+/// Room of a [`PySmartHouse`], looked up by name on every call.
+#[pyclass(name = "Room")]
+struct PyRoom {
+    house: SharedHouse,
+    #[pyo3(get)]
+    name: String,
+}
+
+#[pymethods]
+impl PyRoom {
+    /// Creates a device of `kind`, e.g. `thermometer` or `outlet`, in the room.
+    #[pyo3(signature = (kind, name, description = String::new(), on = false))]
+    fn add_device(
+        &self,
+        kind: String,
+        name: String,
+        description: String,
+        on: bool,
+    ) -> PyResult<PyDevice> {
+        let params = DeviceParams {
+            kind,
+            name,
+            description,
+            on,
+        };
+        let device = lock(&self.house).create_device(self.name.clone(), params)?;
+        Ok(PyDevice { device })
+    }
+
+    fn remove_device(&self, name: String) -> PyResult<()> {
+        lock(&self.house).remove_device(self.name.clone(), name)?;
+        Ok(())
+    }
+
+    fn device(&self, name: String) -> PyResult<PyDevice> {
+        let device = lock(&self.house).device(self.name.clone(), name)?;
+        Ok(PyDevice { device })
+    }
+
+    /// Devices ordered by name.
+    fn devices(&self) -> PyResult<Vec<PyDevice>> {
+        let house = lock(&self.house);
+        let mut names = house.devices(self.name.clone())?;
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| {
+                let device = house.device(self.name.clone(), name.clone())?;
+                Ok(PyDevice { device })
+            })
+            .collect()
+    }
+
+    fn report(&self) -> PyResult<String> {
+        let house = lock(&self.house);
+        let room = house
+            .rooms()
+            .get(&self.name)
+            .ok_or(HouseError::GetRoomError {
+                name: self.name.clone(),
+            })?;
+        Ok(room.report(None))
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Room({:?})", self.name)
+    }
+}
+
+/// Device of a room, stays usable after it is removed from the room.
+#[pyclass(name = "Device")]
+struct PyDevice {
+    device: RwLockDevice,
+}
+
+#[pymethods]
+impl PyDevice {
+    #[getter]
+    fn id(&self) -> String {
+        self.device.state().id
+    }
+
+    #[getter]
+    fn name(&self) -> String {
+        self.device.state().name
+    }
+
+    #[getter]
+    fn kind(&self) -> String {
+        self.device.state().kind
+    }
+
+    #[getter]
+    fn description(&self) -> String {
+        self.device.state().description
+    }
+
+    #[getter]
+    fn on(&self) -> bool {
+        self.device.state().on
+    }
+
+    #[setter]
+    fn set_on(&self, on: bool) {
+        self.device.set_on(on);
+    }
+
+    /// Power of an outlet, temperature of a thermometer.
+    #[getter]
+    fn value(&self) -> i64 {
+        self.device.state().value
+    }
+
+    /// Applies a `temperature`, `motion` or `power` reading. A reading out of the device range
+    /// raises `ValueError`.
+    fn feed(&self, kind: &str, value: f64) -> PyResult<()> {
+        let kind = match kind {
+            "temperature" => Kind::Temperature,
+            "motion" => Kind::Motion,
+            "power" => Kind::Power,
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "Unknown reading kind {:?}",
+                    kind
+                )))
+            }
+        };
+        self.device.feed(kind, value).map_err(|error| match error {
+            FeedError::OutOfRange { .. } => PyValueError::new_err(error.to_string()),
+            FeedError::Unsupported(_) => UnsupportedError::new_err(error.to_string()),
+        })
+    }
+
+    fn __repr__(&self) -> String {
+        let state = self.device.state();
+        format!(
+            "Device(kind={:?}, name={:?}, on={}, value={})",
+            state.kind, state.name, state.on, state.value
+        )
+    }
+}
+
+#[pymodule]
+fn smart_house(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = module.py();
+    module.add_class::<PySmartHouse>()?;
+    module.add_class::<PyRoom>()?;
+    module.add_class::<PyDevice>()?;
+    module.add("SmartHouseError", py.get_type::<SmartHouseError>())?;
+    module.add("NotFoundError", py.get_type::<NotFoundError>())?;
+    module.add("AlreadyExistsError", py.get_type::<AlreadyExistsError>())?;
+    module.add("UnsupportedError", py.get_type::<UnsupportedError>())?;
+    Ok(())
+}
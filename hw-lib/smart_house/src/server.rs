@@ -0,0 +1,331 @@
+use std::future::Future;
+use std::net::SocketAddr;
+
+use anyhow::{anyhow, Result};
+
+use tonic::server::NamedService;
+use tonic::transport::{Server, ServerTlsConfig};
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+use device_grpc::auth::{AuthInterceptor, TokenStore};
+use device_grpc::devices::device_control_server::DeviceControlServer;
+use device_grpc::devices::FILE_DESCRIPTOR_SET;
+
+use crate::device::{LinkState, RwLockDevice};
+pub use crate::link::QuicLink;
+
+/// Имя сервиса устройства в `grpc.health.v1`.
+pub const DEVICE_SERVICE: &str = <DeviceControlServer<RwLockDevice> as NamedService>::NAME;
+
+// gRPC сервер устройства: DeviceControl, grpc.health.v1 и server reflection
+pub struct DeviceServer {
+    device: RwLockDevice,
+    tokens: Option<TokenStore>,
+    link: Option<QuicLink>,
+    tls: Option<ServerTlsConfig>,
+}
+
+impl DeviceServer {
+    pub fn new(device: RwLockDevice) -> Self {
+        DeviceServer {
+            device,
+            tokens: None,
+            link: None,
+            tls: None,
+        }
+    }
+
+    pub fn with_tokens(mut self, tokens: TokenStore) -> Self {
+        self.tokens = Some(tokens);
+        self
+    }
+
+    pub fn with_link(mut self, link: QuicLink) -> Self {
+        self.link = Some(link);
+        self
+    }
+
+    pub fn with_tls(mut self, tls: ServerTlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Запускает сервер на `addr` и работает до завершения `signal`.
+    ///
+    /// Без QUIC канала устройство всегда `SERVING`, с каналом - `NOT_SERVING`, пока канал не поднят.
+    pub async fn serve_with_shutdown<F>(self, addr: SocketAddr, signal: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let (reporter, health_service) = tonic_health::server::health_reporter();
+
+        let reflection_service = tonic_reflection::server::Builder::configure()
+            .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+            .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+            .build()
+            .map_err(|e| anyhow!("failed to build reflection service: {}", e))?;
+
+        // задачи канала запускаются только после успешной настройки сервера
+        let mut builder = match self.tls {
+            Some(tls) => Server::builder()
+                .tls_config(tls)
+                .map_err(|e| anyhow!("failed to configure TLS: {}", e))?,
+            None => Server::builder(),
+        };
+
+        let mut watcher = None;
+        let mut supervisor = None;
+        match self.link {
+            Some(link) => {
+                watcher = Some(tokio::spawn(watch_link(self.device.clone(), reporter)));
+                supervisor = Some(self.device.supervise(link));
+            }
+            None => set_status(&reporter, ServingStatus::Serving).await,
+        }
+
+        let router = match self.tokens {
+            Some(tokens) => builder.add_service(DeviceControlServer::with_interceptor(
+                self.device,
+                AuthInterceptor::new(tokens),
+            )),
+            None => builder.add_service(DeviceControlServer::new(self.device)),
+        };
+
+        let result = router
+            .add_service(health_service)
+            .add_service(reflection_service)
+            .serve_with_shutdown(addr, signal)
+            .await
+            .map_err(|e| anyhow!("failed to serve: {}", e));
+
+        if let Some(supervisor) = supervisor {
+            supervisor.cancel().await;
+        }
+        if let Some(watcher) = watcher {
+            watcher.abort();
+        }
+        result
+    }
+}
+
+async fn set_status(reporter: &HealthReporter, status: ServingStatus) {
+    let mut reporter = reporter.clone();
+    reporter.set_service_status("", status).await;
+    reporter.set_service_status(DEVICE_SERVICE, status).await;
+}
+
+async fn watch_link(device: RwLockDevice, reporter: HealthReporter) {
+    let mut state = device.link_state();
+    loop {
+        let status = match *state.borrow_and_update() {
+            LinkState::Up => ServingStatus::Serving,
+            _ => ServingStatus::NotServing,
+        };
+        set_status(&reporter, status).await;
+        if state.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    use std::sync::{Arc, RwLock};
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+    use tokio::sync::oneshot;
+    use tokio::time::sleep;
+
+    use tonic::transport::{Channel, Endpoint};
+    use tonic_health::pb::health_check_response::ServingStatus as Status;
+    use tonic_health::pb::health_client::HealthClient;
+    use tonic_health::pb::HealthCheckRequest;
+
+    use device_quic::common::make_server_endpoint;
+    use device_quic::settings::QuicSettings;
+
+    use crate::device::outlet::SmartOutlet;
+    use crate::device::thermometer::SmartThermometer;
+    use crate::device::Device;
+    use crate::link::Backoff;
+
+    async fn check(client: &mut HealthClient<Channel>, service: &str) -> Status {
+        let response = client
+            .check(HealthCheckRequest {
+                service: service.to_string(),
+            })
+            .await
+            .unwrap();
+        response.into_inner().status()
+    }
+
+    #[tokio::test]
+    async fn test_health_without_link() {
+        let device = RwLockDevice::new(Arc::new(RwLock::new(Device::new(
+            "test_device".to_string(),
+            Arc::new(RwLock::new(SmartOutlet::new(
+                "test_outlet".to_string(),
+                None,
+            ))),
+            None,
+        ))));
+
+        let addr = "127.0.0.1:50056";
+        let (signal_tx, signal_rx) = oneshot::channel();
+
+        let server = tokio::spawn(DeviceServer::new(device).serve_with_shutdown(
+            addr.parse().unwrap(),
+            async {
+                signal_rx.await.ok();
+            },
+        ));
+
+        let _ = sleep(Duration::from_millis(1000)).await;
+
+        let channel = Endpoint::from_shared("http://".to_owned() + addr)
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = HealthClient::new(channel);
+
+        assert_eq!(check(&mut client, "").await, Status::Serving);
+        assert_eq!(check(&mut client, DEVICE_SERVICE).await, Status::Serving);
+
+        let _ = signal_tx.send(());
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_health_follows_link() -> Result<()> {
+        let device = RwLockDevice::new(Arc::new(RwLock::new(Device::new(
+            "test_device".to_string(),
+            Arc::new(RwLock::new(SmartThermometer::new(
+                "test_thermometer".to_string(),
+                None,
+            ))),
+            None,
+        ))));
+
+        let addr = "127.0.0.1:50057";
+        let server_addr = "127.0.0.1:50058";
+        let cert_address = "localhost";
+
+        let (endpoint_server, server_cert) = make_server_endpoint(
+            server_addr.parse().unwrap(),
+            cert_address,
+            &QuicSettings::default(),
+        )
+        .map_err(|e| anyhow!("failed to create server endpoint: {}", e))?;
+
+        let link = QuicLink {
+            client_addr: "127.0.0.1:50059".to_string(),
+            server_addr: server_addr.to_string(),
+            cert_address: cert_address.to_string(),
+            server_certs: vec![server_cert],
+            server_fingerprints: Vec::new(),
+            connect_timeout: Duration::from_secs(1),
+            backoff: Backoff::default(),
+            settings: QuicSettings::default(),
+            identity: None,
+            publishers: None,
+            manager: None,
+        };
+
+        let (signal_tx, signal_rx) = oneshot::channel();
+
+        let server = tokio::spawn(
+            DeviceServer::new(device)
+                .with_link(link)
+                .serve_with_shutdown(addr.parse().unwrap(), async {
+                    signal_rx.await.ok();
+                }),
+        );
+
+        let _ = sleep(Duration::from_millis(1000)).await;
+
+        let channel = Endpoint::from_shared("http://".to_owned() + addr)
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = HealthClient::new(channel);
+
+        let connection = endpoint_server.accept().await.unwrap().await?;
+
+        assert_eq!(check(&mut client, "").await, Status::Serving);
+        assert_eq!(check(&mut client, DEVICE_SERVICE).await, Status::Serving);
+
+        // сенсор отключился
+        connection.close(0_u8.into(), b"restart");
+        endpoint_server.close(0_u8.into(), b"restart");
+        let _ = sleep(Duration::from_millis(500)).await;
+
+        assert_eq!(check(&mut client, "").await, Status::NotServing);
+        assert_eq!(check(&mut client, DEVICE_SERVICE).await, Status::NotServing);
+
+        let _ = signal_tx.send(());
+        assert!(server.await.unwrap().is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tls_error_starts_no_link() -> Result<()> {
+        let device = RwLockDevice::new(Arc::new(RwLock::new(Device::new(
+            "test_device".to_string(),
+            Arc::new(RwLock::new(SmartThermometer::new(
+                "test_thermometer".to_string(),
+                None,
+            ))),
+            None,
+        ))));
+
+        let server_addr = "127.0.0.1:50065";
+        let cert_address = "localhost";
+
+        let (endpoint_server, server_cert) = make_server_endpoint(
+            server_addr.parse().unwrap(),
+            cert_address,
+            &QuicSettings::default(),
+        )
+        .map_err(|e| anyhow!("failed to create server endpoint: {}", e))?;
+
+        let link = QuicLink {
+            client_addr: "127.0.0.1:50066".to_string(),
+            server_addr: server_addr.to_string(),
+            cert_address: cert_address.to_string(),
+            server_certs: vec![server_cert],
+            server_fingerprints: Vec::new(),
+            connect_timeout: Duration::from_secs(1),
+            backoff: Backoff::default(),
+            settings: QuicSettings::default(),
+            identity: None,
+            publishers: None,
+            manager: None,
+        };
+        let tls = ServerTlsConfig::new().identity(tonic::transport::Identity::from_pem(
+            "не сертификат",
+            "не ключ",
+        ));
+
+        let result = DeviceServer::new(device)
+            .with_link(link)
+            .with_tls(tls)
+            .serve_with_shutdown("127.0.0.1:50067".parse().unwrap(), async {})
+            .await;
+        assert!(result.is_err());
+
+        // канал к сенсору не поднимался
+        let accepted =
+            tokio::time::timeout(Duration::from_millis(1500), endpoint_server.accept()).await;
+        assert!(accepted.is_err());
+
+        Ok(())
+    }
+}
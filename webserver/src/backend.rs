@@ -0,0 +1,35 @@
+use crate::c_lib::LibraryError;
+
+/// House of one session, the handlers call it without knowing which backend runs it.
+pub trait House: Send + Sync {
+    fn add_room(&self, name: String) -> Result<(), LibraryError>;
+
+    fn remove_room(&self, name: String) -> Result<(), LibraryError>;
+
+    fn get_list_rooms_name(&self) -> Result<Vec<String>, LibraryError>;
+
+    fn remove_device(&self, room: String, name: String) -> Result<(), LibraryError>;
+
+    fn get_list_devices_name(&self, room: String) -> Result<Vec<String>, LibraryError>;
+
+    fn report(&self) -> Result<String, LibraryError>;
+
+    fn add_test_device_outlet(
+        &self,
+        room: String,
+        name: String,
+        description: String,
+    ) -> Result<(), LibraryError>;
+
+    /// JSON snapshot of the house for [`Backend::import`].
+    fn export(&self) -> Result<String, LibraryError>;
+}
+
+/// Creates houses: the loaded libsmart_house or, with the `static-link` feature, the linked
+/// `smart_house` crate. Errors of both carry `SmartHouseStatus` codes.
+pub trait Backend: Send + Sync {
+    fn create(&self, name: String) -> Result<Box<dyn House>, LibraryError>;
+
+    /// Creates a house from a snapshot of [`House::export`], possibly made by another backend.
+    fn import(&self, snapshot: &str) -> Result<Box<dyn House>, LibraryError>;
+}
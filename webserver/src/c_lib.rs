@@ -1,340 +1,365 @@
-use core::ffi::{c_char, c_void};
-use core::mem;
-use once_cell::sync::Lazy;
-use std::collections::HashMap;
-use std::ffi::{CStr, CString};
+use core::ffi::{c_char, c_int, c_void};
+use std::ffi::{CStr, CString, OsStr};
+use std::ptr::NonNull;
 use std::sync::Arc;
-use std::sync::RwLock;
 
-use libloading::{Library, Symbol};
+use libloading::Library;
 
-pub static SMARTHOUSESTORE: Lazy<RwLock<HashMap<String, SmartHouseLib>>> =
-    Lazy::new(|| RwLock::new(HashMap::new()));
+use crate::backend::{Backend, House};
 
-enum LibraryFunction {
-    New(Box<dyn Fn(*const c_char) -> *mut c_void>),
-    Destroy(Box<dyn Fn(*mut c_void)>),
-    AddRoom(Box<dyn Fn(*mut c_void, *const c_char)>),
-    RemoveRoom(Box<dyn Fn(*mut c_void, *const c_char)>),
-    GetListRoomsName(Box<dyn Fn(*mut c_void, *mut *mut c_char, *mut usize)>),
-    FreeStringList(Box<dyn Fn(*mut c_char, usize)>),
+// Код статуса `SmartHouseStatus` библиотеки, 0 - успех.
+type Status = c_int;
 
-    RemoveDevice(Box<dyn Fn(*mut c_void, *const c_char, *const c_char)>),
-    GetListDevicesName(Box<dyn Fn(*mut c_void, *const c_char, *mut *mut c_char, *mut usize)>),
-    Report(Box<dyn Fn(*mut c_void) -> *const c_char>),
-    AddTestDeviceOutlet(Box<dyn Fn(*mut c_void, *const c_char, *const c_char, *const c_char)>),
+/// `SmartHouseStatus` codes of `smart_house.h`, see [`LibraryError::status`].
+// сервер различает не все коды
+#[allow(dead_code)]
+pub mod status {
+    pub const OK: i32 = 0;
+    pub const NULL_POINTER: i32 = 1;
+    pub const INVALID_STRING: i32 = 2;
+    pub const NOT_FOUND: i32 = 3;
+    pub const ALREADY_EXISTS: i32 = 4;
+    pub const UNSUPPORTED: i32 = 5;
+    pub const PANIC: i32 = 6;
+    pub const INVALID_ARGUMENT: i32 = 7;
 }
 
-#[allow(dead_code)]
-pub struct SmartHouseLib {
-    lib: *mut c_void,
-    commands: HashMap<String, LibraryFunction>,
-    library: Arc<Library>,
+/// Version of the C ABI (`SMART_HOUSE_ABI_VERSION` of `smart_house.h`) this wrapper is written for.
+pub const ABI_VERSION: u32 = 5;
+
+/// Failed load of the smart house library.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The library file can't be opened.
+    Open(libloading::Error),
+    /// The library doesn't export a function of the C ABI.
+    MissingSymbol {
+        name: &'static str,
+        source: libloading::Error,
+    },
+    /// The library is built for another version of the C ABI.
+    AbiMismatch { found: u32, expected: u32 },
 }
 
-unsafe impl Send for SmartHouseLib {}
-unsafe impl Sync for SmartHouseLib {}
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LoadError::Open(_) => write!(f, "Cannot open libsmart_house"),
+            LoadError::MissingSymbol { name, .. } => {
+                write!(f, "libsmart_house has no function {}", name)
+            }
+            LoadError::AbiMismatch { found, expected } => write!(
+                f,
+                "libsmart_house ABI version {}, expected {}",
+                found, expected
+            ),
+        }
+    }
+}
 
-fn str2c_char(text: &str) -> *const c_char {
-    let c_str = CString::new(text).expect("CString::new failed");
-    let ptr = c_str.as_ptr() as *const c_char;
-    mem::forget(c_str);
-    ptr
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Open(source) | LoadError::MissingSymbol { source, .. } => Some(source),
+            LoadError::AbiMismatch { .. } => None,
+        }
+    }
 }
 
-fn c_char2str(text: *const c_char) -> String {
-    unsafe { CString::from_raw(text as *mut c_char) }
-        .to_str()
-        .expect("Failed to convert CString to &str")
-        .to_string()
+/// Failed call of the smart house library.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LibraryError {
+    /// `SmartHouseStatus` code returned by the library.
+    pub status: i32,
+    /// `smart_house_last_error` message.
+    pub message: String,
 }
 
-fn readbuf(buffer: *mut i8, size: usize) -> Vec<String> {
-    let string_ptrs = unsafe { std::slice::from_raw_parts(buffer as *mut *mut c_char, size) };
-    string_ptrs
-        .iter()
-        .map(|&s| unsafe {
-            CStr::from_ptr(s as *const c_char)
-                .to_str()
-                .unwrap()
-                .to_owned()
-        })
-        .collect()
+impl std::fmt::Display for LibraryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} (status {})", self.message, self.status)
+    }
 }
 
-impl SmartHouseLib {
-    pub fn new(library_path: String, name: String) -> Self {
-        let mut commands = HashMap::new();
-
-        let library =
-            Arc::new(unsafe { Library::new(library_path).expect("Failed to load library") });
-        // New
-        {
-            let library_clone = Arc::clone(&library);
-            commands.insert("new".to_string(), LibraryFunction::New(Box::new(move |n| {
-          let new_func: Symbol<unsafe extern "C" fn(*const c_char) -> *mut c_void> = unsafe {
-              library_clone.get(b"new\0").expect("Failed to load create_struct function")
-          };
-          unsafe { new_func(n) }
-      })));
-        }
-        // Destroy
-        {
-            let library_clone = Arc::clone(&library);
-            commands.insert(
-                "destroy".to_string(),
-                LibraryFunction::Destroy(Box::new(move |v| {
-                    let destroy: Symbol<unsafe extern "C" fn(*mut c_void)> = unsafe {
-                        library_clone
-                            .get(b"destroy\0")
-                            .expect("Failed to load create_struct function")
-                    };
-                    unsafe { destroy(v) }
-                })),
-            );
-        }
-        // AddRoom
-        {
-            let library_clone: Arc<Library> = Arc::clone(&library);
-            commands.insert("add_room".to_string(), LibraryFunction::AddRoom(Box::new(move |v, n| {
-        let add_room: Symbol<unsafe extern "C" fn(*mut c_void, *const c_char)> = unsafe {
-          library_clone.get(b"add_room\0").expect("Failed to load create_struct function")
-        };
-        unsafe { add_room(v, n) }
-      })));
-        }
-        // RemoveRoom
-        {
-            let library_clone = Arc::clone(&library);
-            commands.insert("remove_room".to_string(), LibraryFunction::RemoveRoom(Box::new(move |v, n| {
-        let remove_room: Symbol<unsafe extern "C" fn(*mut c_void, *const c_char)> = unsafe {
-          library_clone.get(b"remove_room\0").expect("Failed to load create_struct function")
-        };
-        unsafe { remove_room(v, n) }
-      })));
-        }
-        // GetListRoomsName
-        {
-            let library_clone = Arc::clone(&library);
-            commands.insert(
-                "get_list_rooms_name".to_string(),
-                LibraryFunction::GetListRoomsName(Box::new(move |v, b, s| {
-                    let get_list_rooms_name: Symbol<
-                        unsafe extern "C" fn(*mut c_void, *mut *mut c_char, *mut usize),
-                    > = unsafe {
-                        library_clone
-                            .get(b"get_list_rooms_name\0")
-                            .expect("Failed to load create_struct function")
-                    };
-                    unsafe { get_list_rooms_name(v, b, s) }
-                })),
-            );
-        }
-        // FreeStringList
-        {
-            let library_clone = Arc::clone(&library);
-            commands.insert("free_string_list".to_string(), LibraryFunction::FreeStringList(Box::new(move |b,s| {
-        let free_string_list: Symbol<unsafe extern "C" fn(*mut c_char, usize)> = unsafe {
-          library_clone.get(b"free_string_list\0").expect("Failed to load create_struct function")
-        };
-        unsafe { free_string_list(b, s) }
-      })));
-        }
-        // RemoveDevice(Box<dyn Fn(*mut c_void, *const c_char, *const c_char)>),
-        {
-            let library_clone = Arc::clone(&library);
-            commands.insert(
-                "remove_device".to_string(),
-                LibraryFunction::RemoveDevice(Box::new(move |v, r, n| {
-                    let remove_device: Symbol<
-                        unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char),
-                    > = unsafe {
-                        library_clone
-                            .get(b"remove_device\0")
-                            .expect("Failed to load create_struct function")
-                    };
-                    unsafe { remove_device(v, r, n) }
-                })),
-            );
-        }
-        //GetListDevicesName(Box<dyn Fn(*mut c_void, *const c_char, *mut *mut c_char, *mut usize)>),
-        {
-            let library_clone = Arc::clone(&library);
-            commands.insert(
-                "get_list_devices_name".to_string(),
-                LibraryFunction::GetListDevicesName(Box::new(move |v, r, b, s| {
-                    let get_list_devices_name: Symbol<
-                        unsafe extern "C" fn(
-                            *mut c_void,
-                            *const c_char,
-                            *mut *mut c_char,
-                            *mut usize,
-                        ),
-                    > = unsafe {
-                        library_clone
-                            .get(b"get_list_devices_name\0")
-                            .expect("Failed to load create_struct function")
-                    };
-                    unsafe { get_list_devices_name(v, r, b, s) }
-                })),
-            );
-        }
-        //Report(Box<dyn Fn(*mut c_void) -> *const c_char>),
-        {
-            let library_clone = Arc::clone(&library);
-            commands.insert("report".to_string(), LibraryFunction::Report(Box::new(move |v| {
-          let report: Symbol<unsafe extern "C" fn(*mut c_void) -> *const c_char> = unsafe {
-              library_clone.get(b"report\0").expect("Failed to load create_struct function")
-          };
-          unsafe { report(v) }
-      })));
+impl std::error::Error for LibraryError {}
+
+// Функции библиотеки с сигнатурами из `smart_house.h`, найденные один раз при загрузке.
+// Указатели действительны, пока жива `_library`.
+struct Api {
+    new: unsafe extern "C" fn(*const c_char, *mut *mut c_void) -> Status,
+    import: unsafe extern "C" fn(*const c_char, *mut *mut c_void) -> Status,
+    export: unsafe extern "C" fn(*mut c_void, *mut *mut c_char) -> Status,
+    destroy: unsafe extern "C" fn(*mut c_void) -> Status,
+    add_room: unsafe extern "C" fn(*mut c_void, *const c_char) -> Status,
+    remove_room: unsafe extern "C" fn(*mut c_void, *const c_char) -> Status,
+    list_rooms: unsafe extern "C" fn(*mut c_void, *mut *mut *mut c_char, *mut usize) -> Status,
+    remove_device: unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char) -> Status,
+    list_devices: unsafe extern "C" fn(
+        *mut c_void,
+        *const c_char,
+        *mut *mut *mut c_char,
+        *mut usize,
+    ) -> Status,
+    add_test_device_outlet:
+        unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char, *const c_char) -> Status,
+    report: unsafe extern "C" fn(*mut c_void, *mut *mut c_char) -> Status,
+    free_string: unsafe extern "C" fn(*mut c_char) -> Status,
+    free_string_list: unsafe extern "C" fn(*mut *mut c_char, usize) -> Status,
+    last_error: unsafe extern "C" fn() -> *const c_char,
+    _library: Library,
+}
+
+// Указатель на функцию `name` библиотеки, `T` должен совпадать с её сигнатурой в `smart_house.h`.
+unsafe fn symbol<T: Copy>(library: &Library, name: &'static str) -> Result<T, LoadError> {
+    library
+        .get::<T>(name.as_bytes())
+        .map(|symbol| *symbol)
+        .map_err(|source| LoadError::MissingSymbol { name, source })
+}
+
+impl Api {
+    fn load(path: &OsStr) -> Result<Self, LoadError> {
+        // SAFETY: загружаем только libsmart_house, её конструкторы ничего не делают.
+        let library = unsafe { Library::new(path) }.map_err(LoadError::Open)?;
+        // до любых других вызовов убеждаемся, что библиотека той же версии ABI
+        let abi_version: unsafe extern "C" fn() -> u32 =
+            unsafe { symbol(&library, "smart_house_abi_version")? };
+        let found = unsafe { abi_version() };
+        if found != ABI_VERSION {
+            return Err(LoadError::AbiMismatch {
+                found,
+                expected: ABI_VERSION,
+            });
         }
-        //AddTestDeviceOutlet(Box<dyn Fn(*mut c_void, *const c_char, *const c_char, *const c_char)>)
-        {
-            let library_clone: Arc<Library> = Arc::clone(&library);
-            commands.insert(
-                "add_test_device_outlet".to_string(),
-                LibraryFunction::AddTestDeviceOutlet(Box::new(move |v, r, n, d| {
-                    let add_test_device_outlet: Symbol<
-                        unsafe extern "C" fn(
-                            *mut c_void,
-                            *const c_char,
-                            *const c_char,
-                            *const c_char,
-                        ),
-                    > = unsafe {
-                        library_clone
-                            .get(b"add_test_device_outlet\0")
-                            .expect("Failed to load create_struct function")
-                    };
-                    unsafe { add_test_device_outlet(v, r, n, d) }
-                })),
-            );
+        // SAFETY: сигнатуры полей `Api` повторяют `smart_house.h` версии `ABI_VERSION`.
+        unsafe {
+            Ok(Api {
+                new: symbol(&library, "smart_house_new")?,
+                import: symbol(&library, "smart_house_import")?,
+                export: symbol(&library, "smart_house_export")?,
+                destroy: symbol(&library, "smart_house_destroy")?,
+                add_room: symbol(&library, "smart_house_add_room")?,
+                remove_room: symbol(&library, "smart_house_remove_room")?,
+                list_rooms: symbol(&library, "smart_house_list_rooms")?,
+                remove_device: symbol(&library, "smart_house_remove_device")?,
+                list_devices: symbol(&library, "smart_house_list_devices")?,
+                add_test_device_outlet: symbol(&library, "smart_house_add_test_device_outlet")?,
+                report: symbol(&library, "smart_house_report")?,
+                free_string: symbol(&library, "smart_house_free_string")?,
+                free_string_list: symbol(&library, "smart_house_free_string_list")?,
+                last_error: symbol(&library, "smart_house_last_error")?,
+                _library: library,
+            })
         }
+    }
+}
 
-        let lib = {
-            let new_func = match commands.get("new").unwrap() {
-                LibraryFunction::New(f) => f,
-                _ => panic!("Invalid function type"),
-            };
+/// Loaded libsmart_house, cheap to clone and share between threads.
+#[derive(Clone)]
+pub struct SmartHouseLibrary {
+    api: Arc<Api>,
+}
 
-            new_func(str2c_char(name.as_str()))
-        };
+impl SmartHouseLibrary {
+    /// Opens the library, checks its ABI version and resolves all functions.
+    pub fn load(path: impl AsRef<OsStr>) -> Result<Self, LoadError> {
+        Ok(SmartHouseLibrary {
+            api: Arc::new(Api::load(path.as_ref())?),
+        })
+    }
 
-        SmartHouseLib {
-            lib,
-            commands,
-            library,
-        }
+    // Дом, который `smart_house_new` или `smart_house_import` записали в `lib`.
+    fn wrap(&self, status: Status, lib: *mut c_void) -> Result<Box<dyn House>, LibraryError> {
+        check(&self.api, status)?;
+        let handle = NonNull::new(lib).ok_or_else(|| LibraryError {
+            status: status::NULL_POINTER,
+            message: "libsmart_house returned no house".to_string(),
+        })?;
+        Ok(Box::new(SmartHouseLib {
+            handle: HouseHandle(handle),
+            api: Arc::clone(&self.api),
+        }))
     }
+}
 
-    pub fn add_room(&self, name: String) {
-        let add_room_func = match self.commands.get("add_room").unwrap() {
-            LibraryFunction::AddRoom(f) => f,
-            _ => panic!("Invalid function type"),
-        };
+impl Backend for SmartHouseLibrary {
+    fn create(&self, name: String) -> Result<Box<dyn House>, LibraryError> {
+        let name = str2c_char(name.as_str())?;
+        let mut lib = std::ptr::null_mut();
+        let status = unsafe { (self.api.new)(name.as_ptr(), &mut lib) };
+        self.wrap(status, lib)
+    }
 
-        add_room_func(self.lib, str2c_char(name.as_str()));
+    fn import(&self, snapshot: &str) -> Result<Box<dyn House>, LibraryError> {
+        let snapshot = str2c_char(snapshot)?;
+        let mut lib = std::ptr::null_mut();
+        let status = unsafe { (self.api.import)(snapshot.as_ptr(), &mut lib) };
+        self.wrap(status, lib)
     }
+}
 
-    pub fn remove_room(&self, name: String) {
-        let remove_room_func = match self.commands.get("remove_room").unwrap() {
-            LibraryFunction::RemoveRoom(f) => f,
-            _ => panic!("Invalid function type"),
-        };
+// Дом `SmartHouseLib *` библиотеки.
+struct HouseHandle(NonNull<c_void>);
+
+// SAFETY: `Api::load` принимает только библиотеку версии `ABI_VERSION` (5). Начиная с ABI 4
+// её дом сам берёт блокировку на каждый вызов и может использоваться и уничтожаться из любого
+// потока. Если `ABI_VERSION` сменится, это нужно проверить заново.
+unsafe impl Send for HouseHandle {}
+unsafe impl Sync for HouseHandle {}
+
+/// House of the loaded library, destroyed on drop.
+pub struct SmartHouseLib {
+    handle: HouseHandle,
+    api: Arc<Api>,
+}
 
-        remove_room_func(self.lib, str2c_char(name.as_str()));
+// Строка, которую библиотека не может принять или вернула испорченной.
+fn invalid_argument(message: String) -> LibraryError {
+    LibraryError {
+        status: status::INVALID_ARGUMENT,
+        message,
     }
+}
 
-    pub fn get_list_rooms_name(&self) -> Vec<String> {
-        let mut buffer: *mut c_char = std::ptr::null_mut();
-        let mut size: usize = 0;
+// Библиотека только заимствует входные строки, они живут до конца вызова.
+fn str2c_char(text: &str) -> Result<CString, LibraryError> {
+    CString::new(text).map_err(|_| invalid_argument(format!("String {:?} contains nul", text)))
+}
 
-        let get_list_rooms_name_func = match self.commands.get("get_list_rooms_name").unwrap() {
-            LibraryFunction::GetListRoomsName(f) => f,
-            _ => panic!("Invalid function type"),
-        };
-        let free_string_list_func = match self.commands.get("free_string_list").unwrap() {
-            LibraryFunction::FreeStringList(f) => f,
-            _ => panic!("Invalid function type"),
-        };
+// Копирует строку библиотеки, освобождать её должна сама библиотека.
+fn c_char2str(text: *const c_char) -> Result<String, LibraryError> {
+    unsafe { CStr::from_ptr(text) }
+        .to_str()
+        .map(str::to_string)
+        .map_err(|e| invalid_argument(format!("libsmart_house returned invalid UTF-8: {}", e)))
+}
 
-        get_list_rooms_name_func(self.lib, &mut buffer, &mut size);
+fn readbuf(buffer: *mut *mut c_char, size: usize) -> Result<Vec<String>, LibraryError> {
+    let string_ptrs = unsafe { std::slice::from_raw_parts(buffer, size) };
+    string_ptrs
+        .iter()
+        .map(|&s| c_char2str(s as *const c_char))
+        .collect()
+}
+
+// Переводит код статуса библиотеки в `Result` с текстом последней ошибки.
+fn check(api: &Api, status: Status) -> Result<(), LibraryError> {
+    if status == status::OK {
+        return Ok(());
+    }
+    let message = unsafe { (api.last_error)() };
+    let message = if message.is_null() {
+        "unknown error".to_string()
+    } else {
+        // текст ошибки нужен и в испорченном виде
+        unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    Err(LibraryError { status, message })
+}
 
+impl SmartHouseLib {
+    fn lib(&self) -> *mut c_void {
+        self.handle.0.as_ptr()
+    }
+
+    fn check(&self, status: Status) -> Result<(), LibraryError> {
+        check(&self.api, status)
+    }
+
+    // Копирует список строк библиотеки и возвращает его ей для освобождения.
+    fn read_string_list(
+        &self,
+        buffer: *mut *mut c_char,
+        size: usize,
+    ) -> Result<Vec<String>, LibraryError> {
+        // список возвращается библиотеке, даже если строки не прочитались
         let vec_str = readbuf(buffer, size);
 
-        free_string_list_func(buffer, size);
+        self.check(unsafe { (self.api.free_string_list)(buffer, size) })?;
 
         vec_str
     }
+}
 
-    pub fn remove_device(&self, room: String, name: String) {
-        let remove_device_func = match self.commands.get("remove_room").unwrap() {
-            LibraryFunction::RemoveDevice(f) => f,
-            _ => panic!("Invalid function type"),
-        };
+impl House for SmartHouseLib {
+    fn add_room(&self, name: String) -> Result<(), LibraryError> {
+        let name = str2c_char(name.as_str())?;
+        self.check(unsafe { (self.api.add_room)(self.lib(), name.as_ptr()) })
+    }
 
-        remove_device_func(
-            self.lib,
-            str2c_char(room.as_str()),
-            str2c_char(name.as_str()),
-        );
+    fn remove_room(&self, name: String) -> Result<(), LibraryError> {
+        let name = str2c_char(name.as_str())?;
+        self.check(unsafe { (self.api.remove_room)(self.lib(), name.as_ptr()) })
     }
 
-    pub fn get_list_devices_name(&self, room: String) -> Vec<String> {
-        let mut buffer: *mut c_char = std::ptr::null_mut();
+    fn get_list_rooms_name(&self) -> Result<Vec<String>, LibraryError> {
+        let mut buffer: *mut *mut c_char = std::ptr::null_mut();
         let mut size: usize = 0;
 
-        let get_list_devices_name_func = match self.commands.get("get_list_devices_name").unwrap() {
-            LibraryFunction::GetListDevicesName(f) => f,
-            _ => panic!("Invalid function type"),
-        };
-        let free_string_list_func = match self.commands.get("free_string_list").unwrap() {
-            LibraryFunction::FreeStringList(f) => f,
-            _ => panic!("Invalid function type"),
-        };
+        self.check(unsafe { (self.api.list_rooms)(self.lib(), &mut buffer, &mut size) })?;
 
-        get_list_devices_name_func(self.lib, str2c_char(room.as_str()), &mut buffer, &mut size);
+        self.read_string_list(buffer, size)
+    }
 
-        let vec_str = readbuf(buffer, size);
+    fn remove_device(&self, room: String, name: String) -> Result<(), LibraryError> {
+        let room = str2c_char(room.as_str())?;
+        let name = str2c_char(name.as_str())?;
+        self.check(unsafe { (self.api.remove_device)(self.lib(), room.as_ptr(), name.as_ptr()) })
+    }
 
-        free_string_list_func(buffer, size);
+    fn get_list_devices_name(&self, room: String) -> Result<Vec<String>, LibraryError> {
+        let mut buffer: *mut *mut c_char = std::ptr::null_mut();
+        let mut size: usize = 0;
 
-        vec_str
-    }
+        let room = str2c_char(room.as_str())?;
+        self.check(unsafe {
+            (self.api.list_devices)(self.lib(), room.as_ptr(), &mut buffer, &mut size)
+        })?;
 
-    pub fn report(&self) -> String {
-        let report_func = match self.commands.get("report").unwrap() {
-            LibraryFunction::Report(f) => f,
-            _ => panic!("Invalid function type"),
-        };
+        self.read_string_list(buffer, size)
+    }
 
-        c_char2str(report_func(self.lib))
+    fn report(&self) -> Result<String, LibraryError> {
+        let mut text = std::ptr::null_mut();
+        self.check(unsafe { (self.api.report)(self.lib(), &mut text) })?;
+        let report = c_char2str(text);
+        self.check(unsafe { (self.api.free_string)(text) })?;
+        report
     }
 
-    pub fn add_test_device_outlet(&self, room: String, name: String, description: String) {
-        let add_test_device_outlet_func = match self.commands.get("add_test_device_outlet").unwrap()
-        {
-            LibraryFunction::AddTestDeviceOutlet(f) => f,
-            _ => panic!("Invalid function type"),
-        };
+    fn export(&self) -> Result<String, LibraryError> {
+        let mut json = std::ptr::null_mut();
+        self.check(unsafe { (self.api.export)(self.lib(), &mut json) })?;
+        let snapshot = c_char2str(json);
+        self.check(unsafe { (self.api.free_string)(json) })?;
+        snapshot
+    }
 
-        add_test_device_outlet_func(
-            self.lib,
-            str2c_char(room.as_str()),
-            str2c_char(name.as_str()),
-            str2c_char(description.as_str()),
-        );
+    fn add_test_device_outlet(
+        &self,
+        room: String,
+        name: String,
+        description: String,
+    ) -> Result<(), LibraryError> {
+        let room = str2c_char(room.as_str())?;
+        let name = str2c_char(name.as_str())?;
+        let description = str2c_char(description.as_str())?;
+        self.check(unsafe {
+            (self.api.add_test_device_outlet)(
+                self.lib(),
+                room.as_ptr(),
+                name.as_ptr(),
+                description.as_ptr(),
+            )
+        })
     }
 }
 
 impl Drop for SmartHouseLib {
     fn drop(&mut self) {
-        let destroy_func = match self.commands.get("destroy").unwrap() {
-            LibraryFunction::Destroy(f) => f,
-            _ => panic!("Invalid function type"),
-        };
-        destroy_func(self.lib);
+        let _ = unsafe { (self.api.destroy)(self.lib()) };
     }
 }
 
@@ -343,77 +368,204 @@ mod tests {
     #[warn(unused_imports)]
     use super::*;
 
+    const LIBRARY_PATH: &str = "libs/libsmart_house.so";
+
+    fn house(name: &str) -> Box<dyn House> {
+        SmartHouseLibrary::load(LIBRARY_PATH)
+            .unwrap()
+            .create(name.to_string())
+            .unwrap()
+    }
+
+    fn sorted(mut names: Vec<String>) -> Vec<String> {
+        names.sort();
+        names
+    }
+
     #[test]
     fn test_add_rooms() {
-        let library_path = "libs/libsmart_house.so";
+        let my_struct = house("тестовая");
 
-        let my_struct = SmartHouseLib::new(library_path.to_string(), "тестовая".to_string());
+        my_struct.add_room("комната 1".to_string()).unwrap();
 
-        my_struct.add_room("комната 1".to_string());
-
-        my_struct.add_room("комната 2".to_string());
+        my_struct.add_room("комната 2".to_string()).unwrap();
 
         assert_eq!(
-            my_struct.get_list_rooms_name().sort(),
-            vec!["комната 1".to_string(), "комната 2".to_string()].sort()
+            sorted(my_struct.get_list_rooms_name().unwrap()),
+            vec!["комната 1".to_string(), "комната 2".to_string()]
         );
 
-        my_struct.remove_room("комната 1".to_string());
+        my_struct.remove_room("комната 1".to_string()).unwrap();
 
         assert_eq!(
-            my_struct.get_list_rooms_name().sort(),
-            vec!["комната 2".to_string()].sort()
+            my_struct.get_list_rooms_name().unwrap(),
+            vec!["комната 2".to_string()]
         );
     }
 
     #[test]
     fn test_add_devices() {
-        let library_path = "libs/libsmart_house.so";
-
-        let my_struct = SmartHouseLib::new(library_path.to_string(), "тестовая".to_string());
+        let my_struct = house("тестовая");
 
         let room_name = "комната 1".to_string();
 
-        my_struct.add_room(room_name.clone());
+        my_struct.add_room(room_name.clone()).unwrap();
 
-        my_struct.add_test_device_outlet(
-            room_name.clone(),
-            "устройство 1".to_string(),
-            "тестовое устройство 1".to_string(),
-        );
+        my_struct
+            .add_test_device_outlet(
+                room_name.clone(),
+                "устройство 1".to_string(),
+                "тестовое устройство 1".to_string(),
+            )
+            .unwrap();
 
-        my_struct.add_test_device_outlet(
-            room_name.clone(),
-            "устройство 2".to_string(),
-            "тестовое устройство 2".to_string(),
+        my_struct
+            .add_test_device_outlet(
+                room_name.clone(),
+                "устройство 2".to_string(),
+                "тестовое устройство 2".to_string(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            sorted(my_struct.get_list_devices_name(room_name.clone()).unwrap()),
+            vec!["устройство 1".to_string(), "устройство 2".to_string()]
         );
 
-        let mut test = my_struct.get_list_devices_name(room_name.clone());
+        my_struct
+            .remove_device(room_name.clone(), "устройство 1".to_string())
+            .unwrap();
 
         assert_eq!(
-            test.sort(),
-            vec!["устройство 1".to_string(), "устройство 2".to_string()].sort()
+            my_struct.get_list_devices_name(room_name).unwrap(),
+            vec!["устройство 2".to_string()]
         );
     }
 
     #[test]
     fn test_report() {
-        let library_path = "libs/libsmart_house.so";
-
-        let my_struct = SmartHouseLib::new(library_path.to_string(), "тестовая".to_string());
+        let my_struct = house("тестовая");
 
         let room_name = "комната 1".to_string();
 
-        my_struct.add_room(room_name.clone());
+        my_struct.add_room(room_name.clone()).unwrap();
 
-        my_struct.add_test_device_outlet(
-            room_name.clone(),
-            "устройство 1".to_string(),
-            "тестовое устройство 1".to_string(),
-        );
+        my_struct
+            .add_test_device_outlet(
+                room_name.clone(),
+                "устройство 1".to_string(),
+                "тестовое устройство 1".to_string(),
+            )
+            .unwrap();
 
-        let test = my_struct.report();
+        let test = my_struct.report().unwrap();
 
         assert_eq!(test, "Name: тестовая,\nRooms:\n[\n{\nName: комната 1,\nDevices:\n[\n{\nName: устройство 1,\nOn: false,\nDescription: тестовое устройство 1,\nPower: 0\n},\n]\n},\n]".to_string());
     }
+
+    #[test]
+    fn test_errors() {
+        let my_struct = house("тестовая");
+
+        let error = my_struct
+            .get_list_devices_name("нет такой".to_string())
+            .unwrap_err();
+        assert_eq!(error.status, status::NOT_FOUND);
+        assert_eq!(error.message, "Cannot get the room named \"нет такой\"");
+
+        assert!(my_struct
+            .remove_device("нет такой".to_string(), "устройство".to_string())
+            .is_err());
+
+        // строку с нулевым байтом не передать в C
+        let error = my_struct.add_room("комната\0 1".to_string()).unwrap_err();
+        assert_eq!(error.status, status::INVALID_ARGUMENT);
+        assert_eq!(
+            my_struct.get_list_rooms_name().unwrap(),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let library = SmartHouseLibrary::load(LIBRARY_PATH).unwrap();
+        let my_struct = library.create("тестовая".to_string()).unwrap();
+        my_struct.add_room("комната 1".to_string()).unwrap();
+        my_struct
+            .add_test_device_outlet(
+                "комната 1".to_string(),
+                "устройство 1".to_string(),
+                "тестовое устройство 1".to_string(),
+            )
+            .unwrap();
+
+        let imported = library.import(&my_struct.export().unwrap()).unwrap();
+        assert_eq!(imported.report().unwrap(), my_struct.report().unwrap());
+
+        let error = library.import("не снимок").err().unwrap();
+        assert_eq!(error.status, status::INVALID_ARGUMENT);
+    }
+
+    // коды совпадают с `SmartHouseStatus`, который видно при статической сборке
+    #[cfg(feature = "static-link")]
+    #[test]
+    fn test_status_codes() {
+        use smart_house::ffi::SmartHouseStatus;
+
+        for (code, expected) in [
+            (status::OK, SmartHouseStatus::Ok),
+            (status::NULL_POINTER, SmartHouseStatus::NullPointer),
+            (status::INVALID_STRING, SmartHouseStatus::InvalidString),
+            (status::NOT_FOUND, SmartHouseStatus::NotFound),
+            (status::ALREADY_EXISTS, SmartHouseStatus::AlreadyExists),
+            (status::UNSUPPORTED, SmartHouseStatus::Unsupported),
+            (status::PANIC, SmartHouseStatus::Panic),
+            (status::INVALID_ARGUMENT, SmartHouseStatus::InvalidArgument),
+        ] {
+            assert_eq!(code, expected as i32);
+        }
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let error = SmartHouseLibrary::load("libs/нет такой.so").err().unwrap();
+        assert!(matches!(error, LoadError::Open(_)));
+
+        // библиотека без C ABI умного дома
+        let error = SmartHouseLibrary::load("libc.so.6").err().unwrap();
+        assert!(matches!(
+            error,
+            LoadError::MissingSymbol {
+                name: "smart_house_abi_version",
+                ..
+            }
+        ));
+        assert_eq!(
+            error.to_string(),
+            "libsmart_house has no function smart_house_abi_version"
+        );
+    }
+
+    #[test]
+    fn test_shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SmartHouseLibrary>();
+        assert_send_sync::<SmartHouseLib>();
+
+        let library = SmartHouseLibrary::load(LIBRARY_PATH).unwrap();
+        let my_struct = Arc::new(library.create("тестовая".to_string()).unwrap());
+        // библиотека остаётся загруженной, пока жив хотя бы один дом
+        drop(library);
+
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let my_struct = my_struct.clone();
+                std::thread::spawn(move || my_struct.add_room(format!("комната {}", i)))
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+        assert_eq!(my_struct.get_list_rooms_name().unwrap().len(), 4);
+    }
 }
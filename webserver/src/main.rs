@@ -1,7 +1,13 @@
+mod backend;
 mod c_lib;
+#[cfg(feature = "static-link")]
+mod static_lib;
+mod store;
 
 use anyhow::Context;
 
+use std::sync::{Arc, RwLock};
+
 use time::Duration;
 
 use tracing::{debug, info};
@@ -22,23 +28,36 @@ use axum::{
     extract::Json,
     http::{Request, StatusCode},
     middleware::{self, Next},
-    response::{Html, IntoResponse},
+    response::{Html, IntoResponse, Response},
     BoxError, Extension,
 };
+use clap::Parser;
 use schemars::JsonSchema;
 use serde::Deserialize;
 use tower::ServiceBuilder;
 use tower_http::{services::ServeDir, trace::TraceLayer};
 use tower_sessions::{Expiry, MemoryStore, Session, SessionManagerLayer};
 
-use c_lib::{SmartHouseLib, SMARTHOUSESTORE};
+use c_lib::{status, LibraryError};
+use store::{HouseStore, SharedStore};
 
 use serde_json::json;
 use uuid::Uuid;
 
 const LIBRARY_PATH: &str = "libs/libsmart_house.so";
+// как часто проверять, не заменили ли файл библиотеки
+const RELOAD_PERIOD: std::time::Duration = std::time::Duration::from_secs(2);
 const SMARTHOUSE_KEY: &str = "SmartHouse";
 
+/// Web interface of the smart house.
+#[derive(Parser)]
+struct Args {
+    /// Load libs/libsmart_house.so and reload it on changes instead of the linked smart_house.
+    #[cfg(feature = "static-link")]
+    #[arg(long)]
+    dynamic: bool,
+}
+
 // We'll need to derive `JsonSchema` for
 // all types that appear in the api documentation.
 #[derive(Deserialize, JsonSchema)]
@@ -78,74 +97,118 @@ where
     next.run(req).await
 }
 
+// Ответ API на ошибку библиотеки умного дома, код HTTP по коду `SmartHouseStatus`.
+fn library_error(error: LibraryError) -> Response {
+    let code = match error.status {
+        status::NULL_POINTER | status::INVALID_STRING | status::INVALID_ARGUMENT => {
+            StatusCode::BAD_REQUEST
+        }
+        status::NOT_FOUND => StatusCode::NOT_FOUND,
+        status::ALREADY_EXISTS => StatusCode::CONFLICT,
+        status::UNSUPPORTED => StatusCode::UNPROCESSABLE_ENTITY,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (
+        code,
+        Json(json!({ "status": error.status, "error": error.message })),
+    )
+        .into_response()
+}
+
+fn json_or_error<T: serde::Serialize>(result: Result<T, LibraryError>) -> Response {
+    match result {
+        Ok(value) => Json(json!(value)).into_response(),
+        Err(error) => library_error(error),
+    }
+}
+
 async fn add_room(
     Extension(key): Extension<String>,
+    Extension(store): Extension<SharedStore>,
     Json(room): Json<Room>,
 ) -> impl IntoApiResponse {
-    let smart_house_store = SMARTHOUSESTORE.read().unwrap();
-
-    let smart_house_lib = smart_house_store.get(&key).expect("Can't get Smart House");
+    let store = store.read().unwrap();
 
-    smart_house_lib.add_room(room.name);
+    let smart_house_lib = store.house(&key).expect("Can't get Smart House");
 
-    Json(json!(smart_house_lib.get_list_rooms_name()))
+    json_or_error(
+        smart_house_lib
+            .add_room(room.name)
+            .and_then(|_| smart_house_lib.get_list_rooms_name()),
+    )
 }
 
 async fn remove_room(
     Extension(key): Extension<String>,
+    Extension(store): Extension<SharedStore>,
     Json(room): Json<Room>,
 ) -> impl IntoApiResponse {
-    let smart_house_store = SMARTHOUSESTORE.read().unwrap();
+    let store = store.read().unwrap();
 
-    let smart_house_lib = smart_house_store.get(&key).expect("Can't get Smart House");
+    let smart_house_lib = store.house(&key).expect("Can't get Smart House");
 
-    smart_house_lib.remove_room(room.name);
-
-    Json(json!(smart_house_lib.get_list_rooms_name()))
+    json_or_error(
+        smart_house_lib
+            .remove_room(room.name)
+            .and_then(|_| smart_house_lib.get_list_rooms_name()),
+    )
 }
 
 async fn add_device(
     Extension(key): Extension<String>,
+    Extension(store): Extension<SharedStore>,
     Json(device): Json<Device>,
 ) -> impl IntoApiResponse {
-    let smart_house_store = SMARTHOUSESTORE.read().unwrap();
-
-    let smart_house_lib = smart_house_store.get(&key).expect("Can't get Smart House");
+    let store = store.read().unwrap();
 
-    smart_house_lib.add_test_device_outlet(device.room.clone(), device.name.clone(), device.name);
+    let smart_house_lib = store.house(&key).expect("Can't get Smart House");
 
-    Json(json!(smart_house_lib.get_list_devices_name(device.room)))
+    json_or_error(
+        smart_house_lib
+            .add_test_device_outlet(device.room.clone(), device.name.clone(), device.name)
+            .and_then(|_| smart_house_lib.get_list_devices_name(device.room)),
+    )
 }
 
 async fn remove_device(
     Extension(key): Extension<String>,
+    Extension(store): Extension<SharedStore>,
     Json(device): Json<Device>,
 ) -> impl IntoApiResponse {
-    let smart_house_store = SMARTHOUSESTORE.read().unwrap();
+    let store = store.read().unwrap();
 
-    let smart_house_lib = smart_house_store.get(&key).expect("Can't get Smart House");
+    let smart_house_lib = store.house(&key).expect("Can't get Smart House");
 
-    smart_house_lib.remove_device(device.room.clone(), device.name);
-
-    Json(json!(smart_house_lib.get_list_devices_name(device.room)))
+    json_or_error(
+        smart_house_lib
+            .remove_device(device.room.clone(), device.name)
+            .and_then(|_| smart_house_lib.get_list_devices_name(device.room)),
+    )
 }
 
-async fn report(Extension(key): Extension<String>) -> impl IntoApiResponse {
-    let smart_house_store = SMARTHOUSESTORE.read().unwrap();
+async fn report(
+    Extension(key): Extension<String>,
+    Extension(store): Extension<SharedStore>,
+) -> impl IntoApiResponse {
+    let store = store.read().unwrap();
 
-    let smart_house_lib = smart_house_store.get(&key).expect("Can't get Smart House");
+    let smart_house_lib = store.house(&key).expect("Can't get Smart House");
 
-    format!("Smart_House {}", smart_house_lib.report())
+    match smart_house_lib.report() {
+        Ok(report) => format!("Smart_House {}", report).into_response(),
+        Err(error) => library_error(error),
+    }
 }
 
-async fn root(Extension(key): Extension<String>) -> impl IntoApiResponse {
-    if !SMARTHOUSESTORE.read().unwrap().contains_key(&key) {
+async fn root(
+    Extension(key): Extension<String>,
+    Extension(store): Extension<SharedStore>,
+) -> impl IntoApiResponse {
+    if store.read().unwrap().house(&key).is_none() {
         debug!("No lib found, creating a new one");
-        let mut smart_house_store_rw = SMARTHOUSESTORE.write().unwrap();
-        smart_house_store_rw.insert(
-            key.clone(),
-            SmartHouseLib::new(LIBRARY_PATH.to_string(), key.clone()),
-        );
+        if let Err(error) = store.write().unwrap().get_or_create(&key) {
+            return library_error(error);
+        }
     }
 
     let template = UITemplate {
@@ -176,8 +239,44 @@ async fn serve_api(Extension(api): Extension<OpenApi>) -> impl IntoApiResponse {
     Json(api)
 }
 
+// Маршруты API, дом сессии берётся из `Extension<String>` и `Extension<SharedStore>`.
+fn api_router() -> ApiRouter {
+    ApiRouter::new()
+        // Change `route` to `api_route` for the route
+        // we'd like to expose in the documentation.
+        .api_route("/add_room", post(add_room))
+        .api_route("/remove_room", post(remove_room))
+        .api_route("/add_device", post(add_device))
+        .api_route("/remove_device", post(remove_device))
+        .api_route("/report", post(report))
+}
+
+// Хранилище домов выбранной реализации: слинкованный smart_house или libsmart_house.
+#[cfg_attr(not(feature = "static-link"), allow(unused_variables))]
+fn house_store(args: &Args) -> anyhow::Result<SharedStore> {
+    #[cfg(feature = "static-link")]
+    if !args.dynamic {
+        info!("using the linked smart_house");
+        let backend = Box::new(static_lib::LinkedBackend);
+        return Ok(Arc::new(RwLock::new(HouseStore::new(backend))));
+    }
+
+    // без библиотеки сервер не запускается, её новые версии подхватываются на ходу
+    let library = store::load_copy(LIBRARY_PATH.as_ref())
+        .with_context(|| format!("cannot load {}", LIBRARY_PATH))?;
+    let store: SharedStore = Arc::new(RwLock::new(HouseStore::new(Box::new(library))));
+    tokio::spawn(store::watch(
+        store.clone(),
+        LIBRARY_PATH.into(),
+        RELOAD_PERIOD,
+    ));
+    Ok(store)
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
@@ -186,6 +285,8 @@ async fn main() -> anyhow::Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let store = house_store(&args)?;
+
     info!("initializing router...");
 
     let assets_path = std::env::current_dir().unwrap();
@@ -204,17 +305,8 @@ async fn main() -> anyhow::Result<()> {
         .layer(session_layer)
         .into_inner();
 
-    let api_router = ApiRouter::new()
-        // Change `route` to `api_route` for the route
-        // we'd like to expose in the documentation.
-        .api_route("/add_room", post(add_room))
-        .api_route("/remove_room", post(remove_room))
-        .api_route("/add_device", post(add_device))
-        .api_route("/remove_device", post(remove_device))
-        .api_route("/report", post(report));
-
     let middleware_router = ApiRouter::new()
-        .nest("/api/v1", api_router)
+        .nest("/api/v1", api_router())
         .route("/", get(root))
         .layer(middleware::from_fn(session_middleware));
 
@@ -247,6 +339,7 @@ async fn main() -> anyhow::Result<()> {
                 .finish_api(&mut api)
                 // Expose the documentation to the handlers.
                 .layer(Extension(api))
+                .layer(Extension(store))
                 .into_make_service(),
         )
         .await
@@ -254,3 +347,118 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    use axum::body::{Body, HttpBody};
+    use axum::Router;
+    use tower::ServiceExt;
+
+    use backend::Backend;
+
+    const KEY: &str = "сессия";
+
+    // Все реализации, собранные в этой сборке, проходят одни и те же тесты.
+    fn backends() -> Vec<(&'static str, Box<dyn Backend>)> {
+        vec![
+            (
+                "libsmart_house",
+                Box::new(store::load_copy(LIBRARY_PATH.as_ref()).unwrap()),
+            ),
+            #[cfg(feature = "static-link")]
+            ("linked", Box::new(static_lib::LinkedBackend)),
+        ]
+    }
+
+    fn app(backend: Box<dyn Backend>) -> Router {
+        let store: SharedStore = Arc::new(RwLock::new(HouseStore::new(backend)));
+        store.write().unwrap().get_or_create(KEY).unwrap();
+        Router::from(api_router())
+            .layer(Extension(store))
+            .layer(Extension(KEY.to_string()))
+    }
+
+    async fn call(app: &Router, uri: &str, body: serde_json::Value) -> (StatusCode, String) {
+        let request = Request::post(uri)
+            .header("Content-Type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let mut response = app.clone().oneshot(request).await.unwrap();
+        let mut text = Vec::new();
+        while let Some(chunk) = response.body_mut().data().await {
+            text.extend_from_slice(&chunk.unwrap());
+        }
+        (response.status(), String::from_utf8(text).unwrap())
+    }
+
+    fn sorted(json: &str) -> Vec<String> {
+        let mut names: Vec<String> = serde_json::from_str(json).unwrap();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn test_rooms() {
+        for (name, backend) in backends() {
+            let app = app(backend);
+
+            call(&app, "/add_room", json!({ "name": "комната 1" })).await;
+            let (status, rooms) = call(&app, "/add_room", json!({ "name": "комната 2" })).await;
+            assert_eq!(status, StatusCode::OK, "{}", name);
+            assert_eq!(sorted(&rooms), vec!["комната 1", "комната 2"], "{}", name);
+
+            let (status, rooms) = call(&app, "/remove_room", json!({ "name": "комната 1" })).await;
+            assert_eq!(status, StatusCode::OK, "{}", name);
+            assert_eq!(sorted(&rooms), vec!["комната 2"], "{}", name);
+
+            let (status, error) = call(&app, "/remove_room", json!({ "name": "нет такой" })).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", name);
+            let error: serde_json::Value = serde_json::from_str(&error).unwrap();
+            assert_eq!(error["status"], status::NOT_FOUND, "{}", name);
+            assert_eq!(
+                error["error"], "Cannot remove the room named \"нет такой\"",
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_devices() {
+        for (name, backend) in backends() {
+            let app = app(backend);
+            call(&app, "/add_room", json!({ "name": "комната 1" })).await;
+
+            for device in ["устройство 1", "устройство 2"] {
+                let device = json!({ "room": "комната 1", "name": device });
+                let (status, _) = call(&app, "/add_device", device).await;
+                assert_eq!(status, StatusCode::OK, "{}", name);
+            }
+            let device = json!({ "room": "комната 1", "name": "устройство 1" });
+            let (status, devices) = call(&app, "/remove_device", device).await;
+            assert_eq!(status, StatusCode::OK, "{}", name);
+            assert_eq!(sorted(&devices), vec!["устройство 2"], "{}", name);
+
+            let device = json!({ "room": "нет такой", "name": "устройство 1" });
+            let (status, _) = call(&app, "/add_device", device).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_report() {
+        for (name, backend) in backends() {
+            let app = app(backend);
+            call(&app, "/add_room", json!({ "name": "комната 1" })).await;
+            let device = json!({ "room": "комната 1", "name": "устройство 1" });
+            call(&app, "/add_device", device).await;
+
+            let (status, report) = call(&app, "/report", json!({})).await;
+            assert_eq!(status, StatusCode::OK, "{}", name);
+            assert_eq!(report, "Smart_House Name: сессия,\nRooms:\n[\n{\nName: комната 1,\nDevices:\n[\n{\nName: устройство 1,\nOn: false,\nDescription: устройство 1,\nPower: 0\n},\n]\n},\n]", "{}", name);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use smart_house::device::DeviceParams;
+use smart_house::ffi::SmartHouseStatus;
+use smart_house::house::{HouseSnapshot, SmartHouse, SmartHouseError};
+
+use crate::backend::{Backend, House};
+use crate::c_lib::LibraryError;
+
+/// `smart_house` linked into the server, used instead of libsmart_house with the
+/// `static-link` feature.
+pub struct LinkedBackend;
+
+/// House of the linked `smart_house`, locked for every call like the library handle.
+struct LinkedHouse {
+    house: Mutex<SmartHouse>,
+}
+
+// Ошибка дома с тем же кодом `SmartHouseStatus`, что вернула бы библиотека.
+fn house_error(error: SmartHouseError) -> LibraryError {
+    LibraryError {
+        status: SmartHouseStatus::from(&error) as i32,
+        message: error.to_string(),
+    }
+}
+
+impl Backend for LinkedBackend {
+    fn create(&self, name: String) -> Result<Box<dyn House>, LibraryError> {
+        Ok(Box::new(LinkedHouse {
+            house: Mutex::new(SmartHouse::new(name)),
+        }))
+    }
+
+    fn import(&self, snapshot: &str) -> Result<Box<dyn House>, LibraryError> {
+        let snapshot: HouseSnapshot =
+            serde_json::from_str(snapshot).map_err(|error| LibraryError {
+                status: SmartHouseStatus::InvalidArgument as i32,
+                message: format!("Invalid house snapshot: {}", error),
+            })?;
+        let house = SmartHouse::restore(snapshot).map_err(house_error)?;
+        Ok(Box::new(LinkedHouse {
+            house: Mutex::new(house),
+        }))
+    }
+}
+
+impl LinkedHouse {
+    // Паника в другом запросе не делает дом недоступным.
+    fn lock(&self) -> MutexGuard<'_, SmartHouse> {
+        self.house.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl House for LinkedHouse {
+    fn add_room(&self, name: String) -> Result<(), LibraryError> {
+        self.lock().add_room(name).map_err(house_error)?;
+        Ok(())
+    }
+
+    fn remove_room(&self, name: String) -> Result<(), LibraryError> {
+        self.lock().remove_room(name).map_err(house_error)?;
+        Ok(())
+    }
+
+    fn get_list_rooms_name(&self) -> Result<Vec<String>, LibraryError> {
+        Ok(self.lock().rooms().keys().cloned().collect())
+    }
+
+    fn remove_device(&self, room: String, name: String) -> Result<(), LibraryError> {
+        self.lock().remove_device(room, name).map_err(house_error)?;
+        Ok(())
+    }
+
+    fn get_list_devices_name(&self, room: String) -> Result<Vec<String>, LibraryError> {
+        let house = self.lock();
+        let names = house.devices(room).map_err(house_error)?;
+        Ok(names.into_iter().cloned().collect())
+    }
+
+    fn report(&self) -> Result<String, LibraryError> {
+        Ok(self.lock().report(None))
+    }
+
+    fn add_test_device_outlet(
+        &self,
+        room: String,
+        name: String,
+        description: String,
+    ) -> Result<(), LibraryError> {
+        let params = DeviceParams {
+            kind: "outlet".to_string(),
+            name,
+            description,
+            on: false,
+        };
+        self.lock()
+            .create_device(room, params)
+            .map_err(house_error)?;
+        Ok(())
+    }
+
+    fn export(&self) -> Result<String, LibraryError> {
+        Ok(serde_json::to_string(&self.lock().snapshot()).expect("house snapshot is serializable"))
+    }
+}
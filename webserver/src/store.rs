@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use std::fs::DirBuilder;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::backend::{Backend, House};
+use crate::c_lib::{LibraryError, LoadError, SmartHouseLibrary};
+
+/// Store shared by the handlers.
+pub type SharedStore = Arc<RwLock<HouseStore>>;
+
+/// Houses of the sessions and the backend they live in.
+pub struct HouseStore {
+    backend: Box<dyn Backend>,
+    houses: HashMap<String, Box<dyn House>>,
+    // номер загруженной версии библиотеки, растёт с каждой заменой
+    generation: u64,
+}
+
+/// Failed reload of the smart house library, the store keeps the previous one.
+#[derive(Debug)]
+pub enum ReloadError {
+    /// The new library can't be opened or fails its ABI check.
+    Load(LoadError),
+    /// The library file can't be copied for loading.
+    Copy(std::io::Error),
+    /// A house can't be moved into the new library.
+    Migrate { key: String, source: LibraryError },
+}
+
+impl std::fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ReloadError::Load(error) => write!(f, "{}", error),
+            ReloadError::Copy(_) => write!(f, "Cannot copy libsmart_house for loading"),
+            ReloadError::Migrate { key, source } => {
+                write!(
+                    f,
+                    "Cannot move the house {} to the new library: {}",
+                    key, source
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReloadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReloadError::Load(error) => Some(error),
+            ReloadError::Copy(error) => Some(error),
+            ReloadError::Migrate { source, .. } => Some(source),
+        }
+    }
+}
+
+impl HouseStore {
+    pub fn new(backend: Box<dyn Backend>) -> Self {
+        HouseStore {
+            backend,
+            houses: HashMap::new(),
+            generation: 1,
+        }
+    }
+
+    pub fn house(&self, key: &str) -> Option<&dyn House> {
+        self.houses.get(key).map(Box::as_ref)
+    }
+
+    /// Creates the house of the session `key` unless it exists.
+    pub fn get_or_create(&mut self, key: &str) -> Result<&dyn House, LibraryError> {
+        if !self.houses.contains_key(key) {
+            let house = self.backend.create(key.to_string())?;
+            self.houses.insert(key.to_string(), house);
+        }
+        Ok(self.houses[key].as_ref())
+    }
+
+    /// Version of the loaded library, 1 for the one the server started with.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Moves every house into `backend` through its snapshot and makes `backend` current.
+    /// On failure the houses stay in the current backend.
+    pub fn migrate(&mut self, backend: Box<dyn Backend>) -> Result<(), ReloadError> {
+        let mut houses = HashMap::with_capacity(self.houses.len());
+        for (key, house) in &self.houses {
+            let migrated = house
+                .export()
+                .and_then(|snapshot| backend.import(&snapshot))
+                .map_err(|source| ReloadError::Migrate {
+                    key: key.clone(),
+                    source,
+                })?;
+            houses.insert(key.clone(), migrated);
+        }
+        // старые дома уничтожаются до выгрузки старой библиотеки
+        self.houses = houses;
+        self.backend = backend;
+        self.generation += 1;
+        Ok(())
+    }
+}
+
+/// Loads the library from a private copy of `path`, so that replacing the file never
+/// touches a loaded library.
+// `dlopen` по уже загруженному пути вернул бы старую библиотеку, а запись поверх
+// отображённого файла обрушила бы сервер по SIGBUS. Копия лежит в новом каталоге с правами
+// 0700, чтобы другой пользователь не подменил её до загрузки. Загруженная копия больше
+// не нужна и сразу удаляется.
+pub fn load_copy(path: &Path) -> Result<SmartHouseLibrary, ReloadError> {
+    let dir = std::env::temp_dir().join(format!("libsmart_house-{}", Uuid::new_v4()));
+    // каталог с таким именем уже мог создать кто-то другой
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .map_err(ReloadError::Copy)?;
+    let copy = dir.join("libsmart_house.so");
+    let library = std::fs::copy(path, &copy)
+        .map_err(ReloadError::Copy)
+        .and_then(|_| SmartHouseLibrary::load(&copy).map_err(ReloadError::Load));
+    let _ = std::fs::remove_dir_all(&dir);
+    library
+}
+
+/// Loads the library at `path` and moves all houses of `store` into it, see
+/// [`HouseStore::migrate`]. Requests wait only while the houses are moved.
+pub fn reload(store: &RwLock<HouseStore>, path: &Path) -> Result<(), ReloadError> {
+    let library = load_copy(path)?;
+    store.write().unwrap().migrate(Box::new(library))
+}
+
+// Время изменения и размер файла библиотеки.
+type Stamp = (SystemTime, u64);
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Notices a new version of the library file.
+pub struct LibraryWatcher {
+    path: PathBuf,
+    // версия, которую уже пробовали загрузить
+    loaded: Option<Stamp>,
+    // версия на прошлой проверке
+    seen: Option<Stamp>,
+}
+
+impl LibraryWatcher {
+    /// Watcher of `path`, its current version counts as loaded.
+    pub fn new(path: PathBuf) -> Self {
+        let loaded = stamp(&path);
+        LibraryWatcher {
+            path,
+            loaded,
+            seen: loaded,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the file has a new version to load. A version counts once it stays the same
+    /// for two checks in a row, so a file still being copied is not loaded.
+    pub fn poll(&mut self) -> bool {
+        let current = stamp(&self.path);
+        let stable = current == self.seen;
+        self.seen = current;
+        if current.is_some() && stable && current != self.loaded {
+            self.loaded = current;
+            return true;
+        }
+        false
+    }
+}
+
+/// Checks the library file every `period` and reloads it into `store` when it changes.
+/// A version that fails to load is skipped until the file changes again.
+pub async fn watch(store: SharedStore, path: PathBuf, period: Duration) {
+    let mut watcher = LibraryWatcher::new(path);
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if !watcher.poll() {
+            continue;
+        }
+        match reload(&store, watcher.path()) {
+            Ok(()) => info!(
+                "reloaded {}, library version {}",
+                watcher.path().display(),
+                store.read().unwrap().generation()
+            ),
+            Err(error) => warn!(
+                "keeping the loaded library, cannot reload {}: {}",
+                watcher.path().display(),
+                error
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[warn(unused_imports)]
+    use super::*;
+
+    const LIBRARY_PATH: &str = "libs/libsmart_house.so";
+
+    fn store_with_house() -> RwLock<HouseStore> {
+        let library = SmartHouseLibrary::load(LIBRARY_PATH).unwrap();
+        let mut store = HouseStore::new(Box::new(library));
+        let house = store.get_or_create("сессия").unwrap();
+        house.add_room("комната 1".to_string()).unwrap();
+        house
+            .add_test_device_outlet(
+                "комната 1".to_string(),
+                "устройство 1".to_string(),
+                "тестовое устройство 1".to_string(),
+            )
+            .unwrap();
+        RwLock::new(store)
+    }
+
+    fn report(store: &RwLock<HouseStore>) -> String {
+        store
+            .read()
+            .unwrap()
+            .house("сессия")
+            .unwrap()
+            .report()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_reload_migrates_houses() {
+        let store = store_with_house();
+        let before = report(&store);
+
+        reload(&store, Path::new(LIBRARY_PATH)).unwrap();
+
+        assert_eq!(store.read().unwrap().generation(), 2);
+        assert_eq!(report(&store), before);
+        // новые дома создаёт новая библиотека
+        let mut store = store.write().unwrap();
+        let house = store.get_or_create("новая сессия").unwrap();
+        assert_eq!(house.get_list_rooms_name().unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_reload_rolls_back() {
+        let store = store_with_house();
+        let before = report(&store);
+
+        let error = reload(&store, Path::new("libs/нет такой.so")).unwrap_err();
+        assert!(matches!(error, ReloadError::Copy(_)));
+
+        let broken = std::env::temp_dir().join(format!("broken-{}.so", std::process::id()));
+        std::fs::write(&broken, "не библиотека").unwrap();
+        let error = reload(&store, &broken).unwrap_err();
+        std::fs::remove_file(&broken).unwrap();
+        assert!(matches!(error, ReloadError::Load(LoadError::Open(_))));
+
+        assert_eq!(store.read().unwrap().generation(), 1);
+        assert_eq!(report(&store), before);
+    }
+
+    #[test]
+    fn test_reload_rolls_back_abi_mismatch() {
+        let store = store_with_house();
+        let before = report(&store);
+
+        // библиотека другой версии ABI, собранная тем же компилятором C, что линкует тесты
+        let dir = std::env::temp_dir().join(format!("abi-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let source = dir.join("abi.c");
+        let library = dir.join("libsmart_house.so");
+        std::fs::write(
+            &source,
+            "unsigned int smart_house_abi_version(void) { return 1; }\n",
+        )
+        .unwrap();
+        let built = std::process::Command::new("cc")
+            .args(["-shared", "-fPIC", "-o"])
+            .arg(&library)
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(built.success());
+
+        let error = reload(&store, &library).unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            error,
+            ReloadError::Load(LoadError::AbiMismatch {
+                found: 1,
+                expected: crate::c_lib::ABI_VERSION,
+            })
+        ));
+
+        assert_eq!(store.read().unwrap().generation(), 1);
+        assert_eq!(report(&store), before);
+    }
+
+    // снимок одной реализации читается другой
+    #[cfg(feature = "static-link")]
+    #[test]
+    fn test_migrate_to_linked() {
+        let store = store_with_house();
+        let before = report(&store);
+
+        let linked = Box::new(crate::static_lib::LinkedBackend);
+        store.write().unwrap().migrate(linked).unwrap();
+
+        assert_eq!(store.read().unwrap().generation(), 2);
+        assert_eq!(report(&store), before);
+    }
+
+    #[test]
+    fn test_watcher() {
+        let path = std::env::temp_dir().join(format!("watched-{}.so", std::process::id()));
+        std::fs::write(&path, "версия 1").unwrap();
+        let mut watcher = LibraryWatcher::new(path.clone());
+        assert!(!watcher.poll());
+
+        std::fs::write(&path, "версия 2, длиннее").unwrap();
+        // файл мог ещё копироваться
+        assert!(!watcher.poll());
+        assert!(watcher.poll());
+        assert!(!watcher.poll());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(!watcher.poll());
+        assert!(!watcher.poll());
+    }
+}